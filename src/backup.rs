@@ -0,0 +1,113 @@
+use crate::format::FORMAT_VERSION;
+
+// A backup is a new directory laid out like a data directory, holding the
+// catalog, one table file per table and the index files, plus a manifest with
+// the size and crc32 of each of them. Rows are stored frozen, visible to every
+// snapshot, so the directory needs no commit log and can be opened as it is.
+//
+// Manifest layout, one file per line after the first:
+//   db_project backup, on-disk format version N
+//   <crc32 as 8 hex digits> <size in bytes> <file name>
+
+pub const MANIFEST_FILE: &str = "backup.manifest";
+
+const HEADER: &str = "db_project backup, on-disk format version ";
+
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub size: u64,
+    pub checksum: u32,
+}
+
+impl Entry {
+    pub fn new(name: &str, bytes: &[u8]) -> Self {
+        Entry {
+            name: name.to_string(),
+            size: bytes.len() as u64,
+            checksum: crc32fast::hash(bytes),
+        }
+    }
+
+    // True if `bytes` are the contents the entry was made from
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        bytes.len() as u64 == self.size && crc32fast::hash(bytes) == self.checksum
+    }
+}
+
+pub fn write(entries: &[Entry]) -> String {
+    let mut manifest = format!("{}{}\n", HEADER, FORMAT_VERSION);
+    for entry in entries {
+        manifest.push_str(&format!(
+            "{:08x} {} {}\n",
+            entry.checksum, entry.size, entry.name
+        ));
+    }
+    manifest
+}
+
+// The entries of a manifest written by this build. Fails with what is wrong
+// with it.
+pub fn read(manifest: &str) -> Result<Vec<Entry>, String> {
+    let mut lines = manifest.lines();
+    let version = lines
+        .next()
+        .and_then(|line| line.strip_prefix(HEADER))
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or("its manifest does not start with a format version")?;
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "it was written in on-disk format version {}, not version {} this build writes",
+            version, FORMAT_VERSION
+        ));
+    }
+    lines
+        .enumerate()
+        .map(|(index, line)| {
+            let mut fields = line.splitn(3, ' ');
+            let checksum = fields.next().and_then(|f| u32::from_str_radix(f, 16).ok());
+            let size = fields.next().and_then(|f| f.parse().ok());
+            // Only plain file names, so restoring never reaches outside the directory
+            let name = fields
+                .next()
+                .filter(|name| !name.is_empty() && !name.contains(['/', '\\']) && *name != "..");
+            match (checksum, size, name) {
+                (Some(checksum), Some(size), Some(name)) => Ok(Entry {
+                    name: name.to_string(),
+                    size,
+                    checksum,
+                }),
+                _ => Err(format!("line {} of its manifest is malformed", index + 2)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_read_back_their_entries() {
+        let entries = vec![
+            Entry::new("dbfile.bin", b"catalog"),
+            Entry::new("t.tab", b""),
+        ];
+        let manifest = write(&entries);
+        assert_eq!(read(&manifest).unwrap(), entries);
+        assert!(entries[0].matches(b"catalog"));
+        assert!(!entries[0].matches(b"catalog!"));
+
+        let newer = manifest.replacen(
+            &FORMAT_VERSION.to_string(),
+            &(FORMAT_VERSION + 1).to_string(),
+            1,
+        );
+        assert!(read(&newer).is_err());
+        let escaping = format!("{}00000000 0 ../dbfile.bin\n", write(&[]));
+        assert_eq!(
+            read(&escaping),
+            Err("line 2 of its manifest is malformed".to_string())
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use crate::file_manager::FileManager;
+use crate::page::PAGE_SIZE;
+use crate::wal::{apply_file, RecoveryReport, Wal};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+// Number of page frames used when no size is configured
+pub const DEFAULT_POOL_SIZE: usize = 256;
+
+// A page is identified by the file it belongs to and its number within that file
+type PageKey = (String, u32);
+
+struct Frame {
+    key: Option<PageKey>,
+    data: Vec<u8>,
+    pin_count: u32,
+    dirty: bool,
+    // Second-chance bit for the clock sweep
+    referenced: bool,
+    // Log record of the last change; the log is flushed up to here before the page is written
+    page_lsn: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BufferPoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub writes: u64,
+}
+
+impl BufferPoolStats {
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+// Fixed-size cache of file pages shared by every statement run through a
+// `DatabaseManager`. Pages are pinned while in use, written back lazily when
+// dirty, and evicted with the clock (second chance) policy.
+//
+// Every write, whether of a page or of a whole file, is logged in the
+// write-ahead log first. A dirty page may be written back before its
+// transaction commits, since the log holds its before image for undo.
+pub struct BufferPool {
+    frames: Vec<Frame>,
+    page_table: HashMap<PageKey, usize>,
+    files: HashMap<String, File>,
+    clock_hand: usize,
+    stats: BufferPoolStats,
+    wal: Wal,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize, wal: Wal) -> Self {
+        BufferPool {
+            frames: (0..capacity.max(1))
+                .map(|_| Frame {
+                    key: None,
+                    data: vec![0; PAGE_SIZE],
+                    pin_count: 0,
+                    dirty: false,
+                    referenced: false,
+                    page_lsn: 0,
+                })
+                .collect(),
+            page_table: HashMap::new(),
+            files: HashMap::new(),
+            clock_hand: 0,
+            stats: BufferPoolStats::default(),
+            wal,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn wal_mut(&mut self) -> &mut Wal {
+        &mut self.wal
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.stats
+    }
+
+    pub fn is_cached(&self, path: &str, page_id: u32) -> bool {
+        self.page_table.contains_key(&(path.to_string(), page_id))
+    }
+
+    fn file(&mut self, path: &str) -> io::Result<&mut File> {
+        if !self.files.contains_key(path) {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            self.files.insert(path.to_string(), file);
+        }
+        Ok(self.files.get_mut(path).expect("file was just opened"))
+    }
+
+    fn write_frame(&mut self, frame_id: usize) -> io::Result<()> {
+        let (path, page_id) = self.frames[frame_id]
+            .key
+            .clone()
+            .expect("only occupied frames are written");
+        self.wal.flush(self.frames[frame_id].page_lsn)?;
+        self.wal.write_point()?;
+        let data = std::mem::take(&mut self.frames[frame_id].data);
+        let result = self.file(&path).and_then(|file| {
+            file.seek(SeekFrom::Start(page_id as u64 * PAGE_SIZE as u64))?;
+            file.write_all(&data)
+        });
+        self.frames[frame_id].data = data;
+        result?;
+        self.frames[frame_id].dirty = false;
+        self.stats.writes += 1;
+        Ok(())
+    }
+
+    // Find a frame to reuse, writing back its page if it is dirty
+    fn victim(&mut self) -> io::Result<usize> {
+        // Two full sweeps clear every reference bit, so a third finds a victim if one exists
+        for _ in 0..self.frames.len() * 3 {
+            let frame_id = self.clock_hand;
+            self.clock_hand = (self.clock_hand + 1) % self.frames.len();
+            let frame = &mut self.frames[frame_id];
+            if frame.pin_count > 0 {
+                continue;
+            }
+            if frame.referenced {
+                frame.referenced = false;
+                continue;
+            }
+            if let Some(key) = frame.key.clone() {
+                if frame.dirty {
+                    self.write_frame(frame_id)?;
+                }
+                self.page_table.remove(&key);
+                self.frames[frame_id].key = None;
+                self.stats.evictions += 1;
+            }
+            return Ok(frame_id);
+        }
+        Err(io::Error::other(
+            "buffer pool exhausted: every page is pinned",
+        ))
+    }
+
+    // Pin the page in a frame, reading it from disk on a miss
+    pub fn fetch_page(&mut self, path: &str, page_id: u32) -> io::Result<usize> {
+        let key = (path.to_string(), page_id);
+        if let Some(&frame_id) = self.page_table.get(&key) {
+            self.stats.hits += 1;
+            let frame = &mut self.frames[frame_id];
+            frame.pin_count += 1;
+            frame.referenced = true;
+            return Ok(frame_id);
+        }
+        self.stats.misses += 1;
+        let frame_id = self.victim()?;
+        let mut data = std::mem::take(&mut self.frames[frame_id].data);
+        let result = self.file(path).and_then(|file| {
+            file.seek(SeekFrom::Start(page_id as u64 * PAGE_SIZE as u64))?;
+            file.read_exact(&mut data)
+        });
+        self.frames[frame_id].data = data;
+        result?;
+        self.install(frame_id, key, false);
+        Ok(frame_id)
+    }
+
+    // Pin a zeroed frame for a page that does not exist on disk yet
+    pub fn new_page(&mut self, path: &str, page_id: u32) -> io::Result<usize> {
+        let key = (path.to_string(), page_id);
+        let frame_id = match self.page_table.get(&key) {
+            Some(&frame_id) => {
+                self.frames[frame_id].pin_count += 1;
+                frame_id
+            }
+            None => {
+                let frame_id = self.victim()?;
+                self.install(frame_id, key, true);
+                frame_id
+            }
+        };
+        self.frames[frame_id].data.fill(0);
+        self.frames[frame_id].dirty = true;
+        Ok(frame_id)
+    }
+
+    fn install(&mut self, frame_id: usize, key: PageKey, dirty: bool) {
+        self.page_table.insert(key.clone(), frame_id);
+        let frame = &mut self.frames[frame_id];
+        frame.key = Some(key);
+        frame.pin_count = 1;
+        frame.dirty = dirty;
+        frame.referenced = true;
+        frame.page_lsn = 0;
+    }
+
+    pub fn page(&self, frame_id: usize) -> &[u8] {
+        &self.frames[frame_id].data
+    }
+
+    // Mutable access marks the page dirty
+    pub fn page_mut(&mut self, frame_id: usize) -> &mut [u8] {
+        self.frames[frame_id].dirty = true;
+        &mut self.frames[frame_id].data
+    }
+
+    pub fn unpin(&mut self, frame_id: usize) {
+        let frame = &mut self.frames[frame_id];
+        debug_assert!(frame.pin_count > 0, "unpin of an unpinned frame");
+        frame.pin_count = frame.pin_count.saturating_sub(1);
+    }
+
+    // Copy of a page's bytes; the page is pinned only for the duration of the copy
+    pub fn read_page(&mut self, path: &str, page_id: u32) -> io::Result<Vec<u8>> {
+        let frame_id = self.fetch_page(path, page_id)?;
+        let data = self.page(frame_id).to_vec();
+        self.unpin(frame_id);
+        Ok(data)
+    }
+
+    // Current bytes of a page on disk; pages past the end of the file read as zeros
+    fn disk_page(&mut self, path: &str, page_id: u32) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(PAGE_SIZE);
+        if self.files.contains_key(path) || Path::new(path).exists() {
+            let file = self.file(path)?;
+            file.seek(SeekFrom::Start(page_id as u64 * PAGE_SIZE as u64))?;
+            file.take(PAGE_SIZE as u64).read_to_end(&mut data)?;
+        }
+        data.resize(PAGE_SIZE, 0);
+        Ok(data)
+    }
+
+    // Overwrite a page in the pool; it reaches the disk when flushed or evicted
+    pub fn write_page(&mut self, path: &str, page_id: u32, data: &[u8]) -> io::Result<()> {
+        let before = match self.page_table.get(&(path.to_string(), page_id)) {
+            Some(&frame_id) => self.frames[frame_id].data.clone(),
+            None => self.disk_page(path, page_id)?,
+        };
+        let lsn = self
+            .wal
+            .log_page_write(path, page_id, before, data.to_vec())?;
+        let frame_id = self.new_page(path, page_id)?;
+        self.page_mut(frame_id).copy_from_slice(data);
+        self.frames[frame_id].page_lsn = lsn;
+        self.unpin(frame_id);
+        Ok(())
+    }
+
+    // Replace a whole file that is not accessed through pages, or remove it when
+    // `contents` is `None`. Cached pages of the file are written out first so the
+    // logged before image is complete.
+    pub fn write_file(&mut self, path: &str, contents: Option<Vec<u8>>) -> io::Result<()> {
+        self.flush_file(path)?;
+        self.discard_file(path);
+        let before = match FileManager::new(path).read_file() {
+            Ok(bytes) => Some(bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        if before.is_none() && contents.is_none() {
+            return Ok(());
+        }
+        self.wal.log_file_write(path, before, contents.clone())?;
+        self.wal.write_point()?;
+        apply_file(path, contents.as_ref())
+    }
+
+    // Write back every dirty page of `path` and sync the file to disk
+    fn flush_file(&mut self, path: &str) -> io::Result<()> {
+        let dirty: Vec<usize> = (0..self.frames.len())
+            .filter(|frame_id| {
+                let frame = &self.frames[*frame_id];
+                frame.dirty && frame.key.as_ref().is_some_and(|(p, _)| p == path)
+            })
+            .collect();
+        for frame_id in dirty {
+            self.write_frame(frame_id)?;
+        }
+        if let Some(file) = self.files.get(path) {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Write back every dirty page and sync every open file
+    pub fn flush_all(&mut self) -> io::Result<()> {
+        for frame_id in 0..self.frames.len() {
+            if self.frames[frame_id].dirty {
+                self.write_frame(frame_id)?;
+            }
+        }
+        for file in self.files.values() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.wal.in_transaction()
+    }
+
+    pub fn write_point(&mut self) -> io::Result<()> {
+        self.wal.write_point()
+    }
+
+    // Wait up to `timeout` for the log lock that makes this process the only
+    // writer. A log left behind by a process that died is recovered first.
+    // Returns `None` if another process kept the lock.
+    pub fn acquire_log(&mut self, timeout: Duration) -> io::Result<Option<RecoveryReport>> {
+        if !self.wal.lock(timeout)? {
+            return Ok(None);
+        }
+        self.recover().map(Some)
+    }
+
+    fn recover(&mut self) -> io::Result<RecoveryReport> {
+        let report = self.wal.recover()?;
+        if report.redone + report.undone > 0 {
+            self.clear();
+        }
+        Ok(report)
+    }
+
+    pub fn release_log(&mut self) -> io::Result<()> {
+        self.wal.unlock()
+    }
+
+    pub fn begin(&mut self, txn_id: u64) -> io::Result<()> {
+        self.wal.begin(txn_id)
+    }
+
+    // Make the transaction durable and force its pages out. The log is emptied
+    // by `end` once the commit has been recorded.
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.wal.commit()?;
+        self.flush_all()
+    }
+
+    // Empty the log after a commit and let other writers in. Should emptying
+    // fail, the next writer finds the committed work in the log and redoes it.
+    pub fn end(&mut self) -> io::Result<()> {
+        let truncated = self.wal.truncate();
+        self.release_log()?;
+        truncated
+    }
+
+    // Empty the log between two statements of a transaction and let other
+    // writers in. Every page must have been written out. Should emptying fail
+    // the log is kept, so the statement can still be rolled back from it.
+    pub fn suspend(&mut self) -> io::Result<()> {
+        self.wal.truncate()?;
+        self.release_log()
+    }
+
+    // Forget every cached page and open file, e.g. after another process changed them
+    pub fn clear(&mut self) {
+        for frame in self.frames.iter_mut() {
+            frame.key = None;
+            frame.dirty = false;
+            frame.pin_count = 0;
+            frame.referenced = false;
+        }
+        self.page_table.clear();
+        self.files.clear();
+    }
+
+    // Every dirty page belongs to the running transaction, so the cache is
+    // dropped and the log writes the before images back to disk
+    pub fn rollback(&mut self) -> io::Result<()> {
+        self.clear();
+        self.wal.rollback()
+    }
+
+    pub fn savepoint(&self) -> usize {
+        self.wal.savepoint()
+    }
+
+    // Pages changed before the savepoint have to survive, so everything is
+    // written out before the later changes are undone on disk
+    pub fn rollback_to(&mut self, savepoint: usize) -> io::Result<()> {
+        self.flush_all()?;
+        self.clear();
+        self.wal.rollback_to(savepoint)
+    }
+
+    // Forget every cached page of `path` without writing it, e.g. before the file is removed
+    pub fn discard_file(&mut self, path: &str) {
+        for frame in self.frames.iter_mut() {
+            if frame.key.as_ref().is_some_and(|(p, _)| p == path) {
+                self.page_table
+                    .remove(frame.key.as_ref().expect("checked above"));
+                frame.key = None;
+                frame.dirty = false;
+                frame.pin_count = 0;
+                frame.referenced = false;
+            }
+        }
+        self.files.remove(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::fs;
+
+    #[test]
+    fn clock_evicts_unpinned_pages_and_writes_back_dirty_ones() {
+        let dir = temp_dir("pool");
+        let path = dir.join("t.tab").to_string_lossy().into_owned();
+        // Page n is filled with the byte n
+        let pages: Vec<u8> = (0..5u8).flat_map(|n| vec![n; PAGE_SIZE]).collect();
+        fs::write(&path, &pages).unwrap();
+        let wal = Wal::open(&dir.join("dbfile.wal").to_string_lossy()).unwrap();
+        let mut pool = BufferPool::new(3, wal);
+        let disk_page = |page_id: usize| fs::read(&path).unwrap()[page_id * PAGE_SIZE];
+
+        for page_id in 0..3 {
+            let frame_id = pool.fetch_page(&path, page_id).unwrap();
+            assert_eq!(pool.page(frame_id)[0], page_id as u8);
+            pool.unpin(frame_id);
+        }
+        // Page 1 stays pinned; page 2 is changed in the pool only
+        let pinned = pool.fetch_page(&path, 1).unwrap();
+        let frame_id = pool.fetch_page(&path, 2).unwrap();
+        pool.page_mut(frame_id).fill(9);
+        pool.unpin(frame_id);
+        assert_eq!(disk_page(2), 2);
+
+        // The sweep clears every reference bit, skips the pinned frame and takes
+        // page 0; the next miss takes page 2, which is written back first
+        for page_id in 3..5 {
+            let frame_id = pool.fetch_page(&path, page_id).unwrap();
+            pool.unpin(frame_id);
+        }
+        assert!(!pool.is_cached(&path, 0));
+        assert!(pool.is_cached(&path, 1));
+        assert!(!pool.is_cached(&path, 2));
+        assert_eq!(disk_page(2), 9);
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.hits, stats.misses, stats.evictions, stats.writes),
+            (2, 5, 2, 1)
+        );
+
+        // With every frame pinned there is nothing left to evict
+        let others: Vec<usize> = [3, 4]
+            .iter()
+            .map(|&page_id| pool.fetch_page(&path, page_id).unwrap())
+            .collect();
+        assert!(pool.fetch_page(&path, 0).is_err());
+        assert_eq!(pool.page(pinned)[0], 1);
+        for frame_id in others {
+            pool.unpin(frame_id);
+        }
+
+        // Dirty pages reach the disk on a flush, clean ones are not rewritten
+        pool.page_mut(pinned).fill(7);
+        pool.unpin(pinned);
+        pool.flush_all().unwrap();
+        assert_eq!(disk_page(1), 7);
+        assert_eq!(pool.stats().writes, 2);
+        assert_eq!(pool.read_page(&path, 2).unwrap(), vec![9; PAGE_SIZE]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+
+// Outcome of `CHECK DATABASE`: how much was looked at and every problem found.
+// Checking goes on past a problem wherever it can, so one run lists them all.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub tables: usize,
+    pub rows: usize,
+    pub indexes: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    // A file that cannot be read, is in an unknown format or fails its checksum
+    CorruptFile {
+        path: String,
+        error: String,
+    },
+    // A data or index file that no table or index in the catalog owns
+    OrphanFile {
+        path: String,
+    },
+    // The catalog contradicts itself, e.g. an index on a missing column
+    BadDefinition {
+        table: String,
+        error: String,
+    },
+    UndecodableRow {
+        table: String,
+        row_id: u64,
+        error: String,
+    },
+    WrongArity {
+        table: String,
+        row_id: u64,
+        expected: usize,
+        found: usize,
+    },
+    WrongType {
+        table: String,
+        row_id: u64,
+        column: String,
+    },
+    NullValue {
+        table: String,
+        row_id: u64,
+        column: String,
+    },
+    TooLong {
+        table: String,
+        row_id: u64,
+        column: String,
+        max: u32,
+    },
+    // Two live rows share the key of a UNIQUE index
+    DuplicateKey {
+        table: String,
+        index: String,
+    },
+    // The index file does not list exactly the rows of its table
+    IndexMismatch {
+        table: String,
+        index: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::CorruptFile { path, error } => {
+                write!(f, "Cannot read '{}': {}", path, error)
+            }
+            Problem::OrphanFile { path } => {
+                write!(f, "'{}' belongs to no table in the catalog", path)
+            }
+            Problem::BadDefinition { table, error } => {
+                write!(f, "Definition of table '{}' is invalid: {}", table, error)
+            }
+            Problem::UndecodableRow {
+                table,
+                row_id,
+                error,
+            } => write!(
+                f,
+                "Row {} of table '{}' cannot be decoded: {}",
+                row_id, table, error
+            ),
+            Problem::WrongArity {
+                table,
+                row_id,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Row {} of table '{}' has {} value(s) for {} column(s)",
+                row_id, table, found, expected
+            ),
+            Problem::WrongType {
+                table,
+                row_id,
+                column,
+            } => write!(
+                f,
+                "Row {} of table '{}' holds a value of the wrong type in column '{}'",
+                row_id, table, column
+            ),
+            Problem::NullValue {
+                table,
+                row_id,
+                column,
+            } => write!(
+                f,
+                "Row {} of table '{}' is NULL in NOT NULL column '{}'",
+                row_id, table, column
+            ),
+            Problem::TooLong {
+                table,
+                row_id,
+                column,
+                max,
+            } => write!(
+                f,
+                "Row {} of table '{}' holds more than {} characters in column '{}'",
+                row_id, table, max, column
+            ),
+            Problem::DuplicateKey { table, index } => write!(
+                f,
+                "Table '{}' holds duplicate keys for unique index '{}'",
+                table, index
+            ),
+            Problem::IndexMismatch { table, index } => write!(
+                f,
+                "Index '{}' does not match the rows of table '{}'",
+                index, table
+            ),
+        }
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}.", problem)?;
+        }
+        write!(
+            f,
+            "Checked {} table(s), {} row(s) and {} index(es): {} problem(s) found.",
+            self.tables,
+            self.rows,
+            self.indexes,
+            self.problems.len()
+        )
+    }
+}
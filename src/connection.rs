@@ -0,0 +1,458 @@
+use crate::buffer_pool::{BufferPoolStats, DEFAULT_POOL_SIZE};
+use crate::check::CheckReport;
+use crate::data_dir;
+use crate::database_manager::{DatabaseError, DatabaseManager};
+use crate::dump;
+use crate::file_lock::DEFAULT_LOCK_TIMEOUT;
+use crate::format::FORMAT_VERSION;
+use crate::generated_types::generated_types::{cell_value, ColumnDefinition, TableDefinition};
+use crate::nom_parser::{parse_command, Command, IsolationLevel, Target, Value};
+use crate::result_set::{type_name, ResultSet, ToCell};
+use crate::statements;
+use crate::wal::Recovery;
+use nom::character::complete::multispace0;
+use nom::sequence::delimited;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+// The public face of the engine: a `Database` names a data directory and how
+// to open it, and each `Connection` to it is a session with its own buffer
+// pool and transaction. Sessions of this and other processes see each other's
+// commits and are kept apart by locks.
+
+pub struct Database {
+    catalog_path: String,
+    buffer_pool_size: usize,
+    lock_timeout: Duration,
+}
+
+impl Database {
+    // Open the database in the data directory `dir`, creating it if it does not exist
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+        Ok(Database {
+            catalog_path: data_dir::open(dir.as_ref())?,
+            buffer_pool_size: DEFAULT_POOL_SIZE,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        })
+    }
+
+    // Number of 4 KiB pages each connection may cache
+    pub fn with_buffer_pool_size(mut self, frames: usize) -> Self {
+        self.buffer_pool_size = frames;
+        self
+    }
+
+    // How long to wait for locks held by other sessions before giving up
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    // A new session. The first one after a crash recovers the database.
+    pub fn connect(&self) -> Result<Connection, DatabaseError> {
+        let manager = DatabaseManager::with_buffer_pool_size(
+            self.catalog_path.clone(),
+            self.buffer_pool_size,
+        )?
+        .with_lock_timeout(self.lock_timeout);
+        Ok(Connection { manager })
+    }
+
+    // Check every file of the database against the catalog
+    pub fn check(&self) -> Result<CheckReport, DatabaseError> {
+        self.connect()?.manager.check_database()
+    }
+
+    // Upgrade the files to the current on-disk format after copying them to
+    // `backup_dir`; returns the files that changed
+    pub fn migrate(&self, backup_dir: &Path) -> Result<Vec<String>, DatabaseError> {
+        self.connect()?.manager.migrate(backup_dir)
+    }
+}
+
+// What a statement did
+#[derive(Debug)]
+pub enum Outcome {
+    Rows(ResultSet),
+    Tables(Vec<TableDefinition>),
+    Schema(TableDefinition),
+    Check(CheckReport),
+    TableCreated(String),
+    // CREATE TABLE IF NOT EXISTS found the table already there
+    TableExists(String),
+    TableDropped(String),
+    // DROP TABLE IF EXISTS found no such table
+    NoSuchTable(String),
+    TableTruncated(String),
+    IndexCreated(String),
+    IndexDropped(String),
+    Inserted(usize),
+    Updated(usize),
+    Deleted(usize),
+    // Rows read from a file into a table, and written from one to a file
+    Imported(usize),
+    Exported(usize),
+    // Tables written to a backup, and read back from one
+    BackedUp(usize),
+    Restored(usize),
+    Begun,
+    Committed,
+    RolledBack,
+    SavepointSet(String),
+    RolledBackTo(String),
+    SavepointReleased(String),
+}
+
+pub struct Connection {
+    manager: DatabaseManager,
+}
+
+impl Connection {
+    // Run one statement
+    pub fn run(&self, statement: &str) -> Result<Outcome, DatabaseError> {
+        self.run_command(without_parameters(parse(statement)?)?)
+    }
+
+    // Run every statement of `sql` in order, stopping at the first failure.
+    // Returns how many rows they inserted, updated and deleted.
+    pub fn execute(&self, sql: &str) -> Result<usize, DatabaseError> {
+        let split = statements::split(sql);
+        let mut changed = 0;
+        for statement in split.statements.iter().chain(&split.rest) {
+            changed += rows_changed(&self.run(&statement.text)?);
+        }
+        Ok(changed)
+    }
+
+    // Rows of a single SELECT
+    pub fn query(&self, sql: &str) -> Result<ResultSet, DatabaseError> {
+        self.select(without_parameters(parse(sql)?)?)
+    }
+
+    // Parse a single statement with `?` or `$n` placeholders for values, to
+    // be run any number of times with values bound to them
+    pub fn prepare(&self, sql: &str) -> Result<Statement<'_>, DatabaseError> {
+        let mut command = parse(sql)?;
+        let table = match command.table() {
+            Some(table) => Some(self.schema(table)?),
+            None => None,
+        };
+        let mut parameters: Vec<Vec<Slot>> = Vec::new();
+        for (target, value) in command.values_mut() {
+            let (Value::Param(number), Some(table)) = (&*value, &table) else {
+                continue;
+            };
+            if parameters.len() < *number {
+                parameters.resize_with(*number, Vec::new);
+            }
+            let (column, stored) = match target {
+                // Rows longer than the table are refused when the statement runs
+                Target::Position(position) => match table.columns.get(position) {
+                    Some(column) => (column, true),
+                    None => continue,
+                },
+                Target::Assigned(name) => (find_column(table, name)?, true),
+                Target::Compared(name) => (find_column(table, name)?, false),
+            };
+            parameters[number - 1].push(Slot {
+                column: column.clone(),
+                stored,
+            });
+        }
+        Ok(Statement {
+            connection: self,
+            command,
+            bindings: vec![None; parameters.len()],
+            parameters,
+        })
+    }
+
+    pub fn tables(&self) -> Result<Vec<TableDefinition>, DatabaseError> {
+        self.manager.list_tables()
+    }
+
+    pub fn schema(&self, table: &str) -> Result<TableDefinition, DatabaseError> {
+        self.manager.table_schema(table)
+    }
+
+    // Write a SQL script that rebuilds the database: every table with its
+    // indexes, then INSERTs of their rows. Everything is read from one
+    // snapshot, that of the open transaction if there is one.
+    pub fn dump(&self, out: &mut impl Write) -> Result<(), DatabaseError> {
+        if self.in_transaction() {
+            return self.write_dump(out);
+        }
+        self.manager.begin_transaction(IsolationLevel::Snapshot)?;
+        let result = self.write_dump(out);
+        // Nothing was changed, so there is nothing to keep
+        self.manager.rollback_transaction()?;
+        result
+    }
+
+    fn write_dump(&self, out: &mut impl Write) -> Result<(), DatabaseError> {
+        let tables = self.tables()?;
+        writeln!(
+            out,
+            "-- db_project dump, on-disk format version {}",
+            FORMAT_VERSION
+        )?;
+        for table in &tables {
+            writeln!(out, "{}", dump::create_table_sql(table))?;
+            for index in &table.indexes {
+                writeln!(out, "{}", dump::create_index_sql(&table.name, index))?;
+            }
+        }
+        for table in &tables {
+            let rows = self.query(&format!("SELECT * FROM {}", table.name))?;
+            let rows: Vec<_> = rows.rows().map(|row| row.cells()).collect();
+            for batch in rows.chunks(dump::ROWS_PER_INSERT) {
+                writeln!(
+                    out,
+                    "{}",
+                    dump::insert_sql(&table.name, batch.iter().copied())
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether BEGIN started a transaction that is still open
+    pub fn in_transaction(&self) -> bool {
+        self.manager.in_explicit_transaction()
+    }
+
+    // What recovering from a crash of another session did since the last
+    // call. The first statement to write after the crash recovers, or
+    // `connect` if it finds the log unfinished.
+    pub fn take_recovery(&self) -> Option<Recovery> {
+        self.manager.take_recovery()
+    }
+
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.manager.buffer_pool_stats()
+    }
+
+    pub fn buffer_pool_capacity(&self) -> usize {
+        self.manager.buffer_pool_capacity()
+    }
+
+    fn select(&self, command: Command) -> Result<ResultSet, DatabaseError> {
+        if !matches!(command, Command::Select { .. }) {
+            return Err(DatabaseError::InvalidCommand("query".to_string()));
+        }
+        self.manager.select(command)
+    }
+
+    fn run_command(&self, command: Command) -> Result<Outcome, DatabaseError> {
+        let db = &self.manager;
+        Ok(match command {
+            Command::CreateTable { ref name, .. } => {
+                let name = name.clone();
+                match db.create_table(command)? {
+                    true => Outcome::TableCreated(name),
+                    false => Outcome::TableExists(name),
+                }
+            }
+            Command::DropTable { name, if_exists } => match db.drop_table(&name, if_exists)? {
+                true => Outcome::TableDropped(name),
+                false => Outcome::NoSuchTable(name),
+            },
+            Command::TruncateTable { name } => {
+                db.truncate_table(&name)?;
+                Outcome::TableTruncated(name)
+            }
+            Command::ListTable => Outcome::Tables(db.list_tables()?),
+            Command::ListSchema { name } => Outcome::Schema(db.table_schema(&name)?),
+            Command::CheckDatabase => Outcome::Check(db.check_database()?),
+            command @ Command::Select { .. } => Outcome::Rows(db.select(command)?),
+            command @ Command::Insert { .. } => Outcome::Inserted(db.insert(command)?),
+            command @ Command::Update { .. } => Outcome::Updated(db.update(command)?),
+            command @ Command::Delete { .. } => Outcome::Deleted(db.delete(command)?),
+            command @ Command::CopyFrom { .. } => Outcome::Imported(db.copy_from(command)?),
+            command @ Command::CopyTo { .. } => Outcome::Exported(db.copy_to(command)?),
+            command @ Command::ImportJson { .. } => Outcome::Imported(db.import_json(command)?),
+            command @ Command::ExportJson { .. } => Outcome::Exported(db.export_json(command)?),
+            command @ Command::Backup { .. } => Outcome::BackedUp(db.backup(command)?),
+            command @ Command::Restore { .. } => Outcome::Restored(db.restore(command)?),
+            Command::CreateIndex { ref name, .. } => {
+                let name = name.clone();
+                db.create_index(command)?;
+                Outcome::IndexCreated(name)
+            }
+            Command::DropIndex { name } => {
+                db.drop_index(&name)?;
+                Outcome::IndexDropped(name)
+            }
+            Command::Begin { isolation } => {
+                db.begin_transaction(isolation)?;
+                Outcome::Begun
+            }
+            Command::Commit => {
+                db.commit_transaction()?;
+                Outcome::Committed
+            }
+            Command::Rollback => {
+                db.rollback_transaction()?;
+                Outcome::RolledBack
+            }
+            Command::Savepoint { name } => {
+                db.savepoint(&name)?;
+                Outcome::SavepointSet(name)
+            }
+            Command::RollbackTo { name } => {
+                db.rollback_to_savepoint(&name)?;
+                Outcome::RolledBackTo(name)
+            }
+            Command::ReleaseSavepoint { name } => {
+                db.release_savepoint(&name)?;
+                Outcome::SavepointReleased(name)
+            }
+        })
+    }
+}
+
+// A transaction left open is rolled back, as if the session had ended
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.in_transaction() {
+            let _ = self.manager.rollback_transaction();
+        }
+    }
+}
+
+// A parsed statement whose placeholders are filled in with `bind` before it
+// runs; values stay bound between runs until bound again.
+//
+//     let mut insert = conn.prepare("INSERT INTO t VALUES (?, ?)")?;
+//     insert.bind(1, 7)?.bind(2, "it's")?.execute()?;
+pub struct Statement<'c> {
+    connection: &'c Connection,
+    command: Command,
+    // Where each placeholder is used, by number
+    parameters: Vec<Vec<Slot>>,
+    bindings: Vec<Option<Value>>,
+}
+
+// A use of a placeholder, for checking the values bound to it
+struct Slot {
+    column: ColumnDefinition,
+    // Stored in the column rather than compared with it
+    stored: bool,
+}
+
+impl Statement<'_> {
+    // Number of placeholders, the highest one used
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.len()
+    }
+
+    // Bind `value` to placeholder `number`, counting from 1. The value must
+    // suit every column the placeholder is used with.
+    pub fn bind(&mut self, number: usize, value: impl ToCell) -> Result<&mut Self, DatabaseError> {
+        let slots = number
+            .checked_sub(1)
+            .and_then(|index| self.parameters.get(index))
+            .ok_or(DatabaseError::NoSuchParameter {
+                number,
+                count: self.parameters.len(),
+            })?;
+        let cell = value.to_cell();
+        let found = type_name(&cell);
+        for slot in slots {
+            let expected = slot.column.col_type().as_str_name();
+            let fits = match found {
+                "NULL" => !(slot.stored && slot.column.not_null),
+                found => found == expected,
+            };
+            if !fits {
+                return Err(DatabaseError::ParameterTypeMismatch {
+                    number,
+                    column: slot.column.name.clone(),
+                    expected,
+                    found,
+                });
+            }
+            if let Some(cell_value::Value::StrVal(text)) = &cell.value {
+                if slot.stored && text.chars().count() > slot.column.length as usize {
+                    return Err(DatabaseError::ParameterTooLong {
+                        number,
+                        column: slot.column.name.clone(),
+                        length: slot.column.length,
+                    });
+                }
+            }
+        }
+        self.bindings[number - 1] = Some(Value::from(cell));
+        Ok(self)
+    }
+
+    // Run the statement; returns how many rows it inserted, updated or deleted
+    pub fn execute(&self) -> Result<usize, DatabaseError> {
+        let outcome = self.connection.run_command(self.bound()?)?;
+        Ok(rows_changed(&outcome))
+    }
+
+    // Rows of the statement, which must be a SELECT
+    pub fn query(&self) -> Result<ResultSet, DatabaseError> {
+        self.connection.select(self.bound()?)
+    }
+
+    // The command with every placeholder replaced by its value
+    fn bound(&self) -> Result<Command, DatabaseError> {
+        let mut command = self.command.clone();
+        for (_, value) in command.values_mut() {
+            if let Value::Param(number) = *value {
+                *value = self.bindings[number - 1]
+                    .clone()
+                    .ok_or(DatabaseError::ParameterNotBound(number))?;
+            }
+        }
+        Ok(command)
+    }
+}
+
+fn rows_changed(outcome: &Outcome) -> usize {
+    match outcome {
+        Outcome::Inserted(count)
+        | Outcome::Updated(count)
+        | Outcome::Deleted(count)
+        | Outcome::Imported(count) => *count,
+        _ => 0,
+    }
+}
+
+fn find_column<'t>(
+    table: &'t TableDefinition,
+    name: &str,
+) -> Result<&'t ColumnDefinition, DatabaseError> {
+    table
+        .columns
+        .iter()
+        .find(|column| column.name == name)
+        .ok_or_else(|| DatabaseError::ColumnDoesNotExist(name.to_string()))
+}
+
+// Placeholders only take values through a prepared statement
+fn without_parameters(mut command: Command) -> Result<Command, DatabaseError> {
+    let unbound = command
+        .values_mut()
+        .into_iter()
+        .find_map(|(_, value)| match value {
+            Value::Param(number) => Some(*number),
+            _ => None,
+        });
+    match unbound {
+        Some(number) => Err(DatabaseError::ParameterNotBound(number)),
+        None => Ok(command),
+    }
+}
+
+// The one statement in `statement`
+fn parse(statement: &str) -> Result<Command, DatabaseError> {
+    match delimited(multispace0, parse_command, multispace0)(statement) {
+        Ok(("", command)) => Ok(command),
+        Ok((rest, _)) => Err(DatabaseError::SyntaxError(rest.trim().to_string())),
+        Err(_) => Err(DatabaseError::SyntaxError(statement.trim().to_string())),
+    }
+}
@@ -0,0 +1,161 @@
+// CSV as COPY reads and writes it (RFC 4180): fields containing the
+// delimiter, a quote or a line break are quoted, with quotes doubled.
+
+// One field of a record. Quoting tells an empty string from NULL when the
+// NULL marker is empty.
+#[derive(Debug, PartialEq)]
+pub struct Field {
+    pub text: String,
+    pub quoted: bool,
+}
+
+// A record with the line of the file it starts on
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub line: usize,
+    pub fields: Vec<Field>,
+}
+
+// Split `input` into records; blank lines are skipped. Fails with the line of
+// a quoted field that never ends.
+pub fn read(input: &str, delimiter: char) -> Result<Vec<Record>, usize> {
+    let mut records = Vec::new();
+    let mut chars = input.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = Field {
+            text: String::new(),
+            quoted: false,
+        };
+        // Whether the field is inside its quotes
+        let mut in_quotes = false;
+        let mut at_field_start = true;
+        loop {
+            let Some(c) = chars.next() else {
+                if in_quotes {
+                    return Err(start);
+                }
+                break;
+            };
+            if in_quotes {
+                match c {
+                    '"' if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.text.push('"');
+                    }
+                    '"' => in_quotes = false,
+                    c => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        field.text.push(c);
+                    }
+                }
+                continue;
+            }
+            match c {
+                '"' if at_field_start => {
+                    in_quotes = true;
+                    field.quoted = true;
+                }
+                c if c == delimiter => {
+                    fields.push(field);
+                    field = Field {
+                        text: String::new(),
+                        quoted: false,
+                    };
+                    at_field_start = true;
+                    continue;
+                }
+                '\r' if chars.peek() == Some(&'\n') => {}
+                '\n' => {
+                    line += 1;
+                    break;
+                }
+                c => field.text.push(c),
+            }
+            at_field_start = false;
+        }
+        if fields.is_empty() && field.text.is_empty() && !field.quoted {
+            continue;
+        }
+        fields.push(field);
+        records.push(Record {
+            line: start,
+            fields,
+        });
+    }
+    Ok(records)
+}
+
+// A field ready to be joined with `delimiter`; `None` is written as `null`.
+// Text that would read back as something else is quoted.
+pub fn field(text: Option<&str>, delimiter: char, null: &str) -> String {
+    match text {
+        None => null.to_string(),
+        Some(text)
+            if text == null
+                || text.starts_with('"')
+                || text.contains([delimiter, '"', '\r', '\n']) =>
+        {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        }
+        Some(text) => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(records: &[Record]) -> Vec<Vec<(&str, bool)>> {
+        records
+            .iter()
+            .map(|record| {
+                record
+                    .fields
+                    .iter()
+                    .map(|field| (field.text.as_str(), field.quoted))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn written_fields_read_back_unchanged() {
+        let values = [
+            Some("plain"),
+            Some(""),
+            None,
+            Some("a,b"),
+            Some("say \"hi\""),
+            Some("two\nlines"),
+        ];
+        let line: Vec<String> = values.iter().map(|v| field(*v, ',', "")).collect();
+        let input = format!("{}\r\n\nlast;x\n", line.join(","));
+        let records = read(&input, ',').unwrap();
+        assert_eq!(
+            texts(&records),
+            [
+                vec![
+                    ("plain", false),
+                    ("", true),
+                    ("", false),
+                    ("a,b", true),
+                    ("say \"hi\"", true),
+                    ("two\nlines", true),
+                ],
+                vec![("last;x", false)],
+            ]
+        );
+        // The quoted line break moves the second record down a line
+        assert_eq!(records[1].line, 4);
+        assert_eq!(
+            texts(&read("1;\"x;y\"", ';').unwrap()),
+            [vec![("1", false), ("x;y", true)]]
+        );
+        assert_eq!(read("1\n2,\"open\nstill open", ','), Err(2));
+    }
+}
@@ -0,0 +1,64 @@
+use crate::database_manager::DatabaseError;
+use std::fs;
+use std::path::Path;
+
+// A data directory holds one database: the catalog, table and index files,
+// the logs and the lock files. A marker file tells it apart from any other
+// directory, so pointing the binary at the wrong place fails instead of quietly
+// starting a new, empty database there.
+
+// Name of the catalog inside a data directory; every other file is named after it
+pub const CATALOG_FILE: &str = "dbfile.bin";
+
+const MARKER_FILE: &str = "db_project.dir";
+
+const MARKER_CONTENTS: &str = "db_project data directory\n";
+
+// Open the data directory `dir`, creating it if it does not exist, and return
+// the path of its catalog. An empty directory becomes a new database.
+pub fn open(dir: &Path) -> Result<String, DatabaseError> {
+    let marker = dir.join(MARKER_FILE);
+    if !marker.exists() {
+        if !dir.exists() {
+            fs::create_dir_all(dir)?;
+        } else if !dir.is_dir() || !is_database(dir)? {
+            return Err(DatabaseError::NotADataDirectory(dir.display().to_string()));
+        }
+        fs::write(&marker, MARKER_CONTENTS)?;
+    }
+    Ok(dir.join(CATALOG_FILE).to_string_lossy().into_owned())
+}
+
+// Empty, or written before data directories were marked, in which case the catalog is there
+fn is_database(dir: &Path) -> Result<bool, DatabaseError> {
+    Ok(dir.join(CATALOG_FILE).exists() || fs::read_dir(dir)?.next().is_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn only_empty_or_marked_directories_are_opened() {
+        let dir = temp_dir("data_dir");
+        let data_dir = dir.join("data");
+        // Created on first use, then recognised by its marker
+        let catalog = open(&data_dir).unwrap();
+        assert_eq!(Path::new(&catalog), data_dir.join(CATALOG_FILE));
+        assert!(data_dir.join(MARKER_FILE).exists());
+        open(&data_dir).unwrap();
+        // The parent now holds something other than a database
+        assert!(matches!(
+            open(&dir),
+            Err(DatabaseError::NotADataDirectory(_))
+        ));
+        // A catalog from before markers is adopted
+        let legacy = dir.join("legacy");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join(CATALOG_FILE), b"").unwrap();
+        fs::write(legacy.join("t.tab"), b"").unwrap();
+        open(&legacy).unwrap();
+        assert!(legacy.join(MARKER_FILE).exists());
+    }
+}
@@ -1,26 +1,112 @@
+use crate::backup::{self, MANIFEST_FILE};
+use crate::buffer_pool::{BufferPool, BufferPoolStats, DEFAULT_POOL_SIZE};
+use crate::check::{CheckReport, Problem};
+use crate::csv;
+use crate::data_dir::CATALOG_FILE;
+use crate::file_lock::{FileLocks, LockMode, LockScope, DEFAULT_LOCK_TIMEOUT};
+use crate::file_manager::FileManager;
+use crate::format::{self, CATALOG_MAGIC, FORMAT_VERSION, INDEX_MAGIC};
 use crate::generated_types::generated_types::{
-    cell_value, CellValue, ColumnDefinition, ColumnType as ProtoColumnType, Database, Row,
-    TableData, TableDefinition,
+    cell_value, CellValue, ColumnDefinition, ColumnType as ProtoColumnType, Database,
+    IndexDefinition, IndexMethod as ProtoIndexMethod, Row, TableData, TableDefinition,
 };
-use crate::nom_parser::{Column, ColumnType, Command, Value};
-use crate::table_definition::Table;
-use core::panic;
-use nom::Err;
+use crate::hash_index::HashIndex;
+use crate::heap_file::{self, HeapFile};
+use crate::index::{key_has_null, IndexKey, IndexValue, OrderedIndex, TableIndex};
+use crate::index_file::{key_size, MAX_KEY_SIZE};
+use crate::json::{self, Json};
+use crate::lock_manager::{LockManager, Wait};
+use crate::mvcc::{CommitLog, Snapshot, FROZEN_XID};
+use crate::nom_parser::{
+    is_identifier, Column, ColumnType, Command, CompareOp, Condition, IndexMethod, IsolationLevel,
+    RowLocking, Value,
+};
+use crate::page::MAX_RECORD_SIZE;
+use crate::result_set::{cell_text, ResultSet};
+use crate::wal::{Recovery, RecoveryReport, Wal};
 use prost::Message;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::fmt;
+use std::fs;
+use std::ops::Bound;
 use std::path::Path;
-use std::{cell, fmt};
+use std::time::Duration;
 
 // Define a custom error type
 #[derive(Debug)]
 pub enum DatabaseError {
     TableAlreadyExists(String),
     TableDoesNotExist(String),
-    NoTablesInDatabase,
+    ColumnDoesNotExist(String),
+    IndexAlreadyExists(String),
+    IndexDoesNotExist(String),
+    UniqueViolation(String),
+    RowTooLarge(usize),
+    KeyTooLarge {
+        index: String,
+        size: usize,
+    },
+    NoActiveTransaction,
+    TransactionAlreadyActive,
+    SavepointDoesNotExist(String),
+    SerializationFailure(String),
+    Locked(String),
+    Deadlock,
+    NotADataDirectory(String),
+    // A file whose contents are not a valid message of the kind expected
+    DecodeError {
+        path: String,
+        error: String,
+    },
+    // A file that fails its checksum or is not in a format this build reads
+    CorruptFile {
+        path: String,
+        error: String,
+    },
+    // A command handed to a method that runs a different kind of command
+    InvalidCommand(String),
+    // Statement text the parser could not make sense of
+    SyntaxError(String),
+    // A cell read as a Rust type that does not match its value
+    TypeMismatch {
+        column: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    // A statement run with a placeholder that has no value
+    ParameterNotBound(usize),
+    NoSuchParameter {
+        number: usize,
+        count: usize,
+    },
+    // A value bound to a placeholder that its column cannot hold
+    ParameterTypeMismatch {
+        number: usize,
+        column: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    // A string bound to a placeholder that is longer than its column allows
+    ParameterTooLong {
+        number: usize,
+        column: String,
+        length: u32,
+    },
+    // A line of an imported file that does not make a row of the table
+    InvalidRecord {
+        path: String,
+        line: usize,
+        error: String,
+    },
+    // A backup directory that cannot be restored as it is
+    InvalidBackup {
+        path: String,
+        error: String,
+    },
     IOError(std::io::Error),
+    #[allow(dead_code)]
     UnknownError,
 }
 
@@ -35,7 +121,106 @@ impl fmt::Display for DatabaseError {
             DatabaseError::TableDoesNotExist(name) => {
                 write!(f, "Table '{}' does not exist.", name)
             }
-            DatabaseError::NoTablesInDatabase => write!(f, "No tables in the database."),
+            DatabaseError::ColumnDoesNotExist(name) => {
+                write!(f, "Column '{}' does not exist.", name)
+            }
+            DatabaseError::IndexAlreadyExists(name) => {
+                write!(f, "Index '{}' already exists.", name)
+            }
+            DatabaseError::IndexDoesNotExist(name) => {
+                write!(f, "Index '{}' does not exist.", name)
+            }
+            DatabaseError::UniqueViolation(name) => {
+                write!(f, "Duplicate key violates unique index '{}'.", name)
+            }
+            DatabaseError::RowTooLarge(size) => write!(
+                f,
+                "Row of {} bytes exceeds the maximum row size of {} bytes.",
+                size, MAX_RECORD_SIZE
+            ),
+            DatabaseError::KeyTooLarge { index, size } => write!(
+                f,
+                "Key of {} bytes exceeds the maximum of {} bytes for index '{}'.",
+                size, MAX_KEY_SIZE, index
+            ),
+            DatabaseError::NoActiveTransaction => write!(f, "No transaction is in progress."),
+            DatabaseError::TransactionAlreadyActive => {
+                write!(f, "A transaction is already in progress.")
+            }
+            DatabaseError::SavepointDoesNotExist(name) => {
+                write!(f, "Savepoint '{}' does not exist.", name)
+            }
+            DatabaseError::SerializationFailure(table) => write!(
+                f,
+                "Could not serialize access to table '{}' because of a concurrent update; the transaction has been rolled back.",
+                table
+            ),
+            DatabaseError::Locked(what) => {
+                write!(f, "Timed out waiting for another session to release {}.", what)
+            }
+            DatabaseError::Deadlock => write!(
+                f,
+                "Deadlock detected; the transaction has been rolled back."
+            ),
+            DatabaseError::NotADataDirectory(dir) => write!(
+                f,
+                "'{}' is not a database directory and is not empty; choose another with --data-dir.",
+                dir
+            ),
+            DatabaseError::DecodeError { path, error } => {
+                write!(f, "'{}' cannot be decoded: {}.", path, error)
+            }
+            DatabaseError::CorruptFile { path, error } => {
+                write!(f, "'{}' cannot be read: {}.", path, error)
+            }
+            DatabaseError::InvalidCommand(method) => {
+                write!(f, "Invalid command passed to {}.", method)
+            }
+            DatabaseError::SyntaxError(text) => {
+                write!(f, "Error parsing command near: {}", text)
+            }
+            DatabaseError::TypeMismatch {
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Column '{}' holds a {} value, which cannot be read as {}.",
+                column, found, expected
+            ),
+            DatabaseError::ParameterNotBound(number) => {
+                write!(f, "Parameter {} has no value bound.", number)
+            }
+            DatabaseError::NoSuchParameter { number, count } => write!(
+                f,
+                "Parameter {} does not exist; the statement has {} parameter(s).",
+                number, count
+            ),
+            DatabaseError::ParameterTypeMismatch {
+                number,
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Parameter {} for column '{}' must be {}, not {}.",
+                number, column, expected, found
+            ),
+            DatabaseError::InvalidRecord { path, line, error } => {
+                write!(f, "'{}' line {}: {}.", path, line, error)
+            }
+            DatabaseError::ParameterTooLong {
+                number,
+                column,
+                length,
+            } => write!(
+                f,
+                "Parameter {} for column '{}' is longer than its {} characters.",
+                number, column, length
+            ),
+            DatabaseError::InvalidBackup { path, error } => {
+                write!(f, "Cannot restore from '{}': {}.", path, error)
+            }
             DatabaseError::UnknownError => write!(f, "An unknown error occurred."),
         }
     }
@@ -44,6 +229,63 @@ impl fmt::Display for DatabaseError {
 // Implement the Error trait for DatabaseError
 impl Error for DatabaseError {}
 
+// Exit statuses, after sysexits.h
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_IOERR: i32 = 74;
+const EX_TEMPFAIL: i32 = 75;
+
+impl DatabaseError {
+    // Exit status of a process that failed with this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DatabaseError::InvalidCommand(_)
+            | DatabaseError::SyntaxError(_)
+            | DatabaseError::ParameterNotBound(_)
+            | DatabaseError::NotADataDirectory(_) => EX_USAGE,
+            DatabaseError::DecodeError { .. }
+            | DatabaseError::CorruptFile { .. }
+            | DatabaseError::InvalidRecord { .. }
+            | DatabaseError::InvalidBackup { .. } => EX_DATAERR,
+            DatabaseError::IOError(_) => EX_IOERR,
+            // Worth trying again once other sessions are done
+            DatabaseError::Locked(_)
+            | DatabaseError::Deadlock
+            | DatabaseError::SerializationFailure(_) => EX_TEMPFAIL,
+            _ => 1,
+        }
+    }
+}
+
+// Name the file an I/O error came from if it is about the file's contents
+fn file_error(path: &str, error: std::io::Error) -> DatabaseError {
+    if error.kind() != std::io::ErrorKind::InvalidData {
+        return DatabaseError::IOError(error);
+    }
+    let path = path.to_string();
+    match error.into_inner() {
+        Some(inner) if inner.is::<prost::DecodeError>() => DatabaseError::DecodeError {
+            path,
+            error: inner.to_string(),
+        },
+        Some(inner) => DatabaseError::CorruptFile {
+            path,
+            error: inner.to_string(),
+        },
+        None => DatabaseError::CorruptFile {
+            path,
+            error: "invalid data".to_string(),
+        },
+    }
+}
+
+fn decode_error(path: &str, error: prost::DecodeError) -> DatabaseError {
+    DatabaseError::DecodeError {
+        path: path.to_string(),
+        error: error.to_string(),
+    }
+}
+
 // Convert std::io::Error into DatabaseError::IOError for convenience
 impl From<std::io::Error> for DatabaseError {
     fn from(error: std::io::Error) -> Self {
@@ -61,7 +303,7 @@ impl From<Column> for ColumnDefinition {
                 ColumnType::STRING(_) => ProtoColumnType::String as i32,
             },
             length: match column.col_type {
-                ColumnType::STRING(len) => len as u32,
+                ColumnType::STRING(len) => len,
                 _ => 0,
             },
             not_null: column.not_null,
@@ -69,306 +311,3886 @@ impl From<Column> for ColumnDefinition {
     }
 }
 
+// Conversion from a parsed literal to the stored cell representation
+impl TryFrom<Value> for CellValue {
+    type Error = DatabaseError;
+
+    fn try_from(value: Value) -> Result<Self, DatabaseError> {
+        Ok(CellValue {
+            value: Some(match value {
+                Value::Int(v) => cell_value::Value::IntVal(v),
+                Value::Str(s) => cell_value::Value::StrVal(s),
+                Value::Null => cell_value::Value::NullVal(true),
+                // Statements are only run once every placeholder has its value
+                Value::Param(_) => return Err(unbound_parameter()),
+            }),
+        })
+    }
+}
+
+// A placeholder left in a statement that is being run
+fn unbound_parameter() -> DatabaseError {
+    DatabaseError::InvalidCommand("unbound parameter".to_string())
+}
+
+// A value bound to a placeholder
+impl From<CellValue> for Value {
+    fn from(cell: CellValue) -> Self {
+        match cell.value {
+            Some(cell_value::Value::IntVal(v)) => Value::Int(v),
+            Some(cell_value::Value::StrVal(s)) => Value::Str(s),
+            Some(cell_value::Value::NullVal(_)) | None => Value::Null,
+        }
+    }
+}
+
+// Evaluate `cell <op> value`. Comparisons involving NULL or mixed types are false.
+fn compare_cell(cell: &CellValue, op: CompareOp, value: &Value) -> bool {
+    let ordering = match (&cell.value, value) {
+        (Some(cell_value::Value::IntVal(a)), Value::Int(b)) => a.cmp(b),
+        (Some(cell_value::Value::StrVal(a)), Value::Str(b)) => a.as_str().cmp(b.as_str()),
+        _ => return false,
+    };
+    match op {
+        CompareOp::Eq => ordering.is_eq(),
+        CompareOp::NotEq => ordering.is_ne(),
+        CompareOp::Lt => ordering.is_lt(),
+        CompareOp::LtEq => ordering.is_le(),
+        CompareOp::Gt => ordering.is_gt(),
+        CompareOp::GtEq => ordering.is_ge(),
+    }
+}
+
+// Position of `column` in the table definition
+fn column_position(table_def: &TableDefinition, column: &str) -> Result<usize, DatabaseError> {
+    table_def
+        .columns
+        .iter()
+        .position(|col| col.name == column)
+        .ok_or_else(|| DatabaseError::ColumnDoesNotExist(column.to_string()))
+}
+
+// WHERE clause with column names resolved to cell positions
+struct Filter {
+    conditions: Vec<(usize, Condition)>,
+}
+
+impl Filter {
+    fn new(table_def: &TableDefinition, conditions: Vec<Condition>) -> Result<Self, DatabaseError> {
+        let conditions = conditions
+            .into_iter()
+            .map(|condition| Ok((column_position(table_def, &condition.column)?, condition)))
+            .collect::<Result<Vec<_>, DatabaseError>>()?;
+        Ok(Filter { conditions })
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        self.conditions.iter().all(|(position, condition)| {
+            row.cells
+                .get(*position)
+                .is_some_and(|cell| compare_cell(cell, condition.op, &condition.value))
+        })
+    }
+
+    // Full key for `columns` if each of them is compared with `=` against a non-NULL literal
+    fn equality_key(&self, columns: &[String]) -> Option<IndexKey> {
+        columns
+            .iter()
+            .map(|column| {
+                self.conditions.iter().find_map(|(_, condition)| {
+                    match (&condition.value, condition.op) {
+                        _ if condition.column != *column => None,
+                        (Value::Int(v), CompareOp::Eq) => Some(IndexValue::Int(*v)),
+                        (Value::Str(s), CompareOp::Eq) => Some(IndexValue::Str(s.clone())),
+                        _ => None,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // Bounds on `column` implied by the conditions, if any condition can use an index
+    fn bounds(&self, column: &str) -> Option<(Bound<IndexValue>, Bound<IndexValue>)> {
+        let mut lower = Bound::Unbounded;
+        let mut upper = Bound::Unbounded;
+        let mut usable = false;
+        for (_, condition) in &self.conditions {
+            if condition.column != column {
+                continue;
+            }
+            let value = match &condition.value {
+                Value::Int(v) => IndexValue::Int(*v),
+                Value::Str(s) => IndexValue::Str(s.clone()),
+                Value::Null | Value::Param(_) => continue,
+            };
+            match condition.op {
+                CompareOp::Eq => {
+                    lower = Bound::Included(value.clone());
+                    upper = Bound::Included(value);
+                }
+                CompareOp::Gt => lower = Bound::Excluded(value),
+                CompareOp::GtEq => lower = Bound::Included(value),
+                CompareOp::Lt => upper = Bound::Excluded(value),
+                CompareOp::LtEq => upper = Bound::Included(value),
+                CompareOp::NotEq => continue,
+            }
+            usable = true;
+        }
+        usable.then_some((lower, upper))
+    }
+}
+
+// Key of `row` for an index over the columns at `positions`
+fn index_key(row: &Row, positions: &[usize]) -> IndexKey {
+    positions
+        .iter()
+        .map(|position| {
+            row.cells
+                .get(*position)
+                .map(IndexValue::from)
+                .unwrap_or(IndexValue::Null)
+        })
+        .collect()
+}
+
+// Report where `row` does not fit the definition of its table
+fn check_row(table_def: &TableDefinition, row_id: u64, row: &Row, problems: &mut Vec<Problem>) {
+    let table = || table_def.name.clone();
+    if row.cells.len() != table_def.columns.len() {
+        problems.push(Problem::WrongArity {
+            table: table(),
+            row_id,
+            expected: table_def.columns.len(),
+            found: row.cells.len(),
+        });
+    }
+    for (column, cell) in table_def.columns.iter().zip(&row.cells) {
+        let problem = match &cell.value {
+            Some(cell_value::Value::NullVal(_)) | None if column.not_null => Problem::NullValue {
+                table: table(),
+                row_id,
+                column: column.name.clone(),
+            },
+            Some(cell_value::Value::NullVal(_)) | None => continue,
+            Some(cell_value::Value::IntVal(_)) if column.col_type() == ProtoColumnType::Int => {
+                continue
+            }
+            Some(cell_value::Value::StrVal(s)) if column.col_type() == ProtoColumnType::String => {
+                if s.chars().count() <= column.length as usize {
+                    continue;
+                }
+                Problem::TooLong {
+                    table: table(),
+                    row_id,
+                    column: column.name.clone(),
+                    max: column.length,
+                }
+            }
+            Some(_) => Problem::WrongType {
+                table: table(),
+                row_id,
+                column: column.name.clone(),
+            },
+        };
+        problems.push(problem);
+    }
+}
+
+// Why `column` cannot hold `value`, if it cannot
+fn check_value(column: &ColumnDefinition, value: &Value) -> Result<(), String> {
+    let expected = column.col_type().as_str_name();
+    match (value, column.col_type()) {
+        (Value::Null, _) if column.not_null => {
+            Err(format!("column '{}' cannot be NULL", column.name))
+        }
+        (Value::Null, _) | (Value::Int(_), ProtoColumnType::Int) => Ok(()),
+        (Value::Str(s), ProtoColumnType::String) if s.chars().count() > column.length as usize => {
+            Err(format!(
+                "'{}' is longer than the {} characters of column '{}'",
+                s, column.length, column.name
+            ))
+        }
+        (Value::Str(_), ProtoColumnType::String) => Ok(()),
+        (Value::Int(v), _) => Err(format!(
+            "column '{}' holds {} values, not {}",
+            column.name, expected, v
+        )),
+        (Value::Str(s), _) => Err(format!(
+            "column '{}' holds {} values, not '{}'",
+            column.name, expected, s
+        )),
+        (Value::Param(_), _) => Err(unbound_parameter().to_string()),
+    }
+}
+
+// The value a CSV field gives `column`
+fn field_value(column: &ColumnDefinition, field: &csv::Field, null: &str) -> Result<Value, String> {
+    let value = if !field.quoted && field.text == null {
+        Value::Null
+    } else if column.col_type() == ProtoColumnType::Int {
+        match field.text.trim().parse() {
+            Ok(v) => Value::Int(v),
+            Err(_) => {
+                return Err(format!(
+                    "'{}' is not a valid INT for column '{}'",
+                    field.text, column.name
+                ))
+            }
+        }
+    } else {
+        Value::Str(field.text.clone())
+    };
+    check_value(column, &value)?;
+    Ok(value)
+}
+
+// The value a member of an imported JSON object gives `column`. Numbers,
+// strings and null keep their type; nothing is converted.
+fn json_value(column: &ColumnDefinition, value: &Json) -> Result<Value, String> {
+    let value = match value {
+        Json::Null => Value::Null,
+        Json::String(s) => Value::Str(s.clone()),
+        Json::Number(n) => match n.parse() {
+            Ok(v) => Value::Int(v),
+            Err(_) if column.col_type() == ProtoColumnType::Int => {
+                return Err(format!(
+                    "{} is not a valid INT for column '{}'",
+                    n, column.name
+                ))
+            }
+            Err(_) => {
+                return Err(format!(
+                    "column '{}' holds {} values, not {}",
+                    column.name,
+                    column.col_type().as_str_name(),
+                    n
+                ))
+            }
+        },
+        other => {
+            return Err(format!(
+                "column '{}' cannot hold {} from JSON",
+                column.name,
+                other.kind()
+            ))
+        }
+    };
+    check_value(column, &value)?;
+    Ok(value)
+}
+
+// Name the file in errors from reading or writing data the user gave
+fn user_file_error(path: &str, error: std::io::Error) -> DatabaseError {
+    DatabaseError::IOError(std::io::Error::new(
+        error.kind(),
+        format!("'{}': {}", path, error),
+    ))
+}
+
+// Contents of the file at `path`, or `None` if there is no such file
+fn read_if_exists(path: &str) -> Result<Option<Vec<u8>>, DatabaseError> {
+    match FileManager::new(path).read_file() {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Name of a file of the database, as stored in a backup
+fn file_name(file_path: &str) -> String {
+    Path::new(file_path)
+        .file_name()
+        .expect("database files have a name")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Write the files of a backup into the data directory `dir`, then the
+// manifest, and read every file back to check it holds what was written
+fn write_backup(
+    dir: &Path,
+    files: &[(String, Vec<u8>)],
+    created: &mut Vec<String>,
+) -> Result<(), DatabaseError> {
+    let mut entries = Vec::new();
+    for (name, bytes) in files {
+        let file_path = dir.join(name).to_string_lossy().into_owned();
+        FileManager::new(&file_path)
+            .write_file(bytes)
+            .map_err(|e| user_file_error(&file_path, e))?;
+        created.push(file_path);
+        entries.push(backup::Entry::new(name, bytes));
+    }
+    let manifest_path = dir.join(MANIFEST_FILE).to_string_lossy().into_owned();
+    FileManager::new(&manifest_path)
+        .write_file(backup::write(&entries).as_bytes())
+        .map_err(|e| user_file_error(&manifest_path, e))?;
+    created.push(manifest_path);
+    for entry in &entries {
+        let file_path = dir.join(&entry.name).to_string_lossy().into_owned();
+        let bytes = FileManager::new(&file_path)
+            .read_file()
+            .map_err(|e| user_file_error(&file_path, e))?;
+        if !entry.matches(&bytes) {
+            return Err(DatabaseError::CorruptFile {
+                path: file_path,
+                error: "it does not read back as it was written".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Build an index over every version in `rows`. Uniqueness only concerns the
+// versions `is_live` accepts; dead versions keep their entries until removed.
+fn build_index(
+    table_def: &TableDefinition,
+    index_def: &IndexDefinition,
+    rows: &[(u64, Row)],
+    mut is_live: impl FnMut(&Row) -> Result<bool, DatabaseError>,
+) -> Result<TableIndex<'static>, DatabaseError> {
+    let positions = index_positions(table_def, index_def)?;
+    let mut index = TableIndex::new(index_def);
+    let mut live_keys = HashSet::new();
+    for (row_id, row) in rows {
+        let key = index_key(row, &positions);
+        check_key_size(index_def, &key)?;
+        if index_def.unique
+            && !key_has_null(&key)
+            && is_live(row)?
+            && !live_keys.insert(key.clone())
+        {
+            return Err(DatabaseError::UniqueViolation(index_def.name.clone()));
+        }
+        index.insert(key, *row_id)?;
+    }
+    Ok(index)
+}
+
+// Fails if `key` is too large to be stored in the index
+fn check_key_size(index_def: &IndexDefinition, key: &IndexKey) -> Result<(), DatabaseError> {
+    let size = key_size(key);
+    if size > MAX_KEY_SIZE {
+        return Err(DatabaseError::KeyTooLarge {
+            index: index_def.name.clone(),
+            size,
+        });
+    }
+    Ok(())
+}
+
+// Cell positions of the indexed columns, in key order
+fn index_positions(
+    table_def: &TableDefinition,
+    index_def: &IndexDefinition,
+) -> Result<Vec<usize>, DatabaseError> {
+    index_def
+        .columns
+        .iter()
+        .map(|column| column_position(table_def, column))
+        .collect()
+}
+
+// Decode a row stored in the table file at `path`
+fn decode_row(path: &str, record: &[u8]) -> Result<Row, DatabaseError> {
+    Row::decode(record).map_err(|e| decode_error(path, e))
+}
+
+// State of the running transaction, whether opened with BEGIN or by a single statement
+struct Transaction {
+    // The transaction id is assigned by the first write
+    snapshot: Snapshot,
+    isolation: IsolationLevel,
+    // Opened with BEGIN and ended by COMMIT or ROLLBACK
+    explicit: bool,
+    savepoints: Vec<(String, usize)>,
+    // Tables and conditions read, checked again at commit under SERIALIZABLE
+    reads: Vec<(String, Vec<Condition>)>,
+    // Data files written, locked again to put their pages back on rollback
+    written: HashSet<String>,
+    // Set once the transaction changes the catalog or sets a savepoint: it then
+    // holds the log until it ends, to be rolled back from the log's images
+    keeps_log: bool,
+}
+
+impl Transaction {
+    fn new(snapshot: Snapshot, isolation: IsolationLevel, explicit: bool) -> Self {
+        Transaction {
+            snapshot,
+            isolation,
+            explicit,
+            savepoints: Vec::new(),
+            reads: Vec::new(),
+            written: HashSet::new(),
+            keeps_log: false,
+        }
+    }
+}
+
 // DatabaseManager struct to handle file operations
 pub struct DatabaseManager {
     file_path: String,
+    // Decoded catalog, read once and kept in sync by `save_database`
+    catalog: RefCell<Option<Database>>,
+    // Page cache shared by every statement run through this manager
+    buffer_pool: RefCell<BufferPool>,
+    // Outcome of every transaction, shared by all processes using the database
+    commit_log: RefCell<CommitLog>,
+    // Last commit the cached catalog and pages are known to include
+    seen_csn: Cell<u64>,
+    // The running transaction, or `None` between statements
+    transaction: RefCell<Option<Transaction>>,
+    // Locks on the catalog and table files. Those taken to change the catalog
+    // or a whole table last until the transaction ends, the others until the
+    // statement does.
+    locks: RefCell<FileLocks>,
+    statement_running: Cell<bool>,
+    // Row locks, held until the transaction ends
+    lock_manager: RefCell<LockManager>,
+    // What recovery did since `take_recovery` was last called
+    recovered: Cell<Recovery>,
 }
 
 impl DatabaseManager {
     // Constructor
-    pub fn new(file_path: String) -> Self {
-        Self { file_path }
+    #[allow(dead_code)]
+    pub fn new(file_path: String) -> Result<Self, DatabaseError> {
+        Self::with_buffer_pool_size(file_path, DEFAULT_POOL_SIZE)
+    }
+
+    // Constructor with a buffer pool of `frames` pages. Work left in the
+    // write-ahead log by a crash is redone or rolled back before anything is
+    // read, unless another process is writing and will take care of it.
+    pub fn with_buffer_pool_size(file_path: String, frames: usize) -> Result<Self, DatabaseError> {
+        let wal_path = Path::new(&file_path).with_extension("wal");
+        let wal_path = wal_path.to_string_lossy();
+        let wal = Wal::open(&wal_path).map_err(|e| file_error(&wal_path, e))?;
+        let clog_path = Path::new(&file_path).with_extension("clog");
+        let clog_path = clog_path.to_string_lossy();
+        let mut commit_log = CommitLog::open(&clog_path).map_err(|e| file_error(&clog_path, e))?;
+        let seen_csn = commit_log
+            .last_csn()
+            .map_err(|e| file_error(&clog_path, e))?;
+        let lock_table_path = Path::new(&file_path).with_extension("locks");
+        let lock_manager = LockManager::open(&lock_table_path.to_string_lossy())?;
+        let manager = Self {
+            file_path,
+            catalog: RefCell::new(None),
+            buffer_pool: RefCell::new(BufferPool::new(frames, wal)),
+            commit_log: RefCell::new(commit_log),
+            seen_csn: Cell::new(seen_csn),
+            transaction: RefCell::new(None),
+            locks: RefCell::new(FileLocks::new(DEFAULT_LOCK_TIMEOUT)),
+            statement_running: Cell::new(false),
+            lock_manager: RefCell::new(lock_manager),
+            recovered: Cell::new(Recovery::default()),
+        };
+        let report = manager
+            .buffer_pool
+            .borrow_mut()
+            .acquire_log(Duration::ZERO)
+            .map_err(|e| file_error(&wal_path, e))?;
+        if let Some(report) = report {
+            let recovered = manager.apply_recovery(&report);
+            manager.buffer_pool.borrow_mut().release_log()?;
+            recovered?;
+        }
+        Ok(manager)
+    }
+
+    // How long to wait for a lock held by another process before giving up
+    pub fn with_lock_timeout(self, timeout: Duration) -> Self {
+        self.locks.borrow_mut().set_timeout(timeout);
+        self
+    }
+
+    // Record the outcome of the transactions recovery finished in the commit log
+    fn apply_recovery(&self, report: &RecoveryReport) -> Result<(), DatabaseError> {
+        let mut recovered = self.recovered.get();
+        recovered.add(report);
+        self.recovered.set(recovered);
+        let mut commit_log = self.commit_log.borrow_mut();
+        for xid in &report.committed {
+            if commit_log.commit_csn(*xid)?.is_none() {
+                commit_log.commit(*xid)?;
+            }
+        }
+        for xid in &report.rolled_back {
+            commit_log.abort(*xid)?;
+        }
+        Ok(())
+    }
+
+    // What recovery did since the last call, if it did anything
+    pub fn take_recovery(&self) -> Option<Recovery> {
+        let recovered = self.recovered.take();
+        (!recovered.is_empty()).then_some(recovered)
+    }
+
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.buffer_pool.borrow().stats()
+    }
+
+    pub fn buffer_pool_capacity(&self) -> usize {
+        self.buffer_pool.borrow().capacity()
     }
 
     // Load the database from file or initialize if not present
-    pub fn load_database(&self) -> Database {
-        let path = Path::new(&self.file_path);
-        if path.exists() {
-            let mut file = OpenOptions::new()
-                .read(true)
-                .open(path)
-                .expect("Failed to open file");
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer).expect("Failed to read file");
-            Database::decode(&*buffer).expect("Failed to decode database")
-        } else {
-            Database { tables: Vec::new() }
+    pub fn load_database(&self) -> Result<Database, DatabaseError> {
+        if let Some(database) = self.catalog.borrow().as_ref() {
+            return Ok(database.clone());
         }
+        let database = self.read_catalog()?;
+        *self.catalog.borrow_mut() = Some(database.clone());
+        Ok(database)
+    }
+
+    // Decode the catalog file, or an empty catalog if there is none yet
+    fn read_catalog(&self) -> Result<Database, DatabaseError> {
+        let Some(buffer) = read_if_exists(&self.file_path)? else {
+            return Ok(Database { tables: Vec::new() });
+        };
+        let message =
+            format::decode(CATALOG_MAGIC, &buffer).map_err(|e| file_error(&self.file_path, e))?;
+        Database::decode(message).map_err(|e| decode_error(&self.file_path, e))
     }
 
     // Save the database to file
-    pub fn save_database(&self, database: &Database) {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&self.file_path)
-            .expect("Failed to open file");
-
-        let mut buffer = Vec::new();
-        database
-            .encode(&mut buffer)
-            .expect("Failed to encode database");
+    pub fn save_database(&self, database: &Database) -> Result<(), DatabaseError> {
+        *self.catalog.borrow_mut() = Some(database.clone());
+        self.buffer_pool.borrow_mut().write_file(
+            &self.file_path,
+            Some(format::encode(CATALOG_MAGIC, &database.encode_to_vec())),
+        )?;
+        Ok(())
+    }
+
+    // Write an index built in memory as a whole file
+    fn save_index(&self, file_path: &str, index: TableIndex<'static>) -> Result<(), DatabaseError> {
+        let image = index.image()?;
+        self.buffer_pool
+            .borrow_mut()
+            .write_file(file_path, Some(image))?;
+        Ok(())
+    }
+
+    // Drop the cached catalog and pages if another process has committed since
+    // they were read. Only called while no cached page is dirty.
+    fn refresh_cache(&self, csn: u64) {
+        if csn != self.seen_csn.get() {
+            self.clear_cache(csn);
+        }
+    }
 
-        file.write_all(&buffer).expect("Failed to write to file");
+    // Drop the cached catalog and pages, which include the commits up to `csn`
+    // once read again. Only called while no cached page is dirty.
+    fn clear_cache(&self, csn: u64) {
+        *self.catalog.borrow_mut() = None;
+        self.buffer_pool.borrow_mut().clear();
+        self.seen_csn.set(csn);
     }
 
-    pub fn has_table(&self, table_name: &str) -> bool {
-        let database = self.load_database();
-        database.tables.iter().any(|table| table.name == table_name)
+    // Snapshot of everything committed so far
+    fn take_snapshot(&self) -> Result<Snapshot, DatabaseError> {
+        let csn = self.commit_log.borrow_mut().last_csn()?;
+        self.refresh_cache(csn);
+        Ok(Snapshot { csn, xid: None })
     }
 
-    // Handle the CREATE TABLE command and update the database
-    pub fn create_table(&self, command: Command) -> Result<(), DatabaseError> {
-        if let Command::CreateTable { name, columns } = command {
-            // Convert columns to protobuf ColumnDefinition
-            if self.has_table(&name) {
-                return Err(DatabaseError::TableAlreadyExists(name));
+    // What the running statement sees; outside of a transaction that is
+    // everything committed
+    fn snapshot(&self) -> Snapshot {
+        self.transaction
+            .borrow()
+            .as_ref()
+            .map(|transaction| transaction.snapshot)
+            .unwrap_or(Snapshot::latest(None))
+    }
+
+    fn is_visible(&self, snapshot: &Snapshot, row: &Row) -> Result<bool, DatabaseError> {
+        Ok(snapshot.is_visible(row, &mut self.commit_log.borrow_mut())?)
+    }
+
+    // A version no committed transaction nor this one has deleted. Uniqueness
+    // is checked against these, whatever the snapshot.
+    fn is_live(&self, row: &Row) -> Result<bool, DatabaseError> {
+        self.is_visible(&Snapshot::latest(self.snapshot().xid), row)
+    }
+
+    // `is_live` for a uniqueness check of `table`. A version created by another
+    // transaction that is still running may yet commit, so the check fails with
+    // a serialization failure; one whose creator died is rolled back here.
+    fn is_live_checked(&self, table: &str, row: &Row) -> Result<bool, DatabaseError> {
+        let creator = row.xmin;
+        if creator != FROZEN_XID
+            && Some(creator) != self.snapshot().xid
+            && !self.commit_log.borrow_mut().is_finished(creator)?
+        {
+            if self.lock_manager.borrow_mut().is_running(creator)? {
+                return Err(DatabaseError::SerializationFailure(table.to_string()));
             }
-            let column_defs: Vec<ColumnDefinition> =
-                columns.into_iter().map(ColumnDefinition::from).collect();
-            let table_def = TableDefinition {
-                name,
-                columns: column_defs,
+            // It may have finished just before its id was unlocked
+            if !self.commit_log.borrow_mut().is_finished(creator)? {
+                self.commit_log.borrow_mut().abort(creator)?;
+            }
+        }
+        self.is_live(row)
+    }
+
+    // Make the running transaction a writer for the statement: take the log,
+    // waiting for whoever holds it, and assign a transaction id on the first
+    // write. Returns the transaction id. Unless the transaction keeps the log,
+    // it is given up when the statement ends, so that transactions changing
+    // different rows write side by side.
+    fn start_write(&self) -> Result<u64, DatabaseError> {
+        let mut transaction = self.transaction.borrow_mut();
+        let transaction = transaction
+            .as_mut()
+            .expect("writes only happen inside a transaction");
+        if self.buffer_pool.borrow().in_transaction() {
+            return Ok(transaction
+                .snapshot
+                .xid
+                .expect("a transaction holding the log has an id"));
+        }
+        let timeout = self.locks.borrow().timeout();
+        let mut report = None;
+        let wait = self.lock_manager.borrow_mut().lock_log(
+            || {
+                report = self.buffer_pool.borrow_mut().acquire_log(Duration::ZERO)?;
+                Ok(report.is_some())
+            },
+            timeout,
+        )?;
+        self.check_wait(wait, "the log")?;
+        let report = report.expect("the log is held once the wait is granted");
+        let started = self.apply_recovery(&report).and_then(|_| {
+            let csn = self.commit_log.borrow_mut().last_csn()?;
+            // Other writers may have changed any page since it was read
+            self.clear_cache(csn);
+            // A single statement sees what was committed before it got to write
+            if !transaction.explicit {
+                transaction.snapshot.csn = csn;
+            }
+            let xid = match transaction.snapshot.xid {
+                Some(xid) => xid,
+                None => {
+                    let xid = self.commit_log.borrow_mut().allocate()?;
+                    let wait = self.lock_manager.borrow_mut().lock_transaction(xid)?;
+                    self.check_wait(wait, "the transaction id")?;
+                    xid
+                }
             };
+            self.buffer_pool.borrow_mut().begin(xid)?;
+            Ok(xid)
+        });
+        match started {
+            Ok(xid) => {
+                transaction.snapshot.xid = Some(xid);
+                Ok(xid)
+            }
+            Err(error) => {
+                let _ = self.release_log();
+                Err(error)
+            }
+        }
+    }
 
-            // Load the current database, add the table, and save it back
-            let mut database = self.load_database();
-            database.tables.push(table_def);
-            self.save_database(&database);
-            Ok(())
-        } else {
-            panic!("Invalid command passed to create_table");
+    // Let other writers have the log
+    fn release_log(&self) -> Result<(), DatabaseError> {
+        let released = self.buffer_pool.borrow_mut().release_log();
+        self.lock_manager.borrow_mut().release_log()?;
+        released?;
+        Ok(())
+    }
+
+    // Give up the log after a statement, unless the transaction keeps it. Its
+    // pages are on disk, so from here on the transaction is rolled back by
+    // recording it as aborted rather than from the log's images.
+    fn end_write(&self) -> Result<(), DatabaseError> {
+        let keeps_log = self
+            .transaction
+            .borrow()
+            .as_ref()
+            .is_some_and(|transaction| transaction.keeps_log);
+        if keeps_log || !self.buffer_pool.borrow().in_transaction() {
+            return Ok(());
         }
+        self.buffer_pool.borrow_mut().suspend()?;
+        self.lock_manager.borrow_mut().release_log()?;
+        Ok(())
     }
 
-    // Implement `list_table` to display all tables in the database
-    pub fn list_tables(&self) -> Result<(), DatabaseError> {
-        // Load the current database from the file
-        let database = self.load_database();
+    fn check_wait(&self, wait: Wait, what: &str) -> Result<(), DatabaseError> {
+        match wait {
+            Wait::Granted => Ok(()),
+            Wait::Deadlock => Err(DatabaseError::Deadlock),
+            Wait::TimedOut => Err(DatabaseError::Locked(what.to_string())),
+        }
+    }
 
-        // Check if there are any tables in the database
-        if database.tables.is_empty() {
-            Err(DatabaseError::NoTablesInDatabase)
-        } else {
-            println!("Tables in the database:");
-            for table in &database.tables {
-                println!("{}", table.name);
-                // println!("- Table: {}", table.name);
-                // println!("  Columns:");
-                // for column in &table.columns {
-                //     println!(
-                //         "    - Column: {} | Type: {:?} | Length: {} | Not Null: {}",
-                //         column.name,
-                //         ProtoColumnType::try_from(column.col_type).unwrap(),
-                //         column.length,
-                //         column.not_null
-                //     );
-                // }
-                // println!();
+    // Changing the catalog or a whole table keeps the log until the transaction ends
+    fn lock(&self, file_path: &str, mode: LockMode, scope: LockScope) -> Result<(), DatabaseError> {
+        if !self.locks.borrow_mut().lock(file_path, mode, scope)? {
+            return Err(DatabaseError::Locked(format!("'{}'", file_path)));
+        }
+        if mode == LockMode::Exclusive && scope == LockScope::Transaction {
+            if let Some(transaction) = self.transaction.borrow_mut().as_mut() {
+                transaction.keeps_log = true;
             }
-            Ok(())
         }
+        Ok(())
     }
 
-    pub fn drop_table(&self, table_name: &str) -> Result<(), DatabaseError> {
-        let mut database = self.load_database();
-        let table_index = database
-            .tables
-            .iter()
-            .position(|table| table.name == table_name);
-        match table_index {
-            Some(index) => {
-                database.tables.remove(index);
-                self.save_database(&database);
-                Ok(())
+    // Taken before the catalog is read, exclusively to change it
+    fn lock_catalog(&self, mode: LockMode) -> Result<(), DatabaseError> {
+        let scope = match mode {
+            LockMode::Shared => LockScope::Statement,
+            LockMode::Exclusive => LockScope::Transaction,
+        };
+        self.lock(&self.file_path, mode, scope)
+    }
+
+    // Covers the table's data file and all of its index files. Changing rows
+    // only needs the table for the statement, as the rows stay locked.
+    fn lock_table(
+        &self,
+        table_name: &str,
+        mode: LockMode,
+        scope: LockScope,
+    ) -> Result<(), DatabaseError> {
+        let file_path = self.table_file_path(table_name);
+        self.lock(&file_path, mode, scope)?;
+        if mode == LockMode::Exclusive {
+            if let Some(transaction) = self.transaction.borrow_mut().as_mut() {
+                transaction.written.insert(file_path);
             }
-            None => Err(DatabaseError::TableDoesNotExist(table_name.to_string())),
         }
+        Ok(())
     }
 
-    pub fn display_schema(&self, table_name: &str) -> Result<(), DatabaseError> {
-        let database = self.load_database();
-        let table = database
-            .tables
-            .iter()
-            .find(|table| table.name == table_name);
-        match table {
-            Some(table) => {
-                println!("Schema for table '{}':", table_name);
-                for column in &table.columns {
-                    // println!(
-                    //     "  - Column: {} | Type: {:?} | Length: {} | Not Null: {}",
-                    //     column.name,
-                    //     ProtoColumnType::try_from(column.col_type).unwrap(),
-                    //     column.length,
-                    //     column.not_null
-                    // );
-                    println!("{:?}", column);
-                }
-                Ok(())
+    // Take the data files back before their pages are restored, so readers
+    // do not see them half-written. Failing that the pages are restored anyway.
+    fn relock_written(&self, written: &HashSet<String>) {
+        for file_path in written {
+            let _ = self.lock(file_path, LockMode::Exclusive, LockScope::Statement);
+        }
+    }
+
+    // Lock the rows an UPDATE or DELETE is about to change, then the table to
+    // write them. Returns false if the rows had to be waited for: the statement
+    // then starts over, as they may have changed meanwhile.
+    fn lock_for_change(&self, table: &str, rows: &[(u64, Row)]) -> Result<bool, DatabaseError> {
+        let row_ids: Vec<u64> = rows.iter().map(|(row_id, _)| *row_id).collect();
+        let wait = self.lock_manager.borrow_mut().lock_rows(
+            table,
+            &row_ids,
+            LockMode::Exclusive,
+            Duration::ZERO,
+        )?;
+        match wait {
+            Wait::Granted => {
+                self.lock_table(table, LockMode::Exclusive, LockScope::Statement)?;
+                return Ok(true);
             }
-            None => Err(DatabaseError::TableDoesNotExist(table_name.to_string())),
+            Wait::Deadlock => return Err(DatabaseError::Deadlock),
+            Wait::TimedOut => {}
         }
+        // The session holding the rows may need the table and the log to finish.
+        // The log is only kept if the statement has written already.
+        self.locks.borrow_mut().release_statement();
+        if self.buffer_pool.borrow().savepoint() == 0 {
+            self.end_write()?;
+        }
+        self.lock_rows(table, &row_ids, LockMode::Exclusive)?;
+        Ok(false)
     }
-    pub fn load_table(&self, table_name: &str) -> Result<TableData, DatabaseError> {
-        if !self.has_table(table_name) {
-            return Err(DatabaseError::TableDoesNotExist(table_name.to_string()));
-        }
-        let database: Database = self.load_database();
-        let file_path = format!("{}.tab", table_name);
-        let path = Path::new(&file_path);
-        if path.exists() {
-            let mut file = OpenOptions::new()
-                .read(true)
-                .open(format!("{}.tab", table_name))
-                .expect("Failed to open file");
-
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer).expect("Failed to read file");
-            let table_data = TableData::decode(&*buffer).expect("Failed to decode database");
-            // println!("table_data {:?}", table_data);
-            Ok(table_data)
-        } else {
-            Ok(TableData {
-                table_name: table_name.to_string(),
-                rows: Vec::new(),
-                num_rows: 0,
-            })
+
+    // Lock rows of `table` until the transaction ends
+    fn lock_rows(&self, table: &str, row_ids: &[u64], mode: LockMode) -> Result<(), DatabaseError> {
+        let timeout = self.locks.borrow().timeout();
+        let wait = self
+            .lock_manager
+            .borrow_mut()
+            .lock_rows(table, row_ids, mode, timeout)?;
+        self.check_wait(wait, &format!("rows of table '{}'", table))
+    }
+
+    // Lock the rows a SELECT ... FOR SHARE or FOR UPDATE returns. The statement's
+    // file locks are let go while waiting, as the session holding the rows may
+    // need them to finish, so the rows are read again once they are locked.
+    fn lock_selected(
+        &self,
+        table_def: &TableDefinition,
+        rows: Vec<(u64, Row)>,
+        locking: RowLocking,
+    ) -> Result<Vec<(u64, Row)>, DatabaseError> {
+        let mode = match locking {
+            RowLocking::Share => LockMode::Shared,
+            RowLocking::Update => LockMode::Exclusive,
+        };
+        let row_ids: Vec<u64> = rows.iter().map(|(row_id, _)| *row_id).collect();
+        self.locks.borrow_mut().release_statement();
+        self.lock_rows(&table_def.name, &row_ids, mode)?;
+        self.lock_catalog(LockMode::Shared)?;
+        self.lock_table(&table_def.name, LockMode::Shared, LockScope::Statement)?;
+        // Unless this transaction holds the log, others may have written meanwhile
+        if !self.buffer_pool.borrow().in_transaction() {
+            let csn = self.commit_log.borrow_mut().last_csn()?;
+            self.clear_cache(csn);
+        }
+        let database = self.load_database()?;
+        let table_def = Self::find_table(&database, &table_def.name)?;
+        let mut heap = self.open_heap(table_def)?;
+        let xid = self.snapshot().xid.unwrap_or(FROZEN_XID);
+        let mut locked = Vec::new();
+        for (row_id, row) in rows {
+            let current = match heap.get(row_id)? {
+                Some(record) => decode_row(heap.path(), &record)?,
+                None => return Err(DatabaseError::SerializationFailure(table_def.name.clone())),
+            };
+            // The version was moved or removed, so someone else has changed it
+            if current.xmin != row.xmin {
+                return Err(DatabaseError::SerializationFailure(table_def.name.clone()));
+            }
+            self.check_not_deleted(table_def, &current, xid)?;
+            locked.push((row_id, current));
         }
+        Ok(locked)
     }
 
-    pub fn select(&self, command: Command) -> Result<(), DatabaseError> {
-        if let Command::Select {
-            columns,
-            table,
-            join_table,
-        } = command
-        {
-            let database = self.load_database();
-            if !self.has_table(&table) {
-                return Err(DatabaseError::TableDoesNotExist(table));
+    // Remember a read so SERIALIZABLE can check at commit that nobody changed its result
+    fn record_read(&self, table: &str, conditions: &[Condition]) {
+        if let Some(transaction) = self.transaction.borrow_mut().as_mut() {
+            if transaction.isolation == IsolationLevel::Serializable {
+                transaction
+                    .reads
+                    .push((table.to_string(), conditions.to_vec()));
             }
+        }
+    }
 
-            let table_definition = database
-                .tables
-                .iter()
-                .find(|table_def| table_def.name == table)
-                .expect("Table not found");
-
-            let table_data = self.load_table(&table)?;
-
-            // println!("Table: {:?}", table_data);
-
-            // Print column headers with fixed width of 30 for each column
-            for col in table_definition.columns.iter() {
-                print!("{:<30}", col.name); // Left-align each column header with padding
-            }
-            println!();
-
-            // Print separator line with width of 80
-            println!("{}", "-".repeat(80));
-
-            // Print each row's values
-            for row in table_data.rows {
-                for cell in &row.cells {
-                    match cell {
-                        CellValue {
-                            value: Some(cell_value::Value::IntVal(v)),
-                        } => print!("{:<30}", v),
-                        CellValue {
-                            value: Some(cell_value::Value::StrVal(s)),
-                        } => print!("{:<30}", s),
-                        CellValue {
-                            value: Some(cell_value::Value::NullVal(is_null)),
-                        } => {
-                            if *is_null {
-                                print!("{:<30}", "NULL");
-                            }
-                        }
-                        CellValue { value: None } => print!("{:<30}", "Matched None"),
-                    }
+    // Fail if a transaction that committed after the snapshot created or deleted
+    // a version matching one of the transaction's reads
+    fn validate_reads(&self, transaction: &Transaction) -> Result<(), DatabaseError> {
+        // Unless this transaction holds the log, others may have written meanwhile
+        if !self.buffer_pool.borrow().in_transaction() {
+            let csn = self.commit_log.borrow_mut().last_csn()?;
+            self.clear_cache(csn);
+        }
+        self.lock_catalog(LockMode::Shared)?;
+        let database = self.load_database()?;
+        for (table, conditions) in &transaction.reads {
+            let Ok(table_def) = Self::find_table(&database, table) else {
+                continue;
+            };
+            self.lock_table(table, LockMode::Shared, LockScope::Statement)?;
+            let filter = Filter::new(table_def, conditions.clone())?;
+            for (_, row) in self.scan_versions(table_def)? {
+                if !filter.matches(&row) {
+                    continue;
+                }
+                let mut commit_log = self.commit_log.borrow_mut();
+                let snapshot = &transaction.snapshot;
+                if snapshot.is_concurrent(row.xmin, &mut commit_log)?
+                    || snapshot.is_concurrent(row.xmax.unwrap_or(0), &mut commit_log)?
+                {
+                    return Err(DatabaseError::SerializationFailure(table.clone()));
                 }
-                println!(); // End of row
             }
-            Ok(())
-        } else {
-            panic!("Invalid command passed to select");
         }
+        Ok(())
     }
 
-    pub fn insert(&self, command: Command) -> Result<(), DatabaseError> {
-        println!("Insert command: {:?}", command);
-        if let Command::Insert { table, values } = command {
-            let table_data = self.load_table(&table)?;
-            let mut new_rows = Vec::new();
-            // Convert each `nom_parser::Value` to `CellValue`
-            let mut cells = Vec::new();
-
-            for row in values {
-                for cell_value in row {
-                    if cfg!(debug_assertions) {
-                        println!("{:?}", cell_value);
+    // Run `operation` as one transaction: its changes are committed together if it
+    // succeeds and rolled back if it fails. Inside an open transaction only the
+    // operation's own changes are rolled back and the transaction stays open.
+    fn in_transaction<T>(
+        &self,
+        operation: impl FnOnce() -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        if self.transaction.borrow().is_some() {
+            let savepoint = self.buffer_pool.borrow().savepoint();
+            let outermost = !self.statement_running.replace(true);
+            let result = operation()
+                .and_then(|value| {
+                    // Write out the statement's pages while its tables are still locked
+                    if outermost && self.buffer_pool.borrow().in_transaction() {
+                        self.buffer_pool.borrow_mut().flush_all()?;
                     }
-
-                    match cell_value {
-                        Value::Int(v) => {
-                            // println!("int value {:?}", v);
-                            cells.push(CellValue {
-                                value: Some(cell_value::Value::IntVal(v)),
-                            });
-                        }
-                        Value::Str(s) => {
-                            // println!("string value {:?}", s);
-                            cells.push(CellValue {
-                                value: Some(cell_value::Value::StrVal(s)),
-                            });
-                        }
-                        Value::Null => {
-                            // println!("Null value found");
-                            cells.push(CellValue {
-                                value: Some(cell_value::Value::NullVal(true)),
-                            });
+                    Ok(value)
+                })
+                .inspect_err(|error| {
+                    *self.catalog.borrow_mut() = None;
+                    // A serialization failure or deadlock dooms the whole transaction
+                    if matches!(
+                        error,
+                        DatabaseError::SerializationFailure(_) | DatabaseError::Deadlock
+                    ) {
+                        self.abort_transaction();
+                        return;
+                    }
+                    if self.buffer_pool.borrow().in_transaction() {
+                        let written = self
+                            .transaction
+                            .borrow()
+                            .as_ref()
+                            .map(|t| t.written.clone());
+                        self.relock_written(&written.unwrap_or_default());
+                        let result = self.buffer_pool.borrow_mut().rollback_to(savepoint);
+                        if result.is_err() {
+                            self.abort_transaction();
                         }
                     }
-                    new_rows.push(Row {
-                        cells: cells.clone(),
-                    });
+                });
+            // A statement of a longer transaction has ended
+            if outermost {
+                self.statement_running.set(false);
+                self.locks.borrow_mut().release_statement();
+                if let Err(error) = self.end_write() {
+                    self.abort_transaction();
+                    return Err(error);
                 }
-                // rows.push(table_row);
-                // table_row.clear();
-                cells.clear();
             }
+            return result;
+        }
+        let snapshot = self.take_snapshot()?;
+        *self.transaction.borrow_mut() =
+            Some(Transaction::new(snapshot, IsolationLevel::Snapshot, false));
+        self.statement_running.set(true);
+        let result = operation();
+        self.statement_running.set(false);
+        match result {
+            Ok(value) => {
+                let transaction = self.transaction.borrow_mut().take();
+                if let Some(transaction) = transaction {
+                    self.commit(transaction)?;
+                }
+                Ok(value)
+            }
+            Err(error) => {
+                self.abort_transaction();
+                Err(error)
+            }
+        }
+    }
 
-            let mut all_rows = table_data.rows.clone();
-            all_rows.extend(new_rows);
-            let len = all_rows.len();
+    // Validate and commit `transaction`, rolling it back if either fails.
+    // Every lock is released once it is over.
+    fn commit(&self, transaction: Transaction) -> Result<(), DatabaseError> {
+        let result = self.commit_locked(transaction);
+        result.and(self.release_locks())
+    }
 
-            let table_data = TableData {
-                table_name: table,
-                rows: all_rows,
-                num_rows: len as u32,
-            };
+    fn release_locks(&self) -> Result<(), DatabaseError> {
+        self.locks.borrow_mut().release_all();
+        self.lock_manager.borrow_mut().release_all()?;
+        Ok(())
+    }
 
-            // println!("table_data : {:?}", table_data);
+    fn commit_locked(&self, transaction: Transaction) -> Result<(), DatabaseError> {
+        let serializable = transaction.isolation == IsolationLevel::Serializable;
+        loop {
+            // Reads are checked against the commits up to `csn`. Should another
+            // transaction commit before this one, they are checked again.
+            let csn = self.commit_log.borrow_mut().last_csn()?;
+            if serializable {
+                if let Err(error) = self.validate_reads(&transaction) {
+                    let _ = self.roll_back(&transaction);
+                    return Err(error);
+                }
+            }
+            // Nothing to do for a transaction that only read
+            let Some(xid) = transaction.snapshot.xid else {
+                return Ok(());
+            };
+            match self.record_commit(xid, serializable.then_some(csn)) {
+                Ok(Some(csn)) => {
+                    if self.buffer_pool.borrow().in_transaction() {
+                        // Nobody else wrote while this transaction held the log
+                        self.seen_csn.set(csn);
+                        self.buffer_pool.borrow_mut().end()?;
+                    }
+                    return Ok(());
+                }
+                Ok(None) => continue,
+                Err(error) => {
+                    let _ = self.roll_back(&transaction);
+                    return Err(error);
+                }
+            }
+        }
+    }
 
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(format!("{}.tab", table_data.table_name))
-                .expect("Failed to open file");
+    // Record the commit of `xid` unless another transaction committed after
+    // `after`. A transaction still holding the log writes its pages out first;
+    // the others already have.
+    fn record_commit(&self, xid: u64, after: Option<u64>) -> Result<Option<u64>, DatabaseError> {
+        let logged = self.buffer_pool.borrow().in_transaction();
+        let committed = self.commit_log.borrow_mut().commit_after(xid, after, || {
+            if logged {
+                let mut buffer_pool = self.buffer_pool.borrow_mut();
+                buffer_pool.commit()?;
+                buffer_pool.write_point()?;
+            }
+            Ok(())
+        })?;
+        Ok(committed)
+    }
 
-            let mut buffer = Vec::new();
-            table_data
-                .encode(&mut buffer)
-                .expect("Failed to encode database");
+    // Undo the writes of `transaction`, if it made any, and let other writers in
+    fn roll_back(&self, transaction: &Transaction) -> Result<(), DatabaseError> {
+        // The cached catalog may hold changes that are being undone
+        *self.catalog.borrow_mut() = None;
+        // Pages are put back by the log before these locks are given up
+        if self.buffer_pool.borrow().in_transaction() {
+            self.relock_written(&transaction.written);
+        }
+        let result = self.undo(transaction);
+        result.and(self.release_locks())
+    }
 
-            file.write_all(&buffer).expect("Failed to write to file");
-            file.sync_all().expect("Failed to sync file");
+    fn undo(&self, transaction: &Transaction) -> Result<(), DatabaseError> {
+        let Some(xid) = transaction.snapshot.xid else {
+            return Ok(());
+        };
+        // Pages written while holding the log are put back from it. Those of
+        // earlier statements stay, hidden by recording the transaction as
+        // aborted. Should the rollback fail, the log still holds the
+        // transaction and the next writer rolls it back instead.
+        let logged = self.buffer_pool.borrow().in_transaction();
+        let rolled_back = if logged {
+            self.buffer_pool.borrow_mut().rollback()
+        } else {
             Ok(())
+        };
+        let rolled_back = rolled_back.and_then(|_| self.commit_log.borrow_mut().abort(xid));
+        let released = if logged {
+            self.buffer_pool.borrow_mut().release_log()
         } else {
-            panic!("Invalid command passed to insert");
+            Ok(())
+        };
+        rolled_back?;
+        released?;
+        Ok(())
+    }
+
+    // Roll back whatever transaction is open after a failure
+    fn abort_transaction(&self) {
+        let transaction = self.transaction.borrow_mut().take();
+        if let Some(transaction) = transaction {
+            let _ = self.roll_back(&transaction);
+        }
+    }
+
+    pub fn in_explicit_transaction(&self) -> bool {
+        self.transaction
+            .borrow()
+            .as_ref()
+            .is_some_and(|transaction| transaction.explicit)
+    }
+
+    // Handle BEGIN: statements up to COMMIT or ROLLBACK form a single transaction
+    // and see the database as it was at this point, apart from their own changes
+    pub fn begin_transaction(&self, isolation: IsolationLevel) -> Result<(), DatabaseError> {
+        if self.in_explicit_transaction() {
+            return Err(DatabaseError::TransactionAlreadyActive);
         }
+        let snapshot = self.take_snapshot()?;
+        *self.transaction.borrow_mut() = Some(Transaction::new(snapshot, isolation, true));
+        Ok(())
+    }
+
+    pub fn commit_transaction(&self) -> Result<(), DatabaseError> {
+        let transaction = self.transaction.borrow_mut().take();
+        match transaction {
+            Some(transaction) => self.commit(transaction),
+            None => Err(DatabaseError::NoActiveTransaction),
+        }
+    }
+
+    pub fn rollback_transaction(&self) -> Result<(), DatabaseError> {
+        let transaction = self.transaction.borrow_mut().take();
+        match transaction {
+            Some(transaction) => self.roll_back(&transaction),
+            None => Err(DatabaseError::NoActiveTransaction),
+        }
+    }
+
+    // A savepoint with the name of an existing one hides it until released.
+    // Changes after it are undone from the log, so the log is kept from then on.
+    pub fn savepoint(&self, name: &str) -> Result<(), DatabaseError> {
+        let mark = self.buffer_pool.borrow().savepoint();
+        match self.transaction.borrow_mut().as_mut() {
+            Some(transaction) => {
+                transaction.savepoints.push((name.to_string(), mark));
+                transaction.keeps_log = true;
+            }
+            None => return Err(DatabaseError::NoActiveTransaction),
+        }
+        Ok(())
+    }
+
+    // Position of the most recent savepoint called `name`
+    fn find_savepoint(&self, name: &str) -> Result<usize, DatabaseError> {
+        self.transaction
+            .borrow()
+            .as_ref()
+            .ok_or(DatabaseError::NoActiveTransaction)?
+            .savepoints
+            .iter()
+            .rposition(|(savepoint, _)| savepoint == name)
+            .ok_or_else(|| DatabaseError::SavepointDoesNotExist(name.to_string()))
+    }
+
+    // Undo everything since the savepoint; the savepoint itself stays usable
+    pub fn rollback_to_savepoint(&self, name: &str) -> Result<(), DatabaseError> {
+        let position = self.find_savepoint(name)?;
+        let mark = {
+            let mut transaction = self.transaction.borrow_mut();
+            let savepoints = &mut transaction
+                .as_mut()
+                .expect("checked by find_savepoint")
+                .savepoints;
+            savepoints.truncate(position + 1);
+            savepoints[position].1
+        };
+        *self.catalog.borrow_mut() = None;
+        // Nothing has been written yet if the transaction has no id
+        if !self.buffer_pool.borrow().in_transaction() {
+            return Ok(());
+        }
+        let written = self
+            .transaction
+            .borrow()
+            .as_ref()
+            .map(|t| t.written.clone());
+        self.relock_written(&written.unwrap_or_default());
+        let result = self.buffer_pool.borrow_mut().rollback_to(mark);
+        self.locks.borrow_mut().release_statement();
+        if let Err(error) = result {
+            self.abort_transaction();
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+    // Forget the savepoint and every one set after it, keeping their changes
+    pub fn release_savepoint(&self, name: &str) -> Result<(), DatabaseError> {
+        let position = self.find_savepoint(name)?;
+        if let Some(transaction) = self.transaction.borrow_mut().as_mut() {
+            transaction.savepoints.truncate(position);
+        }
+        Ok(())
+    }
+
+    // Data and index files live next to the catalog file
+    fn sibling_path(&self, file_name: String) -> String {
+        Path::new(&self.file_path)
+            .with_file_name(file_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Path of the data file holding the rows of `table_name`
+    fn table_file_path(&self, table_name: &str) -> String {
+        self.sibling_path(format!("{}.tab", table_name))
+    }
+
+    // Path of the file holding index `index_name` of `table_name`
+    fn index_file_path(&self, table_name: &str, index_name: &str) -> String {
+        self.sibling_path(format!("{}.{}.idx", table_name, index_name))
+    }
+
+    pub fn has_table(&self, table_name: &str) -> Result<bool, DatabaseError> {
+        let database = self.load_database()?;
+        Ok(database.tables.iter().any(|table| table.name == table_name))
+    }
+
+    // Handle the CREATE TABLE command and update the database.
+    // Returns `false` when IF NOT EXISTS was given and the table already exists.
+    pub fn create_table(&self, command: Command) -> Result<bool, DatabaseError> {
+        self.in_transaction(|| {
+            self.start_write()?;
+            self.lock_catalog(LockMode::Exclusive)?;
+            if let Command::CreateTable {
+                name,
+                columns,
+                if_not_exists,
+            } = command
+            {
+                // Convert columns to protobuf ColumnDefinition
+                if self.has_table(&name)? {
+                    if if_not_exists {
+                        return Ok(false);
+                    }
+                    return Err(DatabaseError::TableAlreadyExists(name));
+                }
+                let column_defs: Vec<ColumnDefinition> =
+                    columns.into_iter().map(ColumnDefinition::from).collect();
+                let table_def = TableDefinition {
+                    name,
+                    columns: column_defs,
+                    indexes: Vec::new(),
+                };
+
+                // Load the current database, add the table, and save it back
+                let mut database = self.load_database()?;
+                database.tables.push(table_def);
+                self.save_database(&database)?;
+                Ok(true)
+            } else {
+                Err(DatabaseError::InvalidCommand("create_table".to_string()))
+            }
+        })
+    }
+
+    // Definitions of every table, in the order they were created
+    pub fn list_tables(&self) -> Result<Vec<TableDefinition>, DatabaseError> {
+        self.in_transaction(|| {
+            self.lock_catalog(LockMode::Shared)?;
+            Ok(self.load_database()?.tables)
+        })
+    }
+
+    // Remove the table from the catalog together with its data file.
+    // Returns `false` when IF EXISTS was given and the table does not exist.
+    pub fn drop_table(&self, table_name: &str, if_exists: bool) -> Result<bool, DatabaseError> {
+        self.in_transaction(|| {
+            self.start_write()?;
+            self.lock_catalog(LockMode::Exclusive)?;
+            let mut database = self.load_database()?;
+            let table_index = database
+                .tables
+                .iter()
+                .position(|table| table.name == table_name);
+            match table_index {
+                Some(index) => {
+                    self.lock_table(table_name, LockMode::Exclusive, LockScope::Transaction)?;
+                    let table_def = database.tables.remove(index);
+                    self.save_database(&database)?;
+                    self.remove_table_files(&table_def)?;
+                    Ok(true)
+                }
+                None if if_exists => Ok(false),
+                None => Err(DatabaseError::TableDoesNotExist(table_name.to_string())),
+            }
+        })
+    }
+
+    // Delete every row of the table but keep its definition in the catalog
+    pub fn truncate_table(&self, table_name: &str) -> Result<(), DatabaseError> {
+        self.in_transaction(|| {
+            self.start_write()?;
+            self.lock_catalog(LockMode::Shared)?;
+            let database = self.load_database()?;
+            let table_def = Self::find_table(&database, table_name)?;
+            self.lock_table(table_name, LockMode::Exclusive, LockScope::Transaction)?;
+            self.remove_table_files(table_def)
+        })
+    }
+
+    // A missing data or index file means it is empty, so removing them empties the table
+    fn remove_table_files(&self, table_def: &TableDefinition) -> Result<(), DatabaseError> {
+        self.remove_file_if_exists(&self.table_file_path(&table_def.name))?;
+        for index_def in &table_def.indexes {
+            self.remove_file_if_exists(&self.index_file_path(&table_def.name, &index_def.name))?;
+        }
+        Ok(())
+    }
+
+    fn remove_file_if_exists(&self, file_path: &str) -> Result<(), DatabaseError> {
+        self.buffer_pool.borrow_mut().write_file(file_path, None)?;
+        Ok(())
+    }
+
+    fn find_table<'a>(
+        database: &'a Database,
+        table_name: &str,
+    ) -> Result<&'a TableDefinition, DatabaseError> {
+        database
+            .tables
+            .iter()
+            .find(|table| table.name == table_name)
+            .ok_or_else(|| DatabaseError::TableDoesNotExist(table_name.to_string()))
+    }
+
+    // Handle CREATE [UNIQUE] INDEX: build the index from the existing rows and register it
+    pub fn create_index(&self, command: Command) -> Result<(), DatabaseError> {
+        self.in_transaction(|| {
+            self.start_write()?;
+            if let Command::CreateIndex {
+                name,
+                table,
+                columns,
+                unique,
+                method,
+            } = command
+            {
+                self.lock_catalog(LockMode::Exclusive)?;
+                let mut database = self.load_database()?;
+                if database
+                    .tables
+                    .iter()
+                    .any(|table_def| table_def.indexes.iter().any(|index| index.name == name))
+                {
+                    return Err(DatabaseError::IndexAlreadyExists(name));
+                }
+                let index_def = IndexDefinition {
+                    name,
+                    columns,
+                    unique,
+                    method: match method {
+                        IndexMethod::BTree => ProtoIndexMethod::Btree as i32,
+                        IndexMethod::Hash => ProtoIndexMethod::Hash as i32,
+                    },
+                };
+                let table_def = database
+                    .tables
+                    .iter_mut()
+                    .find(|table_def| table_def.name == table)
+                    .ok_or_else(|| DatabaseError::TableDoesNotExist(table.clone()))?;
+                self.lock_table(&table, LockMode::Exclusive, LockScope::Transaction)?;
+                let rows = self.scan_versions(table_def)?;
+                let index = build_index(table_def, &index_def, &rows, |row| {
+                    self.is_live_checked(&table, row)
+                })?;
+                self.save_index(&self.index_file_path(&table, &index_def.name), index)?;
+                table_def.indexes.push(index_def);
+                self.save_database(&database)?;
+                Ok(())
+            } else {
+                Err(DatabaseError::InvalidCommand("create_index".to_string()))
+            }
+        })
+    }
+
+    pub fn drop_index(&self, index_name: &str) -> Result<(), DatabaseError> {
+        self.in_transaction(|| {
+            self.start_write()?;
+            self.lock_catalog(LockMode::Exclusive)?;
+            let mut database = self.load_database()?;
+            for table_def in database.tables.iter_mut() {
+                if let Some(position) = table_def
+                    .indexes
+                    .iter()
+                    .position(|index| index.name == index_name)
+                {
+                    self.lock_table(&table_def.name, LockMode::Exclusive, LockScope::Transaction)?;
+                    table_def.indexes.remove(position);
+                    let file_path = self.index_file_path(&table_def.name, index_name);
+                    self.save_database(&database)?;
+                    return self.remove_file_if_exists(&file_path);
+                }
+            }
+            Err(DatabaseError::IndexDoesNotExist(index_name.to_string()))
+        })
+    }
+
+    // The data file may only exist in the buffer pool until its pages are flushed
+    fn table_file_exists(&self, file_path: &str) -> bool {
+        self.buffer_pool.borrow().is_cached(file_path, 0) || Path::new(file_path).exists()
+    }
+
+    // Handle CHECK DATABASE: read every file of the database and check it
+    // against the catalog, without changing anything
+    pub fn check_database(&self) -> Result<CheckReport, DatabaseError> {
+        self.in_transaction(|| {
+            self.lock_catalog(LockMode::Shared)?;
+            let mut report = CheckReport::default();
+            let database = match self.read_catalog() {
+                Ok(database) => database,
+                Err(error) => {
+                    report.problems.push(Problem::CorruptFile {
+                        path: self.file_path.clone(),
+                        error: error.to_string(),
+                    });
+                    return Ok(report);
+                }
+            };
+            self.check_orphans(&database, &mut report)?;
+            let mut names = HashSet::new();
+            for table_def in &database.tables {
+                if !names.insert(&table_def.name) {
+                    report.problems.push(Problem::BadDefinition {
+                        table: table_def.name.clone(),
+                        error: "the table is defined more than once".to_string(),
+                    });
+                    continue;
+                }
+                self.lock_table(&table_def.name, LockMode::Shared, LockScope::Statement)?;
+                self.check_table(table_def, &mut report)?;
+            }
+            Ok(report)
+        })
+    }
+
+    // Data and index files next to the catalog that it knows nothing of
+    fn check_orphans(
+        &self,
+        database: &Database,
+        report: &mut CheckReport,
+    ) -> Result<(), DatabaseError> {
+        let mut known = HashSet::new();
+        for table_def in &database.tables {
+            known.insert(self.table_file_path(&table_def.name));
+            for index_def in &table_def.indexes {
+                known.insert(self.index_file_path(&table_def.name, &index_def.name));
+            }
+        }
+        let dir = match Path::new(&self.file_path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut orphans = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !matches!(
+                path.extension().and_then(|extension| extension.to_str()),
+                Some("tab" | "idx")
+            ) {
+                continue;
+            }
+            let file_name = path.file_name().expect("directory entries have a name");
+            let file_path = self.sibling_path(file_name.to_string_lossy().into_owned());
+            if !known.contains(&file_path) {
+                orphans.push(file_path);
+            }
+        }
+        orphans.sort();
+        report
+            .problems
+            .extend(orphans.into_iter().map(|path| Problem::OrphanFile { path }));
+        Ok(())
+    }
+
+    fn check_table(
+        &self,
+        table_def: &TableDefinition,
+        report: &mut CheckReport,
+    ) -> Result<(), DatabaseError> {
+        report.tables += 1;
+        let file_path = self.table_file_path(&table_def.name);
+        let mut versions = Vec::new();
+        let mut damaged = false;
+        if self.table_file_exists(&file_path) {
+            let mut heap = match HeapFile::open(&self.buffer_pool, &file_path) {
+                Ok(heap) => heap,
+                Err(error) => {
+                    // Without the rows there is nothing to hold the indexes against
+                    report.problems.push(Problem::CorruptFile {
+                        path: file_path,
+                        error: error.to_string(),
+                    });
+                    return Ok(());
+                }
+            };
+            // A damaged page is reported on its own and the other pages are still checked
+            for page_id in 1..heap.page_count() {
+                let records = match heap.page_records(page_id) {
+                    Ok(records) => records,
+                    Err(error) => {
+                        report.problems.push(Problem::CorruptFile {
+                            path: file_path.clone(),
+                            error: error.to_string(),
+                        });
+                        damaged = true;
+                        continue;
+                    }
+                };
+                for (row_id, record) in records {
+                    match decode_row(&file_path, &record) {
+                        Ok(row) => versions.push((row_id, row)),
+                        Err(error) => report.problems.push(Problem::UndecodableRow {
+                            table: table_def.name.clone(),
+                            row_id,
+                            error: error.to_string(),
+                        }),
+                    }
+                }
+            }
+        }
+        // Versions that are deleted for good no longer have to fit the schema
+        for (row_id, row) in &versions {
+            if self.is_live(row)? {
+                report.rows += 1;
+                check_row(table_def, *row_id, row, &mut report.problems);
+            }
+        }
+        // Without every row there is nothing to hold the indexes against
+        if damaged {
+            return Ok(());
+        }
+        for index_def in &table_def.indexes {
+            report.indexes += 1;
+            self.check_index(table_def, index_def, &versions, report)?;
+        }
+        Ok(())
+    }
+
+    fn check_index(
+        &self,
+        table_def: &TableDefinition,
+        index_def: &IndexDefinition,
+        versions: &[(u64, Row)],
+        report: &mut CheckReport,
+    ) -> Result<(), DatabaseError> {
+        let positions = match index_positions(table_def, index_def) {
+            Ok(positions) => positions,
+            Err(error) => {
+                report.problems.push(Problem::BadDefinition {
+                    table: table_def.name.clone(),
+                    error: format!("index '{}': {}", index_def.name, error),
+                });
+                return Ok(());
+            }
+        };
+        if index_def.unique {
+            let mut keys = HashSet::new();
+            for (_, row) in versions {
+                let key = index_key(row, &positions);
+                if !key_has_null(&key) && self.is_live(row)? && !keys.insert(key) {
+                    report.problems.push(Problem::DuplicateKey {
+                        table: table_def.name.clone(),
+                        index: index_def.name.clone(),
+                    });
+                    break;
+                }
+            }
+        }
+        let file_path = self.index_file_path(&table_def.name, &index_def.name);
+        match TableIndex::open(&self.buffer_pool, index_def, &file_path)
+            .and_then(|mut stored| stored.entries())
+        {
+            Ok(stored) => {
+                // Uniqueness was checked above, so no version counts as live here
+                let mut rebuilt = build_index(table_def, index_def, versions, |_| Ok(false))?;
+                if stored != rebuilt.entries()? {
+                    report.problems.push(Problem::IndexMismatch {
+                        table: table_def.name.clone(),
+                        index: index_def.name.clone(),
+                    });
+                }
+            }
+            Err(error) => report.problems.push(Problem::CorruptFile {
+                path: file_path,
+                error: error.to_string(),
+            }),
+        }
+        Ok(())
+    }
+
+    fn open_heap(&self, table_def: &TableDefinition) -> Result<HeapFile<'_>, DatabaseError> {
+        let file_path = self.table_file_path(&table_def.name);
+        HeapFile::open(&self.buffer_pool, &file_path).map_err(|e| file_error(&file_path, e))
+    }
+
+    // Rewrite a protobuf `TableData` dump, what table files held before they had
+    // pages, as a heap file
+    fn convert_table_data(&self, table_def: &TableDefinition) -> Result<(), DatabaseError> {
+        let file_path = self.table_file_path(&table_def.name);
+        let buffer = FileManager::new(&file_path).read_file()?;
+        let table_data = TableData::decode(&*buffer).map_err(|e| decode_error(&file_path, e))?;
+        self.remove_file_if_exists(&file_path)?;
+        let mut heap = HeapFile::open(&self.buffer_pool, &file_path)?;
+        let mut rows = Vec::new();
+        for mut row in table_data.rows {
+            // Old rows stay frozen; room is made for a deleting transaction
+            row.xmax = Some(0);
+            rows.push((heap.insert(&row.encode_to_vec())?, row));
+        }
+        // Row ids of the old format were positions, so every index has to be rebuilt
+        for index_def in &table_def.indexes {
+            let index = build_index(table_def, index_def, &rows, |_| Ok(true))?;
+            self.save_index(
+                &self.index_file_path(&table_def.name, &index_def.name),
+                index,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Bring every file of the database up to the current on-disk format. If any
+    // is older, all of them are first copied to `backup_dir`; the upgrade itself
+    // is a single transaction, so a crash midway leaves the old files in place.
+    // Returns the files that were upgraded.
+    pub fn migrate(&self, backup_dir: &Path) -> Result<Vec<String>, DatabaseError> {
+        self.in_transaction(|| {
+            self.start_write()?;
+            self.lock_catalog(LockMode::Exclusive)?;
+            let Some(catalog) = read_if_exists(&self.file_path)? else {
+                return Ok(Vec::new());
+            };
+            let message = format::decode_any(CATALOG_MAGIC, &catalog)
+                .map_err(|e| file_error(&self.file_path, e))?;
+            let database =
+                Database::decode(message).map_err(|e| decode_error(&self.file_path, e))?;
+            let mut files = vec![self.file_path.clone()];
+            let mut outdated = HashSet::new();
+            if format::version(CATALOG_MAGIC, &catalog) < FORMAT_VERSION {
+                outdated.insert(self.file_path.clone());
+            }
+            for table_def in &database.tables {
+                self.lock_table(&table_def.name, LockMode::Exclusive, LockScope::Transaction)?;
+                let table_path = self.table_file_path(&table_def.name);
+                // The indexes of a protobuf dump are rebuilt along with it
+                let mut legacy = false;
+                if Path::new(&table_path).exists() {
+                    files.push(table_path.clone());
+                    match HeapFile::format_version(&self.buffer_pool, &table_path)? {
+                        Some(version) if version >= FORMAT_VERSION => {
+                            format::check_version(version)
+                                .map_err(|e| file_error(&table_path, e))?
+                        }
+                        version => {
+                            legacy = version.is_none();
+                            outdated.insert(table_path);
+                        }
+                    }
+                }
+                for index_def in &table_def.indexes {
+                    let index_path = self.index_file_path(&table_def.name, &index_def.name);
+                    let Some(bytes) = read_if_exists(&index_path)? else {
+                        continue;
+                    };
+                    files.push(index_path.clone());
+                    let version = format::version(INDEX_MAGIC, &bytes);
+                    if version > FORMAT_VERSION {
+                        format::check_version(version).map_err(|e| file_error(&index_path, e))?;
+                    }
+                    if legacy || version < FORMAT_VERSION {
+                        outdated.insert(index_path);
+                    }
+                }
+            }
+            if outdated.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            fs::create_dir_all(backup_dir)?;
+            for file_path in &files {
+                let file_name = Path::new(file_path)
+                    .file_name()
+                    .expect("database files have a name");
+                let backup_path = backup_dir.join(file_name);
+                FileManager::new(&backup_path.to_string_lossy())
+                    .write_file(&FileManager::new(file_path).read_file()?)?;
+            }
+
+            for table_def in &database.tables {
+                let table_path = self.table_file_path(&table_def.name);
+                let mut rebuilt = false;
+                if outdated.contains(&table_path) {
+                    match HeapFile::format_version(&self.buffer_pool, &table_path)? {
+                        Some(_) => HeapFile::upgrade(&self.buffer_pool, &table_path)?,
+                        None => {
+                            self.convert_table_data(table_def)?;
+                            rebuilt = true;
+                        }
+                    }
+                }
+                // Index files of older versions are built again from the rows,
+                // which is simpler than telling apart what changed in each version
+                let mut rows = None;
+                for index_def in &table_def.indexes {
+                    let index_path = self.index_file_path(&table_def.name, &index_def.name);
+                    if rebuilt || !outdated.contains(&index_path) {
+                        continue;
+                    }
+                    if rows.is_none() {
+                        rows = Some(self.scan_versions(table_def)?);
+                    }
+                    let rows = rows.as_ref().expect("just scanned");
+                    let index = build_index(table_def, index_def, rows, |row| {
+                        self.is_live_checked(&table_def.name, row)
+                    })?;
+                    self.save_index(&index_path, index)?;
+                }
+            }
+            if outdated.contains(&self.file_path) {
+                self.save_database(&database)?;
+            }
+            Ok(files
+                .into_iter()
+                .filter(|file_path| outdated.contains(file_path))
+                .collect())
+        })
+    }
+
+    // Every stored version of the table's rows with its row id, in storage order
+    fn scan_versions(&self, table_def: &TableDefinition) -> Result<Vec<(u64, Row)>, DatabaseError> {
+        if !self.table_file_exists(&self.table_file_path(&table_def.name)) {
+            return Ok(Vec::new());
+        }
+        let mut heap = self.open_heap(table_def)?;
+        heap.scan()
+            .map_err(|e| file_error(heap.path(), e))?
+            .into_iter()
+            .map(|(row_id, record)| Ok((row_id, decode_row(heap.path(), &record)?)))
+            .collect()
+    }
+
+    // The rows of the table the running statement can see
+    fn scan_rows(&self, table_def: &TableDefinition) -> Result<Vec<(u64, Row)>, DatabaseError> {
+        let snapshot = self.snapshot();
+        let mut rows = Vec::new();
+        for (row_id, row) in self.scan_versions(table_def)? {
+            if self.is_visible(&snapshot, &row)? {
+                rows.push((row_id, row));
+            }
+        }
+        Ok(rows)
+    }
+
+    // True if one of `row_ids` of `table`, other than those in `excluded`, is a live version
+    fn any_live(
+        &self,
+        table: &str,
+        heap: &mut HeapFile<'_>,
+        row_ids: Vec<u64>,
+        excluded: &HashSet<u64>,
+    ) -> Result<bool, DatabaseError> {
+        for row_id in row_ids {
+            if excluded.contains(&row_id) {
+                continue;
+            }
+            if let Some(record) = heap.get(row_id)? {
+                if self.is_live_checked(table, &decode_row(heap.path(), &record)?)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // First committer wins: a version another transaction has already replaced
+    // or deleted cannot be changed again
+    fn check_not_deleted(
+        &self,
+        table_def: &TableDefinition,
+        row: &Row,
+        xid: u64,
+    ) -> Result<(), DatabaseError> {
+        let deleter = row.xmax.unwrap_or(0);
+        if deleter != 0
+            && deleter != xid
+            && self.commit_log.borrow_mut().commit_csn(deleter)?.is_some()
+        {
+            return Err(DatabaseError::SerializationFailure(table_def.name.clone()));
+        }
+        Ok(())
+    }
+
+    // Mark the version at `row_id` as deleted by transaction `xid`. The version
+    // keeps its index entries; if it had to move they follow it to its new id.
+    fn delete_version(
+        &self,
+        heap: &mut HeapFile<'_>,
+        indexes: &mut [(String, TableIndex<'_>)],
+        table_def: &TableDefinition,
+        (row_id, row): (u64, &Row),
+        xid: u64,
+    ) -> Result<(), DatabaseError> {
+        self.check_not_deleted(table_def, row, xid)?;
+        let mut deleted = row.clone();
+        deleted.xmax = Some(xid);
+        let new_row_id = heap.update(row_id, &deleted.encode_to_vec())?;
+        if new_row_id == row_id {
+            return Ok(());
+        }
+        // The version stays locked at its new id
+        let wait = self.lock_manager.borrow_mut().lock_rows(
+            &table_def.name,
+            &[new_row_id],
+            LockMode::Exclusive,
+            Duration::ZERO,
+        )?;
+        self.check_wait(wait, &format!("rows of table '{}'", table_def.name))?;
+        for (index_def, (file_path, index)) in table_def.indexes.iter().zip(indexes.iter_mut()) {
+            let key = index_key(row, &index_positions(table_def, index_def)?);
+            index
+                .remove(&key, row_id)
+                .and_then(|()| index.insert(key, new_row_id))
+                .map_err(|e| file_error(file_path, e))?;
+        }
+        Ok(())
+    }
+
+    fn load_indexes(
+        &self,
+        table_def: &TableDefinition,
+    ) -> Result<Vec<(String, TableIndex<'_>)>, DatabaseError> {
+        table_def
+            .indexes
+            .iter()
+            .map(|index_def| {
+                let file_path = self.index_file_path(&table_def.name, &index_def.name);
+                let index = TableIndex::open(&self.buffer_pool, index_def, &file_path)
+                    .map_err(|e| file_error(&file_path, e))?;
+                Ok((file_path, index))
+            })
+            .collect()
+    }
+
+    // Write the index pages the statement changed
+    fn flush_indexes(indexes: &mut [(String, TableIndex<'_>)]) -> Result<(), DatabaseError> {
+        for (file_path, index) in indexes {
+            index.flush().map_err(|e| file_error(file_path, e))?;
+        }
+        Ok(())
+    }
+
+    // Rows matching `filter` with their row ids, using an index on a filtered column if one
+    // exists. A hash index is preferred when every one of its columns is compared with `=`.
+    fn matching_rows(
+        &self,
+        table_def: &TableDefinition,
+        filter: &Filter,
+    ) -> Result<Vec<(u64, Row)>, DatabaseError> {
+        if !self.table_file_exists(&self.table_file_path(&table_def.name)) {
+            return Ok(Vec::new());
+        }
+        let mut candidates = None;
+        for index_def in &table_def.indexes {
+            if index_def.method() != ProtoIndexMethod::Hash {
+                continue;
+            }
+            if let Some(key) = filter.equality_key(&index_def.columns) {
+                let file_path = self.index_file_path(&table_def.name, &index_def.name);
+                let row_ids = HashIndex::open(&self.buffer_pool, &file_path)
+                    .and_then(|mut index| index.get(&key))
+                    .map_err(|e| file_error(&file_path, e))?;
+                candidates = Some(row_ids);
+                break;
+            }
+        }
+        if candidates.is_none() {
+            for index_def in &table_def.indexes {
+                if index_def.method() != ProtoIndexMethod::Btree {
+                    continue;
+                }
+                if let Some((lower, upper)) = filter.bounds(&index_def.columns[0]) {
+                    let file_path = self.index_file_path(&table_def.name, &index_def.name);
+                    let row_ids = OrderedIndex::open(&self.buffer_pool, &file_path)
+                        .and_then(|mut index| index.range(lower.as_ref(), upper.as_ref()))
+                        .map_err(|e| file_error(&file_path, e))?;
+                    candidates = Some(row_ids);
+                    break;
+                }
+            }
+        }
+        let Some(mut row_ids) = candidates else {
+            return Ok(self
+                .scan_rows(table_def)?
+                .into_iter()
+                .filter(|(_, row)| filter.matches(row))
+                .collect());
+        };
+        // Only the pages holding the candidate rows are read. The index lists
+        // every version, so the ones this statement cannot see are skipped.
+        row_ids.sort_unstable();
+        let snapshot = self.snapshot();
+        let mut heap = self.open_heap(table_def)?;
+        let mut rows = Vec::new();
+        for row_id in row_ids {
+            let record = heap.get(row_id).map_err(|e| file_error(heap.path(), e))?;
+            if let Some(record) = record {
+                let row = decode_row(heap.path(), &record)?;
+                if filter.matches(&row) && self.is_visible(&snapshot, &row)? {
+                    rows.push((row_id, row));
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    // Definition of one table: its columns and indexes
+    pub fn table_schema(&self, table_name: &str) -> Result<TableDefinition, DatabaseError> {
+        self.in_transaction(|| {
+            self.lock_catalog(LockMode::Shared)?;
+            let database = self.load_database()?;
+            Self::find_table(&database, table_name).cloned()
+        })
+    }
+
+    // Export the table's rows as a `TableData` message
+    #[allow(dead_code)]
+    pub fn load_table(&self, table_name: &str) -> Result<TableData, DatabaseError> {
+        let database = self.load_database()?;
+        let table_def = Self::find_table(&database, table_name)?;
+        let rows: Vec<Row> = self
+            .scan_rows(table_def)?
+            .into_iter()
+            .map(|(_, row)| row)
+            .collect();
+        Ok(TableData {
+            table_name: table_name.to_string(),
+            num_rows: rows.len() as u32,
+            rows,
+        })
+    }
+
+    pub fn select(&self, command: Command) -> Result<ResultSet, DatabaseError> {
+        self.in_transaction(|| self.run_select(command))
+    }
+
+    fn run_select(&self, command: Command) -> Result<ResultSet, DatabaseError> {
+        if let Command::Select {
+            columns,
+            table,
+            join_table: _,
+            where_clause,
+            locking,
+        } = command
+        {
+            self.lock_catalog(LockMode::Shared)?;
+            let database = self.load_database()?;
+            let table_definition = Self::find_table(&database, &table)?;
+            self.lock_table(&table, LockMode::Shared, LockScope::Statement)?;
+
+            // Resolve the projected columns; `*` selects all of them in table order
+            let positions = if columns.iter().any(|col| col == "*") {
+                (0..table_definition.columns.len()).collect()
+            } else {
+                columns
+                    .iter()
+                    .map(|col| column_position(table_definition, col))
+                    .collect::<Result<Vec<_>, DatabaseError>>()?
+            };
+            self.record_read(&table, &where_clause);
+            let filter = Filter::new(table_definition, where_clause)?;
+            let mut matching = self.matching_rows(table_definition, &filter)?;
+            if let Some(locking) = locking {
+                matching = self.lock_selected(table_definition, matching, locking)?;
+            }
+
+            let columns = positions
+                .iter()
+                .map(|position| table_definition.columns[*position].clone())
+                .collect();
+            let rows = matching
+                .into_iter()
+                .map(|(_, row)| {
+                    positions
+                        .iter()
+                        .map(|position| row.cells.get(*position).cloned().unwrap_or_default())
+                        .collect()
+                })
+                .collect();
+            Ok(ResultSet::new(columns, rows))
+        } else {
+            Err(DatabaseError::InvalidCommand("select".to_string()))
+        }
+    }
+
+    // Handle INSERT; returns the number of rows inserted
+    pub fn insert(&self, command: Command) -> Result<usize, DatabaseError> {
+        self.in_transaction(|| {
+            let xid = self.start_write()?;
+            if let Command::Insert { table, values } = command {
+                self.lock_catalog(LockMode::Shared)?;
+                let database = self.load_database()?;
+                let table_def = Self::find_table(&database, &table)?;
+                self.lock_table(&table, LockMode::Exclusive, LockScope::Statement)?;
+                let mut new_rows = Vec::new();
+                // Convert each `nom_parser::Value` to `CellValue`
+                let mut cells = Vec::new();
+
+                for row in values {
+                    for cell_value in row {
+                        cells.push(CellValue::try_from(cell_value)?);
+                    }
+                    new_rows.push(Row {
+                        cells: cells.clone(),
+                        xmin: xid,
+                        xmax: Some(0),
+                    });
+                    cells.clear();
+                }
+
+                let records: Vec<Vec<u8>> = new_rows.iter().map(Row::encode_to_vec).collect();
+                if let Some(record) = records.iter().find(|record| record.len() > MAX_RECORD_SIZE) {
+                    return Err(DatabaseError::RowTooLarge(record.len()));
+                }
+
+                // Reject duplicate keys before anything is written
+                let mut indexes = self.load_indexes(table_def)?;
+                let mut heap = self.open_heap(table_def)?;
+                for (index_def, (file_path, index)) in
+                    table_def.indexes.iter().zip(indexes.iter_mut())
+                {
+                    let positions = index_positions(table_def, index_def)?;
+                    let mut batch_keys = HashSet::new();
+                    for row in &new_rows {
+                        let key = index_key(row, &positions);
+                        check_key_size(index_def, &key)?;
+                        if !index_def.unique || key_has_null(&key) {
+                            continue;
+                        }
+                        if !batch_keys.insert(key.clone()) {
+                            return Err(DatabaseError::UniqueViolation(index_def.name.clone()));
+                        }
+                        let row_ids = index.get(&key).map_err(|e| file_error(file_path, e))?;
+                        if self.any_live(&table, &mut heap, row_ids, &HashSet::new())? {
+                            return Err(DatabaseError::UniqueViolation(index_def.name.clone()));
+                        }
+                    }
+                }
+
+                let row_ids = heap.insert_all(&records)?;
+                for (row, row_id) in new_rows.iter().zip(row_ids) {
+                    for (index_def, (file_path, index)) in
+                        table_def.indexes.iter().zip(indexes.iter_mut())
+                    {
+                        let positions = index_positions(table_def, index_def)?;
+                        index
+                            .insert(index_key(row, &positions), row_id)
+                            .map_err(|e| file_error(file_path, e))?;
+                    }
+                }
+                Self::flush_indexes(&mut indexes)?;
+                Ok(new_rows.len())
+            } else {
+                Err(DatabaseError::InvalidCommand("insert".to_string()))
+            }
+        })
+    }
+
+    // Handle COPY t FROM: insert the rows of a CSV file as one statement, once
+    // every one of them fits the table
+    pub fn copy_from(&self, command: Command) -> Result<usize, DatabaseError> {
+        let Command::CopyFrom {
+            table,
+            path,
+            options,
+        } = command
+        else {
+            return Err(DatabaseError::InvalidCommand("copy_from".to_string()));
+        };
+        let table_def = self.table_schema(&table)?;
+        let input = fs::read_to_string(&path).map_err(|e| user_file_error(&path, e))?;
+        let invalid = |line, error| DatabaseError::InvalidRecord {
+            path: path.clone(),
+            line,
+            error,
+        };
+        let records = csv::read(&input, options.delimiter)
+            .map_err(|line| invalid(line, "quoted field is not closed".to_string()))?;
+        let mut values = Vec::new();
+        for record in records.iter().skip(options.header as usize) {
+            if record.fields.len() != table_def.columns.len() {
+                return Err(invalid(
+                    record.line,
+                    format!(
+                        "expected {} field(s), found {}",
+                        table_def.columns.len(),
+                        record.fields.len()
+                    ),
+                ));
+            }
+            let row = table_def
+                .columns
+                .iter()
+                .zip(&record.fields)
+                .map(|(column, field)| field_value(column, field, &options.null))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|error| invalid(record.line, error))?;
+            values.push(row);
+        }
+        self.insert(Command::Insert { table, values })
+    }
+
+    // Handle COPY t TO: write every row of the table to a CSV file
+    pub fn copy_to(&self, command: Command) -> Result<usize, DatabaseError> {
+        let Command::CopyTo {
+            table,
+            path,
+            options,
+        } = command
+        else {
+            return Err(DatabaseError::InvalidCommand("copy_to".to_string()));
+        };
+        let result = self.all_rows(table)?;
+        let record = |fields: Vec<Option<String>>| {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|text| csv::field(text.as_deref(), options.delimiter, &options.null))
+                .collect();
+            fields.join(&options.delimiter.to_string()) + "\n"
+        };
+        let mut output = String::new();
+        if options.header {
+            output.push_str(&record(
+                result
+                    .columns()
+                    .iter()
+                    .map(|c| Some(c.name.clone()))
+                    .collect(),
+            ));
+        }
+        for row in result.rows() {
+            output.push_str(&record(row.cells().iter().map(cell_text).collect()));
+        }
+        FileManager::new(&path)
+            .write_file(output.as_bytes())
+            .map_err(|e| user_file_error(&path, e))?;
+        Ok(result.len())
+    }
+
+    // Handle IMPORT JSON: insert a row for each object of a JSON or JSON Lines
+    // file as one statement, once every one of them fits the table
+    pub fn import_json(&self, command: Command) -> Result<usize, DatabaseError> {
+        let Command::ImportJson {
+            table,
+            path,
+            ignore_unknown,
+        } = command
+        else {
+            return Err(DatabaseError::InvalidCommand("import_json".to_string()));
+        };
+        let table_def = self.table_schema(&table)?;
+        let input = fs::read_to_string(&path).map_err(|e| user_file_error(&path, e))?;
+        let invalid = |line, error| DatabaseError::InvalidRecord {
+            path: path.clone(),
+            line,
+            error,
+        };
+        let documents =
+            json::read(&input).map_err(|line| invalid(line, "not valid JSON".to_string()))?;
+        let mut values = Vec::new();
+        for document in documents {
+            let line = document.line;
+            let Json::Object(members) = document.value else {
+                return Err(invalid(
+                    line,
+                    format!("expected an object, found {}", document.value.kind()),
+                ));
+            };
+            let mut row = vec![None; table_def.columns.len()];
+            for (key, value) in &members {
+                let Some(position) = table_def.columns.iter().position(|c| c.name == *key) else {
+                    if ignore_unknown {
+                        continue;
+                    }
+                    return Err(invalid(
+                        line,
+                        format!("key '{}' names no column of table '{}'", key, table),
+                    ));
+                };
+                if row[position].is_some() {
+                    return Err(invalid(line, format!("key '{}' appears twice", key)));
+                }
+                let value = json_value(&table_def.columns[position], value)
+                    .map_err(|error| invalid(line, error))?;
+                row[position] = Some(value);
+            }
+            // Columns have no defaults, so a missing key means NULL
+            let row = table_def
+                .columns
+                .iter()
+                .zip(row)
+                .map(|(column, value)| {
+                    let value = value.unwrap_or(Value::Null);
+                    check_value(column, &value).map(|_| value)
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|error| invalid(line, error))?;
+            values.push(row);
+        }
+        self.insert(Command::Insert { table, values })
+    }
+
+    // Handle EXPORT: write every row of the table to a file, one object per
+    // line for a .jsonl or .ndjson file and as one array otherwise
+    pub fn export_json(&self, command: Command) -> Result<usize, DatabaseError> {
+        let Command::ExportJson { table, path } = command else {
+            return Err(DatabaseError::InvalidCommand("export_json".to_string()));
+        };
+        let result = self.all_rows(table)?;
+        let mut objects = Vec::new();
+        for row in result.rows() {
+            let members: Vec<String> = result
+                .columns()
+                .iter()
+                .zip(row.cells())
+                .map(|(column, cell)| {
+                    let value = match &cell.value {
+                        Some(cell_value::Value::IntVal(v)) => v.to_string(),
+                        Some(cell_value::Value::StrVal(s)) => json::quote(s),
+                        Some(cell_value::Value::NullVal(_)) | None => "null".to_string(),
+                    };
+                    format!("{}:{}", json::quote(&column.name), value)
+                })
+                .collect();
+            objects.push(format!("{{{}}}", members.join(",")));
+        }
+        let extension = Path::new(&path).extension().and_then(|e| e.to_str());
+        let output = if matches!(extension, Some("jsonl" | "ndjson")) {
+            objects
+                .iter()
+                .map(|object| format!("{}\n", object))
+                .collect()
+        } else if objects.is_empty() {
+            "[]\n".to_string()
+        } else {
+            format!("[\n{}\n]\n", objects.join(",\n"))
+        };
+        FileManager::new(&path)
+            .write_file(output.as_bytes())
+            .map_err(|e| user_file_error(&path, e))?;
+        Ok(result.len())
+    }
+
+    // Every row of `table` visible to the statement
+    fn all_rows(&self, table: String) -> Result<ResultSet, DatabaseError> {
+        self.select(Command::Select {
+            columns: vec!["*".to_string()],
+            table,
+            join_table: None,
+            where_clause: Vec::new(),
+            locking: None,
+        })
+    }
+
+    // Handle BACKUP TO: write the catalog, every table as the statement sees
+    // it and the indexes to `path`, a directory that must not exist yet,
+    // with a manifest of their checksums. Tables are only locked while they
+    // are read; the files are written and read back to check them afterwards.
+    // Returns the number of tables.
+    pub fn backup(&self, command: Command) -> Result<usize, DatabaseError> {
+        let Command::Backup { path } = command else {
+            return Err(DatabaseError::InvalidCommand("backup".to_string()));
+        };
+        let (tables, files) = self.in_transaction(|| {
+            self.lock_catalog(LockMode::Shared)?;
+            let database = self.load_database()?;
+            let catalog = format::encode(CATALOG_MAGIC, &database.encode_to_vec());
+            let mut files = vec![(CATALOG_FILE.to_string(), catalog)];
+            for table_def in &database.tables {
+                self.lock_table(&table_def.name, LockMode::Shared, LockScope::Statement)?;
+                files.extend(self.backup_table(table_def)?);
+            }
+            Ok((database.tables.len(), files))
+        })?;
+        // A fresh directory, so a backup never mixes with files already there
+        let dir = Path::new(&path);
+        fs::create_dir(dir).map_err(|e| user_file_error(&path, e))?;
+        let mut created = Vec::new();
+        let written = write_backup(dir, &files, &mut created);
+        if written.is_err() {
+            // A directory written only in part is no backup
+            for file_path in &created {
+                let _ = fs::remove_file(file_path);
+            }
+            let _ = fs::remove_dir(dir);
+        }
+        written.map(|_| tables)
+    }
+
+    // The data and index files of a table in a backup, named as in the data
+    // directory. Rows are frozen so they are visible without the commit log.
+    fn backup_table(
+        &self,
+        table_def: &TableDefinition,
+    ) -> Result<Vec<(String, Vec<u8>)>, DatabaseError> {
+        let rows: Vec<Row> = self
+            .scan_rows(table_def)?
+            .into_iter()
+            .map(|(_, mut row)| {
+                row.xmin = FROZEN_XID;
+                row.xmax = Some(0);
+                row
+            })
+            .collect();
+        let records: Vec<Vec<u8>> = rows.iter().map(Message::encode_to_vec).collect();
+        let (image, row_ids) = heap_file::image(&records)?;
+        let rows: Vec<(u64, Row)> = row_ids.into_iter().zip(rows).collect();
+        let mut files = vec![(file_name(&self.table_file_path(&table_def.name)), image)];
+        for index_def in &table_def.indexes {
+            let index = build_index(table_def, index_def, &rows, |_| Ok(true))?;
+            let file_path = self.index_file_path(&table_def.name, &index_def.name);
+            files.push((file_name(&file_path), index.image()?));
+        }
+        Ok(files)
+    }
+
+    // Handle RESTORE FROM: replace every table with those of the backup in
+    // `path`. The whole backup is read and checked before anything changes,
+    // then its files take the place of the current ones in one transaction.
+    // Returns the number of tables restored. As with BACKUP TO, a relative
+    // `path` is taken from the working directory.
+    pub fn restore(&self, command: Command) -> Result<usize, DatabaseError> {
+        let Command::Restore { path } = command else {
+            return Err(DatabaseError::InvalidCommand("restore".to_string()));
+        };
+        let (database, mut files) = self.read_backup(Path::new(&path))?;
+        self.in_transaction(|| {
+            self.start_write()?;
+            self.lock_catalog(LockMode::Exclusive)?;
+            let current = self.load_database()?;
+            for table_def in current.tables.iter().chain(&database.tables) {
+                self.lock_table(&table_def.name, LockMode::Exclusive, LockScope::Transaction)?;
+            }
+            let mut restored = HashSet::new();
+            for table_def in &database.tables {
+                let mut file_paths = vec![self.table_file_path(&table_def.name)];
+                for index_def in &table_def.indexes {
+                    file_paths.push(self.index_file_path(&table_def.name, &index_def.name));
+                }
+                for file_path in file_paths {
+                    let contents = files.remove(&file_name(&file_path));
+                    self.buffer_pool
+                        .borrow_mut()
+                        .write_file(&file_path, contents)?;
+                    restored.insert(file_path);
+                }
+            }
+            for table_def in &current.tables {
+                if !restored.contains(&self.table_file_path(&table_def.name)) {
+                    self.remove_table_files(table_def)?;
+                    continue;
+                }
+                for index_def in &table_def.indexes {
+                    let file_path = self.index_file_path(&table_def.name, &index_def.name);
+                    if !restored.contains(&file_path) {
+                        self.remove_file_if_exists(&file_path)?;
+                    }
+                }
+            }
+            self.save_database(&database)?;
+            Ok(database.tables.len())
+        })
+    }
+
+    // The catalog of the backup in `dir` and the contents of its files by
+    // name, once every file matches the manifest, decodes, and holds rows
+    // that fit their table and indexes that match the rows
+    fn read_backup(
+        &self,
+        dir: &Path,
+    ) -> Result<(Database, HashMap<String, Vec<u8>>), DatabaseError> {
+        let invalid = |path: &Path, error: String| DatabaseError::InvalidBackup {
+            path: path.display().to_string(),
+            error,
+        };
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let manifest = match fs::read_to_string(&manifest_path) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(invalid(dir, "it holds no backup manifest".to_string()))
+            }
+            Err(e) => return Err(user_file_error(&manifest_path.display().to_string(), e)),
+        };
+        let entries = backup::read(&manifest).map_err(|e| invalid(dir, e))?;
+        let mut files = HashMap::new();
+        for entry in entries {
+            let file_path = dir.join(&entry.name);
+            let bytes = fs::read(&file_path)
+                .map_err(|e| invalid(dir, format!("'{}' cannot be read: {}", entry.name, e)))?;
+            if !entry.matches(&bytes) {
+                return Err(invalid(
+                    dir,
+                    format!("'{}' does not match its checksum", entry.name),
+                ));
+            }
+            files.insert(entry.name, bytes);
+        }
+        let catalog = files
+            .get(CATALOG_FILE)
+            .ok_or_else(|| invalid(dir, "it holds no catalog".to_string()))?;
+        let database = format::decode(CATALOG_MAGIC, catalog)
+            .map_err(|e| e.to_string())
+            .and_then(|message| Database::decode(message).map_err(|e| e.to_string()))
+            .map_err(|e| invalid(dir, format!("'{}' cannot be read: {}", CATALOG_FILE, e)))?;
+        // Names become file names in the data directory, so none may lead out of it
+        let names = database.tables.iter().flat_map(|table_def| {
+            std::iter::once(&table_def.name)
+                .chain(table_def.indexes.iter().map(|index_def| &index_def.name))
+        });
+        for name in names {
+            if !is_identifier(name) {
+                return Err(invalid(
+                    dir,
+                    format!("'{}' in its catalog is not a table or index name", name),
+                ));
+            }
+        }
+        let mut known = HashSet::from([CATALOG_FILE.to_string()]);
+        for table_def in &database.tables {
+            let name = file_name(&self.table_file_path(&table_def.name));
+            let image = files
+                .get(&name)
+                .ok_or_else(|| invalid(dir, format!("'{}' is missing", name)))?;
+            let records = heap_file::read_image(image)
+                .map_err(|e| invalid(dir, format!("'{}' cannot be read: {}", name, e)))?;
+            let mut rows = Vec::new();
+            let mut problems = Vec::new();
+            for (row_id, record) in records {
+                let row = decode_row(&name, &record).map_err(|e| invalid(dir, e.to_string()))?;
+                check_row(table_def, row_id, &row, &mut problems);
+                rows.push((row_id, row));
+            }
+            if let Some(problem) = problems.first() {
+                return Err(invalid(dir, problem.to_string()));
+            }
+            known.insert(name);
+            for index_def in &table_def.indexes {
+                let name = file_name(&self.index_file_path(&table_def.name, &index_def.name));
+                let image = files
+                    .get(&name)
+                    .ok_or_else(|| invalid(dir, format!("'{}' is missing", name)))?;
+                let stored = TableIndex::from_image(index_def, image)
+                    .and_then(|mut stored| stored.entries())
+                    .map_err(|e| invalid(dir, format!("'{}' cannot be read: {}", name, e)))?;
+                let mut rebuilt = build_index(table_def, index_def, &rows, |_| Ok(true))
+                    .map_err(|e| invalid(dir, e.to_string()))?;
+                if stored != rebuilt.entries()? {
+                    return Err(invalid(
+                        dir,
+                        format!("'{}' does not match the rows of its table", name),
+                    ));
+                }
+                known.insert(name);
+            }
+        }
+        if let Some(name) = files.keys().find(|name| !known.contains(*name)) {
+            return Err(invalid(
+                dir,
+                format!("'{}' belongs to no table of the backup", name),
+            ));
+        }
+        Ok((database, files))
+    }
+
+    // Handle UPDATE: mark the matching rows as deleted and insert their new versions
+    pub fn update(&self, command: Command) -> Result<usize, DatabaseError> {
+        self.in_transaction(|| {
+            if let Command::Update {
+                table,
+                assignments,
+                where_clause,
+            } = command
+            {
+                // Rows locked by another transaction are waited for, then matched again
+                loop {
+                    if let Some(updated) = self.update_rows(&table, &assignments, &where_clause)? {
+                        return Ok(updated);
+                    }
+                }
+            } else {
+                Err(DatabaseError::InvalidCommand("update".to_string()))
+            }
+        })
+    }
+
+    // Returns `None` if the statement has to start over
+    fn update_rows(
+        &self,
+        table: &str,
+        assignments: &[(String, Value)],
+        where_clause: &[Condition],
+    ) -> Result<Option<usize>, DatabaseError> {
+        let xid = self.start_write()?;
+        self.lock_catalog(LockMode::Shared)?;
+        let database = self.load_database()?;
+        let table_def = Self::find_table(&database, table)?;
+        self.lock_table(table, LockMode::Shared, LockScope::Statement)?;
+        let assignments = assignments
+            .iter()
+            .map(|(column, value)| Ok((column_position(table_def, column)?, value)))
+            .collect::<Result<Vec<_>, DatabaseError>>()?;
+        self.record_read(table, where_clause);
+        let filter = Filter::new(table_def, where_clause.to_vec())?;
+        let matching = self.matching_rows(table_def, &filter)?;
+        if !self.lock_for_change(table, &matching)? {
+            return Ok(None);
+        }
+
+        let mut changes = Vec::new();
+        for (row_id, old_row) in matching {
+            self.check_not_deleted(table_def, &old_row, xid)?;
+            let mut new_row = old_row.clone();
+            for (position, value) in &assignments {
+                if let Some(cell) = new_row.cells.get_mut(*position) {
+                    *cell = CellValue::try_from((*value).clone())?;
+                }
+            }
+            new_row.xmin = xid;
+            new_row.xmax = Some(0);
+            let record = new_row.encode_to_vec();
+            if record.len() > MAX_RECORD_SIZE {
+                return Err(DatabaseError::RowTooLarge(record.len()));
+            }
+            changes.push((row_id, old_row, new_row, record));
+        }
+
+        // Check every new key first so a duplicate aborts before any write.
+        // The versions being replaced no longer count.
+        let mut indexes = self.load_indexes(table_def)?;
+        let mut heap = self.open_heap(table_def)?;
+        let replaced: HashSet<u64> = changes.iter().map(|(row_id, ..)| *row_id).collect();
+        for (index_def, (file_path, index)) in table_def.indexes.iter().zip(indexes.iter_mut()) {
+            let positions = index_positions(table_def, index_def)?;
+            let mut batch_keys = HashSet::new();
+            for (_, _, new_row, _) in &changes {
+                let key = index_key(new_row, &positions);
+                check_key_size(index_def, &key)?;
+                if !index_def.unique || key_has_null(&key) {
+                    continue;
+                }
+                if !batch_keys.insert(key.clone()) {
+                    return Err(DatabaseError::UniqueViolation(index_def.name.clone()));
+                }
+                let row_ids = index.get(&key).map_err(|e| file_error(file_path, e))?;
+                if self.any_live(table, &mut heap, row_ids, &replaced)? {
+                    return Err(DatabaseError::UniqueViolation(index_def.name.clone()));
+                }
+            }
+        }
+
+        for (row_id, old_row, new_row, record) in &changes {
+            self.delete_version(&mut heap, &mut indexes, table_def, (*row_id, old_row), xid)?;
+            let new_row_id = heap.insert(record)?;
+            for (index_def, (file_path, index)) in table_def.indexes.iter().zip(indexes.iter_mut())
+            {
+                let positions = index_positions(table_def, index_def)?;
+                index
+                    .insert(index_key(new_row, &positions), new_row_id)
+                    .map_err(|e| file_error(file_path, e))?;
+            }
+        }
+        Self::flush_indexes(&mut indexes)?;
+        Ok(Some(changes.len()))
+    }
+
+    // Handle DELETE: mark the matching rows as deleted by this transaction
+    pub fn delete(&self, command: Command) -> Result<usize, DatabaseError> {
+        self.in_transaction(|| {
+            if let Command::Delete {
+                table,
+                where_clause,
+            } = command
+            {
+                // Rows locked by another transaction are waited for, then matched again
+                loop {
+                    if let Some(deleted) = self.delete_rows(&table, &where_clause)? {
+                        return Ok(deleted);
+                    }
+                }
+            } else {
+                Err(DatabaseError::InvalidCommand("delete".to_string()))
+            }
+        })
+    }
+
+    // Returns `None` if the statement has to start over
+    fn delete_rows(
+        &self,
+        table: &str,
+        where_clause: &[Condition],
+    ) -> Result<Option<usize>, DatabaseError> {
+        let xid = self.start_write()?;
+        self.lock_catalog(LockMode::Shared)?;
+        let database = self.load_database()?;
+        let table_def = Self::find_table(&database, table)?;
+        self.lock_table(table, LockMode::Shared, LockScope::Statement)?;
+        self.record_read(table, where_clause);
+        let filter = Filter::new(table_def, where_clause.to_vec())?;
+        let matching = self.matching_rows(table_def, &filter)?;
+        if matching.is_empty() {
+            return Ok(Some(0));
+        }
+        if !self.lock_for_change(table, &matching)? {
+            return Ok(None);
+        }
+
+        let mut indexes = self.load_indexes(table_def)?;
+        let mut heap = self.open_heap(table_def)?;
+        for (row_id, row) in &matching {
+            self.delete_version(&mut heap, &mut indexes, table_def, (*row_id, row), xid)?;
+        }
+        Self::flush_indexes(&mut indexes)?;
+        Ok(Some(matching.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nom_parser::parse_statements;
+    use crate::test_util::temp_dir;
+    use std::fs;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    // Small enough that the statements under test evict dirty pages mid-transaction
+    const FRAMES: usize = 2;
+
+    fn open(dir: &Path) -> DatabaseManager {
+        let file_path = dir.join("dbfile.bin").to_string_lossy().into_owned();
+        DatabaseManager::with_buffer_pool_size(file_path, FRAMES).unwrap()
+    }
+
+    // Run every statement of `sql`, stopping at the first error
+    fn execute(db: &DatabaseManager, sql: &str) -> Result<(), DatabaseError> {
+        let (rest, commands) = parse_statements(sql).expect("test statements parse");
+        assert!(rest.is_empty(), "unparsed test input: {}", rest);
+        commands
+            .into_iter()
+            .try_for_each(|command| execute_command(db, command))
+    }
+
+    fn execute_command(db: &DatabaseManager, command: Command) -> Result<(), DatabaseError> {
+        match command {
+            Command::CreateTable { .. } => db.create_table(command).map(|_| ()),
+            Command::DropTable { name, if_exists } => db.drop_table(&name, if_exists).map(|_| ()),
+            Command::TruncateTable { name } => db.truncate_table(&name),
+            Command::CreateIndex { .. } => db.create_index(command),
+            Command::DropIndex { name } => db.drop_index(&name),
+            Command::Select { .. } => db.select(command).map(|_| ()),
+            Command::Insert { .. } => db.insert(command).map(|_| ()),
+            Command::Update { .. } => db.update(command).map(|_| ()),
+            Command::Delete { .. } => db.delete(command).map(|_| ()),
+            Command::CopyFrom { .. } => db.copy_from(command).map(|_| ()),
+            Command::CopyTo { .. } => db.copy_to(command).map(|_| ()),
+            Command::ImportJson { .. } => db.import_json(command).map(|_| ()),
+            Command::ExportJson { .. } => db.export_json(command).map(|_| ()),
+            Command::Begin { isolation } => db.begin_transaction(isolation),
+            Command::Commit => db.commit_transaction(),
+            Command::Rollback => db.rollback_transaction(),
+            Command::Savepoint { name } => db.savepoint(&name),
+            Command::RollbackTo { name } => db.rollback_to_savepoint(&name),
+            Command::ReleaseSavepoint { name } => db.release_savepoint(&name),
+            Command::CheckDatabase => db.check_database().map(|_| ()),
+            Command::Backup { .. } => db.backup(command).map(|_| ()),
+            Command::Restore { .. } => db.restore(command).map(|_| ()),
+            other => unreachable!("unsupported test statement {:?}", other),
+        }
+    }
+
+    // Catalog entries with their sorted rows, after checking every index against the rows
+    fn state(dir: &Path) -> Vec<(String, Vec<String>)> {
+        let db = open(dir);
+        let database = db.load_database().unwrap();
+        database
+            .tables
+            .iter()
+            .map(|table_def| {
+                let versions = db.scan_versions(table_def).unwrap();
+                for index_def in &table_def.indexes {
+                    let file_path = db.index_file_path(&table_def.name, &index_def.name);
+                    let mut stored =
+                        TableIndex::open(&db.buffer_pool, index_def, &file_path).unwrap();
+                    let mut rebuilt =
+                        build_index(table_def, index_def, &versions, |row| db.is_live(row))
+                            .unwrap();
+                    assert_eq!(
+                        stored.entries().unwrap(),
+                        rebuilt.entries().unwrap(),
+                        "index '{}' does not match its table",
+                        index_def.name
+                    );
+                }
+                let mut rows: Vec<String> = db
+                    .scan_rows(table_def)
+                    .unwrap()
+                    .iter()
+                    .map(|(_, row)| format!("{:?}", row.cells))
+                    .collect();
+                rows.sort();
+                (format!("{:?}", table_def), rows)
+            })
+            .collect()
+    }
+
+    fn setup(dir: &Path) {
+        let db = open(dir);
+        let rows: Vec<String> = (1..=150)
+            .map(|id| format!("({}, 'name number {}')", id, id))
+            .collect();
+        for sql in [
+            "CREATE TABLE t (id INT NOT NULL, name STRING(40))".to_string(),
+            "CREATE UNIQUE INDEX t_id ON t (id)".to_string(),
+            "CREATE INDEX t_name ON t (name) USING HASH".to_string(),
+            format!("INSERT INTO t VALUES {};", rows.join(", ")),
+        ] {
+            execute(&db, &sql).unwrap();
+        }
+    }
+
+    // Crash `statement` at each of its write points in turn. After recovery the
+    // database must be exactly as it was either before or after the statement.
+    fn check_crash_points(name: &str, statement: &str) {
+        let reference = temp_dir(name);
+        setup(&reference);
+        let before = state(&reference);
+        execute(&open(&reference), statement).unwrap();
+        let after = state(&reference);
+        assert_ne!(before, after, "statement under test changed nothing");
+        drop(reference);
+
+        for crash_point in 1.. {
+            let dir = temp_dir(name);
+            setup(&dir);
+            let db = open(&dir);
+            db.buffer_pool
+                .borrow_mut()
+                .wal_mut()
+                .crash_after(crash_point);
+            let result = execute(&db, statement);
+            drop(db);
+
+            let recovered = state(&dir);
+            if result.is_ok() {
+                // Every write point has been passed without a crash
+                assert!(recovered == after);
+                break;
+            }
+            assert!(
+                recovered == before || recovered == after,
+                "crash at write point {} left an inconsistent database",
+                crash_point
+            );
+            // The recovered database has to keep working
+            let db = open(&dir);
+            execute(&db, "CREATE TABLE probe (id INT)").unwrap();
+            execute(&db, "INSERT INTO probe VALUES (1);").unwrap();
+        }
+    }
+
+    #[test]
+    fn unbound_parameters_are_refused() {
+        let dir = temp_dir("unbound_parameter");
+        two_rows(&dir);
+        let db = open(&dir);
+        let insert = Command::Insert {
+            table: "t".to_string(),
+            values: vec![vec![Value::Int(3), Value::Param(1)]],
+        };
+        assert!(matches!(
+            db.insert(insert),
+            Err(DatabaseError::InvalidCommand(message)) if message == "unbound parameter"
+        ));
+        assert_eq!(visible_rows(&db, "t").len(), 2);
+    }
+
+    #[test]
+    fn recovery_is_reported_once() {
+        let dir = temp_dir("recovery_report");
+        setup(&dir);
+        let db = open(&dir);
+        db.buffer_pool.borrow_mut().wal_mut().crash_after(3);
+        assert!(execute(&db, "DELETE FROM t WHERE id > 100").is_err());
+        drop(db);
+        let db = open(&dir);
+        let recovery = db.take_recovery().expect("the crash left work to recover");
+        assert!(!recovery.is_empty());
+        assert_eq!(db.take_recovery(), None);
+    }
+
+    #[test]
+    fn crash_during_create_table() {
+        check_crash_points("create_table", "CREATE TABLE u (id INT)");
+    }
+
+    #[test]
+    fn crash_during_insert() {
+        check_crash_points(
+            "insert",
+            "INSERT INTO t VALUES (500, 'five hundred'), (501, 'five hundred one'), (502, NULL);",
+        );
+    }
+
+    #[test]
+    fn crash_during_update() {
+        check_crash_points(
+            "update",
+            "UPDATE t SET name = 'a name long enough to move the row' WHERE id < 4",
+        );
+    }
+
+    #[test]
+    fn crash_during_delete() {
+        check_crash_points("delete", "DELETE FROM t WHERE id > 145");
+    }
+
+    #[test]
+    fn crash_during_create_index() {
+        check_crash_points("create_index", "CREATE INDEX t_both ON t (name, id)");
+    }
+
+    #[test]
+    fn crash_during_drop_index() {
+        check_crash_points("drop_index", "DROP INDEX t_name");
+    }
+
+    #[test]
+    fn crash_during_drop_table() {
+        check_crash_points("drop_table", "DROP TABLE t");
+    }
+
+    #[test]
+    fn crash_during_truncate_table() {
+        check_crash_points("truncate_table", "TRUNCATE TABLE t");
+    }
+
+    #[test]
+    fn crash_during_transaction() {
+        check_crash_points(
+            "transaction",
+            "BEGIN; DELETE FROM t WHERE id < 5; CREATE TABLE u (id INT); \
+             INSERT INTO u VALUES (1); COMMIT;",
+        );
+    }
+
+    #[test]
+    fn crash_after_rollback_to_savepoint() {
+        check_crash_points(
+            "savepoint",
+            "BEGIN; UPDATE t SET name = 'kept' WHERE id = 1; SAVEPOINT s; \
+             DELETE FROM t WHERE id > 145; CREATE TABLE u (id INT); ROLLBACK TO s; \
+             INSERT INTO t VALUES (200, 'after the savepoint'); COMMIT;",
+        );
+    }
+
+    #[test]
+    fn savepoints_restore_earlier_state() {
+        let dir = temp_dir("savepoints");
+        setup(&dir);
+        let before = state(&dir);
+        let db = open(&dir);
+        execute(
+            &db,
+            "BEGIN; SAVEPOINT a; DELETE FROM t WHERE id < 100; SAVEPOINT b; \
+             DROP TABLE t; ROLLBACK TO b; INSERT INTO t VALUES (1, 'again'); ROLLBACK TO a;",
+        )
+        .unwrap();
+        assert!(matches!(
+            execute(&db, "RELEASE b"),
+            Err(DatabaseError::SavepointDoesNotExist(_))
+        ));
+        // A failing statement only undoes itself
+        execute(&db, "INSERT INTO t VALUES (300, 'three hundred');").unwrap();
+        assert!(matches!(
+            execute(&db, "INSERT INTO t VALUES (301, 'new'), (5, 'clash');"),
+            Err(DatabaseError::UniqueViolation(_))
+        ));
+        execute(&db, "DELETE FROM t WHERE id = 300; COMMIT;").unwrap();
+        drop(db);
+        assert!(state(&dir) == before);
+
+        let db = open(&dir);
+        execute(&db, "BEGIN; DROP TABLE t; ROLLBACK;").unwrap();
+        assert!(matches!(
+            execute(&db, "COMMIT"),
+            Err(DatabaseError::NoActiveTransaction)
+        ));
+        drop(db);
+        assert!(state(&dir) == before);
+    }
+
+    #[test]
+    fn failed_statement_is_rolled_back() {
+        let dir = temp_dir("rollback");
+        setup(&dir);
+        let before = state(&dir);
+        let db = open(&dir);
+        let result: Result<(), DatabaseError> = db.in_transaction(|| {
+            execute(&db, "INSERT INTO t VALUES (900, 'nine hundred');")?;
+            execute(&db, "DELETE FROM t WHERE id < 50")?;
+            execute(&db, "DROP INDEX t_name")?;
+            Err(DatabaseError::UnknownError)
+        });
+        assert!(matches!(result, Err(DatabaseError::UnknownError)));
+        // The same manager keeps working on the restored files
+        execute(&db, "INSERT INTO t VALUES (901, 'nine hundred one');").unwrap();
+        execute(&db, "DELETE FROM t WHERE id = 901").unwrap();
+        drop(db);
+        assert!(state(&dir) == before);
+    }
+
+    // Cells of the rows of `table` that a statement run now by `db` would see
+    fn visible_rows(db: &DatabaseManager, table: &str) -> Vec<String> {
+        db.in_transaction(|| {
+            let database = db.load_database().unwrap();
+            let table_def = DatabaseManager::find_table(&database, table)?;
+            let mut rows: Vec<String> = db
+                .scan_rows(table_def)?
+                .iter()
+                .map(|(_, row)| format!("{:?}", row.cells))
+                .collect();
+            rows.sort();
+            Ok(rows)
+        })
+        .unwrap()
+    }
+
+    fn two_rows(dir: &Path) {
+        execute(
+            &open(dir),
+            "CREATE TABLE t (id INT NOT NULL, name STRING(40)); \
+             CREATE UNIQUE INDEX t_id ON t (id); \
+             INSERT INTO t VALUES (1, 'one'), (2, 'two');",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn drop_and_truncate_remove_rows() {
+        let dir = temp_dir("drop");
+        two_rows(&dir);
+        let db = open(&dir);
+        let (_, mut commands) = parse_statements("CREATE TABLE IF NOT EXISTS t (id INT)").unwrap();
+        assert!(!db.create_table(commands.remove(0)).unwrap());
+        assert_eq!(visible_rows(&db, "t").len(), 2);
+
+        db.truncate_table("t").unwrap();
+        assert!(visible_rows(&db, "t").is_empty());
+        assert_eq!(db.table_schema("t").unwrap().indexes.len(), 1);
+        // The unique index was emptied along with the rows
+        execute(&db, "INSERT INTO t VALUES (1, 'again');").unwrap();
+
+        assert!(db.drop_table("t", false).unwrap());
+        assert!(!db.drop_table("t", true).unwrap());
+        assert!(matches!(
+            db.drop_table("t", false),
+            Err(DatabaseError::TableDoesNotExist(_))
+        ));
+        assert!(matches!(
+            db.truncate_table("t"),
+            Err(DatabaseError::TableDoesNotExist(_))
+        ));
+        assert!(!Path::new(&db.table_file_path("t")).exists());
+        assert!(!Path::new(&db.index_file_path("t", "t_id")).exists());
+
+        // A table created under the same name starts empty
+        execute(&db, "CREATE TABLE IF NOT EXISTS t (id INT)").unwrap();
+        assert!(visible_rows(&db, "t").is_empty());
+        drop(db);
+    }
+
+    #[test]
+    fn queries_return_their_results() {
+        let dir = temp_dir("results");
+        two_rows(&dir);
+        let db = open(&dir);
+        let (_, mut commands) = parse_statements("SELECT name, id FROM t WHERE id > 1").unwrap();
+        let result = db.select(commands.remove(0)).unwrap();
+        let names: Vec<&str> = result.columns().iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["name", "id"]);
+        assert_eq!(
+            result.into_iter().collect::<Vec<_>>(),
+            [vec![
+                CellValue::try_from(Value::Str("two".to_string())).unwrap(),
+                CellValue::try_from(Value::Int(2)).unwrap(),
+            ]]
+        );
+
+        let tables = db.list_tables().unwrap();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(db.table_schema("t").unwrap(), tables[0]);
+        assert_eq!(tables[0].indexes[0].name, "t_id");
+        assert!(matches!(
+            db.table_schema("missing"),
+            Err(DatabaseError::TableDoesNotExist(_))
+        ));
+    }
+
+    #[test]
+    fn indexes_answer_queries_after_a_restart() {
+        let dir = temp_dir("indexes");
+        let db = open(&dir);
+        let rows: Vec<String> = (1..=3000)
+            .map(|id| format!("({}, 'name number {}')", id, id))
+            .collect();
+        execute(
+            &db,
+            &format!(
+                "CREATE TABLE t (id INT NOT NULL, name STRING(40)); \
+                 CREATE UNIQUE INDEX t_id ON t (id); \
+                 CREATE INDEX t_name ON t (name) USING HASH; INSERT INTO t VALUES {};",
+                rows.join(", ")
+            ),
+        )
+        .unwrap();
+        drop(db);
+
+        // The index still finds the rows after a restart, reading a few pages
+        // of a table that has dozens
+        let db = open(&dir);
+        let table_pages = fs::metadata(db.table_file_path("t")).unwrap().len() / 4096;
+        assert!(table_pages > 20);
+        let select = |sql: &str| {
+            let (_, mut commands) = parse_statements(sql).unwrap();
+            let before = db.buffer_pool_stats();
+            let ids: Vec<CellValue> = db
+                .select(commands.remove(0))
+                .unwrap()
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect();
+            let after = db.buffer_pool_stats();
+            (ids, after.hits + after.misses - before.hits - before.misses)
+        };
+        let (ids, pages) = select("SELECT id FROM t WHERE id = 1234");
+        assert_eq!(ids, [CellValue::try_from(Value::Int(1234)).unwrap()]);
+        assert!(pages < 10, "{} pages read", pages);
+        let (ids, pages) = select("SELECT id FROM t WHERE id > 2995");
+        let expected: Vec<CellValue> = (2996..=3000)
+            .map(|id| CellValue::try_from(Value::Int(id)).unwrap())
+            .collect();
+        assert_eq!(ids, expected);
+        assert!(pages < 10, "{} pages read", pages);
+        let (ids, pages) = select("SELECT id FROM t WHERE name = 'name number 2024'");
+        assert_eq!(ids, [CellValue::try_from(Value::Int(2024)).unwrap()]);
+        assert!(pages < 10, "{} pages read", pages);
+        assert!(matches!(
+            execute(&db, "INSERT INTO t VALUES (1234, 'again');"),
+            Err(DatabaseError::UniqueViolation(_))
+        ));
+        execute(
+            &db,
+            "DELETE FROM t WHERE id = 1234; INSERT INTO t VALUES (1234, 'again');",
+        )
+        .unwrap();
+
+        // Adding a row to the big table logs a few page writes, not its index files
+        // A savepoint keeps the log until the transaction ends, so its records can be counted
+        execute(&db, "BEGIN; SAVEPOINT s").unwrap();
+        let logged = db.buffer_pool.borrow().savepoint();
+        execute(&db, "INSERT INTO t VALUES (3001, 'name number 3001');").unwrap();
+        let logged = db.buffer_pool.borrow().savepoint() - logged;
+        assert!(logged <= 6, "{} log records", logged);
+        execute(&db, "COMMIT").unwrap();
+        drop(db);
+        state(&dir);
+    }
+
+    #[test]
+    fn copy_round_trips_rows_through_csv() {
+        let dir = temp_dir("copy");
+        two_rows(&dir);
+        let db = open(&dir);
+        let csv = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        execute(
+            &db,
+            &format!(
+                "INSERT INTO t VALUES (3, NULL), (4, 'a, \"b\"'); \
+                 COPY t TO '{}' WITH HEADER; \
+                 COPY t TO '{}' WITH DELIMITER ';', NULL 'NULL'",
+                csv("t.csv"),
+                csv("t.txt")
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(csv("t.csv")).unwrap(),
+            "id,name\n1,one\n2,two\n3,\n4,\"a, \"\"b\"\"\"\n"
+        );
+        assert_eq!(
+            fs::read_to_string(csv("t.txt")).unwrap(),
+            "1;one\n2;two\n3;NULL\n4;\"a, \"\"b\"\"\"\n"
+        );
+
+        // Both files load back into the rows they came from
+        execute(
+            &db,
+            &format!(
+                "CREATE TABLE u (id INT NOT NULL, name STRING(40)); \
+                 CREATE TABLE v (id INT NOT NULL, name STRING(40)); \
+                 COPY u FROM '{}' WITH HEADER; \
+                 COPY v FROM '{}' WITH NULL 'NULL', DELIMITER ';'",
+                csv("t.csv"),
+                csv("t.txt")
+            ),
+        )
+        .unwrap();
+        let loaded = state(&dir);
+        assert_eq!(loaded[0].1, loaded[1].1);
+        assert_eq!(loaded[0].1, loaded[2].1);
+
+        // A bad line stops the whole file from loading
+        let copy = |table: &str, contents: &str| {
+            fs::write(csv("in.csv"), contents).unwrap();
+            let sql = format!("COPY {} FROM '{}'", table, csv("in.csv"));
+            let (_, mut commands) = parse_statements(&sql).unwrap();
+            db.copy_from(commands.remove(0))
+        };
+        for (contents, line, error) in [
+            ("6,six\nx,seven\n", 2, "'x' is not a valid INT for column 'id'"),
+            ("6,six\n\n,\n", 3, "column 'id' cannot be NULL"),
+            ("6,six,extra\n", 1, "expected 2 field(s), found 3"),
+            ("6,\"six\n", 1, "quoted field is not closed"),
+            (
+                "6,0123456789012345678901234567890123456789x\n",
+                1,
+                "'0123456789012345678901234567890123456789x' is longer than the 40 characters of column 'name'",
+            ),
+        ] {
+            match copy("u", contents) {
+                Err(DatabaseError::InvalidRecord {
+                    line: l, error: e, ..
+                }) => assert_eq!((l, e.as_str()), (line, error)),
+                other => panic!("{:?} loaded as {:?}", contents, other),
+            }
+        }
+        assert!(matches!(
+            copy("t", "6,six\n1,one\n"),
+            Err(DatabaseError::UniqueViolation(_))
+        ));
+        assert_eq!(state(&dir)[0].1, loaded[0].1);
+
+        // Quotes keep an empty string apart from NULL
+        assert_eq!(copy("u", "7,seven\r\n8,\"\"\r\n9,\r\n").unwrap(), 3);
+        execute(&db, &format!("COPY u TO '{}'", csv("u.csv"))).unwrap();
+        assert!(fs::read_to_string(csv("u.csv"))
+            .unwrap()
+            .ends_with("\n7,seven\n8,\"\"\n9,\n"));
+        assert!(matches!(
+            execute(&db, &format!("COPY u FROM '{}'", csv("missing.csv"))),
+            Err(DatabaseError::IOError(_))
+        ));
+    }
+
+    #[test]
+    fn json_round_trips_typed_values() {
+        let dir = temp_dir("json");
+        two_rows(&dir);
+        let db = open(&dir);
+        let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        execute(
+            &db,
+            &format!(
+                "INSERT INTO t VALUES (3, NULL), (4, '5'); \
+                 EXPORT t TO '{}'; \
+                 CREATE TABLE u (id INT NOT NULL, name STRING(40)); \
+                 IMPORT JSON '{}' INTO u",
+                file("t.jsonl"),
+                file("t.jsonl")
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(file("t.jsonl")).unwrap(),
+            "{\"id\":1,\"name\":\"one\"}\n{\"id\":2,\"name\":\"two\"}\n\
+             {\"id\":3,\"name\":null}\n{\"id\":4,\"name\":\"5\"}\n"
+        );
+        let loaded = state(&dir);
+        assert_eq!(loaded[0].1, loaded[1].1);
+        // Any other file holds a single array of the same objects
+        execute(&db, &format!("EXPORT t TO '{}'", file("t.json"))).unwrap();
+        let array = fs::read_to_string(file("t.json")).unwrap();
+        assert!(array.starts_with("[\n{") && array.ends_with("}\n]\n"));
+        let lines = json::read(&fs::read_to_string(file("t.jsonl")).unwrap()).unwrap();
+        let elements = json::read(&array).unwrap();
+        assert_eq!(
+            elements.iter().map(|d| &d.value).collect::<Vec<_>>(),
+            lines.iter().map(|d| &d.value).collect::<Vec<_>>()
+        );
+        // Any other file holds a single array of the same objects
+        execute(&db, &format!("EXPORT t TO '{}'", file("t.json"))).unwrap();
+        let array = fs::read_to_string(file("t.json")).unwrap();
+        assert!(array.starts_with("[\n{") && array.ends_with("}\n]\n"));
+        let lines = json::read(&fs::read_to_string(file("t.jsonl")).unwrap()).unwrap();
+        let elements = json::read(&array).unwrap();
+        assert_eq!(
+            elements.iter().map(|d| &d.value).collect::<Vec<_>>(),
+            lines.iter().map(|d| &d.value).collect::<Vec<_>>()
+        );
+
+        let import = |table: &str, contents: &str, options: &str| {
+            fs::write(file("in.json"), contents).unwrap();
+            let sql = format!(
+                "IMPORT JSON '{}' INTO {}{}",
+                file("in.json"),
+                table,
+                options
+            );
+            let (_, mut commands) = parse_statements(&sql).unwrap();
+            db.import_json(commands.remove(0))
+        };
+        // An array works as well as lines; missing keys are NULL
+        assert_eq!(
+            import(
+                "u",
+                "[\n  {\"id\": 5},\n  {\"name\": \"six\", \"id\": 6}\n]",
+                ""
+            )
+            .unwrap(),
+            2
+        );
+        assert_eq!(
+            import("u", "{\"id\": 7, \"age\": 70}\n", " IGNORE UNKNOWN KEYS").unwrap(),
+            1
+        );
+        for (contents, line, error) in [
+            (
+                "{\"id\": 8, \"age\": 80}",
+                1,
+                "key 'age' names no column of table 'u'",
+            ),
+            (
+                "{\"id\": 8}\n{\"name\": \"x\"}",
+                2,
+                "column 'id' cannot be NULL",
+            ),
+            (
+                "{\"id\": \"8\"}",
+                1,
+                "column 'id' holds INT values, not '8'",
+            ),
+            (
+                "{\"id\": 8, \"name\": 8}",
+                1,
+                "column 'name' holds STRING values, not 8",
+            ),
+            ("{\"id\": 8.5}", 1, "8.5 is not a valid INT for column 'id'"),
+            (
+                "{\"id\": true}",
+                1,
+                "column 'id' cannot hold a boolean from JSON",
+            ),
+            ("{\"id\": 8, \"id\": 9}", 1, "key 'id' appears twice"),
+            (
+                "{\"id\": 8}\n\n[1]",
+                3,
+                "expected an object, found an array",
+            ),
+            ("{\"id\": 8}\n{\"id\": 9", 2, "not valid JSON"),
+        ] {
+            match import("u", contents, "") {
+                Err(DatabaseError::InvalidRecord {
+                    line: l, error: e, ..
+                }) => assert_eq!((l, e.as_str()), (line, error)),
+                other => panic!("{:?} imported as {:?}", contents, other),
+            }
+        }
+        assert!(matches!(
+            import("t", "{\"id\": 9}\n{\"id\": 1}", ""),
+            Err(DatabaseError::UniqueViolation(_))
+        ));
+        assert_eq!(state(&dir)[0].1, loaded[0].1);
+        assert_eq!(state(&dir)[1].1.len(), loaded[1].1.len() + 3);
+    }
+
+    #[test]
+    fn backups_restore_the_state_they_were_taken_in() {
+        let dir = temp_dir("backup");
+        setup(&dir);
+        let db = open(&dir);
+        let writer = open(&dir);
+        let backup_dir = dir.join("backup");
+        let path = backup_dir.to_string_lossy().into_owned();
+        execute(
+            &db,
+            "UPDATE t SET name = 'changed' WHERE id < 10; DELETE FROM t WHERE id > 140",
+        )
+        .unwrap();
+        // Work that is not committed yet stays out of the backup
+        execute(&writer, "BEGIN; INSERT INTO t VALUES (500, 'pending')").unwrap();
+        execute(&db, &format!("BACKUP TO '{}'", path)).unwrap();
+        execute(&writer, "ROLLBACK").unwrap();
+        let backed_up = state(&dir);
+        assert!(!backup_dir.join("db_project.dir").exists());
+        // An existing directory is never written into, even an empty one
+        let manifest = fs::read(backup_dir.join(MANIFEST_FILE)).unwrap();
+        assert!(matches!(
+            execute(&db, &format!("BACKUP TO '{}'", path)),
+            Err(DatabaseError::IOError(_))
+        ));
+        assert_eq!(fs::read(backup_dir.join(MANIFEST_FILE)).unwrap(), manifest);
+        let empty_dir = dir.join("empty");
+        fs::create_dir(&empty_dir).unwrap();
+        assert!(matches!(
+            execute(&db, &format!("BACKUP TO '{}'", empty_dir.display())),
+            Err(DatabaseError::IOError(_))
+        ));
+
+        execute(
+            &db,
+            "INSERT INTO t VALUES (200, 'later'); DROP INDEX t_name; \
+             CREATE TABLE u (id INT); INSERT INTO u VALUES (1)",
+        )
+        .unwrap();
+        execute(&db, &format!("RESTORE FROM '{}'", path)).unwrap();
+        assert_eq!(state(&dir), backed_up);
+        assert!(!dir.join("u.tab").exists());
+        // Other sessions see the restored tables, and keep on writing to them
+        assert_eq!(visible_rows(&writer, "t").len(), 140);
+        execute(&writer, "INSERT INTO t VALUES (200, 'later')").unwrap();
+        assert!(matches!(
+            execute(&writer, "INSERT INTO t VALUES (1, 'again')"),
+            Err(DatabaseError::UniqueViolation(_))
+        ));
+        execute(&db, &format!("RESTORE FROM '{}'", path)).unwrap();
+        assert_eq!(state(&dir), backed_up);
+
+        // A damaged backup is turned away before anything changes
+        let table_file = backup_dir.join("t.tab");
+        let mut bytes = fs::read(&table_file).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&table_file, &bytes).unwrap();
+        execute(&db, "DELETE FROM t WHERE id = 1").unwrap();
+        let changed = state(&dir);
+        match execute(&db, &format!("RESTORE FROM '{}'", path)) {
+            Err(DatabaseError::InvalidBackup { error, .. }) => {
+                assert_eq!(error, "'t.tab' does not match its checksum")
+            }
+            other => panic!("damaged backup restored: {:?}", other),
+        }
+        assert!(matches!(
+            execute(&db, &format!("RESTORE FROM '{}'", dir.display())),
+            Err(DatabaseError::InvalidBackup { .. })
+        ));
+        assert_eq!(state(&dir), changed);
+        // The backup opens as a database of its own
+        bytes[last] ^= 1;
+        fs::write(&table_file, &bytes).unwrap();
+        assert_eq!(state(&backup_dir), backed_up);
+
+        // Catalog names that would lead out of the data directory are refused,
+        // even when the manifest vouches for them
+        let catalog_file = backup_dir.join(CATALOG_FILE);
+        let catalog = fs::read(&catalog_file).unwrap();
+        let message = format::decode(CATALOG_MAGIC, &catalog).unwrap();
+        let mut database = Database::decode(message).unwrap();
+        database.tables[0].name = "../escaped".to_string();
+        let catalog = format::encode(CATALOG_MAGIC, &database.encode_to_vec());
+        fs::write(&catalog_file, &catalog).unwrap();
+        let manifest_path = backup_dir.join(MANIFEST_FILE);
+        let entries: Vec<backup::Entry> =
+            backup::read(&fs::read_to_string(&manifest_path).unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|entry| match entry.name.as_str() {
+                    CATALOG_FILE => backup::Entry::new(CATALOG_FILE, &catalog),
+                    _ => entry,
+                })
+                .collect();
+        fs::write(&manifest_path, backup::write(&entries)).unwrap();
+        match execute(&db, &format!("RESTORE FROM '{}'", path)) {
+            Err(DatabaseError::InvalidBackup { error, .. }) => assert_eq!(
+                error,
+                "'../escaped' in its catalog is not a table or index name"
+            ),
+            other => panic!("backup with a bad name restored: {:?}", other),
+        }
+        assert_eq!(state(&dir), changed);
+        assert!(!dir.join("escaped.tab").exists());
+    }
+
+    #[test]
+    fn snapshot_ignores_later_commits() {
+        let dir = temp_dir("snapshot");
+        two_rows(&dir);
+        let reader = open(&dir);
+        let writer = open(&dir);
+        execute(&reader, "BEGIN").unwrap();
+        let before = visible_rows(&reader, "t");
+        execute(
+            &writer,
+            "INSERT INTO t VALUES (3, 'three'); \
+             UPDATE t SET name = 'uno' WHERE id = 1; \
+             DELETE FROM t WHERE id = 2",
+        )
+        .unwrap();
+        assert_eq!(visible_rows(&reader, "t"), before);
+        execute(&reader, "COMMIT").unwrap();
+        let after = visible_rows(&reader, "t");
+        assert_eq!(after, visible_rows(&writer, "t"));
+        assert_eq!(after.len(), 2);
+        assert_ne!(after, before);
+        // Replaced and deleted versions no longer hold on to their unique keys
+        execute(&writer, "INSERT INTO t VALUES (2, 'two again');").unwrap();
+        assert!(matches!(
+            execute(&reader, "INSERT INTO t VALUES (1, 'one again');"),
+            Err(DatabaseError::UniqueViolation(_))
+        ));
+        drop((reader, writer));
+        state(&dir);
+    }
+
+    #[test]
+    fn writer_runs_next_to_an_open_snapshot() {
+        let dir = temp_dir("writer_next_to_snapshot");
+        two_rows(&dir);
+        let reader = open(&dir).with_lock_timeout(Duration::from_millis(50));
+        let writer = open(&dir).with_lock_timeout(Duration::from_millis(50));
+        execute(&reader, "BEGIN").unwrap();
+        let before = visible_rows(&reader, "t");
+        execute(
+            &writer,
+            "BEGIN; UPDATE t SET name = 'uno' WHERE id = 1; INSERT INTO t VALUES (3, 'three');",
+        )
+        .unwrap();
+        // Neither waits for the other while the writer's transaction is open
+        assert_eq!(visible_rows(&reader, "t"), before);
+        execute(&reader, "SELECT * FROM t WHERE id = 1").unwrap();
+        execute(&writer, "COMMIT").unwrap();
+        assert_eq!(visible_rows(&reader, "t"), before);
+        // The snapshot's own transaction may change rows the other left alone
+        execute(&reader, "UPDATE t SET name = 'dos' WHERE id = 2").unwrap();
+        let rows = visible_rows(&reader, "t");
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].contains("one") && rows[1].contains("dos"));
+        execute(&reader, "COMMIT").unwrap();
+        let rows = visible_rows(&reader, "t");
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].contains("uno") && rows[1].contains("dos"));
+        drop((reader, writer));
+        state(&dir);
+    }
+
+    #[test]
+    fn concurrent_update_of_same_row_fails() {
+        let dir = temp_dir("write_conflict");
+        two_rows(&dir);
+        let first = open(&dir);
+        let second = open(&dir);
+        execute(&first, "BEGIN").unwrap();
+        visible_rows(&first, "t");
+        execute(&second, "UPDATE t SET name = 'second' WHERE id = 1").unwrap();
+        let result = execute(&first, "UPDATE t SET name = 'first' WHERE id = 1");
+        assert!(matches!(
+            result,
+            Err(DatabaseError::SerializationFailure(_))
+        ));
+        // The whole transaction has been rolled back
+        assert!(!first.in_explicit_transaction());
+        // Rows the other transaction left alone can still be changed
+        execute(
+            &first,
+            "BEGIN; UPDATE t SET name = 'first' WHERE id = 2; COMMIT",
+        )
+        .unwrap();
+        drop((first, second));
+        state(&dir);
+    }
+
+    // Each transaction reads one row and writes the other. Snapshot isolation
+    // lets both commit; SERIALIZABLE rejects whichever commits second.
+    fn write_skew(isolation: &str) -> Result<(), DatabaseError> {
+        let dir = temp_dir("write_skew");
+        two_rows(&dir);
+        let first = open(&dir);
+        let second = open(&dir);
+        execute(&first, &format!("BEGIN ISOLATION LEVEL {}", isolation)).unwrap();
+        execute(&first, "SELECT * FROM t WHERE id = 1").unwrap();
+        execute(&second, "UPDATE t SET name = 'changed' WHERE id = 1").unwrap();
+        execute(&first, "UPDATE t SET name = 'changed' WHERE id = 2").unwrap();
+        let result = execute(&first, "COMMIT");
+        assert!(!first.in_explicit_transaction());
+        drop((first, second));
+        state(&dir);
+        result
+    }
+
+    #[test]
+    fn serializable_detects_read_write_conflict() {
+        assert!(write_skew("SNAPSHOT").is_ok());
+        assert!(matches!(
+            write_skew("SERIALIZABLE"),
+            Err(DatabaseError::SerializationFailure(_))
+        ));
+    }
+
+    #[test]
+    fn locks_time_out() {
+        let dir = temp_dir("locks");
+        two_rows(&dir);
+        let writer = open(&dir);
+        let other = open(&dir).with_lock_timeout(Duration::from_millis(50));
+        execute(&writer, "BEGIN; CREATE INDEX t_name ON t (name);").unwrap();
+        // The table stays locked until the writer commits
+        assert!(matches!(
+            execute(&other, "SELECT * FROM t"),
+            Err(DatabaseError::Locked(_))
+        ));
+        // So does the right to write at all
+        assert!(matches!(
+            execute(&other, "CREATE TABLE u (id INT)"),
+            Err(DatabaseError::Locked(_))
+        ));
+        execute(&writer, "COMMIT").unwrap();
+        execute(&other, "SELECT * FROM t").unwrap();
+        // A reader only holds its locks until each statement ends
+        execute(&other, "BEGIN; SELECT * FROM t").unwrap();
+        execute(&writer, "INSERT INTO t VALUES (4, 'four');").unwrap();
+        execute(&other, "COMMIT").unwrap();
+        drop((writer, other));
+        assert_eq!(state(&dir)[0].1.len(), 3);
+    }
+
+    #[test]
+    fn locked_rows_wait_for_their_holder() {
+        let dir = temp_dir("row_locks");
+        two_rows(&dir);
+        let holder = open(&dir);
+        let other = open(&dir).with_lock_timeout(Duration::from_millis(50));
+        execute(&holder, "BEGIN; SELECT * FROM t WHERE id = 1 FOR UPDATE").unwrap();
+        assert!(matches!(
+            execute(&other, "UPDATE t SET name = 'uno' WHERE id = 1"),
+            Err(DatabaseError::Locked(_))
+        ));
+        assert!(matches!(
+            execute(&other, "SELECT * FROM t WHERE id = 1 FOR SHARE"),
+            Err(DatabaseError::Locked(_))
+        ));
+        // Rows nobody locked can still be changed
+        execute(&other, "UPDATE t SET name = 'dos' WHERE id = 2").unwrap();
+        execute(&holder, "COMMIT").unwrap();
+        execute(&other, "UPDATE t SET name = 'uno' WHERE id = 1").unwrap();
+
+        // Shared locks only keep writers out
+        execute(&holder, "BEGIN; SELECT * FROM t FOR SHARE").unwrap();
+        execute(&other, "SELECT * FROM t FOR SHARE").unwrap();
+        assert!(matches!(
+            execute(&other, "DELETE FROM t WHERE id = 2"),
+            Err(DatabaseError::Locked(_))
+        ));
+        execute(&holder, "ROLLBACK").unwrap();
+        execute(&other, "DELETE FROM t WHERE id = 2").unwrap();
+        let rows = visible_rows(&holder, "t");
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].contains("uno"));
+        drop((holder, other));
+        state(&dir);
+    }
+
+    #[test]
+    fn deadlock_rolls_back_one_side() {
+        let dir = temp_dir("deadlock");
+        two_rows(&dir);
+        let barrier = Arc::new(Barrier::new(2));
+        let sessions: Vec<_> = [(1, 2), (2, 1)]
+            .into_iter()
+            .map(|(changed, wanted)| {
+                let dir = dir.to_path_buf();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let db = open(&dir);
+                    let change = format!("BEGIN; UPDATE t SET name = 'x' WHERE id = {}", changed);
+                    execute(&db, &change).unwrap();
+                    barrier.wait();
+                    // Each waits for the row the other has changed
+                    let update = format!("UPDATE t SET name = 'y' WHERE id = {}", wanted);
+                    let result = execute(&db, &update);
+                    if result.is_ok() {
+                        execute(&db, "COMMIT").unwrap();
+                    }
+                    result
+                })
+            })
+            .collect();
+        let results: Vec<_> = sessions
+            .into_iter()
+            .map(|session| session.join().unwrap())
+            .collect();
+        let deadlocks = results
+            .iter()
+            .filter(|result| matches!(result, Err(DatabaseError::Deadlock)))
+            .count();
+        assert_eq!(deadlocks, 1);
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        // Only the survivor's changes remain
+        let db = open(&dir);
+        let rows = visible_rows(&db, "t");
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().any(|row| row.contains("\"x\"")));
+        assert!(rows.iter().any(|row| row.contains("\"y\"")));
+        drop(db);
+        state(&dir);
+    }
+
+    #[test]
+    fn writers_to_different_rows_both_commit() {
+        let dir = temp_dir("two_writers");
+        two_rows(&dir);
+        let first = open(&dir).with_lock_timeout(Duration::from_millis(50));
+        let second = open(&dir).with_lock_timeout(Duration::from_millis(50));
+        // Neither waits for the other, or the short timeout would fail it
+        execute(&first, "BEGIN; UPDATE t SET name = 'uno' WHERE id = 1").unwrap();
+        execute(&second, "BEGIN; UPDATE t SET name = 'dos' WHERE id = 2").unwrap();
+        execute(&second, "INSERT INTO t VALUES (3, 'three')").unwrap();
+        execute(&first, "INSERT INTO t VALUES (4, 'four')").unwrap();
+        // Uncommitted rows of the other are not seen
+        assert_eq!(visible_rows(&first, "t").len(), 3);
+        execute(&second, "COMMIT").unwrap();
+        execute(&first, "COMMIT").unwrap();
+        let rows = visible_rows(&first, "t");
+        assert_eq!(rows.len(), 4);
+        assert!(rows[0].contains("uno") && rows[1].contains("dos"));
+        // A key the other has inserted but not committed cannot be decided yet
+        execute(&first, "BEGIN; INSERT INTO t VALUES (5, 'five')").unwrap();
+        assert!(matches!(
+            execute(&second, "INSERT INTO t VALUES (5, 'again')"),
+            Err(DatabaseError::SerializationFailure(_))
+        ));
+        execute(&first, "ROLLBACK").unwrap();
+        execute(&second, "INSERT INTO t VALUES (5, 'again')").unwrap();
+        drop((first, second));
+        state(&dir);
+    }
+
+    #[test]
+    fn rows_of_a_dead_writer_are_rolled_back() {
+        let dir = temp_dir("dead_writer");
+        two_rows(&dir);
+        let writer = open(&dir);
+        execute(
+            &writer,
+            "BEGIN; INSERT INTO t VALUES (3, 'three'); DELETE FROM t WHERE id = 1",
+        )
+        .unwrap();
+        // The session goes away without ending its transaction
+        drop(writer);
+        let db = open(&dir).with_lock_timeout(Duration::from_millis(50));
+        assert_eq!(visible_rows(&db, "t").len(), 2);
+        execute(&db, "INSERT INTO t VALUES (3, 'three')").unwrap();
+        execute(&db, "UPDATE t SET name = 'uno' WHERE id = 1").unwrap();
+        let rows = visible_rows(&db, "t");
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].contains("uno"));
+        drop(db);
+        state(&dir);
+    }
+
+    // Leave the file as it was written before versioning: without the header
+    // in front of the message or, for a table file, with zeros in its fields
+    fn unversion(dir: &Path, file_name: &str) {
+        let file_path = dir.join(file_name);
+        let mut bytes = fs::read(&file_path).unwrap();
+        if file_name.ends_with(".tab") {
+            bytes[8..16].fill(0);
+        } else {
+            bytes.drain(..12);
+        }
+        fs::write(&file_path, bytes).unwrap();
+    }
+
+    #[test]
+    fn migrate_upgrades_unversioned_files() {
+        let dir = temp_dir("migrate");
+        two_rows(&dir);
+        execute(
+            &open(&dir),
+            "CREATE TABLE u (id INT); CREATE UNIQUE INDEX u_id ON u (id); INSERT INTO u VALUES (1);",
+        )
+        .unwrap();
+        let mut expected = state(&dir);
+        unversion(&dir, "t.tab");
+        // Outdated files are refused until they are migrated
+        assert!(matches!(
+            execute(&open(&dir), "SELECT * FROM t"),
+            Err(DatabaseError::CorruptFile { .. })
+        ));
+        unversion(&dir, "t.t_id.idx");
+        unversion(&dir, "dbfile.bin");
+        // Table files from before pages were protobuf dumps, with row ids that were positions
+        let legacy = TableData {
+            table_name: "u".to_string(),
+            num_rows: 2,
+            rows: [7, 8]
+                .into_iter()
+                .map(|id| Row {
+                    cells: vec![CellValue {
+                        value: Some(cell_value::Value::IntVal(id)),
+                    }],
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        fs::write(dir.join("u.tab"), legacy.encode_to_vec()).unwrap();
+        let legacy_rows = [
+            "[CellValue { value: Some(IntVal(7)) }]",
+            "[CellValue { value: Some(IntVal(8)) }]",
+        ];
+        expected[1].1 = legacy_rows.iter().map(|row| row.to_string()).collect();
+
+        let db = open(&dir);
+        let backup_dir = dir.join("backup");
+        let mut upgraded = db.migrate(&backup_dir).unwrap();
+        upgraded.sort();
+        let names: Vec<String> = upgraded
+            .iter()
+            .map(|file_path| {
+                Path::new(file_path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            ["dbfile.bin", "t.t_id.idx", "t.tab", "u.tab", "u.u_id.idx"]
+        );
+        drop(db);
+        assert_eq!(state(&dir), expected);
+        // The backup holds the files as they were
+        assert_eq!(
+            fs::read(backup_dir.join("u.tab")).unwrap(),
+            legacy.encode_to_vec()
+        );
+        assert_eq!(fs::read_dir(&backup_dir).unwrap().count(), 5);
+        // Nothing is left to do the second time
+        assert!(open(&dir).migrate(&dir.join("again")).unwrap().is_empty());
+        assert!(!dir.join("again").exists());
+    }
+
+    #[test]
+    fn check_reports_every_problem() {
+        let dir = temp_dir("check");
+        two_rows(&dir);
+        let db = open(&dir);
+        let report = db.check_database().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!((report.tables, report.rows, report.indexes), (1, 2, 1));
+
+        // INSERT does not enforce the schema, so rows that break it can be stored
+        execute(
+            &db,
+            "INSERT INTO t VALUES (3); \
+             INSERT INTO t VALUES (NULL, 'four'), (5, 6), (7, 'a name longer than forty characters in all');",
+        )
+        .unwrap();
+        drop(db);
+        fs::write(dir.join("gone.tab"), b"").unwrap();
+        let report = open(&dir).check_database().unwrap();
+        let row_ids: Vec<u64> = report
+            .problems
+            .iter()
+            .filter_map(|problem| match problem {
+                Problem::WrongArity { row_id, .. }
+                | Problem::NullValue { row_id, .. }
+                | Problem::WrongType { row_id, .. }
+                | Problem::TooLong { row_id, .. } => Some(*row_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(row_ids.len(), 4, "{}", report);
+        assert_eq!(
+            report.problems[0],
+            Problem::OrphanFile {
+                path: dir.join("gone.tab").to_string_lossy().into_owned()
+            }
+        );
+
+        // A flipped bit in the header of the index fails its checksum
+        let index_path = dir.join("t.t_id.idx");
+        let mut bytes = fs::read(&index_path).unwrap();
+        bytes[20] ^= 1;
+        fs::write(&index_path, bytes).unwrap();
+        let report = open(&dir).check_database().unwrap();
+        assert!(report.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::CorruptFile { path, .. } if path.ends_with("t.t_id.idx")
+        )));
+    }
+
+    #[test]
+    fn damaged_data_page_is_reported() {
+        let dir = temp_dir("damaged_page");
+        two_rows(&dir);
+        // The first slot of the first data page points past the end of the page
+        let table_path = dir.join("t.tab");
+        let mut bytes = fs::read(&table_path).unwrap();
+        bytes[4096 + 16..4096 + 18].fill(0xFF);
+        fs::write(&table_path, bytes).unwrap();
+        let db = open(&dir);
+        assert!(matches!(
+            execute(&db, "SELECT * FROM t"),
+            Err(DatabaseError::CorruptFile { path, .. }) if path.ends_with("t.tab")
+        ));
+        let report = db.check_database().unwrap();
+        assert!(report.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::CorruptFile { path, .. } if path.ends_with("t.tab")
+        )));
+        drop(db);
+    }
+
+    #[test]
+    fn each_damaged_page_is_reported() {
+        let dir = temp_dir("damaged_pages");
+        let db = open(&dir);
+        let rows: Vec<String> = (1..=300)
+            .map(|id| format!("({}, 'name number {}')", id, id))
+            .collect();
+        execute(
+            &db,
+            &format!(
+                "CREATE TABLE t (id INT NOT NULL, name STRING(40)); INSERT INTO t VALUES {};",
+                rows.join(", ")
+            ),
+        )
+        .unwrap();
+        drop(db);
+        // Flip a bit in a row of the first and of the last data page
+        let table_path = dir.join("t.tab");
+        let mut bytes = fs::read(&table_path).unwrap();
+        let pages = bytes.len() / 4096;
+        assert!(pages > 3);
+        bytes[2 * 4096 - 1] ^= 1;
+        bytes[pages * 4096 - 1] ^= 1;
+        fs::write(&table_path, bytes).unwrap();
+        let report = open(&dir).check_database().unwrap();
+        let errors: Vec<String> = report
+            .problems
+            .iter()
+            .map(|problem| match problem {
+                Problem::CorruptFile { path, error } if path.ends_with("t.tab") => error.clone(),
+                other => panic!("unexpected problem {}", other),
+            })
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("data page 1 ") && errors[0].contains("checksum"));
+        assert!(errors[1].contains(&format!("data page {} ", pages - 1)));
+        // The rows of the good pages are still checked
+        assert!(report.rows > 0 && report.rows < 300);
+    }
+
+    #[test]
+    fn bad_files_are_reported_as_errors() {
+        let dir = temp_dir("bad_files");
+        two_rows(&dir);
+        let catalog_path = dir.join("dbfile.bin");
+        let catalog = fs::read(&catalog_path).unwrap();
+
+        // A flipped bit fails the checksum
+        let mut bytes = catalog.clone();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&catalog_path, bytes).unwrap();
+        let error = execute(&open(&dir), "SELECT * FROM t").unwrap_err();
+        assert!(
+            matches!(&error, DatabaseError::CorruptFile { path, .. } if path.ends_with("dbfile.bin")),
+            "{}",
+            error
+        );
+        assert_eq!(error.exit_code(), 65);
+
+        // A message that matches its checksum but is not a catalog
+        fs::write(&catalog_path, format::encode(CATALOG_MAGIC, &[0xff; 8])).unwrap();
+        let error = execute(&open(&dir), "SELECT * FROM t").unwrap_err();
+        assert!(
+            matches!(error, DatabaseError::DecodeError { .. }),
+            "{}",
+            error
+        );
+
+        // A command handed to the wrong method
+        fs::write(&catalog_path, catalog).unwrap();
+        let db = open(&dir);
+        let (_, mut commands) = parse_statements("DROP TABLE t").unwrap();
+        let error = db.insert(commands.remove(0)).unwrap_err();
+        assert!(
+            matches!(error, DatabaseError::InvalidCommand(_)),
+            "{}",
+            error
+        );
+        assert_eq!(error.exit_code(), 64);
+        assert_eq!(visible_rows(&db, "t").len(), 2);
     }
 }
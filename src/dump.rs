@@ -0,0 +1,58 @@
+use crate::generated_types::generated_types::{
+    cell_value, CellValue, ColumnDefinition, ColumnType, IndexDefinition, TableDefinition,
+};
+
+// SQL that recreates tables, indexes and rows, written so that a script of it
+// runs back through the same parser.
+
+// Rows per INSERT statement of a dump
+pub const ROWS_PER_INSERT: usize = 100;
+
+// A column as CREATE TABLE takes it, e.g. `name STRING(20) NOT NULL`
+pub fn column_sql(column: &ColumnDefinition) -> String {
+    let mut sql = format!("{} {}", column.name, column.col_type().as_str_name());
+    if column.col_type() == ColumnType::String {
+        sql.push_str(&format!("({})", column.length));
+    }
+    if column.not_null {
+        sql.push_str(" NOT NULL");
+    }
+    sql
+}
+
+pub fn create_table_sql(table: &TableDefinition) -> String {
+    let columns: Vec<String> = table.columns.iter().map(column_sql).collect();
+    format!("CREATE TABLE {} ({});", table.name, columns.join(", "))
+}
+
+pub fn create_index_sql(table: &str, index: &IndexDefinition) -> String {
+    format!(
+        "CREATE {}INDEX {} ON {} ({}) USING {};",
+        if index.unique { "UNIQUE " } else { "" },
+        index.name,
+        table,
+        index.columns.join(", "),
+        index.method().as_str_name()
+    )
+}
+
+// One INSERT of `rows`, each tuple on a line of its own
+pub fn insert_sql<'a>(table: &str, rows: impl IntoIterator<Item = &'a [CellValue]>) -> String {
+    let tuples: Vec<String> = rows
+        .into_iter()
+        .map(|row| {
+            let values: Vec<String> = row.iter().map(literal).collect();
+            format!("({})", values.join(", "))
+        })
+        .collect();
+    format!("INSERT INTO {} VALUES\n  {};", table, tuples.join(",\n  "))
+}
+
+// A cell as a SQL literal
+pub fn literal(cell: &CellValue) -> String {
+    match &cell.value {
+        Some(cell_value::Value::IntVal(v)) => v.to_string(),
+        Some(cell_value::Value::StrVal(s)) => format!("'{}'", s.replace('\'', "''")),
+        Some(cell_value::Value::NullVal(_)) | None => "NULL".to_string(),
+    }
+}
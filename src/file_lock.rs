@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Advisory locks that keep processes sharing a database out of each other's way.
+//
+// Readers take shared locks and writers exclusive ones. The catalog and index
+// files are replaced by renaming a new file over them, which would leave a lock
+// on the old file behind, so each file is locked through a `<file>.lock` next
+// to it that is never replaced.
+
+// How long to wait for a lock unless configured otherwise
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often a lock held elsewhere is tried again
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+// Lock `file`, retrying until `timeout` has passed. Returns false if it never became free.
+pub fn lock_file(file: &File, mode: LockMode, timeout: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let attempt = match mode {
+            LockMode::Shared => file.try_lock_shared(),
+            LockMode::Exclusive => file.try_lock(),
+        };
+        match attempt {
+            Ok(()) => return Ok(true),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// How long a lock is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockScope {
+    Statement,
+    Transaction,
+}
+
+// The locks one `DatabaseManager` holds, by path of the file they protect
+pub struct FileLocks {
+    timeout: Duration,
+    held: HashMap<String, (File, LockMode, LockScope)>,
+}
+
+impl FileLocks {
+    pub fn new(timeout: Duration) -> Self {
+        FileLocks {
+            timeout,
+            held: HashMap::new(),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // Lock `path` in `mode` for at least `scope`, upgrading a shared lock already
+    // held. Returns false if the timeout expired; a shared lock being upgraded is
+    // lost in that case.
+    pub fn lock(&mut self, path: &str, mode: LockMode, scope: LockScope) -> io::Result<bool> {
+        let (file, scope) = match self.held.remove(path) {
+            Some((file, held_mode, held_scope))
+                if held_mode == LockMode::Exclusive || held_mode == mode =>
+            {
+                self.held
+                    .insert(path.to_string(), (file, held_mode, held_scope.max(scope)));
+                return Ok(true);
+            }
+            Some((file, _, held_scope)) => (file, held_scope.max(scope)),
+            None => (
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(format!("{}.lock", path))?,
+                scope,
+            ),
+        };
+        if !lock_file(&file, mode, self.timeout)? {
+            return Ok(false);
+        }
+        self.held.insert(path.to_string(), (file, mode, scope));
+        Ok(true)
+    }
+
+    // Let go of the locks taken for the statement that just ended
+    pub fn release_statement(&mut self) {
+        self.held
+            .retain(|_, (_, _, scope)| *scope == LockScope::Transaction);
+    }
+
+    // Closing a lock file releases its lock
+    pub fn release_all(&mut self) {
+        self.held.clear();
+    }
+}
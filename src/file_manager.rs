@@ -1,4 +1,4 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write};
 use std::path::Path;
 
@@ -20,12 +20,75 @@ impl FileManager {
         Ok(buffer)
     }
 
+    // Replace the file's contents atomically. The data goes to a temporary file
+    // that is synced and then renamed over the original, so a crash leaves either
+    // the old or the new contents in place, never a truncated or torn file.
     pub fn write_file(&self, data: &[u8]) -> io::Result<()> {
-        let mut file = OpenOptions::new()
+        let temp_path = format!("{}.tmp", self.path);
+        let written = OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&self.path)?;
-        file.write_all(data)?;
-        Ok(())
+            .truncate(true)
+            .open(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp_path, &self.path));
+        if written.is_err() {
+            // The original is untouched; leave no half-written copy beside it
+            let _ = fs::remove_file(&temp_path);
+        }
+        written?;
+        self.sync_dir()
+    }
+
+    // Remove the file if it exists and make the removal durable
+    pub fn remove_file(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => self.sync_dir(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // A rename or removal is only durable once the directory entry is synced
+    fn sync_dir(&self) -> io::Result<()> {
+        let dir = match Path::new(&self.path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn write_file_replaces_the_whole_file() {
+        let dir = temp_dir("files");
+        let path = dir.join("t.tab").to_string_lossy().into_owned();
+        let manager = FileManager::new(&path);
+
+        manager.write_file(b"a longer first version").unwrap();
+        manager.write_file(b"second").unwrap();
+        assert_eq!(manager.read_file().unwrap(), b"second");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+        // A failed rename leaves the target as it was and no temporary file
+        let occupied = dir.join("occupied");
+        fs::create_dir(&occupied).unwrap();
+        fs::write(occupied.join("kept"), b"").unwrap();
+        let blocked = FileManager::new(&occupied.to_string_lossy());
+        assert!(blocked.write_file(b"lost").is_err());
+        manager.remove_file().unwrap();
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["occupied"]);
     }
 }
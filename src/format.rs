@@ -0,0 +1,83 @@
+use std::io;
+
+// Versioning of the files a database is made of.
+//
+// The catalog is a protobuf message behind a header:
+//   magic (4) | format version (4) | crc32 of the message (4) | message
+//
+// Table data files carry the same fields in their header page, see `heap_file`,
+// and index files start with them, see `index_file`. Files written before
+// versioning have no header and count as version 0; they are only read by
+// `db_project migrate`, which rewrites them in this format.
+//
+// Version 2 stores indexes in pages; version 1 index files held a protobuf
+// message like the catalog and are rebuilt from the rows by `migrate`.
+// Version 3 adds a checksum to every data page of a table file.
+
+// Version written by this build. Raise it whenever the layout of a file or the
+// meaning of a message in `proto/database.proto` changes, and teach `migrate`
+// how to bring older files forward.
+pub const FORMAT_VERSION: u32 = 3;
+
+pub const CATALOG_MAGIC: &[u8; 4] = b"DBCT";
+pub const INDEX_MAGIC: &[u8; 4] = b"DBIX";
+
+const HEADER_SIZE: usize = 12;
+
+// `message` behind a header for a file of the kind `magic` stands for
+pub fn encode(magic: &[u8; 4], message: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + message.len());
+    bytes.extend_from_slice(magic);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(message).to_le_bytes());
+    bytes.extend_from_slice(message);
+    bytes
+}
+
+// Format version of `bytes`; a legacy protobuf message never starts with a magic
+pub fn version(magic: &[u8; 4], bytes: &[u8]) -> u32 {
+    if bytes.len() < HEADER_SIZE || &bytes[0..4] != magic {
+        return 0;
+    }
+    u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]])
+}
+
+// The message stored in `bytes`, which must be in the current format. Errors
+// do not name the file; callers know which one they read.
+pub fn decode<'a>(magic: &[u8; 4], bytes: &'a [u8]) -> io::Result<&'a [u8]> {
+    check_version(version(magic, bytes))?;
+    let checksum = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+    let message = &bytes[HEADER_SIZE..];
+    if crc32fast::hash(message) != checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "its checksum does not match",
+        ));
+    }
+    Ok(message)
+}
+
+// The message in `bytes` whatever version wrote them, for migrating old files
+pub fn decode_any<'a>(magic: &[u8; 4], bytes: &'a [u8]) -> io::Result<&'a [u8]> {
+    match version(magic, bytes) {
+        0 => Ok(bytes),
+        _ => decode(magic, bytes),
+    }
+}
+
+// Files written by an older build have to be migrated first, and those of a
+// newer one cannot be understood at all
+pub fn check_version(version: u32) -> io::Result<()> {
+    let problem = match version {
+        FORMAT_VERSION => return Ok(()),
+        version if version < FORMAT_VERSION => format!(
+            "it uses on-disk format version {}; run `db_project migrate` to upgrade it to version {}",
+            version, FORMAT_VERSION
+        ),
+        version => format!(
+            "it uses on-disk format version {}, newer than version {} this build understands",
+            version, FORMAT_VERSION
+        ),
+    };
+    Err(io::Error::new(io::ErrorKind::InvalidData, problem))
+}
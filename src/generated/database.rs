@@ -32,11 +32,35 @@ pub mod cell_value {
         NullVal(bool),
     }
 }
-/// Represents a row, with cells in a defined order based on ColumnDefinition
+/// Represents a row, with cells in a defined order based on ColumnDefinition.
+/// Every update writes a new version of the row; xmin and xmax tell which
+/// transactions may see a version.
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Row {
     #[prost(message, repeated, tag = "1")]
     pub cells: ::prost::alloc::vec::Vec<CellValue>,
+    /// Transaction that created this version; 0 for rows older than versioning
+    #[prost(fixed64, tag = "2")]
+    pub xmin: u64,
+    /// Transaction that deleted or replaced it; 0 while it is current
+    #[prost(fixed64, optional, tag = "3")]
+    pub xmax: ::core::option::Option<u64>,
+}
+/// Represents a secondary index over one or more columns of a table
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct IndexDefinition {
+    /// Index name, unique across the database
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    /// Indexed columns, in key order
+    #[prost(string, repeated, tag = "2")]
+    pub columns: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// Rejects duplicate keys when set
+    #[prost(bool, tag = "3")]
+    pub unique: bool,
+    /// How the index is organised on disk
+    #[prost(enumeration = "IndexMethod", tag = "4")]
+    pub method: i32,
 }
 /// Represents a table, containing metadata and data rows
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -47,6 +71,9 @@ pub struct TableDefinition {
     /// Column definitions
     #[prost(message, repeated, tag = "2")]
     pub columns: ::prost::alloc::vec::Vec<ColumnDefinition>,
+    /// Secondary indexes on the table
+    #[prost(message, repeated, tag = "3")]
+    pub indexes: ::prost::alloc::vec::Vec<IndexDefinition>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Database {
@@ -66,6 +93,73 @@ pub struct TableData {
     #[prost(message, repeated, tag = "3")]
     pub rows: ::prost::alloc::vec::Vec<Row>,
 }
+/// A single write-ahead log record. Page and file writes carry both images so
+/// committed work can be redone and uncommitted work undone after a crash.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogRecord {
+    /// Log sequence number, increasing within a log
+    #[prost(uint64, tag = "1")]
+    pub lsn: u64,
+    /// Transaction that made the change
+    #[prost(uint64, tag = "2")]
+    pub txn_id: u64,
+    #[prost(enumeration = "LogRecordType", tag = "3")]
+    pub kind: i32,
+    /// File the change applies to
+    #[prost(string, tag = "4")]
+    pub path: ::prost::alloc::string::String,
+    /// Page number, for PAGE_WRITE
+    #[prost(uint32, tag = "5")]
+    pub page_id: u32,
+    /// Image before the change; unset if the file did not exist
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub before: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    /// Image after the change; unset if the file was removed
+    #[prost(bytes = "vec", optional, tag = "7")]
+    pub after: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+}
+/// A row version locked by a session until its transaction ends. A writing
+/// transaction also locks its own id, under an empty table name.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RowLock {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    /// Transaction id if `table` is empty
+    #[prost(fixed64, tag = "2")]
+    pub row_id: u64,
+    /// Session holding the lock
+    #[prost(uint64, tag = "3")]
+    pub owner: u64,
+    /// Set for UPDATE, DELETE and FOR UPDATE; unset for FOR SHARE
+    #[prost(bool, tag = "4")]
+    pub exclusive: bool,
+}
+/// A session waiting for locks held by other sessions
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LockWait {
+    #[prost(uint64, tag = "1")]
+    pub owner: u64,
+    /// Sessions it waits for
+    #[prost(uint64, repeated, tag = "2")]
+    pub holders: ::prost::alloc::vec::Vec<u64>,
+}
+/// Lock table shared by every process using the database
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LockTable {
+    /// Id handed to the next session
+    #[prost(uint64, tag = "1")]
+    pub next_owner: u64,
+    /// Sessions that have taken locks and are still registered
+    #[prost(uint64, repeated, tag = "2")]
+    pub owners: ::prost::alloc::vec::Vec<u64>,
+    #[prost(message, repeated, tag = "3")]
+    pub locks: ::prost::alloc::vec::Vec<RowLock>,
+    #[prost(message, repeated, tag = "4")]
+    pub waits: ::prost::alloc::vec::Vec<LockWait>,
+    /// Session writing to the log, 0 if none
+    #[prost(uint64, tag = "5")]
+    pub log_holder: u64,
+}
 /// Column data types
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
@@ -93,3 +187,70 @@ impl ColumnType {
         }
     }
 }
+/// Index access methods
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum IndexMethod {
+    /// Ordered index, serves equality and range lookups
+    Btree = 0,
+    /// Hash index, serves equality lookups on the full key
+    Hash = 1,
+}
+impl IndexMethod {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Btree => "BTREE",
+            Self::Hash => "HASH",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "BTREE" => Some(Self::Btree),
+            "HASH" => Some(Self::Hash),
+            _ => None,
+        }
+    }
+}
+/// Kinds of write-ahead log records
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum LogRecordType {
+    Begin = 0,
+    Commit = 1,
+    Abort = 2,
+    /// One page of a table data file was overwritten
+    PageWrite = 3,
+    /// A whole file was created, replaced or removed
+    FileWrite = 4,
+}
+impl LogRecordType {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Begin => "BEGIN",
+            Self::Commit => "COMMIT",
+            Self::Abort => "ABORT",
+            Self::PageWrite => "PAGE_WRITE",
+            Self::FileWrite => "FILE_WRITE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "BEGIN" => Some(Self::Begin),
+            "COMMIT" => Some(Self::Commit),
+            "ABORT" => Some(Self::Abort),
+            "PAGE_WRITE" => Some(Self::PageWrite),
+            "FILE_WRITE" => Some(Self::FileWrite),
+            _ => None,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception, clippy::enum_variant_names)]
 pub mod generated_types {
     include!(concat!("generated", "/database.rs"));
 }
@@ -0,0 +1,495 @@
+use crate::buffer_pool::BufferPool;
+use crate::index::{IndexKey, IndexValue};
+use crate::index_file::{
+    self, decode_key, encode_key, key_size, new_header, read_u16, read_u32, read_u64, seal_header,
+    write_u32, write_u64, IndexFile, FIELDS_OFFSET, HASH,
+};
+use crate::page::PAGE_SIZE;
+use std::cell::RefCell;
+use std::io;
+
+// Linear hash index mapping a full key to the ids of the rows that hold it,
+// kept in the pages of an index file. Buckets are split one at a time in
+// round-robin order, so growing the index only ever rewrites a single bucket
+// per insert, and a statement writes back only the pages it changed.
+//
+// Header fields:
+//   level (4) | next split (4) | entry count (8) | free page (4) |
+//   directory page count (4) | directory pages (4 each)
+//
+// Directory pages hold the first page of each bucket, 1024 to a page.
+//
+// Bucket page layout:
+//   kind (1) | entry count (2) | next page of the bucket (4) | entries
+//
+// Each entry is key | row id (8). A bucket takes more pages as it fills up
+// and is packed again when it is split. Pages a split no longer needs are
+// chained through their next field from the free page for reuse.
+
+// Number of buckets a new hash index starts with
+const INITIAL_BUCKETS: usize = 4;
+// Average number of entries per bucket above which the next bucket is split
+const MAX_LOAD_FACTOR: u64 = 64;
+
+const LEVEL_OFFSET: usize = FIELDS_OFFSET;
+const NEXT_SPLIT_OFFSET: usize = FIELDS_OFFSET + 4;
+const ENTRY_COUNT_OFFSET: usize = FIELDS_OFFSET + 8;
+const FREE_OFFSET: usize = FIELDS_OFFSET + 16;
+const DIRECTORY_COUNT_OFFSET: usize = FIELDS_OFFSET + 20;
+const DIRECTORY_OFFSET: usize = FIELDS_OFFSET + 24;
+const MAX_DIRECTORY_PAGES: usize = (PAGE_SIZE - DIRECTORY_OFFSET) / 4;
+const BUCKETS_PER_PAGE: usize = PAGE_SIZE / 4;
+
+const BUCKET: u8 = 1;
+const FREE: u8 = 2;
+const BUCKET_HEADER_SIZE: usize = 7;
+
+// Stable FNV-1a hash of a key. The std hasher is randomly seeded per process,
+// which would scatter persisted keys across different buckets on every run.
+fn hash_key(key: &IndexKey) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    for value in key {
+        match value {
+            IndexValue::Null => feed(&[0]),
+            IndexValue::Int(v) => {
+                feed(&[1]);
+                feed(&v.to_le_bytes());
+            }
+            IndexValue::Str(s) => {
+                feed(&[2]);
+                feed(&(s.len() as u32).to_le_bytes());
+                feed(s.as_bytes());
+            }
+        }
+    }
+    hash
+}
+
+fn damaged() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "an index page is damaged")
+}
+
+fn entry_size(key: &IndexKey) -> usize {
+    key_size(key) + 8
+}
+
+#[derive(Default)]
+struct BucketPage {
+    next: u32,
+    entries: Vec<(IndexKey, u64)>,
+}
+
+impl BucketPage {
+    fn size(&self) -> usize {
+        BUCKET_HEADER_SIZE
+            + self
+                .entries
+                .iter()
+                .map(|(key, _)| entry_size(key))
+                .sum::<usize>()
+    }
+
+    fn decode(page: &[u8]) -> io::Result<BucketPage> {
+        if page[0] != BUCKET {
+            return Err(damaged());
+        }
+        let count = read_u16(page, 1) as usize;
+        let next = read_u32(page, 3);
+        let mut pos = BUCKET_HEADER_SIZE;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let key = decode_key(page, &mut pos)?;
+            if pos + 8 > page.len() {
+                return Err(damaged());
+            }
+            entries.push((key, read_u64(page, pos)));
+            pos += 8;
+        }
+        Ok(BucketPage { next, entries })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        page.push(BUCKET);
+        page.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        page.extend_from_slice(&self.next.to_le_bytes());
+        for (key, row_id) in &self.entries {
+            encode_key(key, &mut page);
+            page.extend_from_slice(&row_id.to_le_bytes());
+        }
+        page.resize(PAGE_SIZE, 0);
+        page
+    }
+}
+
+pub struct HashIndex<'a> {
+    file: IndexFile<'a>,
+    level: u32,
+    next_split: usize,
+    num_entries: u64,
+    free: u32,
+    directory: Vec<u32>,
+}
+
+impl<'a> HashIndex<'a> {
+    // Open the index file at `path` through the pool; a missing file is an empty index
+    pub fn open(pool: &'a RefCell<BufferPool>, path: &str) -> io::Result<Self> {
+        let (file, header) = IndexFile::open(pool, path, HASH)?;
+        Self::with_file(file, header)
+    }
+
+    fn with_file(file: IndexFile<'a>, header: Option<Vec<u8>>) -> io::Result<Self> {
+        let mut index = HashIndex {
+            file,
+            level: 0,
+            next_split: 0,
+            num_entries: 0,
+            free: 0,
+            directory: Vec::new(),
+        };
+        match header {
+            Some(header) => {
+                index.level = read_u32(&header, LEVEL_OFFSET);
+                index.next_split = read_u32(&header, NEXT_SPLIT_OFFSET) as usize;
+                index.num_entries = read_u64(&header, ENTRY_COUNT_OFFSET);
+                index.free = read_u32(&header, FREE_OFFSET);
+                let count = read_u32(&header, DIRECTORY_COUNT_OFFSET) as usize;
+                if count > MAX_DIRECTORY_PAGES
+                    || index.level > 24
+                    || index.bucket_count() > count * BUCKETS_PER_PAGE
+                {
+                    return Err(damaged());
+                }
+                index.directory = (0..count)
+                    .map(|i| read_u32(&header, DIRECTORY_OFFSET + i * 4))
+                    .collect();
+            }
+            None => {
+                // The header, then one empty page per bucket
+                index.file.append();
+                for bucket in 0..INITIAL_BUCKETS {
+                    let page_id = index.file.append();
+                    index.write_bucket(page_id, &BucketPage::default());
+                    index.set_first_page(bucket, page_id)?;
+                }
+                index.write_header();
+            }
+        }
+        Ok(index)
+    }
+
+    fn write_header(&mut self) {
+        let mut header = new_header(HASH, self.file.page_count);
+        write_u32(&mut header, LEVEL_OFFSET, self.level);
+        write_u32(&mut header, NEXT_SPLIT_OFFSET, self.next_split as u32);
+        write_u64(&mut header, ENTRY_COUNT_OFFSET, self.num_entries);
+        write_u32(&mut header, FREE_OFFSET, self.free);
+        write_u32(
+            &mut header,
+            DIRECTORY_COUNT_OFFSET,
+            self.directory.len() as u32,
+        );
+        for (i, page_id) in self.directory.iter().enumerate() {
+            write_u32(&mut header, DIRECTORY_OFFSET + i * 4, *page_id);
+        }
+        seal_header(&mut header);
+        self.file.write(0, header);
+    }
+
+    fn bucket_count(&self) -> usize {
+        (INITIAL_BUCKETS << self.level) + self.next_split
+    }
+
+    // Bucket that `hash` lives in given the current level and split pointer
+    fn bucket_of(&self, hash: u64) -> usize {
+        let round_size = (INITIAL_BUCKETS << self.level) as u64;
+        let bucket = (hash % round_size) as usize;
+        if bucket < self.next_split {
+            (hash % (round_size * 2)) as usize
+        } else {
+            bucket
+        }
+    }
+
+    fn check_page_id(&self, page_id: u32) -> io::Result<()> {
+        if page_id == 0 || page_id >= self.file.page_count {
+            return Err(damaged());
+        }
+        Ok(())
+    }
+
+    fn first_page(&mut self, bucket: usize) -> io::Result<u32> {
+        let directory_page = self.directory[bucket / BUCKETS_PER_PAGE];
+        self.check_page_id(directory_page)?;
+        let page = self.file.read(directory_page)?;
+        Ok(read_u32(&page, bucket % BUCKETS_PER_PAGE * 4))
+    }
+
+    fn set_first_page(&mut self, bucket: usize, page_id: u32) -> io::Result<()> {
+        let index = bucket / BUCKETS_PER_PAGE;
+        let mut page = if index == self.directory.len() {
+            if index == MAX_DIRECTORY_PAGES {
+                return Err(io::Error::other(
+                    "the hash index has reached its largest number of buckets",
+                ));
+            }
+            let directory_page = self.allocate()?;
+            self.directory.push(directory_page);
+            vec![0; PAGE_SIZE]
+        } else {
+            self.file.read(self.directory[index])?
+        };
+        write_u32(&mut page, bucket % BUCKETS_PER_PAGE * 4, page_id);
+        self.file.write(self.directory[index], page);
+        Ok(())
+    }
+
+    // A page for new contents, taken from the free pages before the file grows
+    fn allocate(&mut self) -> io::Result<u32> {
+        if self.free == 0 {
+            return Ok(self.file.append());
+        }
+        let page_id = self.free;
+        self.check_page_id(page_id)?;
+        let page = self.file.read(page_id)?;
+        if page[0] != FREE {
+            return Err(damaged());
+        }
+        self.free = read_u32(&page, 3);
+        Ok(page_id)
+    }
+
+    fn release(&mut self, page_id: u32) {
+        let mut page = vec![0; PAGE_SIZE];
+        page[0] = FREE;
+        write_u32(&mut page, 3, self.free);
+        self.file.write(page_id, page);
+        self.free = page_id;
+    }
+
+    fn write_bucket(&mut self, page_id: u32, page: &BucketPage) {
+        self.file.write(page_id, page.encode());
+    }
+
+    // The pages of a bucket in chain order
+    fn read_bucket(&mut self, bucket: usize) -> io::Result<Vec<(u32, BucketPage)>> {
+        let mut pages = Vec::new();
+        let mut page_id = self.first_page(bucket)?;
+        while page_id != 0 {
+            self.check_page_id(page_id)?;
+            if pages.len() >= self.file.page_count as usize {
+                return Err(damaged());
+            }
+            let page = BucketPage::decode(&self.file.read(page_id)?)?;
+            let next = page.next;
+            pages.push((page_id, page));
+            page_id = next;
+        }
+        Ok(pages)
+    }
+
+    pub fn get(&mut self, key: &IndexKey) -> io::Result<Vec<u64>> {
+        let bucket = self.bucket_of(hash_key(key));
+        Ok(self
+            .read_bucket(bucket)?
+            .into_iter()
+            .flat_map(|(_, page)| page.entries)
+            .filter(|(entry_key, _)| entry_key == key)
+            .map(|(_, row_id)| row_id)
+            .collect())
+    }
+
+    pub fn insert(&mut self, key: IndexKey, row_id: u64) -> io::Result<()> {
+        index_file::check_key(&key)?;
+        let bucket = self.bucket_of(hash_key(&key));
+        let mut pages = self.read_bucket(bucket)?;
+        let size = entry_size(&key);
+        match pages
+            .iter_mut()
+            .find(|(_, page)| page.size() + size <= PAGE_SIZE)
+        {
+            Some((page_id, page)) => {
+                page.entries.push((key, row_id));
+                self.write_bucket(*page_id, page);
+            }
+            None => {
+                let page_id = self.allocate()?;
+                self.write_bucket(
+                    page_id,
+                    &BucketPage {
+                        next: 0,
+                        entries: vec![(key, row_id)],
+                    },
+                );
+                let (last_id, last) = pages.last_mut().ok_or_else(damaged)?;
+                last.next = page_id;
+                self.write_bucket(*last_id, last);
+            }
+        }
+        self.num_entries += 1;
+        if self.num_entries > self.bucket_count() as u64 * MAX_LOAD_FACTOR {
+            self.split()?;
+        }
+        self.write_header();
+        Ok(())
+    }
+
+    // Buckets are never merged back; emptied pages stay in their bucket
+    pub fn remove(&mut self, key: &IndexKey, row_id: u64) -> io::Result<()> {
+        let bucket = self.bucket_of(hash_key(key));
+        for (page_id, mut page) in self.read_bucket(bucket)? {
+            if let Some(position) = page
+                .entries
+                .iter()
+                .position(|(entry_key, id)| entry_key == key && *id == row_id)
+            {
+                page.entries.swap_remove(position);
+                self.write_bucket(page_id, &page);
+                self.num_entries -= 1;
+                self.write_header();
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Write the pages changed since the index was opened or last flushed
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    // Every (key, row id) pair, in no particular order
+    pub fn entries(&mut self) -> io::Result<Vec<(IndexKey, u64)>> {
+        let mut entries = Vec::new();
+        for bucket in 0..self.bucket_count() {
+            for (_, page) in self.read_bucket(bucket)? {
+                entries.extend(page.entries);
+            }
+        }
+        Ok(entries)
+    }
+
+    // Split the bucket under the split pointer into itself and a new bucket at the end
+    fn split(&mut self) -> io::Result<()> {
+        let old_bucket = self.next_split;
+        let new_bucket = self.bucket_count();
+        self.next_split += 1;
+        if self.next_split == INITIAL_BUCKETS << self.level {
+            self.level += 1;
+            self.next_split = 0;
+        }
+        let pages = self.read_bucket(old_bucket)?;
+        let page_ids = pages.iter().map(|(page_id, _)| *page_id).collect();
+        let (staying, moving): (Vec<_>, Vec<_>) = pages
+            .into_iter()
+            .flat_map(|(_, page)| page.entries)
+            .partition(|(key, _)| self.bucket_of(hash_key(key)) == old_bucket);
+        self.write_chain(page_ids, staying)?;
+        let first_page = self.write_chain(Vec::new(), moving)?;
+        self.set_first_page(new_bucket, first_page)
+    }
+
+    // Pack `entries` into a chain of pages, reusing `page_ids` in order before
+    // taking others and freeing those left over. Returns the first page.
+    fn write_chain(
+        &mut self,
+        page_ids: Vec<u32>,
+        entries: Vec<(IndexKey, u64)>,
+    ) -> io::Result<u32> {
+        let mut chain = vec![BucketPage::default()];
+        for (key, row_id) in entries {
+            let last = chain.last_mut().expect("a chain has a first page");
+            if last.size() + entry_size(&key) > PAGE_SIZE {
+                chain.push(BucketPage::default());
+            }
+            let last = chain.last_mut().expect("a chain has a first page");
+            last.entries.push((key, row_id));
+        }
+        let mut reused = page_ids.into_iter();
+        let mut ids = Vec::with_capacity(chain.len());
+        for _ in &chain {
+            ids.push(match reused.next() {
+                Some(page_id) => page_id,
+                None => self.allocate()?,
+            });
+        }
+        for page_id in reused {
+            self.release(page_id);
+        }
+        for (position, mut page) in chain.into_iter().enumerate() {
+            page.next = ids.get(position + 1).copied().unwrap_or(0);
+            self.write_bucket(ids[position], &page);
+        }
+        Ok(ids[0])
+    }
+}
+
+impl HashIndex<'static> {
+    // An empty index held in memory
+    pub fn new() -> Self {
+        Self::with_file(IndexFile::new(), None).expect("a new index needs no reading")
+    }
+
+    // An index file read whole into `bytes`
+    pub fn from_image(bytes: &[u8]) -> io::Result<Self> {
+        let (file, header) = IndexFile::from_image(bytes, HASH)?;
+        Self::with_file(file, Some(header))
+    }
+
+    // The whole file of an index held in memory
+    pub fn image(self) -> io::Result<Vec<u8>> {
+        self.file.image()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use crate::wal::Wal;
+
+    #[test]
+    fn buckets_split_as_the_index_grows_and_read_back_from_their_pages() {
+        let dir = temp_dir("hash");
+        let path = dir.join("t.t_name.idx").to_string_lossy().into_owned();
+        let log_path = dir.join("dbfile.wal").to_string_lossy().into_owned();
+        // Every key is held by two rows, and long keys fill overflow pages
+        let key = |n: u64| vec![IndexValue::Str(format!("{:0>100}", n / 2))];
+
+        let pool = RefCell::new(BufferPool::new(4, Wal::open(&log_path).unwrap()));
+        pool.borrow_mut().begin(1).unwrap();
+        let mut index = HashIndex::open(&pool, &path).unwrap();
+        for n in 0..4000 {
+            index.insert(key(n), n).unwrap();
+        }
+        index.flush().unwrap();
+        assert!(index.bucket_count() > 50);
+        assert_eq!(index.get(&key(10)).unwrap(), vec![10, 11]);
+
+        // Another entry only writes its bucket page and the header
+        let logged = pool.borrow().savepoint();
+        index.remove(&key(10), 10).unwrap();
+        index.flush().unwrap();
+        assert_eq!(pool.borrow().savepoint() - logged, 2);
+        pool.borrow_mut().flush_all().unwrap();
+
+        // A fresh pool reads the same entries back from the file
+        let pool = RefCell::new(BufferPool::new(4, Wal::open(&log_path).unwrap()));
+        let mut index = HashIndex::open(&pool, &path).unwrap();
+        assert_eq!(index.get(&key(10)).unwrap(), vec![11]);
+        assert_eq!(index.get(&key(3998)).unwrap(), vec![3998, 3999]);
+        assert!(index.get(&key(4000)).unwrap().is_empty());
+        let mut entries = index.entries().unwrap();
+        entries.sort_by_key(|(_, row_id)| *row_id);
+        assert_eq!(entries.len(), 3999);
+        assert!(entries
+            .iter()
+            .all(|(entry_key, row_id)| *entry_key == key(*row_id)));
+    }
+}
@@ -0,0 +1,715 @@
+use crate::buffer_pool::BufferPool;
+use crate::format::{self, FORMAT_VERSION};
+use crate::page::{Page, MAX_RECORD_SIZE, PAGE_SIZE};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+
+// Table data file made of fixed-size pages. Page 0 is the file header and
+// every following page is a slotted data page holding encoded rows. All page
+// I/O goes through the shared buffer pool.
+//
+// Header page layout:
+//   magic (4) | page_count (4) | format version (4) | crc32 (4) | first map page (4) | free-space map
+//
+// The checksum covers the whole header page with the checksum field zeroed.
+// Files from before versioning have zeros in its place, so they read as version 0.
+//
+// Each free-space map byte is the free space of a page in 16-byte units,
+// rounded down, so a page is only picked for a row that is known to fit.
+// The map goes on in map pages once the header is full. They are appended
+// like data pages and chained from the header:
+//   magic (4) | next map page (4) | crc32 (4) | free-space map
+//
+// Data pages carry a checksum of their own from format version 3 on, see `page`.
+// Older versions kept no map pages; their map ended with the header page.
+
+pub const HEAP_MAGIC: &[u8; 4] = b"DBTB";
+const MAP_MAGIC: &[u8; 4] = b"DBFM";
+
+const VERSION_OFFSET: usize = 8;
+const CHECKSUM_OFFSET: usize = 12;
+const MAP_LINK_OFFSET: usize = 16;
+const FSM_OFFSET: usize = 20;
+const FSM_UNIT: usize = 16;
+// Pages tracked in the header; the rest are tracked in map pages
+const FSM_CAPACITY: usize = PAGE_SIZE - FSM_OFFSET;
+const MAP_CHECKSUM_OFFSET: usize = 8;
+const MAP_ENTRIES_OFFSET: usize = 12;
+const MAP_CAPACITY: usize = PAGE_SIZE - MAP_ENTRIES_OFFSET;
+// First format version whose data pages have a checksum
+const PAGE_CHECKSUM_VERSION: u32 = 3;
+
+// A row id packs the page number and the slot within the page
+pub fn make_row_id(page_id: u32, slot: u16) -> u64 {
+    ((page_id as u64) << 16) | slot as u64
+}
+
+pub fn split_row_id(row_id: u64) -> (u32, u16) {
+    ((row_id >> 16) as u32, row_id as u16)
+}
+
+pub struct HeapFile<'a> {
+    pool: &'a RefCell<BufferPool>,
+    path: String,
+    page_count: u32,
+    // One entry per page after the header, map pages included
+    free_space_map: Vec<u8>,
+    // Ids of the map pages in chain order, which is also ascending
+    map_pages: Vec<u32>,
+    // Unset while an older file is being upgraded
+    checksummed: bool,
+}
+
+impl<'a> HeapFile<'a> {
+    // Format version of the page file at `path`, or `None` if it holds a
+    // protobuf dump from before table files had pages
+    pub fn format_version(pool: &RefCell<BufferPool>, path: &str) -> io::Result<Option<u32>> {
+        let header = if pool.borrow().is_cached(path, 0) {
+            pool.borrow_mut().read_page(path, 0)?
+        } else {
+            // A protobuf dump may be shorter than a page
+            let mut header = Vec::new();
+            File::open(path)?
+                .take(FSM_OFFSET as u64)
+                .read_to_end(&mut header)?;
+            header
+        };
+        if header.len() < FSM_OFFSET || &header[0..4] != HEAP_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(read_u32(&header, VERSION_OFFSET)))
+    }
+
+    // Open the heap file at `path`, creating an empty one if it does not exist
+    pub fn open(pool: &'a RefCell<BufferPool>, path: &str) -> io::Result<Self> {
+        Self::open_version(pool, path, false)
+    }
+
+    // Rewrite an existing heap file in the current format. Every data page is
+    // written again, which gives pages of older versions their checksum.
+    pub fn upgrade(pool: &'a RefCell<BufferPool>, path: &str) -> io::Result<()> {
+        let mut heap = Self::open_version(pool, path, true)?;
+        let page_count = heap.page_count;
+        // Older maps end with the header, so larger files get map pages first
+        while heap.free_space_map.len() > map_capacity(heap.map_pages.len()) {
+            heap.add_map_page()?;
+        }
+        for page_id in 1..page_count {
+            if heap.is_data_page(page_id) {
+                let page = heap.read_page(page_id)?;
+                heap.write_page(&page)?;
+            }
+        }
+        heap.write_header()
+    }
+
+    fn open_version(
+        pool: &'a RefCell<BufferPool>,
+        path: &str,
+        any_version: bool,
+    ) -> io::Result<Self> {
+        let exists = pool.borrow().is_cached(path, 0)
+            || fs::metadata(path).is_ok_and(|metadata| metadata.len() > 0);
+        let mut heap = HeapFile {
+            pool,
+            path: path.to_string(),
+            page_count: 1,
+            free_space_map: Vec::new(),
+            map_pages: Vec::new(),
+            checksummed: true,
+        };
+        if exists {
+            let header = heap.read_raw(0)?;
+            if &header[0..4] != HEAP_MAGIC {
+                // Most likely a protobuf dump from before table files had pages
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "it is not a table data file; if an older version wrote it, run `db_project migrate`",
+                ));
+            }
+            check_header(&header, any_version)?;
+            heap.checksummed = read_u32(&header, VERSION_OFFSET) >= PAGE_CHECKSUM_VERSION;
+            heap.page_count = read_u32(&header, 4);
+            let pages = heap.page_count as usize - 1;
+            if !heap.checksummed {
+                // Rebuilt by `upgrade` as it rewrites every page
+                heap.free_space_map = vec![0; pages];
+                return Ok(heap);
+            }
+            let tracked = pages.min(FSM_CAPACITY);
+            heap.free_space_map = header[FSM_OFFSET..FSM_OFFSET + tracked].to_vec();
+            let mut link = read_u32(&header, MAP_LINK_OFFSET);
+            while link != 0 {
+                // Map pages only ever go up, which also rules out a loop
+                if link >= heap.page_count
+                    || heap.map_pages.last().is_some_and(|&last| link <= last)
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("its free-space map links to page {}", link),
+                    ));
+                }
+                let data = heap.read_raw(link)?;
+                let next = check_map_page(&data, link)?;
+                let tracked = (pages - heap.free_space_map.len()).min(MAP_CAPACITY);
+                heap.free_space_map
+                    .extend_from_slice(&data[MAP_ENTRIES_OFFSET..MAP_ENTRIES_OFFSET + tracked]);
+                heap.map_pages.push(link);
+                link = next;
+            }
+            if heap.free_space_map.len() < pages {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "its free-space map does not cover every page",
+                ));
+            }
+        } else {
+            pool.borrow_mut().write_file(path, Some(Vec::new()))?;
+            heap.write_header()?;
+        }
+        Ok(heap)
+    }
+
+    fn read_raw(&mut self, page_id: u32) -> io::Result<Vec<u8>> {
+        self.pool.borrow_mut().read_page(&self.path, page_id)
+    }
+
+    fn write_raw(&mut self, page_id: u32, data: &[u8]) -> io::Result<()> {
+        self.pool.borrow_mut().write_page(&self.path, page_id, data)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let header = header(
+            self.page_count,
+            segment(&self.free_space_map, 0),
+            self.map_pages.first().copied().unwrap_or(0),
+        );
+        self.write_raw(0, &header)
+    }
+
+    // Write the part of the map kept in the header (0) or in map page `number` - 1
+    fn write_segment(&mut self, number: usize) -> io::Result<()> {
+        if number == 0 {
+            return self.write_header();
+        }
+        let next = self.map_pages.get(number).copied().unwrap_or(0);
+        let page = map_page(next, segment(&self.free_space_map, number));
+        self.write_raw(self.map_pages[number - 1], &page)
+    }
+
+    // Append a map page and link it from the end of the chain
+    fn add_map_page(&mut self) -> io::Result<()> {
+        self.map_pages.push(self.page_count);
+        self.page_count += 1;
+        self.free_space_map.push(0);
+        let number = self.map_pages.len();
+        self.write_segment(number)?;
+        self.write_segment(number - 1)
+    }
+
+    // Append an empty data page, after a map page if the map is full
+    fn new_page(&mut self) -> io::Result<Page> {
+        if self.free_space_map.len() >= map_capacity(self.map_pages.len()) {
+            self.add_map_page()?;
+        }
+        let page = Page::new(self.page_count);
+        self.page_count += 1;
+        self.free_space_map.push(0);
+        Ok(page)
+    }
+
+    fn is_data_page(&self, page_id: u32) -> bool {
+        page_id != 0 && page_id < self.page_count && self.map_pages.binary_search(&page_id).is_err()
+    }
+
+    fn read_page(&mut self, page_id: u32) -> io::Result<Page> {
+        let data = self.read_raw(page_id)?;
+        Page::from_bytes(data, self.checksummed).map_err(|e| page_error(page_id, e))
+    }
+
+    // Write a data page back and record its new free space in the map
+    fn write_page(&mut self, page: &Page) -> io::Result<()> {
+        self.write_raw(page.page_id(), &page.to_bytes())?;
+        let index = page.page_id() as usize - 1;
+        let units = free_units(page);
+        if self.free_space_map[index] != units {
+            self.free_space_map[index] = units;
+            self.write_segment(segment_number(index))?;
+        }
+        Ok(())
+    }
+
+    // Data page with room for `length` more bytes, appending a new one if none has
+    fn page_with_room(&mut self, length: usize) -> io::Result<Page> {
+        // At least one unit, so map pages with their zero entries are never picked
+        let needed = length.div_ceil(FSM_UNIT).max(1);
+        if let Some(index) = self
+            .free_space_map
+            .iter()
+            .position(|units| *units as usize >= needed)
+        {
+            return self.read_page(index as u32 + 1);
+        }
+        self.new_page()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Store a record and return its row id
+    pub fn insert(&mut self, record: &[u8]) -> io::Result<u64> {
+        Ok(self.insert_all(&[record])?[0])
+    }
+
+    // Store records in order and return their row ids. Each page they go to
+    // is written once, however many of them it takes.
+    pub fn insert_all(&mut self, records: &[impl AsRef<[u8]>]) -> io::Result<Vec<u64>> {
+        if let Some(record) = records
+            .iter()
+            .find(|record| record.as_ref().len() > MAX_RECORD_SIZE)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "row of {} bytes exceeds the maximum of {} bytes",
+                    record.as_ref().len(),
+                    MAX_RECORD_SIZE
+                ),
+            ));
+        }
+        let page_count = self.page_count;
+        let mut row_ids = Vec::with_capacity(records.len());
+        let mut current: Option<Page> = None;
+        for record in records {
+            let record = record.as_ref();
+            if let Some(slot) = current.as_mut().and_then(|page| page.insert(record)) {
+                row_ids.push(make_row_id(current.as_ref().unwrap().page_id(), slot));
+                continue;
+            }
+            // Written first so the free space map no longer offers the full page
+            if let Some(full) = current.take() {
+                self.write_page(&full)?;
+            }
+            let mut page = self.page_with_room(record.len())?;
+            let slot = match page.insert(record) {
+                Some(slot) => slot,
+                None => {
+                    // The map rounds down, so only an out-of-date entry gets here
+                    page = self.new_page()?;
+                    page.insert(record).expect("record fits on an empty page")
+                }
+            };
+            row_ids.push(make_row_id(page.page_id(), slot));
+            current = Some(page);
+        }
+        if let Some(page) = current {
+            self.write_page(&page)?;
+        }
+        if self.page_count != page_count {
+            self.write_header()?;
+        }
+        Ok(row_ids)
+    }
+
+    pub fn get(&mut self, row_id: u64) -> io::Result<Option<Vec<u8>>> {
+        let (page_id, slot) = split_row_id(row_id);
+        if !self.is_data_page(page_id) {
+            return Ok(None);
+        }
+        let page = self.read_page(page_id)?;
+        Ok(page.get(slot).map(|record| record.to_vec()))
+    }
+
+    pub fn delete(&mut self, row_id: u64) -> io::Result<bool> {
+        let (page_id, slot) = split_row_id(row_id);
+        if !self.is_data_page(page_id) {
+            return Ok(false);
+        }
+        let mut page = self.read_page(page_id)?;
+        let deleted = page.delete(slot);
+        if deleted {
+            self.write_page(&page)?;
+        }
+        Ok(deleted)
+    }
+
+    // Replace a record, moving it to another page if it no longer fits.
+    // Returns the row id the record now lives at.
+    pub fn update(&mut self, row_id: u64, record: &[u8]) -> io::Result<u64> {
+        let (page_id, slot) = split_row_id(row_id);
+        let missing = || {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("there is no row with id {}", row_id),
+            )
+        };
+        if !self.is_data_page(page_id) {
+            return Err(missing());
+        }
+        let mut page = self.read_page(page_id)?;
+        if page.get(slot).is_none() {
+            return Err(missing());
+        }
+        if page.update(slot, record) {
+            self.write_page(&page)?;
+            return Ok(row_id);
+        }
+        let new_row_id = self.insert(record)?;
+        self.delete(row_id)?;
+        Ok(new_row_id)
+    }
+
+    // Number of pages, the header page included
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    // Every live record of page `page_id` with its row id; map pages have none
+    pub fn page_records(&mut self, page_id: u32) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        if !self.is_data_page(page_id) {
+            return Ok(Vec::new());
+        }
+        let page = self.read_page(page_id)?;
+        Ok(page
+            .records()
+            .map(|(slot, record)| (make_row_id(page_id, slot), record.to_vec()))
+            .collect())
+    }
+
+    // Every live record in page order
+    pub fn scan(&mut self) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let mut records = Vec::new();
+        for page_id in 1..self.page_count {
+            records.extend(self.page_records(page_id)?);
+        }
+        Ok(records)
+    }
+}
+
+// A whole heap file holding `records` in order, page after page, with their
+// row ids. Written without the buffer pool, for files outside the database.
+pub fn image(records: &[impl AsRef<[u8]>]) -> io::Result<(Vec<u8>, Vec<u64>)> {
+    // Pages after the header in order, `None` for map pages
+    let mut pages: Vec<Option<Page>> = Vec::new();
+    let mut map_pages = Vec::new();
+    let mut row_ids = Vec::with_capacity(records.len());
+    for record in records {
+        let record = record.as_ref();
+        if record.len() > MAX_RECORD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "row of {} bytes exceeds the maximum of {} bytes",
+                    record.len(),
+                    MAX_RECORD_SIZE
+                ),
+            ));
+        }
+        let last = pages.last_mut().and_then(Option::as_mut);
+        let slot = match last.and_then(|page| page.insert(record)) {
+            Some(slot) => slot,
+            None => {
+                if pages.len() >= map_capacity(map_pages.len()) {
+                    pages.push(None);
+                    map_pages.push(pages.len() as u32);
+                }
+                let mut page = Page::new(pages.len() as u32 + 1);
+                let slot = page.insert(record).expect("record fits on an empty page");
+                pages.push(Some(page));
+                slot
+            }
+        };
+        row_ids.push(make_row_id(pages.len() as u32, slot));
+    }
+    let free_space_map: Vec<u8> = pages
+        .iter()
+        .map(|page| page.as_ref().map_or(0, free_units))
+        .collect();
+    let first_map_page = map_pages.first().copied().unwrap_or(0);
+    let mut bytes = header(
+        pages.len() as u32 + 1,
+        segment(&free_space_map, 0),
+        first_map_page,
+    );
+    let mut number = 0;
+    for page in &pages {
+        match page {
+            Some(page) => bytes.extend_from_slice(&page.to_bytes()),
+            None => {
+                number += 1;
+                let next = map_pages.get(number).copied().unwrap_or(0);
+                bytes.extend_from_slice(&map_page(next, segment(&free_space_map, number)));
+            }
+        }
+    }
+    Ok((bytes, row_ids))
+}
+
+// The records of a heap file read whole into `bytes`, in page order, after
+// checking its header as opening the file would
+pub fn read_image(bytes: &[u8]) -> io::Result<Vec<(u64, Vec<u8>)>> {
+    let invalid = |error: &str| io::Error::new(io::ErrorKind::InvalidData, error.to_string());
+    if bytes.len() < PAGE_SIZE || !bytes.len().is_multiple_of(PAGE_SIZE) {
+        return Err(invalid("it is not made of whole pages"));
+    }
+    let header = &bytes[..PAGE_SIZE];
+    if &header[0..4] != HEAP_MAGIC {
+        return Err(invalid("it is not a table data file"));
+    }
+    check_header(header, false)?;
+    let page_count = read_u32(header, 4) as usize;
+    if page_count != bytes.len() / PAGE_SIZE {
+        return Err(invalid("its header does not match its length"));
+    }
+    let mut map_pages = HashSet::new();
+    let mut link = read_u32(header, MAP_LINK_OFFSET) as usize;
+    while link != 0 {
+        if link >= page_count || !map_pages.insert(link) {
+            return Err(invalid("its free-space map links outside the file"));
+        }
+        link = check_map_page(
+            &bytes[link * PAGE_SIZE..(link + 1) * PAGE_SIZE],
+            link as u32,
+        )? as usize;
+    }
+    let mut records = Vec::new();
+    for (page_id, data) in bytes.chunks(PAGE_SIZE).enumerate().skip(1) {
+        if map_pages.contains(&page_id) {
+            continue;
+        }
+        let page =
+            Page::from_bytes(data.to_vec(), true).map_err(|e| page_error(page_id as u32, e))?;
+        for (slot, record) in page.records() {
+            records.push((make_row_id(page_id as u32, slot), record.to_vec()));
+        }
+    }
+    Ok(records)
+}
+
+// Header page for a file of `page_count` pages, header included
+fn header(page_count: u32, free_space_map: &[u8], first_map_page: u32) -> Vec<u8> {
+    let mut header = vec![0; PAGE_SIZE];
+    header[0..4].copy_from_slice(HEAP_MAGIC);
+    header[4..8].copy_from_slice(&page_count.to_le_bytes());
+    header[VERSION_OFFSET..VERSION_OFFSET + 4].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header[MAP_LINK_OFFSET..MAP_LINK_OFFSET + 4].copy_from_slice(&first_map_page.to_le_bytes());
+    header[FSM_OFFSET..FSM_OFFSET + free_space_map.len()].copy_from_slice(free_space_map);
+    let checksum = header_checksum(&header);
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+    header
+}
+
+// Map page holding `free_space_map` and linking to the map page `next`, 0 for none
+fn map_page(next: u32, free_space_map: &[u8]) -> Vec<u8> {
+    let mut page = vec![0; PAGE_SIZE];
+    page[0..4].copy_from_slice(MAP_MAGIC);
+    page[4..8].copy_from_slice(&next.to_le_bytes());
+    page[MAP_ENTRIES_OFFSET..MAP_ENTRIES_OFFSET + free_space_map.len()]
+        .copy_from_slice(free_space_map);
+    let checksum = crc32fast::hash(&page);
+    page[MAP_CHECKSUM_OFFSET..MAP_CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+    page
+}
+
+// Check the magic and checksum of map page `page_id` and return the next one
+fn check_map_page(page: &[u8], page_id: u32) -> io::Result<u32> {
+    let mut zeroed = page.to_vec();
+    zeroed[MAP_CHECKSUM_OFFSET..MAP_CHECKSUM_OFFSET + 4].fill(0);
+    if &page[0..4] != MAP_MAGIC || read_u32(page, MAP_CHECKSUM_OFFSET) != crc32fast::hash(&zeroed) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("free-space map page {} is damaged", page_id),
+        ));
+    }
+    Ok(read_u32(page, 4))
+}
+
+// Number of pages a map with `map_pages` map pages can track
+fn map_capacity(map_pages: usize) -> usize {
+    FSM_CAPACITY + map_pages * MAP_CAPACITY
+}
+
+// Which part of the map holds entry `index`: 0 for the header, n for map page n
+fn segment_number(index: usize) -> usize {
+    match index.checked_sub(FSM_CAPACITY) {
+        Some(rest) => rest / MAP_CAPACITY + 1,
+        None => 0,
+    }
+}
+
+// The entries of `free_space_map` kept in part `number` of the map
+fn segment(free_space_map: &[u8], number: usize) -> &[u8] {
+    let (start, capacity) = match number {
+        0 => (0, FSM_CAPACITY),
+        _ => (map_capacity(number - 1), MAP_CAPACITY),
+    };
+    let start = start.min(free_space_map.len());
+    let end = (start + capacity).min(free_space_map.len());
+    &free_space_map[start..end]
+}
+
+// Map entry for a data page
+fn free_units(page: &Page) -> u8 {
+    (page.free_space() / FSM_UNIT).min(u8::MAX as usize) as u8
+}
+
+// Check the format version, unless any will do, and the checksum of a header page
+fn check_header(header: &[u8], any_version: bool) -> io::Result<()> {
+    let version = read_u32(header, VERSION_OFFSET);
+    if !any_version {
+        format::check_version(version)?;
+    }
+    if version != 0 && read_u32(header, CHECKSUM_OFFSET) != header_checksum(header) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "its header checksum does not match",
+        ));
+    }
+    Ok(())
+}
+
+// Name the data page an error came from
+fn page_error(page_id: u32, error: io::Error) -> io::Error {
+    io::Error::new(
+        error.kind(),
+        format!("data page {} is damaged: {}", page_id, error),
+    )
+}
+
+fn read_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        page[offset],
+        page[offset + 1],
+        page[offset + 2],
+        page[offset + 3],
+    ])
+}
+
+fn header_checksum(header: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[..CHECKSUM_OFFSET]);
+    hasher.update(&[0; 4]);
+    hasher.update(&header[CHECKSUM_OFFSET + 4..]);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use crate::wal::Wal;
+
+    #[test]
+    fn rows_fill_many_pages_and_survive_reopening() {
+        let dir = temp_dir("heap");
+        let path = dir.join("t.tab").to_string_lossy().into_owned();
+        let log_path = dir.join("dbfile.wal").to_string_lossy().into_owned();
+        let records: Vec<Vec<u8>> = (0..200u32)
+            .map(|n| {
+                let mut record = n.to_le_bytes().to_vec();
+                record.resize(100, n as u8);
+                record
+            })
+            .collect();
+
+        // More pages than the pool has frames
+        let pool = RefCell::new(BufferPool::new(3, Wal::open(&log_path).unwrap()));
+        pool.borrow_mut().begin(1).unwrap();
+        let mut heap = HeapFile::open(&pool, &path).unwrap();
+        let row_ids = heap.insert_all(&records).unwrap();
+        let pages: HashSet<u32> = row_ids.iter().map(|&id| split_row_id(id).0).collect();
+        assert!(pages.len() > 3);
+
+        // Deleted slots are filled again before the file grows
+        for &row_id in &row_ids[..10] {
+            assert!(heap.delete(row_id).unwrap());
+        }
+        let page_count = heap.page_count;
+        assert_eq!(heap.insert(&records[0]).unwrap(), row_ids[0]);
+        assert_eq!(heap.page_count, page_count);
+        pool.borrow_mut().flush_all().unwrap();
+
+        // A fresh pool reads the same rows back from the file
+        let pool = RefCell::new(BufferPool::new(3, Wal::open(&log_path).unwrap()));
+        let mut heap = HeapFile::open(&pool, &path).unwrap();
+        let rows = heap.scan().unwrap();
+        assert_eq!(rows.len(), 191);
+        assert_eq!(heap.get(row_ids[0]).unwrap().as_ref(), Some(&records[0]));
+        assert_eq!(heap.get(row_ids[5]).unwrap(), None);
+        assert_eq!(
+            heap.get(row_ids[150]).unwrap().as_ref(),
+            Some(&records[150])
+        );
+        assert_eq!(read_image(&fs::read(&path).unwrap()).unwrap(), rows);
+
+        // A row id that was never handed out cannot be updated
+        let missing = make_row_id(heap.page_count, 0);
+        let error = heap.update(missing, &records[0]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        // A slot pointing outside its page is reported, not followed
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[PAGE_SIZE + 16..PAGE_SIZE + 18].fill(0xFF);
+        fs::write(&path, &bytes).unwrap();
+        let pool = RefCell::new(BufferPool::new(3, Wal::open(&log_path).unwrap()));
+        let mut heap = HeapFile::open(&pool, &path).unwrap();
+        assert_eq!(heap.scan().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            heap.get(row_ids[0]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(read_image(&bytes).is_err());
+    }
+
+    #[test]
+    fn free_space_past_the_header_is_tracked_and_reused() {
+        let dir = temp_dir("heap_map");
+        let path = dir.join("t.tab").to_string_lossy().into_owned();
+        let log_path = dir.join("dbfile.wal").to_string_lossy().into_owned();
+        // One record per page, enough to need a map page
+        let records: Vec<Vec<u8>> = (0..FSM_CAPACITY as u32 + 100)
+            .map(|n| {
+                let mut record = n.to_le_bytes().to_vec();
+                record.resize(3000, n as u8);
+                record
+            })
+            .collect();
+        let pool = RefCell::new(BufferPool::new(3, Wal::open(&log_path).unwrap()));
+        pool.borrow_mut().begin(1).unwrap();
+        let mut heap = HeapFile::open(&pool, &path).unwrap();
+        let row_ids = heap.insert_all(&records).unwrap();
+        assert_eq!(heap.map_pages, vec![FSM_CAPACITY as u32 + 1]);
+        assert_eq!(heap.page_count as usize, records.len() + 2);
+        pool.borrow_mut().flush_all().unwrap();
+
+        // A fresh pool reads the map pages back and reuses a page they track
+        let pool = RefCell::new(BufferPool::new(3, Wal::open(&log_path).unwrap()));
+        pool.borrow_mut().begin(2).unwrap();
+        let mut heap = HeapFile::open(&pool, &path).unwrap();
+        let last = *row_ids.last().unwrap();
+        assert!(heap.delete(last).unwrap());
+        let page_count = heap.page_count;
+        assert_eq!(heap.insert(&records[0]).unwrap(), last);
+        assert_eq!(heap.page_count, page_count);
+        let map_row = make_row_id(FSM_CAPACITY as u32 + 1, 0);
+        assert_eq!(heap.get(map_row).unwrap(), None);
+        assert!(!heap.delete(map_row).unwrap());
+        let rows = heap.scan().unwrap();
+        assert_eq!(rows.len(), records.len());
+        pool.borrow_mut().flush_all().unwrap();
+        assert_eq!(read_image(&fs::read(&path).unwrap()).unwrap(), rows);
+
+        // Images lay out map pages the same way
+        let (bytes, image_ids) = image(&records).unwrap();
+        assert_eq!(image_ids, row_ids);
+        let read: Vec<Vec<u8>> = read_image(&bytes)
+            .unwrap()
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect();
+        assert_eq!(read, records);
+    }
+}
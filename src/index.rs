@@ -0,0 +1,587 @@
+use crate::buffer_pool::BufferPool;
+use crate::generated_types::generated_types::{
+    cell_value, CellValue, IndexDefinition, IndexMethod,
+};
+use crate::hash_index::HashIndex;
+use crate::index_file::{
+    self, decode_key, encode_key, key_size, new_header, read_u16, read_u32, read_u64, seal_header,
+    write_u32, IndexFile, BTREE, FIELDS_OFFSET,
+};
+use crate::page::PAGE_SIZE;
+use std::cell::RefCell;
+use std::io;
+use std::ops::Bound;
+
+// Orderable form of a `CellValue`. NULL sorts before every INT, and every
+// INT sorts before every STRING, so mixed keys still have a total order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IndexValue {
+    Null,
+    Int(i32),
+    Str(String),
+}
+
+impl From<&CellValue> for IndexValue {
+    fn from(cell: &CellValue) -> Self {
+        match &cell.value {
+            Some(cell_value::Value::IntVal(v)) => IndexValue::Int(*v),
+            Some(cell_value::Value::StrVal(s)) => IndexValue::Str(s.clone()),
+            Some(cell_value::Value::NullVal(_)) | None => IndexValue::Null,
+        }
+    }
+}
+
+impl From<&IndexValue> for CellValue {
+    fn from(value: &IndexValue) -> Self {
+        CellValue {
+            value: Some(match value {
+                IndexValue::Null => cell_value::Value::NullVal(true),
+                IndexValue::Int(v) => cell_value::Value::IntVal(*v),
+                IndexValue::Str(s) => cell_value::Value::StrVal(s.clone()),
+            }),
+        }
+    }
+}
+
+pub type IndexKey = Vec<IndexValue>;
+
+// Returns true if any part of the key is NULL; such keys never clash in a UNIQUE index
+pub fn key_has_null(key: &IndexKey) -> bool {
+    key.contains(&IndexValue::Null)
+}
+
+// Ordered secondary index: a B+tree of (key, row id) entries kept in the
+// pages of an index file. A statement reads the nodes on its way down and
+// writes back only those it changed. Nodes split when they fill up and are
+// never merged; emptied leaves stay linked in place.
+//
+// Header fields:
+//   root page (4)
+//
+// Node page layout:
+//   kind (1) | entry count (2) | link (4) | entries
+//
+// A leaf's entries are key | row id (8), in order, and its link is the next
+// leaf to the right, 0 for the last one. An inner node's link is its leftmost
+// child and each entry is key | row id (8) | child (4): the child holds the
+// entries from that (key, row id) up to the one of the next entry.
+
+const ROOT_OFFSET: usize = FIELDS_OFFSET;
+const LEAF: u8 = 1;
+const INNER: u8 = 2;
+const NODE_HEADER_SIZE: usize = 7;
+// Deeper than any tree of 4 GiB of pages; a longer path means a damaged file
+const MAX_DEPTH: usize = 32;
+
+fn damaged() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "an index page is damaged")
+}
+
+struct Node {
+    leaf: bool,
+    link: u32,
+    // (key, row id, child); the child is 0 in a leaf
+    entries: Vec<(IndexKey, u64, u32)>,
+}
+
+impl Node {
+    fn entry_size(&self, key: &IndexKey) -> usize {
+        key_size(key) + if self.leaf { 8 } else { 12 }
+    }
+
+    fn size(&self) -> usize {
+        NODE_HEADER_SIZE
+            + self
+                .entries
+                .iter()
+                .map(|(key, ..)| self.entry_size(key))
+                .sum::<usize>()
+    }
+
+    fn decode(page: &[u8]) -> io::Result<Node> {
+        let leaf = match page[0] {
+            LEAF => true,
+            INNER => false,
+            _ => return Err(damaged()),
+        };
+        let count = read_u16(page, 1) as usize;
+        let link = read_u32(page, 3);
+        let tail = if leaf { 8 } else { 12 };
+        let mut pos = NODE_HEADER_SIZE;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let key = decode_key(page, &mut pos)?;
+            if pos + tail > page.len() {
+                return Err(damaged());
+            }
+            let row_id = read_u64(page, pos);
+            let child = if leaf { 0 } else { read_u32(page, pos + 8) };
+            pos += tail;
+            entries.push((key, row_id, child));
+        }
+        Ok(Node {
+            leaf,
+            link,
+            entries,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        page.push(if self.leaf { LEAF } else { INNER });
+        page.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        page.extend_from_slice(&self.link.to_le_bytes());
+        for (key, row_id, child) in &self.entries {
+            encode_key(key, &mut page);
+            page.extend_from_slice(&row_id.to_le_bytes());
+            if !self.leaf {
+                page.extend_from_slice(&child.to_le_bytes());
+            }
+        }
+        page.resize(PAGE_SIZE, 0);
+        page
+    }
+
+    // The child of an inner node whose entries (key, row id) belongs among
+    fn child(&self, key: &IndexKey, row_id: u64) -> u32 {
+        match self
+            .entries
+            .partition_point(|(k, r, _)| (k, *r) <= (key, row_id))
+        {
+            0 => self.link,
+            position => self.entries[position - 1].2,
+        }
+    }
+
+    // Position of the first entry not before (key, row id)
+    fn position(&self, key: &IndexKey, row_id: u64) -> usize {
+        self.entries
+            .partition_point(|(k, r, _)| (k, *r) < (key, row_id))
+    }
+}
+
+pub struct OrderedIndex<'a> {
+    file: IndexFile<'a>,
+    root: u32,
+}
+
+impl<'a> OrderedIndex<'a> {
+    // Open the index file at `path` through the pool; a missing file is an empty index
+    pub fn open(pool: &'a RefCell<BufferPool>, path: &str) -> io::Result<Self> {
+        let (file, header) = IndexFile::open(pool, path, BTREE)?;
+        Self::with_file(file, header)
+    }
+
+    fn with_file(file: IndexFile<'a>, header: Option<Vec<u8>>) -> io::Result<Self> {
+        let mut index = OrderedIndex { file, root: 0 };
+        match header {
+            Some(header) => {
+                index.root = read_u32(&header, ROOT_OFFSET);
+                if index.root == 0 || index.root >= index.file.page_count {
+                    return Err(damaged());
+                }
+            }
+            None => {
+                // The header, then an empty leaf as the root
+                index.file.append();
+                index.root = index.file.append();
+                index.write_node(
+                    index.root,
+                    &Node {
+                        leaf: true,
+                        link: 0,
+                        entries: Vec::new(),
+                    },
+                );
+                index.write_header();
+            }
+        }
+        Ok(index)
+    }
+
+    fn write_header(&mut self) {
+        let mut header = new_header(BTREE, self.file.page_count);
+        write_u32(&mut header, ROOT_OFFSET, self.root);
+        seal_header(&mut header);
+        self.file.write(0, header);
+    }
+
+    fn read_node(&mut self, page_id: u32) -> io::Result<Node> {
+        if page_id == 0 || page_id >= self.file.page_count {
+            return Err(damaged());
+        }
+        Node::decode(&self.file.read(page_id)?)
+    }
+
+    fn write_node(&mut self, page_id: u32, node: &Node) {
+        self.file.write(page_id, node.encode());
+    }
+
+    // The nodes from the root down to the leaf (key, row id) belongs in
+    fn descend(&mut self, key: &IndexKey, row_id: u64) -> io::Result<Vec<(u32, Node)>> {
+        let mut path = Vec::new();
+        let mut page_id = self.root;
+        loop {
+            let node = self.read_node(page_id)?;
+            if node.leaf {
+                path.push((page_id, node));
+                return Ok(path);
+            }
+            let child = node.child(key, row_id);
+            path.push((page_id, node));
+            page_id = child;
+            if path.len() > MAX_DEPTH {
+                return Err(damaged());
+            }
+        }
+    }
+
+    // Write a node back, splitting it in two if it no longer fits a page.
+    // Returns the entry for the new right half its parent has to take.
+    fn store(&mut self, page_id: u32, mut node: Node) -> Option<(IndexKey, u64, u32)> {
+        if node.size() <= PAGE_SIZE {
+            self.write_node(page_id, &node);
+            return None;
+        }
+        // Split at the middle byte rather than the middle entry, so both
+        // halves fit whatever the sizes of their keys
+        let half = (node.size() - NODE_HEADER_SIZE) / 2;
+        let mut used = 0;
+        let mut middle = 0;
+        while used < half {
+            used += node.entry_size(&node.entries[middle].0);
+            middle += 1;
+        }
+        let middle = middle.min(node.entries.len() - 1);
+        let right_id = self.file.append();
+        let mut entries = node.entries.split_off(middle);
+        let (right, separator) = if node.leaf {
+            let separator = (entries[0].0.clone(), entries[0].1, right_id);
+            let right = Node {
+                leaf: true,
+                link: node.link,
+                entries,
+            };
+            node.link = right_id;
+            (right, separator)
+        } else {
+            // The middle entry moves up and its child becomes the leftmost of the right half
+            let (key, row_id, child) = entries.remove(0);
+            let right = Node {
+                leaf: false,
+                link: child,
+                entries,
+            };
+            (right, (key, row_id, right_id))
+        };
+        self.write_node(page_id, &node);
+        self.write_node(right_id, &right);
+        Some(separator)
+    }
+
+    pub fn insert(&mut self, key: IndexKey, row_id: u64) -> io::Result<()> {
+        index_file::check_key(&key)?;
+        let page_count = self.file.page_count;
+        let mut path = self.descend(&key, row_id)?;
+        let (mut page_id, mut node) = path.pop().expect("a path ends at a leaf");
+        let position = node.position(&key, row_id);
+        node.entries.insert(position, (key, row_id, 0));
+        while let Some(separator) = self.store(page_id, node) {
+            match path.pop() {
+                Some((parent_id, mut parent)) => {
+                    let position = parent.position(&separator.0, separator.1);
+                    parent.entries.insert(position, separator);
+                    (page_id, node) = (parent_id, parent);
+                }
+                None => {
+                    // The root split, so the tree grows a level
+                    let root = self.file.append();
+                    let old_root = self.root;
+                    self.write_node(
+                        root,
+                        &Node {
+                            leaf: false,
+                            link: old_root,
+                            entries: vec![separator],
+                        },
+                    );
+                    self.root = root;
+                    break;
+                }
+            }
+        }
+        if self.file.page_count != page_count {
+            self.write_header();
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &IndexKey, row_id: u64) -> io::Result<()> {
+        let (page_id, mut node) = self
+            .descend(key, row_id)?
+            .pop()
+            .expect("a path ends at a leaf");
+        let position = node.position(key, row_id);
+        if node
+            .entries
+            .get(position)
+            .is_some_and(|(k, r, _)| k == key && *r == row_id)
+        {
+            node.entries.remove(position);
+            self.write_node(page_id, &node);
+        }
+        Ok(())
+    }
+
+    // Visit the entries from (key, row id) on, in order, until `visit` returns false
+    fn scan_from(
+        &mut self,
+        key: &IndexKey,
+        row_id: u64,
+        mut visit: impl FnMut(&IndexKey, u64) -> bool,
+    ) -> io::Result<()> {
+        let (_, mut node) = self
+            .descend(key, row_id)?
+            .pop()
+            .expect("a path ends at a leaf");
+        let mut start = node.position(key, row_id);
+        loop {
+            for (key, row_id, _) in &node.entries[start..] {
+                if !visit(key, *row_id) {
+                    return Ok(());
+                }
+            }
+            if node.link == 0 {
+                return Ok(());
+            }
+            node = self.read_node(node.link)?;
+            if !node.leaf {
+                return Err(damaged());
+            }
+            start = 0;
+        }
+    }
+
+    pub fn get(&mut self, key: &IndexKey) -> io::Result<Vec<u64>> {
+        let mut row_ids = Vec::new();
+        self.scan_from(key, 0, |entry_key, row_id| {
+            let matches = entry_key == key;
+            if matches {
+                row_ids.push(row_id);
+            }
+            matches
+        })?;
+        Ok(row_ids)
+    }
+
+    // Collect the row ids whose first key column lies between the given bounds
+    pub fn range(
+        &mut self,
+        lower: Bound<&IndexValue>,
+        upper: Bound<&IndexValue>,
+    ) -> io::Result<Vec<u64>> {
+        let start = match lower {
+            Bound::Included(value) | Bound::Excluded(value) => vec![value.clone()],
+            Bound::Unbounded => Vec::new(),
+        };
+        let mut row_ids = Vec::new();
+        self.scan_from(&start, 0, |key, row_id| {
+            let first = &key[0];
+            let in_upper = match upper {
+                Bound::Included(value) => first <= value,
+                Bound::Excluded(value) => first < value,
+                Bound::Unbounded => true,
+            };
+            if in_upper && lower != Bound::Excluded(first) {
+                row_ids.push(row_id);
+            }
+            in_upper
+        })?;
+        Ok(row_ids)
+    }
+
+    // Write the pages changed since the index was opened or last flushed
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    // Every (key, row id) pair in order
+    pub fn entries(&mut self) -> io::Result<Vec<(IndexKey, u64)>> {
+        let mut entries = Vec::new();
+        self.scan_from(&Vec::new(), 0, |key, row_id| {
+            entries.push((key.clone(), row_id));
+            true
+        })?;
+        Ok(entries)
+    }
+}
+
+impl OrderedIndex<'static> {
+    // An empty index held in memory
+    pub fn new() -> Self {
+        Self::with_file(IndexFile::new(), None).expect("a new index needs no reading")
+    }
+
+    // An index file read whole into `bytes`
+    pub fn from_image(bytes: &[u8]) -> io::Result<Self> {
+        let (file, header) = IndexFile::from_image(bytes, BTREE)?;
+        Self::with_file(file, Some(header))
+    }
+
+    // The whole file of an index held in memory
+    pub fn image(self) -> io::Result<Vec<u8>> {
+        self.file.image()
+    }
+}
+
+// A secondary index of either access method, as described by its `IndexDefinition`.
+// Changes are kept until `flush` writes them.
+pub enum TableIndex<'a> {
+    Ordered(OrderedIndex<'a>),
+    Hash(HashIndex<'a>),
+}
+
+impl<'a> TableIndex<'a> {
+    // Open the index file at `path` through the pool; a missing file is an empty index
+    pub fn open(
+        pool: &'a RefCell<BufferPool>,
+        index_def: &IndexDefinition,
+        path: &str,
+    ) -> io::Result<Self> {
+        Ok(match index_def.method() {
+            IndexMethod::Btree => TableIndex::Ordered(OrderedIndex::open(pool, path)?),
+            IndexMethod::Hash => TableIndex::Hash(HashIndex::open(pool, path)?),
+        })
+    }
+
+    // Ids of every row version stored under `key`
+    pub fn get(&mut self, key: &IndexKey) -> io::Result<Vec<u64>> {
+        match self {
+            TableIndex::Ordered(index) => index.get(key),
+            TableIndex::Hash(index) => index.get(key),
+        }
+    }
+
+    pub fn insert(&mut self, key: IndexKey, row_id: u64) -> io::Result<()> {
+        match self {
+            TableIndex::Ordered(index) => index.insert(key, row_id),
+            TableIndex::Hash(index) => index.insert(key, row_id),
+        }
+    }
+
+    pub fn remove(&mut self, key: &IndexKey, row_id: u64) -> io::Result<()> {
+        match self {
+            TableIndex::Ordered(index) => index.remove(key, row_id),
+            TableIndex::Hash(index) => index.remove(key, row_id),
+        }
+    }
+
+    // Write the pages changed since the index was opened or last flushed
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TableIndex::Ordered(index) => index.flush(),
+            TableIndex::Hash(index) => index.flush(),
+        }
+    }
+
+    // Every (key, row id) pair in sorted order, so two indexes can be compared
+    pub fn entries(&mut self) -> io::Result<Vec<(IndexKey, u64)>> {
+        let mut entries = match self {
+            TableIndex::Ordered(index) => index.entries()?,
+            TableIndex::Hash(index) => index.entries()?,
+        };
+        entries.sort();
+        Ok(entries)
+    }
+}
+
+impl TableIndex<'static> {
+    // An empty index held in memory, for building a whole index file at once
+    pub fn new(index_def: &IndexDefinition) -> Self {
+        match index_def.method() {
+            IndexMethod::Btree => TableIndex::Ordered(OrderedIndex::new()),
+            IndexMethod::Hash => TableIndex::Hash(HashIndex::new()),
+        }
+    }
+
+    // An index file read whole into `bytes`
+    pub fn from_image(index_def: &IndexDefinition, bytes: &[u8]) -> io::Result<Self> {
+        Ok(match index_def.method() {
+            IndexMethod::Btree => TableIndex::Ordered(OrderedIndex::from_image(bytes)?),
+            IndexMethod::Hash => TableIndex::Hash(HashIndex::from_image(bytes)?),
+        })
+    }
+
+    // The whole file of an index held in memory
+    pub fn image(self) -> io::Result<Vec<u8>> {
+        match self {
+            TableIndex::Ordered(index) => index.image(),
+            TableIndex::Hash(index) => index.image(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use crate::wal::Wal;
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    #[test]
+    fn btree_splits_and_reads_back_from_its_pages() {
+        let dir = temp_dir("btree");
+        let path = dir.join("t.t_id.idx").to_string_lossy().into_owned();
+        let log_path = dir.join("dbfile.wal").to_string_lossy().into_owned();
+        // Long keys, so a few thousand entries take several levels of nodes
+        let key = |n: u64| {
+            vec![
+                IndexValue::Int((n % 500) as i32),
+                IndexValue::Str(format!("{:0>200}", n)),
+            ]
+        };
+
+        let pool = RefCell::new(BufferPool::new(4, Wal::open(&log_path).unwrap()));
+        pool.borrow_mut().begin(1).unwrap();
+        let mut index = OrderedIndex::open(&pool, &path).unwrap();
+        for n in 0..3000 {
+            let n = n * 7919 % 3000;
+            index.insert(key(n), n).unwrap();
+        }
+        index.flush().unwrap();
+        assert!(index.file.page_count > 100);
+        assert!(!index.read_node(index.root).unwrap().leaf);
+
+        // Another entry only writes the pages on its way down
+        let logged = pool.borrow().savepoint();
+        index.insert(key(3000), 3000).unwrap();
+        index.remove(&key(10), 10).unwrap();
+        index.flush().unwrap();
+        assert!(pool.borrow().savepoint() - logged <= 4);
+        pool.borrow_mut().flush_all().unwrap();
+
+        // A fresh pool reads the same entries back from the file
+        let pool = RefCell::new(BufferPool::new(4, Wal::open(&log_path).unwrap()));
+        let mut index = OrderedIndex::open(&pool, &path).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            index.file.page_count as u64 * PAGE_SIZE as u64
+        );
+        assert_eq!(index.get(&key(10)).unwrap(), Vec::<u64>::new());
+        assert_eq!(index.get(&key(2999)).unwrap(), vec![2999]);
+        let found: BTreeSet<u64> = index
+            .range(
+                Bound::Excluded(&IndexValue::Int(4)),
+                Bound::Included(&IndexValue::Int(6)),
+            )
+            .unwrap()
+            .into_iter()
+            .collect();
+        let expected: BTreeSet<u64> = (0..=3000).filter(|n| [5, 6].contains(&(n % 500))).collect();
+        assert_eq!(found, expected);
+        let entries = index.entries().unwrap();
+        assert_eq!(entries.len(), 3000);
+        assert!(entries.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::buffer_pool::BufferPool;
+use crate::format::{self, FORMAT_VERSION, INDEX_MAGIC};
+use crate::index::{IndexKey, IndexValue};
+use crate::page::PAGE_SIZE;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+
+// Index file made of fixed-size pages, read and changed a page at a time.
+// Inside the database the pages go through the shared buffer pool; indexes
+// built whole, or read from a backup, keep theirs in memory.
+//
+// Header page layout, whatever the access method:
+//   magic (4) | format version (4) | crc32 (4) | page_count (4) | method (1) | method fields
+//
+// The checksum covers the whole header page with the checksum field zeroed.
+// The first three fields sit where the protobuf index files of version 1 had
+// theirs, so `format::version` reads the version of either kind of file.
+//
+// Keys are stored as a count byte followed by each value: a tag byte (0 NULL,
+// 1 INT, 2 STRING), then the INT in 4 bytes or the STRING as a 2-byte length
+// and its UTF-8 bytes.
+
+const VERSION_OFFSET: usize = 4;
+const CHECKSUM_OFFSET: usize = 8;
+const PAGE_COUNT_OFFSET: usize = 12;
+const METHOD_OFFSET: usize = 16;
+// Where the header fields of each access method start
+pub const FIELDS_OFFSET: usize = 17;
+
+// Largest encoded key an index takes, so every page holds several entries
+pub const MAX_KEY_SIZE: usize = PAGE_SIZE / 4;
+
+// Access method byte of the header page
+pub const BTREE: u8 = 1;
+pub const HASH: u8 = 2;
+
+enum Pages<'a> {
+    Pool {
+        pool: &'a RefCell<BufferPool>,
+        path: String,
+        // The file does not exist yet and is created on the first flush
+        missing: bool,
+    },
+    Memory(Vec<Vec<u8>>),
+}
+
+pub struct IndexFile<'a> {
+    pages: Pages<'a>,
+    // Pages changed by the running statement, each written once on `flush`
+    changed: BTreeMap<u32, Vec<u8>>,
+    pub page_count: u32,
+}
+
+impl<'a> IndexFile<'a> {
+    // Open the index file at `path` through the pool. Returns the header page,
+    // or `None` if the file does not exist yet and the index starts empty.
+    pub fn open(
+        pool: &'a RefCell<BufferPool>,
+        path: &str,
+        method: u8,
+    ) -> io::Result<(Self, Option<Vec<u8>>)> {
+        let exists = pool.borrow().is_cached(path, 0)
+            || fs::metadata(path).is_ok_and(|metadata| metadata.len() > 0);
+        let mut file = IndexFile {
+            pages: Pages::Pool {
+                pool,
+                path: path.to_string(),
+                missing: !exists,
+            },
+            changed: BTreeMap::new(),
+            page_count: 0,
+        };
+        if !exists {
+            return Ok((file, None));
+        }
+        if !pool.borrow().is_cached(path, 0) {
+            // A protobuf index file of an older version may be shorter than a page
+            let mut start = Vec::new();
+            File::open(path)?
+                .take(FIELDS_OFFSET as u64)
+                .read_to_end(&mut start)?;
+            format::check_version(format::version(INDEX_MAGIC, &start))?;
+        }
+        let header = file.read(0)?;
+        file.page_count = check_header(&header, method)?;
+        Ok((file, Some(header)))
+    }
+
+    // An empty index file held in memory
+    pub fn new() -> IndexFile<'static> {
+        IndexFile {
+            pages: Pages::Memory(Vec::new()),
+            changed: BTreeMap::new(),
+            page_count: 0,
+        }
+    }
+
+    // An index file read whole into `bytes`, held in memory, with its header page
+    pub fn from_image(bytes: &[u8], method: u8) -> io::Result<(IndexFile<'static>, Vec<u8>)> {
+        let invalid = |error: &str| io::Error::new(io::ErrorKind::InvalidData, error.to_string());
+        format::check_version(format::version(INDEX_MAGIC, bytes))?;
+        if bytes.len() < PAGE_SIZE || !bytes.len().is_multiple_of(PAGE_SIZE) {
+            return Err(invalid("it is not made of whole pages"));
+        }
+        let pages: Vec<Vec<u8>> = bytes.chunks(PAGE_SIZE).map(<[u8]>::to_vec).collect();
+        let header = pages[0].clone();
+        let page_count = check_header(&header, method)?;
+        if page_count as usize != pages.len() {
+            return Err(invalid("its header does not match its length"));
+        }
+        let file = IndexFile {
+            pages: Pages::Memory(pages),
+            changed: BTreeMap::new(),
+            page_count,
+        };
+        Ok((file, header))
+    }
+
+    // The whole file of an index held in memory
+    pub fn image(mut self) -> io::Result<Vec<u8>> {
+        self.flush()?;
+        match self.pages {
+            Pages::Memory(pages) => Ok(pages.concat()),
+            Pages::Pool { .. } => Err(io::Error::other("the index is not held in memory")),
+        }
+    }
+
+    pub fn read(&mut self, page_id: u32) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.changed.get(&page_id) {
+            return Ok(data.clone());
+        }
+        match &self.pages {
+            Pages::Pool { pool, path, .. } => pool.borrow_mut().read_page(path, page_id),
+            Pages::Memory(pages) => pages.get(page_id as usize).cloned().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "a page link points past its end",
+                )
+            }),
+        }
+    }
+
+    pub fn write(&mut self, page_id: u32, data: Vec<u8>) {
+        debug_assert_eq!(data.len(), PAGE_SIZE);
+        self.changed.insert(page_id, data);
+    }
+
+    // Number of a new page at the end of the file
+    pub fn append(&mut self) -> u32 {
+        self.page_count += 1;
+        self.page_count - 1
+    }
+
+    // Write the pages the statement changed. A new file is created first, so
+    // rolling back removes it again.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let changed = std::mem::take(&mut self.changed);
+        match &mut self.pages {
+            Pages::Pool {
+                pool,
+                path,
+                missing,
+            } => {
+                let mut pool = pool.borrow_mut();
+                if *missing && !changed.is_empty() {
+                    pool.write_file(path, Some(Vec::new()))?;
+                    *missing = false;
+                }
+                for (page_id, data) in changed {
+                    pool.write_page(path, page_id, &data)?;
+                }
+            }
+            Pages::Memory(pages) => {
+                for (page_id, data) in changed {
+                    let page_id = page_id as usize;
+                    if pages.len() <= page_id {
+                        pages.resize(page_id + 1, vec![0; PAGE_SIZE]);
+                    }
+                    pages[page_id] = data;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Header page of an index of `method` with room for its fields, which are
+// filled in by the caller before `seal_header`
+pub fn new_header(method: u8, page_count: u32) -> Vec<u8> {
+    let mut header = vec![0; PAGE_SIZE];
+    header[0..4].copy_from_slice(INDEX_MAGIC);
+    write_u32(&mut header, VERSION_OFFSET, FORMAT_VERSION);
+    write_u32(&mut header, PAGE_COUNT_OFFSET, page_count);
+    header[METHOD_OFFSET] = method;
+    header
+}
+
+pub fn seal_header(header: &mut [u8]) {
+    let checksum = header_checksum(header);
+    write_u32(header, CHECKSUM_OFFSET, checksum);
+}
+
+// Check a header page and return the page count it records
+fn check_header(header: &[u8], method: u8) -> io::Result<u32> {
+    let invalid = |error: &str| io::Error::new(io::ErrorKind::InvalidData, error.to_string());
+    if &header[0..4] != INDEX_MAGIC {
+        return Err(invalid("it is not an index file"));
+    }
+    format::check_version(read_u32(header, VERSION_OFFSET))?;
+    if read_u32(header, CHECKSUM_OFFSET) != header_checksum(header) {
+        return Err(invalid("its header checksum does not match"));
+    }
+    if header[METHOD_OFFSET] != method {
+        return Err(invalid("it holds an index of another access method"));
+    }
+    Ok(read_u32(header, PAGE_COUNT_OFFSET))
+}
+
+fn header_checksum(header: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[..CHECKSUM_OFFSET]);
+    hasher.update(&[0; 4]);
+    hasher.update(&header[CHECKSUM_OFFSET + 4..]);
+    hasher.finalize()
+}
+
+pub fn read_u16(page: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([page[offset], page[offset + 1]])
+}
+
+pub fn read_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        page[offset],
+        page[offset + 1],
+        page[offset + 2],
+        page[offset + 3],
+    ])
+}
+
+pub fn read_u64(page: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&page[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub fn write_u32(page: &mut [u8], offset: usize, value: u32) {
+    page[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u64(page: &mut [u8], offset: usize, value: u64) {
+    page[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+// Encoded size of a key
+pub fn key_size(key: &IndexKey) -> usize {
+    1 + key
+        .iter()
+        .map(|value| match value {
+            IndexValue::Null => 1,
+            IndexValue::Int(_) => 5,
+            IndexValue::Str(s) => 3 + s.len(),
+        })
+        .sum::<usize>()
+}
+
+pub fn encode_key(key: &IndexKey, out: &mut Vec<u8>) {
+    out.push(key.len() as u8);
+    for value in key {
+        match value {
+            IndexValue::Null => out.push(0),
+            IndexValue::Int(v) => {
+                out.push(1);
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            IndexValue::Str(s) => {
+                out.push(2);
+                out.extend_from_slice(&(s.len() as u16).to_le_bytes());
+                out.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+}
+
+// Key at `*pos` in `page`, moving `*pos` past it
+pub fn decode_key(page: &[u8], pos: &mut usize) -> io::Result<IndexKey> {
+    let damaged = || io::Error::new(io::ErrorKind::InvalidData, "an index page is damaged");
+    let mut take = |len: usize| -> io::Result<&[u8]> {
+        let bytes = page.get(*pos..*pos + len).ok_or_else(damaged)?;
+        *pos += len;
+        Ok(bytes)
+    };
+    let count = take(1)?[0];
+    let mut key = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        key.push(match take(1)?[0] {
+            0 => IndexValue::Null,
+            1 => IndexValue::Int(i32::from_le_bytes(take(4)?.try_into().expect("four bytes"))),
+            2 => {
+                let len = u16::from_le_bytes(take(2)?.try_into().expect("two bytes"));
+                let bytes = take(len as usize)?.to_vec();
+                IndexValue::Str(String::from_utf8(bytes).map_err(|_| damaged())?)
+            }
+            _ => return Err(damaged()),
+        });
+    }
+    Ok(key)
+}
+
+// Fails for a key too large to be stored
+pub fn check_key(key: &IndexKey) -> io::Result<()> {
+    let size = key_size(key);
+    if size > MAX_KEY_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "index key of {} bytes exceeds the maximum of {} bytes",
+                size, MAX_KEY_SIZE
+            ),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, digit1, multispace0, one_of},
+    combinator::{all_consuming, map, opt, recognize},
+    multi::separated_list0,
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult,
+};
+
+// JSON as IMPORT JSON reads it and EXPORT writes it: one object per row,
+// either one per line (JSON Lines) or as the elements of an array.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    // Kept as written so integers and other numbers can be told apart
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    // Members in the order written, duplicates included
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    // What kind of value this is, for error messages, e.g. "an array"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(_) => "a boolean",
+            Json::Number(_) => "a number",
+            Json::String(_) => "a string",
+            Json::Array(_) => "an array",
+            Json::Object(_) => "an object",
+        }
+    }
+}
+
+// A value of a file with the line it starts on
+#[derive(Debug, PartialEq)]
+pub struct Document {
+    pub line: usize,
+    pub value: Json,
+}
+
+// The values of `input`: the elements of a top-level array, or else one
+// value per non-blank line. Fails with the line of the first value that is
+// not valid JSON.
+pub fn read(input: &str) -> Result<Vec<Document>, usize> {
+    let line_at = |rest: &str| input[..input.len() - rest.len()].matches('\n').count() + 1;
+    let trimmed = input.trim_start();
+    if !trimmed.starts_with('[') {
+        return input
+            .lines()
+            .enumerate()
+            .filter(|(_, text)| !text.trim().is_empty())
+            .map(
+                |(index, text)| match all_consuming(terminated(value, multispace0))(text) {
+                    Ok((_, value)) => Ok(Document {
+                        line: index + 1,
+                        value,
+                    }),
+                    Err(_) => Err(index + 1),
+                },
+            )
+            .collect();
+    }
+    let mut documents = Vec::new();
+    let mut rest = &trimmed[1..];
+    loop {
+        let element = rest.trim_start();
+        if documents.is_empty() {
+            if let Some(after) = element.strip_prefix(']') {
+                rest = after;
+                break;
+            }
+        }
+        let Ok((after, parsed)) = value(element) else {
+            return Err(line_at(element));
+        };
+        documents.push(Document {
+            line: line_at(element),
+            value: parsed,
+        });
+        let after = after.trim_start();
+        if let Some(after) = after.strip_prefix(',') {
+            rest = after;
+        } else if let Some(after) = after.strip_prefix(']') {
+            rest = after;
+            break;
+        } else {
+            return Err(line_at(after));
+        }
+    }
+    match rest.trim() {
+        "" => Ok(documents),
+        _ => Err(line_at(rest.trim_start())),
+    }
+}
+
+fn value(input: &str) -> IResult<&str, Json> {
+    preceded(
+        multispace0,
+        alt((
+            map(tag("null"), |_| Json::Null),
+            map(tag("true"), |_| Json::Bool(true)),
+            map(tag("false"), |_| Json::Bool(false)),
+            map(number, |text: &str| Json::Number(text.to_string())),
+            map(string, Json::String),
+            map(array, Json::Array),
+            map(object, Json::Object),
+        )),
+    )(input)
+}
+
+fn number(input: &str) -> IResult<&str, &str> {
+    recognize(tuple((
+        opt(char('-')),
+        digit1,
+        opt(pair(char('.'), digit1)),
+        opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+    )))(input)
+}
+
+fn array(input: &str) -> IResult<&str, Vec<Json>> {
+    delimited(
+        char('['),
+        separated_list0(preceded(multispace0, char(',')), value),
+        preceded(multispace0, char(']')),
+    )(input)
+}
+
+fn object(input: &str) -> IResult<&str, Vec<(String, Json)>> {
+    delimited(
+        char('{'),
+        separated_list0(
+            preceded(multispace0, char(',')),
+            separated_pair(
+                preceded(multispace0, string),
+                preceded(multispace0, char(':')),
+                value,
+            ),
+        ),
+        preceded(multispace0, char('}')),
+    )(input)
+}
+
+// A quoted string with its escapes decoded
+fn string(input: &str) -> IResult<&str, String> {
+    let fail = |at| {
+        Err(nom::Err::Error(nom::error::Error::new(
+            at,
+            nom::error::ErrorKind::Char,
+        )))
+    };
+    let Some(body) = input.strip_prefix('"') else {
+        return fail(input);
+    };
+    let mut text = String::new();
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((&body[i + 1..], text)),
+            '\\' => {
+                let decoded = match chars.next().map(|(_, c)| c) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let code = match utf16_unit(&mut chars) {
+                            // A surrogate pair spells one character outside the BMP
+                            Some(high @ 0xD800..=0xDBFF) => {
+                                let low = match (chars.next(), chars.next()) {
+                                    (Some((_, '\\')), Some((_, 'u'))) => utf16_unit(&mut chars),
+                                    _ => None,
+                                };
+                                match low {
+                                    Some(low @ 0xDC00..=0xDFFF) => {
+                                        Some(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+                                    }
+                                    _ => None,
+                                }
+                            }
+                            code => code,
+                        };
+                        match code.and_then(char::from_u32) {
+                            Some(c) => c,
+                            None => return fail(&body[i..]),
+                        }
+                    }
+                    _ => return fail(&body[i..]),
+                };
+                text.push(decoded);
+            }
+            c if (c as u32) < 0x20 => return fail(&body[i..]),
+            c => text.push(c),
+        }
+    }
+    fail(input)
+}
+
+// The four hex digits of a `\u` escape
+fn utf16_unit(chars: &mut std::str::CharIndices) -> Option<u32> {
+    let hex: String = chars.take(4).map(|(_, c)| c).collect();
+    u32::from_str_radix(&hex, 16)
+        .ok()
+        .filter(|_| hex.len() == 4)
+}
+
+// `text` as a JSON string
+pub fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(members: &[(&str, Json)]) -> Json {
+        Json::Object(
+            members
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn lines_and_arrays_hold_the_same_values() {
+        let expected = [
+            object(&[
+                ("id", Json::Number("-1".to_string())),
+                ("name", Json::String("a \"b\"\n\u{e9}\u{1f600}".to_string())),
+            ]),
+            object(&[
+                ("id", Json::Number("2.5e3".to_string())),
+                ("tags", Json::Array(vec![Json::Bool(true), Json::Null])),
+            ]),
+        ];
+        let name = quote("a \"b\"\n\u{e9}\u{1f600}");
+        let lines = format!(
+            "{{\"id\": -1, \"name\": {}}}\n\n {{\"id\":2.5e3,\"tags\":[true, null]}} \n",
+            name.replace('\u{1f600}', "\\ud83d\\ude00")
+        );
+        let array = format!(
+            "[\n  {{\"id\": -1, \"name\": {}}},\n  {{\"id\":2.5e3,\"tags\":[true, null]}}\n]\n",
+            name
+        );
+        for (input, lines) in [(lines, [1, 3]), (array, [2, 3])] {
+            let documents = read(&input).unwrap();
+            let values: Vec<Json> = documents.iter().map(|d| d.value.clone()).collect();
+            assert_eq!(values, expected);
+            assert_eq!(documents.iter().map(|d| d.line).collect::<Vec<_>>(), lines);
+        }
+
+        assert_eq!(read("[]").unwrap(), []);
+        assert_eq!(read("{\"a\": 1}\n{\"a\": }\n"), Err(2));
+        assert_eq!(read("[{\"a\": 1},\n{\"a\": 2}\n{\"a\": 3}]"), Err(3));
+        assert_eq!(read("{\"a\": \"\\ud83d\"}"), Err(1));
+    }
+}
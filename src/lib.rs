@@ -0,0 +1,57 @@
+// An embeddable relational database: open a data directory with
+// `Database::open`, then run SQL through a `Connection`.
+//
+//     let db = Database::open("data")?;
+//     let conn = db.connect()?;
+//     conn.execute("CREATE TABLE t (id INT, name STRING(20)); INSERT INTO t VALUES (1, 'one');")?;
+//     for row in conn.query("SELECT * FROM t")?.rows() {
+//         let name: Option<String> = row.get("name")?;
+//     }
+//
+// Values from elsewhere are best bound to placeholders than spliced into SQL:
+//
+//     let mut insert = conn.prepare("INSERT INTO t VALUES (?, ?)")?;
+//     insert.bind(1, 2)?.bind(2, name)?.execute()?;
+
+mod backup;
+mod buffer_pool;
+mod check;
+mod connection;
+mod csv;
+mod data_dir;
+mod database_manager;
+mod dump;
+mod file_lock;
+mod file_manager;
+mod format;
+mod generated_types;
+mod hash_index;
+mod heap_file;
+mod index;
+mod index_file;
+mod json;
+mod lock_manager;
+mod mvcc;
+mod nom_parser;
+mod page;
+mod result_set;
+mod statements;
+#[cfg(test)]
+mod test_util;
+mod wal;
+
+pub use buffer_pool::{BufferPoolStats, DEFAULT_POOL_SIZE};
+pub use check::{CheckReport, Problem};
+pub use connection::{Connection, Database, Outcome, Statement};
+pub use database_manager::DatabaseError;
+pub use file_lock::DEFAULT_LOCK_TIMEOUT;
+pub use format::FORMAT_VERSION;
+pub use generated_types::generated_types::{
+    cell_value, CellValue, ColumnDefinition, ColumnType, IndexDefinition, IndexMethod,
+    TableDefinition,
+};
+pub use result_set::{cell_text, ColumnIndex, FromCell, ResultSet, Row, ToCell};
+pub use wal::Recovery;
+// For the command-line shell and scripts; not part of the library's API
+#[doc(hidden)]
+pub use statements::{split as split_statements, Split, StatementText};
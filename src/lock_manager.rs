@@ -0,0 +1,323 @@
+use crate::file_lock::{LockMode, POLL_INTERVAL};
+use crate::generated_types::generated_types::{LockTable, LockWait, RowLock};
+use prost::Message;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Row locks shared by every process using the database.
+//
+// The lock table is a small file that is read and rewritten under an exclusive
+// lock on itself. Locks belong to sessions: a session registers on its first
+// lock request and holds an exclusive lock on `<lock table>.<id>` for as long as
+// it lives, so locks left behind by a process that died are recognised and
+// dropped by whoever looks next.
+//
+// A session that has to wait records which sessions it waits for. Holding the
+// write-ahead log counts as a lock as well, because a transaction that has
+// locked rows may still need the log to change them. Whoever adds the wait that
+// closes a cycle is the victim of the deadlock.
+//
+// A writing transaction locks its own id for as long as it runs, so others can
+// tell a transaction that is still running from one whose process died.
+
+// Table name under which transactions lock their ids
+const TRANSACTIONS: &str = "";
+
+// Outcome of waiting for a lock
+#[derive(Debug, PartialEq)]
+pub enum Wait {
+    Granted,
+    Deadlock,
+    TimedOut,
+}
+
+pub struct LockManager {
+    path: String,
+    file: File,
+    // This session's id and liveness file, once registered
+    session: Option<(u64, File)>,
+}
+
+impl LockManager {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(LockManager {
+            path: path.to_string(),
+            file,
+            session: None,
+        })
+    }
+
+    fn liveness_path(&self, owner: u64) -> String {
+        format!("{}.{}", self.path, owner)
+    }
+
+    // The owner's liveness file can only be locked once its process is gone
+    fn is_dead(&self, owner: u64) -> bool {
+        match File::open(self.liveness_path(owner)) {
+            Ok(file) => file.try_lock().is_ok(),
+            Err(_) => true,
+        }
+    }
+
+    // Run `change` on the lock table while holding it, then write it back.
+    // Sessions that died are dropped first.
+    fn with_table<T>(
+        &mut self,
+        change: impl FnOnce(&mut Self, &mut LockTable) -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.file.lock()?;
+        let result = self.read_table().and_then(|mut table| {
+            self.remove_dead(&mut table);
+            let value = change(self, &mut table)?;
+            self.write_table(&table)?;
+            Ok(value)
+        });
+        self.file.unlock()?;
+        result
+    }
+
+    fn read_table(&mut self) -> io::Result<LockTable> {
+        let mut buffer = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buffer)?;
+        // Nothing in the table outlives its processes, so a table torn by a
+        // crash only ever held locks of sessions that are gone
+        Ok(LockTable::decode(&*buffer).unwrap_or_default())
+    }
+
+    fn write_table(&mut self, table: &LockTable) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&table.encode_to_vec())
+    }
+
+    fn remove_dead(&self, table: &mut LockTable) {
+        let own = self.session.as_ref().map(|(id, _)| *id);
+        let dead: HashSet<u64> = table
+            .owners
+            .iter()
+            .copied()
+            .filter(|owner| Some(*owner) != own && self.is_dead(*owner))
+            .collect();
+        if dead.is_empty() {
+            return;
+        }
+        for owner in &dead {
+            let _ = fs::remove_file(self.liveness_path(*owner));
+        }
+        remove_owners(table, &dead);
+    }
+
+    // This session's id, registering it first if needed
+    fn register(&mut self, table: &mut LockTable) -> io::Result<u64> {
+        if let Some((id, _)) = &self.session {
+            return Ok(*id);
+        }
+        let id = table.next_owner.max(1);
+        let liveness = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.liveness_path(id))?;
+        liveness.lock()?;
+        table.next_owner = id + 1;
+        table.owners.push(id);
+        self.session = Some((id, liveness));
+        Ok(id)
+    }
+
+    // Lock every row in `row_ids` of `table`, waiting up to `timeout` for
+    // conflicting locks to be released
+    pub fn lock_rows(
+        &mut self,
+        table: &str,
+        row_ids: &[u64],
+        mode: LockMode,
+        timeout: Duration,
+    ) -> io::Result<Wait> {
+        let exclusive = mode == LockMode::Exclusive;
+        self.wait(timeout, |owner, lock_table| {
+            let mut holders = HashSet::new();
+            for row_id in row_ids {
+                let locks = &mut lock_table.locks;
+                let same_row = |lock: &RowLock| lock.table == table && lock.row_id == *row_id;
+                let conflicting: Vec<u64> = locks
+                    .iter()
+                    .filter(|lock| {
+                        same_row(lock) && lock.owner != owner && (exclusive || lock.exclusive)
+                    })
+                    .map(|lock| lock.owner)
+                    .collect();
+                if !conflicting.is_empty() {
+                    holders.extend(conflicting);
+                    continue;
+                }
+                match locks
+                    .iter_mut()
+                    .find(|lock| same_row(lock) && lock.owner == owner)
+                {
+                    Some(lock) => lock.exclusive |= exclusive,
+                    None => locks.push(RowLock {
+                        table: table.to_string(),
+                        row_id: *row_id,
+                        owner,
+                        exclusive,
+                    }),
+                }
+            }
+            Ok((!holders.is_empty()).then_some(holders))
+        })
+    }
+
+    // Lock the id of the transaction this session has started writing
+    pub fn lock_transaction(&mut self, xid: u64) -> io::Result<Wait> {
+        self.lock_rows(TRANSACTIONS, &[xid], LockMode::Exclusive, Duration::ZERO)
+    }
+
+    // True if the transaction `xid` is running in a live session
+    pub fn is_running(&mut self, xid: u64) -> io::Result<bool> {
+        self.with_table(|_, table| {
+            Ok(table
+                .locks
+                .iter()
+                .any(|lock| lock.table == TRANSACTIONS && lock.row_id == xid))
+        })
+    }
+
+    // Take the log through `try_lock` once the session holding it lets go
+    pub fn lock_log(
+        &mut self,
+        mut try_lock: impl FnMut() -> io::Result<bool>,
+        timeout: Duration,
+    ) -> io::Result<Wait> {
+        self.wait(timeout, |owner, table| {
+            if try_lock()? {
+                table.log_holder = owner;
+                return Ok(None);
+            }
+            // The holder may not have recorded itself yet
+            let mut holders = HashSet::new();
+            if table.log_holder != 0 && table.log_holder != owner {
+                holders.insert(table.log_holder);
+            }
+            Ok(Some(holders))
+        })
+    }
+
+    // Record that this session no longer holds the log
+    pub fn release_log(&mut self) -> io::Result<()> {
+        let Some((owner, _)) = &self.session else {
+            return Ok(());
+        };
+        let owner = *owner;
+        self.with_table(|_, table| {
+            if table.log_holder == owner {
+                table.log_holder = 0;
+            }
+            Ok(())
+        })
+    }
+
+    // Retry `attempt` until it succeeds by returning `None` instead of the
+    // sessions to wait for. The wait is recorded each time so deadlocks can be found.
+    fn wait(
+        &mut self,
+        timeout: Duration,
+        mut attempt: impl FnMut(u64, &mut LockTable) -> io::Result<Option<HashSet<u64>>>,
+    ) -> io::Result<Wait> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let outcome = self.with_table(|manager, table| {
+                let owner = manager.register(table)?;
+                table.waits.retain(|wait| wait.owner != owner);
+                let Some(holders) = attempt(owner, table)? else {
+                    return Ok(Some(Wait::Granted));
+                };
+                if closes_cycle(table, owner, &holders) {
+                    return Ok(Some(Wait::Deadlock));
+                }
+                if Instant::now() >= deadline {
+                    return Ok(Some(Wait::TimedOut));
+                }
+                table.waits.push(LockWait {
+                    owner,
+                    holders: holders.into_iter().collect(),
+                });
+                Ok(None)
+            })?;
+            if let Some(outcome) = outcome {
+                return Ok(outcome);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    // Drop every lock of the session, at the end of its transaction
+    pub fn release_all(&mut self) -> io::Result<()> {
+        let Some((owner, _)) = &self.session else {
+            return Ok(());
+        };
+        let owner = *owner;
+        self.with_table(|_, table| {
+            release(table, owner);
+            Ok(())
+        })
+    }
+}
+
+impl Drop for LockManager {
+    fn drop(&mut self) {
+        let Some((owner, _)) = &self.session else {
+            return;
+        };
+        let owner = *owner;
+        let _ = self.with_table(|_, table| {
+            remove_owners(table, &HashSet::from([owner]));
+            Ok(())
+        });
+        self.session = None;
+        let _ = fs::remove_file(self.liveness_path(owner));
+    }
+}
+
+fn release(table: &mut LockTable, owner: u64) {
+    table.locks.retain(|lock| lock.owner != owner);
+    table.waits.retain(|wait| wait.owner != owner);
+    if table.log_holder == owner {
+        table.log_holder = 0;
+    }
+}
+
+fn remove_owners(table: &mut LockTable, owners: &HashSet<u64>) {
+    for owner in owners {
+        release(table, *owner);
+    }
+    table.owners.retain(|owner| !owners.contains(owner));
+}
+
+// True if one of `holders` already waits, directly or not, for `owner`
+fn closes_cycle(table: &LockTable, owner: u64, holders: &HashSet<u64>) -> bool {
+    let mut pending: Vec<u64> = holders.iter().copied().collect();
+    let mut seen = HashSet::new();
+    while let Some(session) = pending.pop() {
+        if session == owner {
+            return true;
+        }
+        if !seen.insert(session) {
+            continue;
+        }
+        for wait in table.waits.iter().filter(|wait| wait.owner == session) {
+            pending.extend(&wait.holders);
+        }
+    }
+    false
+}
@@ -1,101 +1,225 @@
-mod column_definition;
-mod database_manager;
-mod file_manager;
-mod generated_types;
-mod nom_parser;
-mod parser;
-mod semantic_processor;
-mod table_definition;
-mod table_manager;
-mod token;
-
-use clap::Parser as ClapParser;
-use column_definition::{Column, ColumnType};
-use database_manager::{DatabaseError, DatabaseManager};
-use file_manager::FileManager;
-use nom_parser::{parse_command, run_parser, Command as ParsedCommand};
-use parser::Parser;
-use table_definition::Table;
-use token::{Token, TokenType};
+mod render;
+mod script;
+mod shell;
+#[cfg(test)]
+#[path = "test_util.rs"]
+mod test_util;
+
+use clap::{Parser as ClapParser, Subcommand};
+use db_project::{
+    Connection, Database, DatabaseError, DEFAULT_LOCK_TIMEOUT, DEFAULT_POOL_SIZE, FORMAT_VERSION,
+};
+use render::Format;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // Define the CLI structure with `clap`
 #[derive(ClapParser, Debug)]
 #[clap(about = "A simple database manager CLI")]
 struct Cli {
-    /// SQL command to execute (e.g., "CREATE TABLE ...")
-    #[clap()]
-    command: String,
+    /// SQL command to execute (e.g., "CREATE TABLE ..."); several may be separated by `;`.
+    /// Without one, statements are read from --file or a redirected stdin, or else an
+    /// interactive shell is started
+    #[clap(value_name = "SQL")]
+    command: Option<String>,
+
+    /// Run the statements of a SQL script
+    #[clap(long, short, value_name = "PATH", conflicts_with = "command")]
+    file: Option<PathBuf>,
+
+    /// Stop at the first statement that fails instead of running the rest
+    #[clap(long)]
+    bail: bool,
+
+    /// Layout of query results; `.mode` changes it in the shell
+    #[clap(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    #[clap(subcommand)]
+    action: Option<Action>,
+
+    /// Directory holding the database files; created if it does not exist
+    #[clap(long, env = "DB_DATA_DIR", default_value = ".", global = true)]
+    data_dir: PathBuf,
+
+    /// Number of 4 KiB pages the buffer pool may cache
+    #[clap(long, default_value_t = DEFAULT_POOL_SIZE, global = true)]
+    buffer_pool_size: usize,
+
+    /// Milliseconds to wait for locks held by other processes before giving up
+    #[clap(long, default_value_t = DEFAULT_LOCK_TIMEOUT.as_millis() as u64, global = true)]
+    lock_timeout: u64,
+
+    /// Print buffer pool hit/miss statistics after the command
+    #[clap(long)]
+    pool_stats: bool,
 }
 
-fn main() {
-    // run_parser();
-    // return;
+// Maintenance tasks run instead of a SQL command
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Upgrade the files of the data directory to the current on-disk format
+    Migrate {
+        /// Where the files are copied before they change [default: a new
+        /// `migrate-backup-<time>` directory in the data directory]
+        #[clap(long)]
+        backup_dir: Option<PathBuf>,
+    },
+    /// Check every file of the database against the catalog; exits with status 1 on problems
+    Check,
+    /// Write SQL that rebuilds the database to stdout; run it with --file or on stdin
+    /// against an empty data directory to restore it
+    Dump,
+}
 
+fn main() {
     // Step 1: Parse the command-line arguments
     let args = Cli::parse();
 
-    // Step 2: Initialize the DatabaseManager
-    let db_manager = DatabaseManager::new("dbfile.bin".to_string());
+    // Step 2: Open the database
+    let database = match Database::open(&args.data_dir) {
+        Ok(database) => database
+            .with_buffer_pool_size(args.buffer_pool_size)
+            .with_lock_timeout(Duration::from_millis(args.lock_timeout)),
+        Err(e) => fail(&e),
+    };
 
-    // Step 3: Parse the SQL command
-    let parsed_command = match parse_command(&args.command) {
-        Ok((_, command)) => command,
-        Err(e) => {
-            eprintln!("Error parsing command: {:?}", e);
-            return;
+    if let Some(Action::Check) = args.action {
+        match database.check() {
+            Ok(report) => {
+                println!("{}", report);
+                if !report.is_ok() {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => fail(&e),
         }
+        return;
+    }
+    if let Some(Action::Migrate { backup_dir }) = args.action {
+        let backup_dir = backup_dir.unwrap_or_else(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            args.data_dir
+                .join(format!("migrate-backup-{}", now.as_secs()))
+        });
+        match database.migrate(&backup_dir) {
+            Ok(files) if files.is_empty() => {
+                println!(
+                    "Database is already at on-disk format version {}.",
+                    FORMAT_VERSION
+                )
+            }
+            Ok(files) => {
+                for file in &files {
+                    println!("Upgraded '{}'.", file);
+                }
+                println!(
+                    "Migrated to on-disk format version {}; the previous files were copied to '{}'.",
+                    FORMAT_VERSION,
+                    backup_dir.display()
+                );
+            }
+            Err(e) => fail(&e),
+        }
+        return;
+    }
+
+    let connection = match database.connect() {
+        Ok(connection) => connection,
+        Err(e) => fail(&e),
     };
+    report_recovery(&connection);
 
-    // Step 4: Match parsed command to call DatabaseManager methods
-    match parsed_command {
-        ParsedCommand::CreateTable { name, columns } => {
-            match db_manager.create_table(ParsedCommand::CreateTable {
-                // Cloning here to be able to print it below
-                name: name.clone(),
-                columns,
-            }) {
-                Ok(_) => println!("Table created successfully."),
-                Err(e) => eprintln!("{}", e),
-            }
+    if let Some(Action::Dump) = args.action {
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        if let Err(e) = connection.dump(&mut out).and_then(|_| Ok(out.flush()?)) {
+            fail(&e);
         }
-        ParsedCommand::DropTable { name } => match db_manager.drop_table(&name) {
-            Ok(_) => println!("Table '{}' dropped .", name),
-            Err(e) => eprintln!("{}", e),
-        },
-        ParsedCommand::ListTable => match db_manager.list_tables() {
-            Ok(_) => println!("Tables listed successfully."),
-            Err(e) => eprintln!("{}", e),
-        },
-        ParsedCommand::ListSchema { name } => {
-            match db_manager.display_schema(&name) {
-                Ok(_) => {}
-                Err(e) => eprintln!("{}", e),
+        return;
+    }
+
+    // Step 3: Read the SQL to run; scripts report where a failed statement is
+    let (input, source) = match (args.command, &args.file) {
+        (Some(command), _) => (command, None),
+        (None, Some(file)) => match fs::read_to_string(file) {
+            Ok(input) => (input, Some(file.display().to_string())),
+            Err(e) => {
+                eprintln!("Cannot read '{}': {}", file.display(), e);
+                std::process::exit(DatabaseError::IOError(e).exit_code());
             }
+        },
+        (None, None) if io::stdin().is_terminal() => {
+            shell::run(&connection, args.format);
+            report_recovery(&connection);
+            return;
         }
-        ParsedCommand::Select {
-            columns,
-            table,
-            join_table,
-        } => {
-            match db_manager.select(ParsedCommand::Select {
-                columns,
-                table,
-                join_table,
-            }) {
-                Ok(_) => println!("Select executed successfully."),
-                Err(e) => eprintln!("{}", e),
+        (None, None) => {
+            let mut input = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut input) {
+                fail(&DatabaseError::IOError(e));
             }
+            (input, Some("<stdin>".to_string()))
         }
-        
-        ParsedCommand::Insert { table, values } => {
-            match db_manager.insert(ParsedCommand::Insert { table, values }) {
-                Ok(_) => println!("Insert executed successfully."),
-                Err(e) => eprintln!("{}", e),
+    };
+
+    // Step 4: Run each statement in order; unless --bail is given a failed one
+    // does not stop the rest, but the status reports the first failure
+    let summary = script::run(
+        &connection,
+        &input,
+        source.as_deref(),
+        args.format,
+        args.bail,
+    );
+    if source.is_some() {
+        println!("{}", summary);
+    }
+    report_recovery(&connection);
+    let mut exit_code = summary.exit_code;
+    // Work of a transaction that was never committed is discarded
+    if connection.in_transaction() {
+        match connection.run("ROLLBACK") {
+            Ok(_) => eprintln!("Transaction was not committed and has been rolled back."),
+            Err(e) => {
+                eprintln!("{}", e);
+                if exit_code == 0 {
+                    exit_code = e.exit_code();
+                }
             }
         }
     }
 
-    // Step 5: Print the updated database for verification
-    // let database = db_manager.load_database();
-    // println!("Current Database: {:#?}", database.tables);
+    if args.pool_stats {
+        let stats = connection.buffer_pool_stats();
+        println!(
+            "Buffer pool: {} frames, {} hits, {} misses ({:.1}% hit ratio), {} evictions, {} writes",
+            connection.buffer_pool_capacity(),
+            stats.hits,
+            stats.misses,
+            stats.hit_ratio() * 100.0,
+            stats.evictions,
+            stats.writes
+        );
+    }
+
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+// Say what recovering from a crash of another session did, if it did anything
+fn report_recovery(connection: &Connection) {
+    if let Some(recovery) = connection.take_recovery() {
+        eprintln!("{}", recovery);
+    }
+}
+
+// Report an error that leaves nothing to run and exit with its status
+fn fail(error: &DatabaseError) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(error.exit_code())
 }
@@ -0,0 +1,191 @@
+use crate::generated_types::generated_types::Row;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// Multi-version concurrency control.
+//
+// Rows are never changed in place: an update writes a new version and marks
+// the old one with the updating transaction in `xmax`, and a delete only sets
+// `xmax`. Whether a version is visible depends on whether the transactions in
+// its `xmin` and `xmax` had committed when the reader took its snapshot.
+//
+// Versions let readers go on while a transaction writes, and let writers
+// share pages: a transaction holds the write-ahead log only while one of its
+// statements writes, and is rolled back by recording it as aborted here, which
+// hides every version it created and voids its deletes. Writers lock the rows
+// they change, so of two transactions changing the same row the second waits
+// for the first to end, then fails if it committed.
+//
+// The commit log records, per transaction id, the commit sequence number (CSN)
+// it committed with. CSNs grow by one with every commit, so a snapshot is just
+// the last CSN at the time it was taken.
+//
+// Commit log layout:
+//   next_xid (8) | last_csn (8) | one 8-byte entry per transaction id
+//
+// An entry of 0 means the transaction has not committed (it is running or
+// died without finishing), `ABORTED` that it was rolled back.
+
+const HEADER_SIZE: u64 = 16;
+const ENTRY_SIZE: u64 = 8;
+const ABORTED: u64 = u64::MAX;
+
+// Transaction id 0 marks rows written before versioning; they are always visible
+pub const FROZEN_XID: u64 = 0;
+
+pub struct CommitLog {
+    file: File,
+    // Outcomes never change once recorded, so they are cached
+    finished: HashMap<u64, u64>,
+}
+
+impl CommitLog {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(CommitLog {
+            file,
+            finished: HashMap::new(),
+        })
+    }
+
+    fn read_u64(&mut self, offset: u64) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        self.file.seek(SeekFrom::Start(offset))?;
+        match self.file.read_exact(&mut bytes) {
+            Ok(()) => Ok(u64::from_le_bytes(bytes)),
+            // Past the end of the file nothing has been recorded yet
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_u64(&mut self, offset: u64, value: u64) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&value.to_le_bytes())
+    }
+
+    // CSN of the most recent commit, i.e. a snapshot of everything committed so far
+    pub fn last_csn(&mut self) -> io::Result<u64> {
+        self.read_u64(8)
+    }
+
+    // Hand out the next transaction id. Only the writer holding the log lock calls this.
+    pub fn allocate(&mut self) -> io::Result<u64> {
+        let xid = self.read_u64(0)?.max(1);
+        self.write_u64(0, xid + 1)?;
+        self.file.sync_data()?;
+        Ok(xid)
+    }
+
+    // Record that `xid` committed and return its CSN
+    pub fn commit(&mut self, xid: u64) -> io::Result<u64> {
+        let csn = self.commit_after(xid, None, || Ok(()))?;
+        Ok(csn.expect("without `after` the commit always happens"))
+    }
+
+    // Commit `xid` once `durable` has made its changes durable. With `after`,
+    // nothing happens and `None` is returned if another transaction committed
+    // since that CSN. Writers commit without holding the log, so the file is
+    // locked to hand out each CSN once.
+    pub fn commit_after(
+        &mut self,
+        xid: u64,
+        after: Option<u64>,
+        durable: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<Option<u64>> {
+        self.file.lock()?;
+        let result = self.last_csn().and_then(|last_csn| {
+            if after.is_some_and(|csn| csn != last_csn) {
+                return Ok(None);
+            }
+            durable()?;
+            let csn = last_csn + 1;
+            self.write_u64(HEADER_SIZE + xid * ENTRY_SIZE, csn)?;
+            self.write_u64(8, csn)?;
+            self.file.sync_data()?;
+            self.finished.insert(xid, csn);
+            Ok(Some(csn))
+        });
+        self.file.unlock()?;
+        result
+    }
+
+    pub fn abort(&mut self, xid: u64) -> io::Result<()> {
+        self.write_u64(HEADER_SIZE + xid * ENTRY_SIZE, ABORTED)?;
+        self.file.sync_data()?;
+        self.finished.insert(xid, ABORTED);
+        Ok(())
+    }
+
+    fn entry(&mut self, xid: u64) -> io::Result<u64> {
+        if let Some(entry) = self.finished.get(&xid) {
+            return Ok(*entry);
+        }
+        let entry = self.read_u64(HEADER_SIZE + xid * ENTRY_SIZE)?;
+        if entry != 0 {
+            self.finished.insert(xid, entry);
+        }
+        Ok(entry)
+    }
+
+    // CSN `xid` committed with, or `None` if it has not committed
+    pub fn commit_csn(&mut self, xid: u64) -> io::Result<Option<u64>> {
+        let entry = self.entry(xid)?;
+        Ok((entry != 0 && entry != ABORTED).then_some(entry))
+    }
+
+    // True once `xid` has either committed or been rolled back
+    pub fn is_finished(&mut self, xid: u64) -> io::Result<bool> {
+        Ok(self.entry(xid)? != 0)
+    }
+}
+
+// What a transaction can see: everything committed up to `csn`, plus its own
+// changes once it has a transaction id
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub csn: u64,
+    pub xid: Option<u64>,
+}
+
+impl Snapshot {
+    // Sees every committed change, however recent. Used for uniqueness checks,
+    // which must not miss a row just because it was committed concurrently.
+    pub fn latest(xid: Option<u64>) -> Self {
+        Snapshot { csn: u64::MAX, xid }
+    }
+
+    fn sees(&self, xid: u64, commit_log: &mut CommitLog) -> io::Result<bool> {
+        if xid == FROZEN_XID || Some(xid) == self.xid {
+            return Ok(true);
+        }
+        Ok(commit_log
+            .commit_csn(xid)?
+            .is_some_and(|csn| csn <= self.csn))
+    }
+
+    // A version is visible if its creator is and its deleter, if any, is not
+    pub fn is_visible(&self, row: &Row, commit_log: &mut CommitLog) -> io::Result<bool> {
+        let deleter = row.xmax.unwrap_or(0);
+        Ok(
+            self.sees(row.xmin, commit_log)?
+                && (deleter == 0 || !self.sees(deleter, commit_log)?),
+        )
+    }
+
+    // True if `xid` committed after this snapshot was taken
+    pub fn is_concurrent(&self, xid: u64, commit_log: &mut CommitLog) -> io::Result<bool> {
+        if xid == FROZEN_XID || Some(xid) == self.xid {
+            return Ok(false);
+        }
+        Ok(commit_log
+            .commit_csn(xid)?
+            .is_some_and(|csn| csn > self.csn))
+    }
+}
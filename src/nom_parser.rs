@@ -1,51 +1,258 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
+    bytes::complete::{is_not, tag, take_while1},
     character::complete::{char, digit1, multispace0},
-    combinator::{map, opt},
-    multi::{separated_list0, separated_list1},
-    sequence::{delimited, pair, preceded, terminated, tuple},
+    combinator::{map, map_res, opt, recognize, verify},
+    multi::{many0, separated_list0, separated_list1},
+    sequence::{delimited, pair, preceded, tuple},
     IResult,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Int(i32),
     Str(String),
     Null,
+    // A `?` or `$n` placeholder for a value bound later, numbered from 1
+    Param(usize),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+// A single `column <op> literal` predicate of a WHERE clause
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub column: String,
+    pub op: CompareOp,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone)]
 pub enum Command {
     CreateTable {
         name: String,
         columns: Vec<Column>,
+        if_not_exists: bool,
     },
     DropTable {
         name: String,
+        if_exists: bool,
+    },
+    TruncateTable {
+        name: String,
     },
     ListTable,
+    CheckDatabase,
     ListSchema {
         name: String,
     },
     Select {
         columns: Vec<String>,
         table: String,
+        // Parsed but not used by the engine yet
+        #[allow(dead_code)]
         join_table: Option<String>,
+        // Conditions are AND-ed together; empty means every row
+        where_clause: Vec<Condition>,
+        // FOR SHARE or FOR UPDATE
+        locking: Option<RowLocking>,
     },
     Insert {
         table: String,
         values: Vec<Vec<Value>>,
     },
+    Update {
+        table: String,
+        assignments: Vec<(String, Value)>,
+        where_clause: Vec<Condition>,
+    },
+    Delete {
+        table: String,
+        where_clause: Vec<Condition>,
+    },
+    CreateIndex {
+        name: String,
+        table: String,
+        columns: Vec<String>,
+        unique: bool,
+        method: IndexMethod,
+    },
+    DropIndex {
+        name: String,
+    },
+    Begin {
+        isolation: IsolationLevel,
+    },
+    Commit,
+    Rollback,
+    Savepoint {
+        name: String,
+    },
+    RollbackTo {
+        name: String,
+    },
+    ReleaseSavepoint {
+        name: String,
+    },
+    // COPY t FROM 'file'
+    CopyFrom {
+        table: String,
+        path: String,
+        options: CopyOptions,
+    },
+    // COPY t TO 'file'
+    CopyTo {
+        table: String,
+        path: String,
+        options: CopyOptions,
+    },
+    // IMPORT JSON 'file' INTO t [IGNORE UNKNOWN KEYS]
+    ImportJson {
+        table: String,
+        path: String,
+        // Skip object keys that name no column instead of failing
+        ignore_unknown: bool,
+    },
+    // EXPORT t TO 'file'
+    ExportJson {
+        table: String,
+        path: String,
+    },
+    // BACKUP TO 'dir'. Like the files of COPY, IMPORT and EXPORT, a relative
+    // path is taken from the working directory, not the data directory.
+    Backup {
+        path: String,
+    },
+    // RESTORE FROM 'dir'
+    Restore {
+        path: String,
+    },
 }
 
-#[derive(Debug)]
+// How COPY reads and writes CSV files
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyOptions {
+    // The first line names the columns
+    pub header: bool,
+    pub delimiter: char,
+    // Unquoted text that stands for NULL
+    pub null: String,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            header: false,
+            delimiter: ',',
+            null: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ColumnType {
     INT,
     STRING(u32),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexMethod {
+    BTree,
+    Hash,
+}
+
+// How strictly a transaction is kept apart from concurrent ones
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IsolationLevel {
+    // Reads see the database as of BEGIN; concurrent updates of the same row conflict
+    Snapshot,
+    // Additionally fails at COMMIT if anything it read was changed concurrently
+    Serializable,
+}
+
+// Locks a SELECT takes on the rows it returns, held until the transaction ends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RowLocking {
+    // Others may lock the rows for sharing too, but not change them
+    Share,
+    // As if the rows were about to be changed
+    Update,
+}
+
+// Where a value written in a statement goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target<'a> {
+    // Position in an inserted row
+    Position(usize),
+    // Column it is stored in by UPDATE
+    Assigned(&'a str),
+    // Column it is compared with in a WHERE clause
+    Compared(&'a str),
+}
+
+impl Command {
+    // Every value written in the statement, in the order they appear
+    pub fn values_mut(&mut self) -> Vec<(Target<'_>, &mut Value)> {
+        match self {
+            Command::Insert { values, .. } => values
+                .iter_mut()
+                .flat_map(|row| {
+                    row.iter_mut()
+                        .enumerate()
+                        .map(|(position, value)| (Target::Position(position), value))
+                })
+                .collect(),
+            Command::Update {
+                assignments,
+                where_clause,
+                ..
+            } => assignments
+                .iter_mut()
+                .map(|(column, value)| (Target::Assigned(column.as_str()), value))
+                .chain(compared_values(where_clause))
+                .collect(),
+            Command::Select { where_clause, .. } | Command::Delete { where_clause, .. } => {
+                compared_values(where_clause).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Table the statement reads or changes rows of
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            Command::Select { table, .. }
+            | Command::Insert { table, .. }
+            | Command::Update { table, .. }
+            | Command::Delete { table, .. }
+            | Command::CopyFrom { table, .. }
+            | Command::CopyTo { table, .. }
+            | Command::ImportJson { table, .. }
+            | Command::ExportJson { table, .. } => Some(table),
+            _ => None,
+        }
+    }
+}
+
+fn compared_values(
+    where_clause: &mut [Condition],
+) -> impl Iterator<Item = (Target<'_>, &mut Value)> {
+    where_clause.iter_mut().map(|condition| {
+        let Condition { column, value, .. } = condition;
+        (Target::Compared(column.as_str()), value)
+    })
+}
+
+#[derive(Debug, Clone)]
 pub struct Column {
     pub name: String,
     pub col_type: ColumnType,
@@ -57,24 +264,91 @@ fn identifier(input: &str) -> IResult<&str, &str> {
     take_while1(|c: char| c.is_alphanumeric() || c == '_')(input)
 }
 
+// Whether all of `name` is an identifier, as table and index names are
+pub fn is_identifier(name: &str) -> bool {
+    matches!(identifier(name), Ok(("", _)))
+}
+
+// Integers that do not fit an INT are not accepted
 fn integer(input: &str) -> IResult<&str, Value> {
-    map(digit1, |s: &str| Value::Int(s.parse().unwrap()))(input)
+    map_res(recognize(pair(opt(char('-')), digit1)), |s: &str| {
+        s.parse().map(Value::Int)
+    })(input)
 }
 
 fn string(input: &str) -> IResult<&str, Value> {
-    map(
-        delimited(char('\''), take_while1(|c| c != '\''), char('\'')),
-        |s: &str| Value::Str(s.to_string()),
-    )(input)
+    map(quoted, Value::Str)(input)
 }
 
 fn null_value(input: &str) -> IResult<&str, Value> {
     map(tag("NULL"), |_| Value::Null)(input)
 }
 
+// `$n` placeholder, or `?` parsed as 0 until `number_parameters` numbers it
+fn parameter(input: &str) -> IResult<&str, Value> {
+    alt((
+        map(char('?'), |_| Value::Param(0)),
+        map(
+            preceded(
+                char('$'),
+                verify(map_res(digit1, |s: &str| s.parse::<usize>()), |n| *n > 0),
+            ),
+            Value::Param,
+        ),
+    ))(input)
+}
+
+// Quoted text, in which `''` stands for a quote
+fn quoted(input: &str) -> IResult<&str, String> {
+    delimited(
+        char('\''),
+        map(
+            many0(alt((is_not("'"), map(tag("''"), |_| "'")))),
+            |parts| parts.concat(),
+        ),
+        char('\''),
+    )(input)
+}
+
 // Parse a single value
 fn value(input: &str) -> IResult<&str, Value> {
-    alt((integer, string, null_value))(input)
+    alt((integer, string, null_value, parameter))(input)
+}
+
+// Comparison operator parser; two-character operators are tried first
+fn compare_op(input: &str) -> IResult<&str, CompareOp> {
+    alt((
+        map(tag("<="), |_| CompareOp::LtEq),
+        map(tag(">="), |_| CompareOp::GtEq),
+        map(tag("!="), |_| CompareOp::NotEq),
+        map(tag("<>"), |_| CompareOp::NotEq),
+        map(tag("="), |_| CompareOp::Eq),
+        map(tag("<"), |_| CompareOp::Lt),
+        map(tag(">"), |_| CompareOp::Gt),
+    ))(input)
+}
+
+// Parser for `column <op> value`
+fn condition(input: &str) -> IResult<&str, Condition> {
+    map(
+        tuple((
+            map(identifier, String::from),
+            preceded(multispace0, compare_op),
+            preceded(multispace0, value),
+        )),
+        |(column, op, value)| Condition { column, op, value },
+    )(input)
+}
+
+// Optional WHERE clause made of conditions joined by AND
+fn where_clause(input: &str) -> IResult<&str, Vec<Condition>> {
+    map(
+        opt(preceded(
+            tuple((multispace0, tag("WHERE"), multispace0)),
+            separated_list1(tuple((multispace0, tag("AND"), multispace0)), condition),
+        )),
+        |conditions| conditions.unwrap_or_default(),
+    )(input)
 }
 
 // Parser for a single tuple of values
@@ -97,6 +371,7 @@ fn column_type(input: &str) -> IResult<&str, ColumnType> {
 fn create_table(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("CREATE TABLE")(input)?;
     let (input, _) = multispace0(input)?;
+    let (input, if_not_exists) = opt(pair(tag("IF NOT EXISTS"), multispace0))(input)?;
     let (input, name) = identifier(input)?;
     let (input, _) = multispace0(input)?;
     let (input, _) = char('(')(input)?;
@@ -123,6 +398,7 @@ fn create_table(input: &str) -> IResult<&str, Command> {
         Command::CreateTable {
             name: name.to_string(),
             columns,
+            if_not_exists: if_not_exists.is_some(),
         },
     ))
 }
@@ -131,12 +407,28 @@ fn create_table(input: &str) -> IResult<&str, Command> {
 fn drop_table(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("DROP TABLE")(input)?;
     let (input, _) = multispace0(input)?;
+    let (input, if_exists) = opt(pair(tag("IF EXISTS"), multispace0))(input)?;
     let (input, name) = identifier(input)?;
     let (input, _) = opt(char(';'))(input)?; // Optional semicolon
     Ok((
         input,
         Command::DropTable {
             name: name.to_string(),
+            if_exists: if_exists.is_some(),
+        },
+    ))
+}
+
+// TRUNCATE TABLE parser
+fn truncate_table(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("TRUNCATE TABLE")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, name) = identifier(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::TruncateTable {
+            name: name.to_string(),
         },
     ))
 }
@@ -148,6 +440,13 @@ fn list_table(input: &str) -> IResult<&str, Command> {
     Ok((input, Command::ListTable))
 }
 
+// CHECK DATABASE parser
+fn check_database(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("CHECK DATABASE")(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((input, Command::CheckDatabase))
+}
+
 // SCHEMA parser
 fn display_schema(input: &str) -> IResult<&str, Command> {
     let (input, _) = tag("SCHEMA")(input)?;
@@ -175,6 +474,14 @@ fn select_statement(input: &str) -> IResult<&str, Command> {
         pair(multispace0, tag("JOIN")),
         preceded(multispace0, identifier),
     ))(input)?;
+    let (input, where_clause) = where_clause(input)?;
+    let (input, locking) = opt(preceded(
+        tuple((multispace0, tag("FOR"), multispace0)),
+        alt((
+            map(tag("SHARE"), |_| RowLocking::Share),
+            map(tag("UPDATE"), |_| RowLocking::Update),
+        )),
+    ))(input)?;
     let (input, _) = opt(char(';'))(input)?; // Optional semicolon
     Ok((
         input,
@@ -182,6 +489,8 @@ fn select_statement(input: &str) -> IResult<&str, Command> {
             columns,
             table: table.to_string(),
             join_table: join_table.map(|s| s.to_string()),
+            where_clause,
+            locking,
         },
     ))
 }
@@ -216,42 +525,414 @@ fn insert_into(input: &str) -> IResult<&str, Command> {
     ))
 }
 
+// UPDATE parser: UPDATE t SET col = value, ... [WHERE ...]
+fn update_statement(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("UPDATE")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, table) = identifier(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("SET")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, assignments) = separated_list1(
+        tag(", "),
+        map(
+            tuple((
+                map(identifier, String::from),
+                preceded(multispace0, char('=')),
+                preceded(multispace0, value),
+            )),
+            |(column, _, value)| (column, value),
+        ),
+    )(input)?;
+    let (input, where_clause) = where_clause(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::Update {
+            table: table.to_string(),
+            assignments,
+            where_clause,
+        },
+    ))
+}
+
+// DELETE parser: DELETE FROM t [WHERE ...]
+fn delete_statement(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("DELETE FROM")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, table) = identifier(input)?;
+    let (input, where_clause) = where_clause(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::Delete {
+            table: table.to_string(),
+            where_clause,
+        },
+    ))
+}
+
+// CREATE [UNIQUE] INDEX parser: CREATE INDEX idx ON t (col, ...) [USING BTREE|HASH]
+fn create_index(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("CREATE")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, unique) = opt(pair(tag("UNIQUE"), multispace0))(input)?;
+    let (input, _) = tag("INDEX")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, name) = identifier(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("ON")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, table) = identifier(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, columns) = delimited(
+        char('('),
+        separated_list1(tag(", "), map(identifier, String::from)),
+        char(')'),
+    )(input)?;
+    let (input, method) = opt(preceded(
+        tuple((multispace0, tag("USING"), multispace0)),
+        alt((
+            map(tag("BTREE"), |_| IndexMethod::BTree),
+            map(tag("HASH"), |_| IndexMethod::Hash),
+        )),
+    ))(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::CreateIndex {
+            name: name.to_string(),
+            table: table.to_string(),
+            columns,
+            unique: unique.is_some(),
+            method: method.unwrap_or(IndexMethod::BTree),
+        },
+    ))
+}
+
+// DROP INDEX parser
+fn drop_index(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("DROP INDEX")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, name) = identifier(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::DropIndex {
+            name: name.to_string(),
+        },
+    ))
+}
+
+// BEGIN [TRANSACTION] [ISOLATION LEVEL SNAPSHOT | SERIALIZABLE] parser
+fn begin_transaction(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("BEGIN")(input)?;
+    let (input, _) = opt(preceded(multispace0, tag("TRANSACTION")))(input)?;
+    let (input, isolation) = opt(preceded(
+        tuple((multispace0, tag("ISOLATION LEVEL"), multispace0)),
+        alt((
+            map(tag("SNAPSHOT"), |_| IsolationLevel::Snapshot),
+            map(tag("SERIALIZABLE"), |_| IsolationLevel::Serializable),
+        )),
+    ))(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::Begin {
+            isolation: isolation.unwrap_or(IsolationLevel::Snapshot),
+        },
+    ))
+}
+
+// COMMIT parser
+fn commit_transaction(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("COMMIT")(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((input, Command::Commit))
+}
+
+// ROLLBACK and ROLLBACK TO [SAVEPOINT] name parser
+fn rollback_transaction(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("ROLLBACK")(input)?;
+    let (input, savepoint) = opt(preceded(
+        tuple((
+            multispace0,
+            tag("TO"),
+            multispace0,
+            opt(pair(tag("SAVEPOINT"), multispace0)),
+        )),
+        identifier,
+    ))(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    let command = match savepoint {
+        Some(name) => Command::RollbackTo {
+            name: name.to_string(),
+        },
+        None => Command::Rollback,
+    };
+    Ok((input, command))
+}
+
+// SAVEPOINT name parser
+fn savepoint(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("SAVEPOINT")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, name) = identifier(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::Savepoint {
+            name: name.to_string(),
+        },
+    ))
+}
+
+// RELEASE [SAVEPOINT] name parser
+fn release_savepoint(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("RELEASE")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = opt(pair(tag("SAVEPOINT"), multispace0))(input)?;
+    let (input, name) = identifier(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::ReleaseSavepoint {
+            name: name.to_string(),
+        },
+    ))
+}
+
+// COPY option: HEADER, DELIMITER 'c' or NULL 'text'
+enum CopyOption {
+    Header,
+    Delimiter(char),
+    Null(String),
+}
+
+fn copy_option(input: &str) -> IResult<&str, CopyOption> {
+    alt((
+        map(tag("HEADER"), |_| CopyOption::Header),
+        map(
+            preceded(
+                pair(tag("DELIMITER"), multispace0),
+                map_res(quoted, |text| match text.chars().collect::<Vec<_>>()[..] {
+                    [c] if !matches!(c, '"' | '\r' | '\n') => Ok(c),
+                    _ => Err("a delimiter is a single character other than a quote or line break"),
+                }),
+            ),
+            CopyOption::Delimiter,
+        ),
+        map(
+            preceded(pair(tag("NULL"), multispace0), quoted),
+            CopyOption::Null,
+        ),
+    ))(input)
+}
+
+// COPY t FROM|TO 'file' [WITH option, ...]
+fn copy(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("COPY")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, table) = identifier(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, from) = alt((map(tag("FROM"), |_| true), map(tag("TO"), |_| false)))(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, path) = quoted(input)?;
+    let (input, given) = opt(preceded(
+        tuple((multispace0, tag("WITH"), multispace0)),
+        separated_list1(tuple((multispace0, char(','), multispace0)), copy_option),
+    ))(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    let mut options = CopyOptions::default();
+    for option in given.unwrap_or_default() {
+        match option {
+            CopyOption::Header => options.header = true,
+            CopyOption::Delimiter(delimiter) => options.delimiter = delimiter,
+            CopyOption::Null(null) => options.null = null,
+        }
+    }
+    let table = table.to_string();
+    let command = match from {
+        true => Command::CopyFrom {
+            table,
+            path,
+            options,
+        },
+        false => Command::CopyTo {
+            table,
+            path,
+            options,
+        },
+    };
+    Ok((input, command))
+}
+
+// IMPORT JSON 'file' INTO t [IGNORE UNKNOWN KEYS]
+fn import_json(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("IMPORT JSON")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, path) = quoted(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("INTO")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, table) = identifier(input)?;
+    let (input, ignore_unknown) = opt(preceded(multispace0, tag("IGNORE UNKNOWN KEYS")))(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::ImportJson {
+            table: table.to_string(),
+            path,
+            ignore_unknown: ignore_unknown.is_some(),
+        },
+    ))
+}
+
+// EXPORT t TO 'file'
+fn export_json(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("EXPORT")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, table) = identifier(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = tag("TO")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, path) = quoted(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((
+        input,
+        Command::ExportJson {
+            table: table.to_string(),
+            path,
+        },
+    ))
+}
+
+// BACKUP TO 'dir'
+fn backup(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("BACKUP TO")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, path) = quoted(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((input, Command::Backup { path }))
+}
+
+// RESTORE FROM 'dir'
+fn restore(input: &str) -> IResult<&str, Command> {
+    let (input, _) = tag("RESTORE FROM")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, path) = quoted(input)?;
+    let (input, _) = opt(char(';'))(input)?; // Optional semicolon
+    Ok((input, Command::Restore { path }))
+}
+
 // Top-level parser for any command
 pub fn parse_command(input: &str) -> IResult<&str, Command> {
+    map_res(command, number_parameters)(input)
+}
+
+// Each `?` takes the number after the highest placeholder before it, so
+// `?, ?, $1` is `$1, $2, $1`. A `$n` may not go past the number of
+// placeholders, which keeps a statement from asking for millions of them.
+fn number_parameters(mut command: Command) -> Result<Command, String> {
+    let mut values = command.values_mut();
+    let count = values
+        .iter()
+        .filter(|(_, value)| matches!(value, Value::Param(_)))
+        .count();
+    let mut highest = 0;
+    for (_, value) in values.iter_mut() {
+        if let Value::Param(number) = value {
+            if *number > count {
+                return Err(format!(
+                    "${} is past the {} placeholder(s) of the statement",
+                    number, count
+                ));
+            }
+            if *number == 0 {
+                *number = highest + 1;
+            }
+            highest = highest.max(*number);
+        }
+    }
+    Ok(command)
+}
+
+fn command(input: &str) -> IResult<&str, Command> {
     alt((
         create_table,
         drop_table,
+        truncate_table,
         list_table,
+        check_database,
         display_schema,
         select_statement,
         insert_into,
+        update_statement,
+        delete_statement,
+        create_index,
+        drop_index,
+        begin_transaction,
+        commit_transaction,
+        rollback_transaction,
+        savepoint,
+        release_savepoint,
+        // alt takes at most 21 parsers
+        alt((copy, import_json, export_json, backup, restore)),
     ))(input)
 }
 
-// Test cases to parse and pretty-print each command
-pub fn run_parser() {
-    let tests = vec![
-        "CREATE TABLE users (id INT, name STRING(20));",
-        "DELETE FROM orders;",
-        "LIST TABLES;",
-        "SCHEMA users;",
-        "SELECT * FROM users JOIN orders;",
-        "SELECT id, name FROM users;",
-        "SELECT * FROM users;",
-        "CREATE TABLE users (id INT, name STRING(20) NOT NULL, age INT NOT NULL);",
-        "CREATE TABLE products (code STRING(10), price INT);",
-        "INSERT INTO users VALUES (1, 'John Doe');", // Single tuple insert
-        "INSERT INTO users VALUES (1, 'John Doe'), (2, 'Jane Smith');", // Multi-tuple insert
-    ];
-
-    for test in tests {
-        match parse_command(test) {
-            Ok((_, command)) => {
-                if cfg!(debug_assertions) {
-                    println!("{:?}", command);
-                }
-            }
-            Err(e) => println!("Error parsing '{}': {:?}", test, e),
+// Parser for a script of commands, each ended by its optional semicolon
+#[cfg(test)]
+pub fn parse_statements(input: &str) -> IResult<&str, Vec<Command>> {
+    let (input, commands) = many0(delimited(multispace0, parse_command, multispace0))(input)?;
+    Ok((input, commands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_statements_parse() {
+        for statement in [
+            "CREATE TABLE users (id INT, name STRING(20));",
+            "DELETE FROM orders;",
+            "DROP TABLE IF EXISTS orders;",
+            "TRUNCATE TABLE orders;",
+            "CREATE TABLE IF NOT EXISTS orders (id INT);",
+            "LIST TABLES;",
+            "SCHEMA users;",
+            "CHECK DATABASE;",
+            "SELECT * FROM users JOIN orders;",
+            "SELECT id, name FROM users;",
+            "SELECT * FROM users;",
+            "SELECT id, name FROM users WHERE id >= 2 AND name = 'John Doe';",
+            "SELECT * FROM users WHERE id = 1 FOR UPDATE;",
+            "SELECT name FROM users FOR SHARE;",
+            "UPDATE users SET name = 'Jane Doe' WHERE id = 2;",
+            "DELETE FROM users WHERE id < 10;",
+            "CREATE UNIQUE INDEX users_id ON users (id);",
+            "CREATE INDEX users_name ON users (name) USING HASH;",
+            "DROP INDEX users_id;",
+            "CREATE TABLE users (id INT, name STRING(20) NOT NULL, age INT NOT NULL);",
+            "CREATE TABLE products (code STRING(10), price INT);",
+            "INSERT INTO users VALUES (1, 'John Doe');", // Single tuple insert
+            "INSERT INTO users VALUES (1, 'John Doe'), (2, 'Jane Smith');", // Multi-tuple insert
+            "BEGIN;",
+            "BEGIN ISOLATION LEVEL SERIALIZABLE;",
+            "SAVEPOINT before_orders;",
+            "ROLLBACK TO SAVEPOINT before_orders;",
+            "RELEASE before_orders;",
+            "COMMIT;",
+            "ROLLBACK;",
+            "BACKUP TO 'backups/monday';",
+            "RESTORE FROM 'backups/monday';",
+        ] {
+            assert!(
+                matches!(parse_command(statement), Ok(("", _))),
+                "cannot parse {}",
+                statement
+            );
         }
     }
 }
@@ -0,0 +1,336 @@
+// Slotted page layout shared by every data page of a table file.
+//
+// +--------+----------------------+------------ ... ------------+---------+
+// | header | slot directory  ---> |         free space          | <- rows |
+// +--------+----------------------+------------ ... ------------+---------+
+//
+// The slot directory grows forward from the header and row bytes grow
+// backward from the end of the page. A slot with offset 0 is unused.
+//
+// The checksum covers the whole page with the checksum field zeroed. It is
+// filled in when the page is written out and checked when it is read back.
+
+use std::io;
+
+pub const PAGE_SIZE: usize = 4096;
+
+// page_id (4) + num_slots (2) + free_end (2) + crc32 (4) + reserved (4)
+pub const PAGE_HEADER_SIZE: usize = 16;
+const CHECKSUM_OFFSET: usize = 8;
+// offset (2) + length (2)
+pub const SLOT_SIZE: usize = 4;
+
+// Largest row that fits on an otherwise empty page
+pub const MAX_RECORD_SIZE: usize = PAGE_SIZE - PAGE_HEADER_SIZE - SLOT_SIZE;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&data[..CHECKSUM_OFFSET]);
+    hasher.update(&[0; 4]);
+    hasher.update(&data[CHECKSUM_OFFSET + 4..]);
+    hasher.finalize()
+}
+
+fn damaged(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[derive(Clone)]
+pub struct Page {
+    data: Vec<u8>,
+}
+
+impl Page {
+    pub fn new(page_id: u32) -> Self {
+        let mut page = Page {
+            data: vec![0; PAGE_SIZE],
+        };
+        page.data[0..4].copy_from_slice(&page_id.to_le_bytes());
+        page.set_free_end(PAGE_SIZE);
+        page
+    }
+
+    // A page read back from a file, checked against its checksum unless it was
+    // written before pages had one. Fails if the page is damaged.
+    pub fn from_bytes(data: Vec<u8>, checksummed: bool) -> io::Result<Self> {
+        debug_assert_eq!(data.len(), PAGE_SIZE);
+        let page = Page { data };
+        if checksummed && read_u32(&page.data, CHECKSUM_OFFSET) != checksum(&page.data) {
+            return Err(damaged("its checksum does not match".to_string()));
+        }
+        page.check()?;
+        Ok(page)
+    }
+
+    // The slot directory and every row must lie within the page
+    fn check(&self) -> io::Result<()> {
+        let free_end = read_u16(&self.data, 6) as usize;
+        if free_end > PAGE_SIZE || self.directory_end() > self.free_end() {
+            return Err(damaged(
+                "its slot directory does not fit the page".to_string(),
+            ));
+        }
+        for slot in 0..self.num_slots() {
+            let (offset, length) = self.slot(slot);
+            if offset != 0 && (offset < self.directory_end() || offset + length > PAGE_SIZE) {
+                return Err(damaged(format!("slot {} points outside the page", slot)));
+            }
+        }
+        Ok(())
+    }
+
+    // The page as it is written to its file, checksum included
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        let checksum = checksum(&data);
+        data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        data
+    }
+
+    pub fn page_id(&self) -> u32 {
+        u32::from_le_bytes([self.data[0], self.data[1], self.data[2], self.data[3]])
+    }
+
+    pub fn num_slots(&self) -> u16 {
+        read_u16(&self.data, 4)
+    }
+
+    fn set_num_slots(&mut self, num_slots: u16) {
+        write_u16(&mut self.data, 4, num_slots);
+    }
+
+    // Start of the row area. Stored as `PAGE_SIZE - free_end` so a zeroed page reads as empty.
+    fn free_end(&self) -> usize {
+        PAGE_SIZE - read_u16(&self.data, 6) as usize
+    }
+
+    fn set_free_end(&mut self, free_end: usize) {
+        write_u16(&mut self.data, 6, (PAGE_SIZE - free_end) as u16);
+    }
+
+    fn slot(&self, slot: u16) -> (usize, usize) {
+        let base = PAGE_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        (
+            read_u16(&self.data, base) as usize,
+            read_u16(&self.data, base + 2) as usize,
+        )
+    }
+
+    fn set_slot(&mut self, slot: u16, offset: usize, length: usize) {
+        let base = PAGE_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        write_u16(&mut self.data, base, offset as u16);
+        write_u16(&mut self.data, base + 2, length as u16);
+    }
+
+    fn directory_end(&self) -> usize {
+        PAGE_HEADER_SIZE + self.num_slots() as usize * SLOT_SIZE
+    }
+
+    fn free_slot(&self) -> Option<u16> {
+        (0..self.num_slots()).find(|slot| self.slot(*slot).0 == 0)
+    }
+
+    // Bytes available to a new row, counting space left behind by deleted rows
+    // and the directory entry the row will need.
+    pub fn free_space(&self) -> usize {
+        let live: usize = self.records().map(|(_, record)| record.len()).sum();
+        let free = PAGE_SIZE - self.directory_end() - live;
+        if self.free_slot().is_some() {
+            free
+        } else {
+            free.saturating_sub(SLOT_SIZE)
+        }
+    }
+
+    pub fn get(&self, slot: u16) -> Option<&[u8]> {
+        if slot >= self.num_slots() {
+            return None;
+        }
+        match self.slot(slot) {
+            (0, _) => None,
+            (offset, length) => Some(&self.data[offset..offset + length]),
+        }
+    }
+
+    // Live rows of the page together with their slot numbers
+    pub fn records(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        (0..self.num_slots()).filter_map(move |slot| self.get(slot).map(|record| (slot, record)))
+    }
+
+    // Store `record` and return its slot, or `None` if the page is too full
+    pub fn insert(&mut self, record: &[u8]) -> Option<u16> {
+        if record.len() > self.free_space() {
+            return None;
+        }
+        let slot = match self.free_slot() {
+            Some(slot) => slot,
+            None => {
+                // The new directory entry must not run into the rows
+                if self.free_end() - self.directory_end() < SLOT_SIZE + record.len() {
+                    self.compact();
+                }
+                let slot = self.num_slots();
+                self.set_num_slots(slot + 1);
+                slot
+            }
+        };
+        self.place(slot, record);
+        Some(slot)
+    }
+
+    pub fn delete(&mut self, slot: u16) -> bool {
+        if self.get(slot).is_none() {
+            return false;
+        }
+        self.set_slot(slot, 0, 0);
+        true
+    }
+
+    // Replace the row in `slot`, keeping its slot number. Returns false if the
+    // new row does not fit on this page; the old row is left untouched then.
+    pub fn update(&mut self, slot: u16, record: &[u8]) -> bool {
+        let old_length = match self.get(slot) {
+            Some(old) => old.len(),
+            None => return false,
+        };
+        let (offset, _) = self.slot(slot);
+        if record.len() <= old_length {
+            self.data[offset..offset + record.len()].copy_from_slice(record);
+            self.set_slot(slot, offset, record.len());
+            return true;
+        }
+        let available =
+            PAGE_SIZE - self.directory_end() - self.records().map(|(_, r)| r.len()).sum::<usize>()
+                + old_length;
+        if record.len() > available {
+            return false;
+        }
+        self.set_slot(slot, 0, 0);
+        self.place(slot, record);
+        true
+    }
+
+    // Copy `record` into the row area, compacting the page first if the
+    // contiguous free space is too small.
+    fn place(&mut self, slot: u16, record: &[u8]) {
+        if self.free_end() - self.directory_end() < record.len() {
+            self.compact();
+        }
+        let offset = self.free_end() - record.len();
+        self.data[offset..offset + record.len()].copy_from_slice(record);
+        self.set_slot(slot, offset, record.len());
+        self.set_free_end(offset);
+    }
+
+    // Move every live row to the end of the page so the free space is contiguous
+    fn compact(&mut self) {
+        let records: Vec<(u16, Vec<u8>)> = self
+            .records()
+            .map(|(slot, record)| (slot, record.to_vec()))
+            .collect();
+        let mut free_end = PAGE_SIZE;
+        for (slot, record) in records {
+            free_end -= record.len();
+            self.data[free_end..free_end + record.len()].copy_from_slice(&record);
+            self.set_slot(slot, free_end, record.len());
+        }
+        self.set_free_end(free_end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deleted_slots_and_space_are_reused() {
+        let mut page = Page::new(3);
+        let slots: Vec<u16> = (1..=3u8)
+            .map(|n| page.insert(&[n; 1000]).unwrap())
+            .collect();
+        assert!(page.insert(&[4; 1200]).is_none());
+        assert!(page.delete(slots[1]));
+        assert!(!page.delete(slots[1]));
+
+        // The freed slot is handed out again, and the page is compacted to
+        // make its bytes and the free space one contiguous run
+        assert_eq!(page.insert(&[4; 1500]), Some(slots[1]));
+        assert_eq!(page.get(slots[0]), Some(&[1; 1000][..]));
+        assert_eq!(page.get(slots[1]), Some(&[4; 1500][..]));
+        assert_eq!(page.get(slots[2]), Some(&[3; 1000][..]));
+
+        // A row grows in place while the page has room, and stays as it was otherwise
+        assert!(page.update(slots[0], &[5; 1100]));
+        assert!(!page.update(slots[2], &[6; 2000]));
+        let page = Page::from_bytes(page.to_bytes(), true).unwrap();
+        assert_eq!(page.page_id(), 3);
+        let records: Vec<(u16, Vec<u8>)> = page
+            .records()
+            .map(|(slot, record)| (slot, record.to_vec()))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (slots[0], vec![5; 1100]),
+                (slots[1], vec![4; 1500]),
+                (slots[2], vec![3; 1000]),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_slot_does_not_run_into_rows() {
+        let mut page = Page::new(1);
+        // Rows up to two bytes short of the directory, then a hole left by a shrunk row
+        let first = page.insert(&[1; 2000]).unwrap();
+        let length = MAX_RECORD_SIZE - 2000 - SLOT_SIZE - 2;
+        let second = page.insert(&vec![2; length]).unwrap();
+        assert!(page.update(first, &[1; 1000]));
+        let third = page.insert(&[3; 500]).unwrap();
+        assert_eq!(page.get(first), Some(&[1; 1000][..]));
+        assert_eq!(page.get(second), Some(&vec![2; length][..]));
+        assert_eq!(page.get(third), Some(&[3; 500][..]));
+        Page::from_bytes(page.to_bytes(), true).unwrap();
+    }
+
+    #[test]
+    fn damaged_pages_are_refused() {
+        let mut page = Page::new(2);
+        page.insert(&[1; 100]).unwrap();
+        let damaged = |change: &dyn Fn(&mut Vec<u8>), checksummed| {
+            let mut bytes = page.to_bytes();
+            change(&mut bytes);
+            let error = Page::from_bytes(bytes, checksummed).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            error.to_string()
+        };
+        // A flipped bit in a row fails the checksum
+        let error = damaged(&|bytes| bytes[PAGE_SIZE - 1] ^= 1, true);
+        assert!(error.contains("checksum"), "{}", error);
+        // Pages without a checksum are still held to their layout
+        let error = damaged(&|bytes| bytes[16..18].fill(0xFF), false);
+        assert!(error.contains("slot 0"), "{}", error);
+        let error = damaged(
+            &|bytes| bytes[4..6].copy_from_slice(&2000u16.to_le_bytes()),
+            false,
+        );
+        assert!(error.contains("directory"), "{}", error);
+    }
+}
@@ -1,25 +0,0 @@
-use crate::token::{Token, TokenType};
-use std::str::Chars;
-
-pub struct Parser<'a> {
-    input: Chars<'a>,
-}
-
-impl<'a> Parser<'a> {
-    pub fn new(command: &'a str) -> Self {
-        Parser {
-            input: command.chars(),
-        }
-    }
-
-    pub fn parse_tokens(&mut self) -> Vec<Token> {
-        // This method will parse the input command into tokens
-        // Placeholder logic for parsing
-        let tokens: Vec<Token> = Vec::new();
-
-        // Iterate through input and tokenize accordingly
-        // Implement the parsing logic based on input
-
-        tokens
-    }
-}
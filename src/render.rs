@@ -0,0 +1,374 @@
+use clap::ValueEnum;
+use db_project::{
+    cell_text, cell_value, CellValue, ColumnType, Outcome, ResultSet, TableDefinition,
+};
+use std::io::{self, Write};
+
+// Layouts a result set can be printed in, chosen with --format or `.mode`
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Columns sized to their contents inside a box
+    Table,
+    Csv,
+    Tsv,
+    /// An array with one object per row
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    Markdown,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Table => "table",
+            Format::Csv => "csv",
+            Format::Tsv => "tsv",
+            Format::Json => "json",
+            Format::Jsonl => "jsonl",
+            Format::Markdown => "markdown",
+        }
+    }
+}
+
+pub fn render(result: &ResultSet, format: Format, out: &mut impl Write) -> io::Result<()> {
+    match format {
+        Format::Table => table(result, out),
+        Format::Csv => delimited(result, ',', csv_field, out),
+        Format::Tsv => delimited(result, '\t', tsv_field, out),
+        Format::Json => {
+            writeln!(out, "[")?;
+            for (i, row) in result.rows().enumerate() {
+                let separator = if i + 1 < result.len() { "," } else { "" };
+                writeln!(out, "  {}{}", json_object(result, row.cells()), separator)?;
+            }
+            writeln!(out, "]")
+        }
+        Format::Jsonl => {
+            for row in result.rows() {
+                writeln!(out, "{}", json_object(result, row.cells()))?;
+            }
+            Ok(())
+        }
+        Format::Markdown => markdown(result, out),
+    }
+}
+
+// Cells as shown to people, with NULL spelled out
+fn display_rows(result: &ResultSet) -> Vec<Vec<String>> {
+    result
+        .rows()
+        .map(|row| {
+            row.cells()
+                .iter()
+                .map(|cell| cell_text(cell).unwrap_or_else(|| "NULL".to_string()))
+                .collect()
+        })
+        .collect()
+}
+
+fn is_numeric(result: &ResultSet, column: usize) -> bool {
+    result.columns()[column].col_type() == ColumnType::Int
+}
+
+fn table(result: &ResultSet, out: &mut impl Write) -> io::Result<()> {
+    // Tabs and line breaks would throw the columns out of line
+    let rows: Vec<Vec<String>> = display_rows(result)
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|text| text.replace('\t', "\\t").replace('\n', "\\n"))
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = result
+        .columns()
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain([column.name.chars().count()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let border: String = widths
+        .iter()
+        .map(|width| format!("+{}", "-".repeat(width + 2)))
+        .chain(["+".to_string()])
+        .collect();
+    let line = |cells: Vec<(&str, bool)>, out: &mut dyn Write| -> io::Result<()> {
+        for ((text, right), width) in cells.into_iter().zip(&widths) {
+            let padding = " ".repeat(width - text.chars().count());
+            if right {
+                write!(out, "| {}{} ", padding, text)?;
+            } else {
+                write!(out, "| {}{} ", text, padding)?;
+            }
+        }
+        writeln!(out, "|")
+    };
+
+    writeln!(out, "{}", border)?;
+    let header = result.columns().iter().map(|c| (c.name.as_str(), false));
+    line(header.collect(), out)?;
+    writeln!(out, "{}", border)?;
+    for row in &rows {
+        // Numbers line up on their last digit
+        let cells = row
+            .iter()
+            .enumerate()
+            .map(|(i, text)| (text.as_str(), is_numeric(result, i)));
+        line(cells.collect(), out)?;
+    }
+    if !result.is_empty() {
+        writeln!(out, "{}", border)?;
+    }
+    writeln!(out, "{} row(s).", rows.len())
+}
+
+fn delimited(
+    result: &ResultSet,
+    separator: char,
+    field: fn(Option<&str>) -> String,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut record = |fields: Vec<String>| writeln!(out, "{}", fields.join(&separator.to_string()));
+    record(
+        result
+            .columns()
+            .iter()
+            .map(|column| field(Some(&column.name)))
+            .collect(),
+    )?;
+    for row in result.rows() {
+        record(
+            row.cells()
+                .iter()
+                .map(|cell| field(cell_text(cell).as_deref()))
+                .collect(),
+        )?;
+    }
+    Ok(())
+}
+
+// RFC 4180: quoted when it holds a separator, quote or line break; NULL is empty
+fn csv_field(text: Option<&str>) -> String {
+    match text {
+        Some(text) if text.contains([',', '"', '\n', '\r']) => {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        }
+        Some(text) => text.to_string(),
+        None => String::new(),
+    }
+}
+
+// Tabs and line breaks would split the field, so they are escaped; NULL is `\N`
+fn tsv_field(text: Option<&str>) -> String {
+    match text {
+        Some(text) => text
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r"),
+        None => "\\N".to_string(),
+    }
+}
+
+fn json_object(result: &ResultSet, row: &[CellValue]) -> String {
+    let members: Vec<String> = result
+        .columns()
+        .iter()
+        .zip(row)
+        .map(|(column, cell)| format!("{}:{}", json_string(&column.name), json_value(cell)))
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+fn json_value(cell: &CellValue) -> String {
+    match &cell.value {
+        Some(cell_value::Value::IntVal(v)) => v.to_string(),
+        Some(cell_value::Value::StrVal(s)) => json_string(s),
+        Some(cell_value::Value::NullVal(_)) | None => "null".to_string(),
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn markdown(result: &ResultSet, out: &mut impl Write) -> io::Result<()> {
+    // A `|` inside a cell would end it
+    let escape = |text: &str| text.replace('|', "\\|").replace('\n', "<br>");
+    let header: Vec<String> = result.columns().iter().map(|c| escape(&c.name)).collect();
+    writeln!(out, "| {} |", header.join(" | "))?;
+    let rule: Vec<&str> = (0..result.columns().len())
+        .map(|i| if is_numeric(result, i) { "---:" } else { "---" })
+        .collect();
+    writeln!(out, "| {} |", rule.join(" | "))?;
+    for row in display_rows(result) {
+        let cells: Vec<String> = row.iter().map(|text| escape(text)).collect();
+        writeln!(out, "| {} |", cells.join(" | "))?;
+    }
+    Ok(())
+}
+
+// What a statement did, as the CLI reports it
+pub fn outcome(outcome: Outcome, format: Format, out: &mut impl Write) -> io::Result<()> {
+    match outcome {
+        Outcome::Rows(result) => render(&result, format, out),
+        Outcome::Tables(tables) => table_list(&tables, out),
+        Outcome::Schema(table) => schema(&table, out),
+        Outcome::Check(report) => writeln!(out, "{}", report),
+        Outcome::TableCreated(_) => writeln!(out, "Table created successfully."),
+        Outcome::TableExists(name) => writeln!(out, "Table '{}' already exists, skipping.", name),
+        Outcome::TableDropped(name) => writeln!(out, "Table '{}' dropped.", name),
+        Outcome::NoSuchTable(name) => writeln!(out, "Table '{}' does not exist, skipping.", name),
+        Outcome::TableTruncated(name) => writeln!(out, "Table '{}' truncated.", name),
+        Outcome::IndexCreated(name) => writeln!(out, "Index '{}' created.", name),
+        Outcome::IndexDropped(name) => writeln!(out, "Index '{}' dropped.", name),
+        Outcome::Inserted(count) => writeln!(out, "{} row(s) inserted.", count),
+        Outcome::Updated(count) => writeln!(out, "{} row(s) updated.", count),
+        Outcome::Deleted(count) => writeln!(out, "{} row(s) deleted.", count),
+        Outcome::Imported(count) => writeln!(out, "{} row(s) imported.", count),
+        Outcome::Exported(count) => writeln!(out, "{} row(s) exported.", count),
+        Outcome::BackedUp(count) => writeln!(out, "{} table(s) backed up.", count),
+        Outcome::Restored(count) => writeln!(out, "{} table(s) restored.", count),
+        Outcome::Begun => writeln!(out, "Transaction started."),
+        Outcome::Committed => writeln!(out, "Transaction committed."),
+        Outcome::RolledBack => writeln!(out, "Transaction rolled back."),
+        Outcome::SavepointSet(name) => writeln!(out, "Savepoint '{}' set.", name),
+        Outcome::RolledBackTo(name) => writeln!(out, "Rolled back to savepoint '{}'.", name),
+        Outcome::SavepointReleased(name) => writeln!(out, "Savepoint '{}' released.", name),
+    }
+}
+
+pub fn table_list(tables: &[TableDefinition], out: &mut impl Write) -> io::Result<()> {
+    if tables.is_empty() {
+        return writeln!(out, "No tables in the database.");
+    }
+    writeln!(out, "Tables in the database:")?;
+    for table in tables {
+        writeln!(out, "{}", table.name)?;
+    }
+    Ok(())
+}
+
+// Columns and indexes of a table, written the way CREATE TABLE and CREATE INDEX take them
+pub fn schema(table: &TableDefinition, out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "Schema for table '{}':", table.name)?;
+    for column in &table.columns {
+        write!(out, "  {} {}", column.name, column.col_type().as_str_name())?;
+        if column.col_type() == ColumnType::String {
+            write!(out, "({})", column.length)?;
+        }
+        writeln!(out, "{}", if column.not_null { " NOT NULL" } else { "" })?;
+    }
+    if !table.indexes.is_empty() {
+        writeln!(out, "Indexes:")?;
+        for index in &table.indexes {
+            writeln!(
+                out,
+                "  {}{} ({}) USING {}",
+                index.name,
+                if index.unique { " UNIQUE" } else { "" },
+                index.columns.join(", "),
+                index.method().as_str_name()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db_project::ColumnDefinition;
+
+    fn sample() -> ResultSet {
+        let column = |name: &str, col_type: ColumnType| ColumnDefinition {
+            name: name.to_string(),
+            col_type: col_type as i32,
+            length: 0,
+            not_null: false,
+        };
+        let int = |v| CellValue {
+            value: Some(cell_value::Value::IntVal(v)),
+        };
+        let text = |s: &str| CellValue {
+            value: Some(cell_value::Value::StrVal(s.to_string())),
+        };
+        let null = CellValue {
+            value: Some(cell_value::Value::NullVal(true)),
+        };
+        ResultSet::new(
+            vec![
+                column("id", ColumnType::Int),
+                column("name", ColumnType::String),
+            ],
+            vec![
+                vec![int(1), text("Ann, \"A\"")],
+                vec![int(42), null],
+                vec![int(7), text("tab\there|x")],
+            ],
+        )
+    }
+
+    fn rendered(format: Format) -> String {
+        let mut out = Vec::new();
+        render(&sample(), format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn every_format_renders_the_same_rows() {
+        assert_eq!(
+            rendered(Format::Table),
+            "\
++----+-------------+
+| id | name        |
++----+-------------+
+|  1 | Ann, \"A\"    |
+| 42 | NULL        |
+|  7 | tab\\there|x |
++----+-------------+
+3 row(s).
+"
+        );
+        assert_eq!(
+            rendered(Format::Csv),
+            "id,name\n1,\"Ann, \"\"A\"\"\"\n42,\n7,tab\there|x\n"
+        );
+        assert_eq!(
+            rendered(Format::Tsv),
+            "id\tname\n1\tAnn, \"A\"\n42\t\\N\n7\ttab\\there|x\n"
+        );
+        assert_eq!(
+            rendered(Format::Json),
+            "[\n  {\"id\":1,\"name\":\"Ann, \\\"A\\\"\"},\n  {\"id\":42,\"name\":null},\n  {\"id\":7,\"name\":\"tab\\there|x\"}\n]\n"
+        );
+        assert_eq!(
+            rendered(Format::Jsonl).lines().nth(1),
+            Some("{\"id\":42,\"name\":null}")
+        );
+        assert_eq!(
+            rendered(Format::Markdown),
+            "| id | name |\n| ---: | --- |\n| 1 | Ann, \"A\" |\n| 42 | NULL |\n| 7 | tab\there\\|x |\n"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use crate::database_manager::DatabaseError;
+use crate::generated_types::generated_types::{cell_value, CellValue, ColumnDefinition};
+
+// Rows a query produced together with the columns they hold, in order. How
+// they are shown is up to the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultSet {
+    columns: Vec<ColumnDefinition>,
+    rows: Vec<Vec<CellValue>>,
+}
+
+impl ResultSet {
+    // Every row holds one cell per column
+    pub fn new(columns: Vec<ColumnDefinition>, rows: Vec<Vec<CellValue>>) -> Self {
+        debug_assert!(rows.iter().all(|row| row.len() == columns.len()));
+        ResultSet { columns, rows }
+    }
+
+    pub fn columns(&self) -> &[ColumnDefinition] {
+        &self.columns
+    }
+
+    pub fn rows(&self) -> impl ExactSizeIterator<Item = Row<'_>> {
+        self.rows.iter().map(|cells| Row {
+            columns: &self.columns,
+            cells,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+}
+
+impl IntoIterator for ResultSet {
+    type Item = Vec<CellValue>;
+    type IntoIter = std::vec::IntoIter<Vec<CellValue>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.rows.into_iter()
+    }
+}
+
+// One row of a result set; `get` reads a cell as a Rust value
+#[derive(Debug, Clone, Copy)]
+pub struct Row<'a> {
+    columns: &'a [ColumnDefinition],
+    cells: &'a [CellValue],
+}
+
+impl<'a> Row<'a> {
+    pub fn cells(&self) -> &'a [CellValue] {
+        self.cells
+    }
+
+    // The cell in `column`, given by position or by name, e.g. `row.get::<i32>("id")`.
+    // Read nullable columns as an `Option`.
+    pub fn get<T: FromCell>(&self, column: impl ColumnIndex) -> Result<T, DatabaseError> {
+        let position = column
+            .position(self.columns)
+            .ok_or_else(|| DatabaseError::ColumnDoesNotExist(column.to_string()))?;
+        let cell = &self.cells[position];
+        T::from_cell(cell).ok_or_else(|| DatabaseError::TypeMismatch {
+            column: self.columns[position].name.clone(),
+            expected: T::TYPE_NAME,
+            found: type_name(cell),
+        })
+    }
+}
+
+// How a column of a row is picked out
+pub trait ColumnIndex: ToString {
+    fn position(&self, columns: &[ColumnDefinition]) -> Option<usize>;
+}
+
+impl ColumnIndex for usize {
+    fn position(&self, columns: &[ColumnDefinition]) -> Option<usize> {
+        (*self < columns.len()).then_some(*self)
+    }
+}
+
+impl ColumnIndex for &str {
+    fn position(&self, columns: &[ColumnDefinition]) -> Option<usize> {
+        columns.iter().position(|column| column.name == *self)
+    }
+}
+
+// Rust types a cell can be read as
+pub trait FromCell: Sized {
+    const TYPE_NAME: &'static str;
+
+    // `None` when the cell holds another type
+    fn from_cell(cell: &CellValue) -> Option<Self>;
+}
+
+impl FromCell for i32 {
+    const TYPE_NAME: &'static str = "INT";
+
+    fn from_cell(cell: &CellValue) -> Option<Self> {
+        match cell.value {
+            Some(cell_value::Value::IntVal(v)) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl FromCell for String {
+    const TYPE_NAME: &'static str = "STRING";
+
+    fn from_cell(cell: &CellValue) -> Option<Self> {
+        match &cell.value {
+            Some(cell_value::Value::StrVal(s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromCell> FromCell for Option<T> {
+    const TYPE_NAME: &'static str = T::TYPE_NAME;
+
+    fn from_cell(cell: &CellValue) -> Option<Self> {
+        match cell_text(cell) {
+            None => Some(None),
+            Some(_) => T::from_cell(cell).map(Some),
+        }
+    }
+}
+
+// Rust values a placeholder can be bound to
+pub trait ToCell {
+    fn to_cell(&self) -> CellValue;
+}
+
+impl ToCell for i32 {
+    fn to_cell(&self) -> CellValue {
+        CellValue {
+            value: Some(cell_value::Value::IntVal(*self)),
+        }
+    }
+}
+
+impl ToCell for str {
+    fn to_cell(&self) -> CellValue {
+        CellValue {
+            value: Some(cell_value::Value::StrVal(self.to_string())),
+        }
+    }
+}
+
+impl ToCell for String {
+    fn to_cell(&self) -> CellValue {
+        self.as_str().to_cell()
+    }
+}
+
+// `None` binds NULL
+impl<T: ToCell> ToCell for Option<T> {
+    fn to_cell(&self) -> CellValue {
+        match self {
+            Some(value) => value.to_cell(),
+            None => CellValue {
+                value: Some(cell_value::Value::NullVal(true)),
+            },
+        }
+    }
+}
+
+impl<T: ToCell + ?Sized> ToCell for &T {
+    fn to_cell(&self) -> CellValue {
+        (**self).to_cell()
+    }
+}
+
+pub(crate) fn type_name(cell: &CellValue) -> &'static str {
+    match cell.value {
+        Some(cell_value::Value::IntVal(_)) => "INT",
+        Some(cell_value::Value::StrVal(_)) => "STRING",
+        Some(cell_value::Value::NullVal(_)) | None => "NULL",
+    }
+}
+
+// Text of a cell as the user typed it, or `None` for NULL
+pub fn cell_text(cell: &CellValue) -> Option<String> {
+    match &cell.value {
+        Some(cell_value::Value::IntVal(v)) => Some(v.to_string()),
+        Some(cell_value::Value::StrVal(s)) => Some(s.clone()),
+        Some(cell_value::Value::NullVal(_)) | None => None,
+    }
+}
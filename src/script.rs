@@ -0,0 +1,133 @@
+use crate::render::{self, Format};
+use db_project::{split_statements, Connection, Outcome, Split};
+use std::fmt;
+use std::io;
+
+// Scripts: statements from the command line, a file or stdin, run one after
+// the other with errors reported against the line they start on.
+
+// Outcome of running a script
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub succeeded: usize,
+    pub failed: usize,
+    // Statements left out after a failure with `--bail`
+    pub skipped: usize,
+    // Exit status for the first failure, 0 when there was none
+    pub exit_code: i32,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} statement(s) succeeded, {} failed",
+            self.succeeded, self.failed
+        )?;
+        if self.skipped > 0 {
+            write!(f, ", {} not run", self.skipped)?;
+        }
+        write!(f, ".")
+    }
+}
+
+// Run every statement of `input`, or up to the first failure if `bail` is set.
+// Errors are printed as they happen, prefixed with `source` and the line of
+// the statement when a source is given.
+pub fn run(
+    connection: &Connection,
+    input: &str,
+    source: Option<&str>,
+    format: Format,
+    bail: bool,
+) -> Summary {
+    let Split {
+        mut statements,
+        rest,
+    } = split_statements(input);
+    statements.extend(rest);
+    let mut summary = Summary::default();
+    for (position, statement) in statements.iter().enumerate() {
+        let result = connection.run(&statement.text).and_then(|outcome| {
+            // A check that finds problems fails, as `db_project check` does
+            let problems = matches!(&outcome, Outcome::Check(report) if !report.is_ok());
+            render::outcome(outcome, format, &mut io::stdout().lock())?;
+            Ok(problems)
+        });
+        let exit_code = match result {
+            Ok(false) => {
+                summary.succeeded += 1;
+                continue;
+            }
+            Ok(true) => 1,
+            Err(e) => {
+                match source {
+                    Some(source) => eprintln!("{}:{}: {}", source, statement.line, e),
+                    None => eprintln!("{}", e),
+                }
+                e.exit_code()
+            }
+        };
+        summary.failed += 1;
+        if summary.exit_code == 0 {
+            summary.exit_code = exit_code;
+        }
+        if bail {
+            summary.skipped = statements.len() - position - 1;
+            break;
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use db_project::Database;
+
+    #[test]
+    fn bail_stops_at_the_first_failure() {
+        let dir = temp_dir("script");
+        let db = Database::open(&dir).unwrap().connect().unwrap();
+        let script = "CREATE TABLE t (id INT);
+            INSERT INTO missing VALUES (1);
+            INSERT INTO t VALUES (1);
+            SELEC * FROM t;
+            INSERT INTO t VALUES (2)";
+
+        let summary = run(&db, script, None, Format::Table, true);
+        assert_eq!(
+            (summary.succeeded, summary.failed, summary.skipped),
+            (1, 1, 3)
+        );
+        assert_eq!(summary.exit_code, 1);
+
+        let summary = run(&db, "DROP TABLE t;", None, Format::Table, false);
+        assert_eq!(summary.failed, 0);
+        let summary = run(&db, script, None, Format::Table, false);
+        assert_eq!(
+            (summary.succeeded, summary.failed, summary.skipped),
+            (3, 2, 0)
+        );
+        assert_eq!(summary.exit_code, 1);
+        let summary = run(&db, "SELEC 1;", None, Format::Table, false);
+        assert_eq!(summary.exit_code, 64);
+
+        // A check that finds problems fails the script like the check subcommand
+        let summary = run(&db, "CHECK DATABASE;", None, Format::Table, false);
+        assert_eq!((summary.failed, summary.exit_code), (0, 0));
+        std::fs::write(dir.join("stray.tab"), b"").unwrap();
+        let summary = run(
+            &db,
+            "CHECK DATABASE; SELECT * FROM t;",
+            None,
+            Format::Table,
+            true,
+        );
+        assert_eq!(
+            (summary.failed, summary.skipped, summary.exit_code),
+            (1, 1, 1)
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use crate::render::{self, Format};
+use crate::script;
+use clap::ValueEnum;
+use db_project::{split_statements, Connection};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+
+// Interactive shell run when no SQL is given on the command line. Statements
+// may span several lines and run once a line ends with `;`; lines starting
+// with `.` are meta-commands handled here. Every statement goes to the same
+// `Connection`, so the catalog and buffer pool stay warm between them.
+
+const PROMPT: &str = "db> ";
+const CONTINUATION_PROMPT: &str = "...> ";
+
+// Name of the history file kept in the home directory
+const HISTORY_FILE: &str = ".db_project_history";
+
+const HELP: &str = "\
+.help           Show this message
+.mode [MODE]    Show or set the output mode
+.quit           Leave the shell (also .exit or Ctrl-D)
+.schema [TABLE] Show the schema of TABLE, or of every table
+.tables         List the tables
+.timer on|off   Show how long each statement takes";
+
+struct Shell<'a> {
+    connection: &'a Connection,
+    format: Format,
+    timer: bool,
+    // Text of a statement still being typed
+    statement: String,
+}
+
+// What the shell does after a line
+#[derive(Debug, PartialEq)]
+enum Next {
+    Continue,
+    Quit,
+}
+
+pub fn run(connection: &Connection, format: Format) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Cannot start the shell: {}", e);
+            return;
+        }
+    };
+    let history = history_path();
+    if let Some(history) = &history {
+        // There is no history yet on first use
+        let _ = editor.load_history(history);
+    }
+    let mut shell = Shell::new(connection, format);
+    println!("Enter SQL statements terminated by `;`, or .help for other commands.");
+
+    loop {
+        let prompt = if shell.statement.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C abandons the statement being typed
+            Err(ReadlineError::Interrupted) => {
+                shell.statement.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        };
+        let (next, entries) = shell.handle_line(&line);
+        for entry in entries {
+            let _ = editor.add_history_entry(entry);
+        }
+        if next == Next::Quit {
+            break;
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("Cannot save the shell history: {}", e);
+        }
+    }
+    // Work of a transaction that was never committed is discarded
+    if connection.in_transaction() {
+        match connection.run("ROLLBACK") {
+            Ok(_) => eprintln!("Transaction was not committed and has been rolled back."),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+impl<'a> Shell<'a> {
+    fn new(connection: &'a Connection, format: Format) -> Self {
+        Shell {
+            connection,
+            format,
+            timer: false,
+            statement: String::new(),
+        }
+    }
+
+    // Handle one line of input: a meta-command, or part of a statement that
+    // runs once a line ends it. Returns what to do next and the entries the
+    // line completed for the history.
+    fn handle_line(&mut self, line: &str) -> (Next, Vec<String>) {
+        if self.statement.is_empty() && line.trim_start().starts_with('.') {
+            let next = self.meta_command(line.trim());
+            return (next, vec![line.trim().to_string()]);
+        }
+        if self.statement.is_empty() && line.trim().is_empty() {
+            return (Next::Continue, Vec::new());
+        }
+        self.statement.push_str(line);
+        self.statement.push('\n');
+        let split = split_statements(&self.statement);
+        let mut entries = Vec::new();
+        for complete in split.statements {
+            self.execute(&complete.text);
+            entries.push(complete.text);
+        }
+        // The line break that ended the unfinished part still separates it from the next line
+        self.statement = split.rest.map(|rest| rest.text + "\n").unwrap_or_default();
+        (Next::Continue, entries)
+    }
+
+    fn execute(&self, statement: &str) {
+        let started = Instant::now();
+        script::run(self.connection, statement, None, self.format, false);
+        if self.timer {
+            println!("Run Time: {:.3}s", started.elapsed().as_secs_f64());
+        }
+    }
+
+    fn meta_command(&mut self, line: &str) -> Next {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let argument = words.next();
+        if words.next().is_some() {
+            eprintln!("Too many arguments for {}.", name);
+            return Next::Continue;
+        }
+        let result = match (name, argument) {
+            (".quit" | ".exit", None) => return Next::Quit,
+            (".help", None) => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            (".tables", None) => self
+                .connection
+                .tables()
+                .and_then(|tables| Ok(render::table_list(&tables, &mut io::stdout().lock())?)),
+            (".schema", Some(table)) => self
+                .connection
+                .schema(table)
+                .and_then(|table| Ok(render::schema(&table, &mut io::stdout().lock())?)),
+            (".schema", None) => self.connection.tables().and_then(|tables| {
+                let mut out = io::stdout().lock();
+                Ok(tables
+                    .iter()
+                    .try_for_each(|table| render::schema(table, &mut out))?)
+            }),
+            (".mode", None) => {
+                println!("Output mode: {}.", self.format.name());
+                Ok(())
+            }
+            (".mode", Some(name)) => {
+                match Format::from_str(name, true) {
+                    Ok(format) => self.format = format,
+                    Err(_) => eprintln!(
+                        "Unknown mode '{}'; choose one of: {}.",
+                        name,
+                        Format::value_variants()
+                            .iter()
+                            .map(|format| format.name())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }
+                Ok(())
+            }
+            (".timer", Some("on")) => {
+                self.timer = true;
+                Ok(())
+            }
+            (".timer", Some("off")) => {
+                self.timer = false;
+                Ok(())
+            }
+            _ => {
+                eprintln!("Unknown command or wrong arguments: {}; try .help.", line);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+        Next::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use db_project::Database;
+
+    #[test]
+    fn lines_are_gathered_into_statements_and_meta_commands() {
+        let dir = temp_dir("shell");
+        let db = Database::open(&dir).unwrap().connect().unwrap();
+        let mut shell = Shell::new(&db, Format::Table);
+
+        // A statement runs once a line ends it
+        assert_eq!(
+            shell.handle_line("CREATE TABLE t (id INT)"),
+            (Next::Continue, Vec::new())
+        );
+        assert!(db.tables().unwrap().is_empty());
+        // Inside a statement a line starting with `.` is just more of it
+        let (next, entries) = shell.handle_line(".5 ;");
+        assert_eq!(next, Next::Continue);
+        assert_eq!(entries.len(), 1);
+        assert!(shell.statement.is_empty());
+        assert!(db.tables().unwrap().is_empty());
+        shell.handle_line("CREATE TABLE t");
+        shell.handle_line("(id INT);");
+        assert_eq!(db.tables().unwrap().len(), 1);
+        let (_, entries) =
+            shell.handle_line("INSERT INTO t VALUES (1); INSERT INTO t VALUES (2); SELECT *");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(shell.statement, "SELECT *\n");
+        shell.handle_line("FROM t;");
+        assert_eq!(db.query("SELECT * FROM t").unwrap().len(), 2);
+        assert_eq!(shell.handle_line("   "), (Next::Continue, Vec::new()));
+
+        // Meta-commands change the shell, or are refused without changing it
+        assert_eq!(
+            shell.handle_line("  .mode csv "),
+            (Next::Continue, vec![".mode csv".to_string()])
+        );
+        assert_eq!(shell.format, Format::Csv);
+        shell.handle_line(".mode fancy");
+        assert_eq!(shell.format, Format::Csv);
+        shell.handle_line(".timer on");
+        assert!(shell.timer);
+        shell.handle_line(".timer sometimes");
+        assert!(shell.timer);
+        assert_eq!(shell.handle_line(".frobnicate").0, Next::Continue);
+        assert_eq!(shell.handle_line(".quit now").0, Next::Continue);
+        assert_eq!(shell.handle_line(".quit").0, Next::Quit);
+        assert_eq!(shell.handle_line(".exit").0, Next::Quit);
+    }
+}
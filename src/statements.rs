@@ -0,0 +1,109 @@
+// SQL text holding any number of statements, as read from the command line,
+// a file, stdin or typed into the shell. Statements end with `;`, may span
+// lines and may be interleaved with `--` comments.
+
+// One statement of a script with the line it starts on, for error messages
+#[derive(Debug, PartialEq)]
+pub struct StatementText {
+    pub line: usize,
+    pub text: String,
+}
+
+// Statements of a script; `rest` is text after the last `;`, which the shell
+// waits to see completed and a script runs as its final statement
+#[derive(Debug, PartialEq)]
+pub struct Split {
+    pub statements: Vec<StatementText>,
+    pub rest: Option<StatementText>,
+}
+
+// Split `input` into statements. Comments are dropped and line breaks become
+// spaces, since the parser expects a statement on one line; string literals
+// are kept as written.
+pub fn split(input: &str) -> Split {
+    let mut statements = Vec::new();
+    let mut text = String::new();
+    let mut start = 1;
+    let mut line = 1;
+    let mut in_string = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_string {
+            in_string = c != '\'';
+            text.push(c);
+        } else if c == '-' && chars.peek() == Some(&'-') {
+            // Skip to the line break, which ends the comment
+            while chars.next_if(|&c| c != '\n').is_some() {}
+        } else if c.is_whitespace() {
+            if !text.is_empty() && !text.ends_with(' ') {
+                text.push(' ');
+            }
+        } else {
+            if text.is_empty() {
+                start = line;
+            }
+            in_string = c == '\'';
+            text.push(c);
+            if c == ';' {
+                if text != ";" {
+                    statements.push(StatementText {
+                        line: start,
+                        text: std::mem::take(&mut text),
+                    });
+                }
+                text.clear();
+            }
+        }
+        if c == '\n' {
+            line += 1;
+        }
+    }
+    let rest = Some(text.trim_end())
+        .filter(|text| !text.is_empty())
+        .map(|text| StatementText {
+            line: start,
+            text: text.to_string(),
+        });
+    Split { statements, rest }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statement(line: usize, text: &str) -> StatementText {
+        StatementText {
+            line,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn statements_are_split_at_semicolons_outside_strings() {
+        let script = "\
+-- Set up
+CREATE TABLE t (id INT,
+    name STRING(10));
+
+INSERT INTO t VALUES (1, 'a; -- b'); INSERT INTO t VALUES (2, 'c');;
+SELECT *
+  FROM t -- all of it
+";
+        assert_eq!(
+            split(script),
+            Split {
+                statements: vec![
+                    statement(2, "CREATE TABLE t (id INT, name STRING(10));"),
+                    statement(5, "INSERT INTO t VALUES (1, 'a; -- b');"),
+                    statement(5, "INSERT INTO t VALUES (2, 'c');"),
+                ],
+                rest: Some(statement(6, "SELECT * FROM t")),
+            }
+        );
+        assert_eq!(split("  -- nothing\n").rest, None);
+        assert_eq!(
+            split("INSERT INTO t VALUES ('a;").rest,
+            Some(statement(1, "INSERT INTO t VALUES ('a;"))
+        );
+    }
+}
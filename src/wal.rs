@@ -0,0 +1,429 @@
+use crate::file_lock::{lock_file, LockMode};
+use crate::file_manager::FileManager;
+use crate::generated_types::generated_types::{LogRecord, LogRecordType};
+use crate::page::PAGE_SIZE;
+use prost::Message;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+// Write-ahead log shared by the catalog, index and table files.
+//
+// Every change is appended here before it reaches its file: page writes are
+// logged by the buffer pool and whole-file writes by the `DatabaseManager`.
+// Both carry before and after images. At commit the log is synced, the dirty
+// pages are forced to disk and the log is emptied. After a crash `recover`
+// redoes a committed transaction from its after images or rolls an unfinished
+// one back from its before images.
+//
+// A statement that writes holds an exclusive lock on the log file until it
+// ends, so processes write one at a time and the log only ever holds the
+// records of one transaction. Between statements the log is emptied and given
+// up, and a transaction is then rolled back through the commit log instead.
+// Transactions that change the catalog or set a savepoint keep the log until
+// they end, as they are rolled back from its images. Readers do not take the
+// log and go on while a transaction writes.
+//
+// On disk each record is framed as: length (4) | crc32 of payload (4) | payload.
+// A frame that is short or fails its checksum marks the torn end of the log.
+
+const FRAME_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    pub redone: usize,
+    pub undone: usize,
+    // Transactions whose work was redone or rolled back
+    pub committed: Vec<u64>,
+    pub rolled_back: Vec<u64>,
+}
+
+// How much recovery did, summed over every time a session recovered
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Recovery {
+    pub redone: usize,
+    pub undone: usize,
+}
+
+impl Recovery {
+    pub fn add(&mut self, report: &RecoveryReport) {
+        self.redone += report.redone;
+        self.undone += report.undone;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.redone + self.undone == 0
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Recovered from the log: {} change(s) redone, {} rolled back.",
+            self.redone, self.undone
+        )
+    }
+}
+
+pub struct Wal {
+    file: File,
+    next_lsn: u64,
+    flushed_lsn: u64,
+    active_txn: Option<u64>,
+    // Records of the active transaction, kept for rollback
+    records: Vec<LogRecord>,
+    // Remaining write points before a simulated crash (tests only)
+    crash_countdown: Option<usize>,
+}
+
+// Overwrite one page of `path` in place
+pub fn apply_page(path: &str, page_id: u32, image: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.seek(SeekFrom::Start(page_id as u64 * PAGE_SIZE as u64))?;
+    file.write_all(image)?;
+    file.sync_all()
+}
+
+// Replace `path` with `image`, or remove it when there is no image
+pub fn apply_file(path: &str, image: Option<&Vec<u8>>) -> io::Result<()> {
+    let file_manager = FileManager::new(path);
+    match image {
+        Some(bytes) => file_manager.write_file(bytes),
+        None => file_manager.remove_file(),
+    }
+}
+
+impl Wal {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Wal {
+            file,
+            next_lsn: 1,
+            flushed_lsn: 0,
+            active_txn: None,
+            records: Vec::new(),
+            crash_countdown: None,
+        })
+    }
+
+    // Make the `n`th write point from now fail, and every one after it, as if the
+    // process had died there
+    #[cfg(test)]
+    pub fn crash_after(&mut self, n: usize) {
+        self.crash_countdown = Some(n);
+    }
+
+    // Called before every durable write so tests can cut the sequence short
+    pub fn write_point(&mut self) -> io::Result<()> {
+        if let Some(remaining) = self.crash_countdown.as_mut() {
+            if *remaining <= 1 {
+                *remaining = 0;
+                return Err(io::Error::other("simulated crash"));
+            }
+            *remaining -= 1;
+        }
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.active_txn.is_some()
+    }
+
+    // Read every intact record, stopping at the first torn or corrupt frame
+    fn read_records(&mut self) -> io::Result<Vec<LogRecord>> {
+        let mut buffer = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buffer)?;
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset + FRAME_HEADER_SIZE <= buffer.len() {
+            let length = u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap());
+            let checksum = u32::from_le_bytes(buffer[offset + 4..offset + 8].try_into().unwrap());
+            let start = offset + FRAME_HEADER_SIZE;
+            let end = start + length as usize;
+            if end > buffer.len() || crc32fast::hash(&buffer[start..end]) != checksum {
+                break;
+            }
+            match LogRecord::decode(&buffer[start..end]) {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+            offset = end;
+        }
+        Ok(records)
+    }
+
+    // Wait up to `timeout` until no other process is writing, then keep the log
+    // to ourselves. Returns false if another process still holds it.
+    pub fn lock(&mut self, timeout: Duration) -> io::Result<bool> {
+        lock_file(&self.file, LockMode::Exclusive, timeout)
+    }
+
+    pub fn unlock(&mut self) -> io::Result<()> {
+        self.file.unlock()
+    }
+
+    // Bring the data files to a consistent state using whatever the log holds,
+    // then empty it. Only called with the log locked, so no writer is running
+    // and anything left in it comes from a process that died.
+    pub fn recover(&mut self) -> io::Result<RecoveryReport> {
+        let records = self.read_records()?;
+        let mut report = RecoveryReport::default();
+        if records.is_empty() {
+            return Ok(report);
+        }
+        let finished: HashSet<u64> = records
+            .iter()
+            .filter(|record| matches!(record.kind(), LogRecordType::Commit | LogRecordType::Abort))
+            .map(|record| record.txn_id)
+            .collect();
+        let committed: HashSet<u64> = records
+            .iter()
+            .filter(|record| record.kind() == LogRecordType::Commit)
+            .map(|record| record.txn_id)
+            .collect();
+
+        // Redo committed work in log order, then undo work that never committed or
+        // aborted, newest change first. An aborted transaction was already rolled
+        // back before its ABORT was logged.
+        report.redone = self.apply_images(
+            records.iter().filter(|r| committed.contains(&r.txn_id)),
+            true,
+        )?;
+        report.undone = self.apply_images(
+            records
+                .iter()
+                .rev()
+                .filter(|r| !finished.contains(&r.txn_id)),
+            false,
+        )?;
+        report.committed = committed.into_iter().collect();
+        report.rolled_back = records
+            .iter()
+            .filter(|r| r.kind() == LogRecordType::Begin && !finished.contains(&r.txn_id))
+            .map(|r| r.txn_id)
+            .collect();
+        self.truncate()?;
+        Ok(report)
+    }
+
+    // Write the after images (redo) or before images (undo) of `records` in the
+    // order given; undo must be given the newest change first
+    fn apply_images<'r>(
+        &mut self,
+        records: impl Iterator<Item = &'r LogRecord>,
+        redo: bool,
+    ) -> io::Result<usize> {
+        let mut applied = 0;
+        for record in records {
+            let image = if redo { &record.after } else { &record.before };
+            match record.kind() {
+                LogRecordType::PageWrite => {
+                    self.write_point()?;
+                    let image = image.as_deref().unwrap_or_default();
+                    apply_page(&record.path, record.page_id, image)?;
+                }
+                LogRecordType::FileWrite => {
+                    self.write_point()?;
+                    apply_file(&record.path, image.as_ref())?;
+                }
+                _ => continue,
+            }
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    fn append(&mut self, mut record: LogRecord) -> io::Result<u64> {
+        self.write_point()?;
+        record.lsn = self.next_lsn;
+        self.next_lsn += 1;
+        let payload = record.encode_to_vec();
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&frame)?;
+        let lsn = record.lsn;
+        if matches!(
+            record.kind(),
+            LogRecordType::PageWrite | LogRecordType::FileWrite
+        ) {
+            self.records.push(record);
+        }
+        Ok(lsn)
+    }
+
+    fn record(&self, kind: LogRecordType) -> LogRecord {
+        let mut record = LogRecord {
+            txn_id: self.active_txn.expect("log record outside a transaction"),
+            ..Default::default()
+        };
+        record.set_kind(kind);
+        record
+    }
+
+    // Sync the log to disk if it has not been synced up to `lsn` yet
+    pub fn flush(&mut self, lsn: u64) -> io::Result<()> {
+        if lsn > self.flushed_lsn {
+            self.write_point()?;
+            self.file.sync_data()?;
+            self.flushed_lsn = self.next_lsn - 1;
+        }
+        Ok(())
+    }
+
+    pub fn begin(&mut self, txn_id: u64) -> io::Result<()> {
+        self.active_txn = Some(txn_id);
+        self.records.clear();
+        let record = self.record(LogRecordType::Begin);
+        self.append(record).map(|_| ())
+    }
+
+    // Log a page overwrite; the returned LSN must be flushed before the page is written
+    pub fn log_page_write(
+        &mut self,
+        path: &str,
+        page_id: u32,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    ) -> io::Result<u64> {
+        let mut record = self.record(LogRecordType::PageWrite);
+        record.path = path.to_string();
+        record.page_id = page_id;
+        record.before = Some(before);
+        record.after = Some(after);
+        self.append(record)
+    }
+
+    // Log a whole-file change and sync the log, since the file is written right after
+    pub fn log_file_write(
+        &mut self,
+        path: &str,
+        before: Option<Vec<u8>>,
+        after: Option<Vec<u8>>,
+    ) -> io::Result<()> {
+        let mut record = self.record(LogRecordType::FileWrite);
+        record.path = path.to_string();
+        record.before = before;
+        record.after = after;
+        let lsn = self.append(record)?;
+        self.flush(lsn)
+    }
+
+    // Make the transaction durable. Its pages may still be written back afterwards.
+    pub fn commit(&mut self) -> io::Result<()> {
+        let record = self.record(LogRecordType::Commit);
+        let lsn = self.append(record)?;
+        self.flush(lsn)
+    }
+
+    // Record that the transaction's changes have been rolled back and written out
+    fn abort(&mut self) -> io::Result<()> {
+        let record = self.record(LogRecordType::Abort);
+        let lsn = self.append(record)?;
+        self.flush(lsn)
+    }
+
+    // Empty the log once every change it describes is on disk
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.write_point()?;
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.active_txn = None;
+        self.records.clear();
+        self.flushed_lsn = self.next_lsn - 1;
+        Ok(())
+    }
+
+    // Undo the active transaction on disk and close it with an ABORT record.
+    // The caller must drop every cached page the transaction touched first.
+    pub fn rollback(&mut self) -> io::Result<()> {
+        let records = std::mem::take(&mut self.records);
+        self.apply_images(records.iter().rev(), false)?;
+        self.abort()?;
+        self.truncate()
+    }
+
+    // Position in the active transaction that `rollback_to` can return to
+    pub fn savepoint(&self) -> usize {
+        self.records.len()
+    }
+
+    // Undo the changes made since `savepoint` while keeping the transaction open.
+    // The caller must write out and drop every cached page first. Each undo is
+    // logged as a change of its own, so redoing the transaction after a crash
+    // replays the rollback too, and undoing it reverses the rollback first.
+    pub fn rollback_to(&mut self, savepoint: usize) -> io::Result<()> {
+        let undone = self.records.split_off(savepoint);
+        let mut last_lsn = 0;
+        for record in undone.iter().rev() {
+            let mut compensation = record.clone();
+            std::mem::swap(&mut compensation.before, &mut compensation.after);
+            last_lsn = self.append(compensation)?;
+        }
+        self.records.truncate(savepoint);
+        self.flush(last_lsn)?;
+        self.apply_images(undone.iter().rev(), false)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::fs;
+
+    #[test]
+    fn torn_commit_record_is_rolled_back() {
+        let dir = temp_dir("wal");
+        let log_path = dir.join("dbfile.wal").to_string_lossy().into_owned();
+        let data_path = dir.join("t.tab").to_string_lossy().into_owned();
+        apply_page(&data_path, 0, &[1; PAGE_SIZE]).unwrap();
+
+        let mut wal = Wal::open(&log_path).unwrap();
+        wal.begin(7).unwrap();
+        wal.log_page_write(&data_path, 0, vec![1; PAGE_SIZE], vec![2; PAGE_SIZE])
+            .unwrap();
+        apply_page(&data_path, 0, &[2; PAGE_SIZE]).unwrap();
+        wal.commit().unwrap();
+        drop(wal);
+        // Cut the COMMIT frame short, as a crash during its write would
+        let length = fs::metadata(&log_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(length - 2)
+            .unwrap();
+
+        let mut wal = Wal::open(&log_path).unwrap();
+        assert!(wal.lock(Duration::ZERO).unwrap());
+        let report = wal.recover().unwrap();
+        assert_eq!(
+            report,
+            RecoveryReport {
+                redone: 0,
+                undone: 1,
+                committed: Vec::new(),
+                rolled_back: vec![7],
+            }
+        );
+        assert_eq!(fs::read(&data_path).unwrap(), vec![1; PAGE_SIZE]);
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), 0);
+    }
+}
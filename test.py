@@ -24,10 +24,10 @@ columns = [
 columns_str = ", ".join(columns)
 create_table_command = f"CREATE TABLE {table_name} ({columns_str});"
 
-# Step 1: Execute create table command
-subprocess.run(["./target/release/db_project", create_table_command], check=True)
+# Every statement goes into one script, run by a single process
+statements = [create_table_command]
 
-# Step 2: Insert 1,000 rows of data
+# Step 1: Insert 1,000 rows of data
 def generate_random_string(length=10):
     return ''.join(random.choices(string.ascii_letters, k=length))
 
@@ -49,8 +49,12 @@ for row_id in range(1, 1001):
     # Join values to create the insert statement
     values_str = ", ".join(values)
     insert_command = f"INSERT INTO {table_name} VALUES ({values_str});"
-    subprocess.run(["./target/release/db_project", insert_command], check=True)
+    statements.append(insert_command)
 
-# Step 3: Select all data to verify
+# Step 2: Select all data to verify
 select_command = f"SELECT * FROM {table_name};"
-subprocess.run(["./target/release/db_project", select_command], check=True)
+statements.append(select_command)
+
+# Step 3: Run the script through stdin, stopping at the first error
+script = "\n".join(statements) + "\n"
+subprocess.run(["./target/release/db_project", "--bail"], input=script, text=True, check=True)
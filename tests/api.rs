@@ -0,0 +1,344 @@
+// The engine as an embedding application sees it, through the public API only
+
+#[path = "../src/test_util.rs"]
+mod test_util;
+
+use db_project::{Database, DatabaseError};
+use test_util::temp_dir;
+
+#[test]
+fn statements_report_the_rows_they_change() {
+    let dir = temp_dir("execute");
+    let db = Database::open(&dir).unwrap();
+    let conn = db.connect().unwrap();
+
+    let changed = conn
+        .execute(
+            "CREATE TABLE t (id INT NOT NULL, name STRING(20));
+             INSERT INTO t VALUES (1, 'one');
+             INSERT INTO t VALUES (2, 'two');
+             INSERT INTO t VALUES (3, NULL);",
+        )
+        .unwrap();
+    assert_eq!(changed, 3);
+    assert_eq!(
+        conn.execute("UPDATE t SET name = 'deux' WHERE id = 2")
+            .unwrap(),
+        1
+    );
+    assert_eq!(conn.execute("DELETE FROM t WHERE id = 1;").unwrap(), 1);
+    assert_eq!(conn.tables().unwrap().len(), 1);
+    assert_eq!(conn.schema("t").unwrap().columns.len(), 2);
+
+    // A failing statement stops the rest
+    assert!(matches!(
+        conn.execute("INSERT INTO missing VALUES (1); INSERT INTO t VALUES (4, 'four');"),
+        Err(DatabaseError::TableDoesNotExist(_))
+    ));
+    assert_eq!(conn.query("SELECT * FROM t").unwrap().len(), 2);
+    drop(conn);
+}
+
+#[test]
+fn rows_are_read_as_rust_values() {
+    let dir = temp_dir("query");
+    let conn = Database::open(&dir).unwrap().connect().unwrap();
+    conn.execute(
+        "CREATE TABLE t (id INT, name STRING(20));
+         INSERT INTO t VALUES (1, 'one');
+         INSERT INTO t VALUES (2, NULL);",
+    )
+    .unwrap();
+
+    let result = conn.query("SELECT id, name FROM t").unwrap();
+    let names: Vec<String> = result.columns().iter().map(|c| c.name.clone()).collect();
+    assert_eq!(names, ["id", "name"]);
+    let rows: Vec<(i32, Option<String>)> = result
+        .rows()
+        .map(|row| (row.get("id").unwrap(), row.get(1).unwrap()))
+        .collect();
+    assert_eq!(rows, [(1, Some("one".to_string())), (2, None)]);
+
+    let row = result.rows().nth(1).unwrap();
+    assert!(matches!(
+        row.get::<String>("name"),
+        Err(DatabaseError::TypeMismatch { .. })
+    ));
+    assert!(matches!(
+        row.get::<String>("id"),
+        Err(DatabaseError::TypeMismatch { .. })
+    ));
+    assert!(matches!(
+        row.get::<i32>("age"),
+        Err(DatabaseError::ColumnDoesNotExist(_))
+    ));
+    assert!(matches!(
+        row.get::<i32>(2),
+        Err(DatabaseError::ColumnDoesNotExist(_))
+    ));
+
+    // Only SELECT returns rows; anything else is refused without running it
+    assert!(matches!(
+        conn.query("DELETE FROM t"),
+        Err(DatabaseError::InvalidCommand(_))
+    ));
+    assert!(matches!(
+        conn.query("SELEC * FROM t"),
+        Err(DatabaseError::SyntaxError(_))
+    ));
+    assert_eq!(conn.query("SELECT * FROM t").unwrap().len(), 2);
+    drop(conn);
+}
+
+#[test]
+fn connections_share_committed_work_only() {
+    let dir = temp_dir("connections");
+    let db = Database::open(&dir).unwrap();
+    let first = db.connect().unwrap();
+    first
+        .execute("CREATE TABLE t (id INT); INSERT INTO t VALUES (1);")
+        .unwrap();
+
+    first.execute("BEGIN; INSERT INTO t VALUES (2);").unwrap();
+    assert!(first.in_transaction());
+    let second = db.connect().unwrap();
+    assert_eq!(second.query("SELECT * FROM t").unwrap().len(), 1);
+
+    // Dropping a connection rolls back what it left uncommitted
+    drop(first);
+    assert_eq!(second.query("SELECT * FROM t").unwrap().len(), 1);
+    drop(second);
+
+    let reopened = Database::open(&dir).unwrap().connect().unwrap();
+    assert_eq!(reopened.query("SELECT * FROM t").unwrap().len(), 1);
+    assert!(db.check().unwrap().is_ok());
+    drop(reopened);
+}
+
+#[test]
+fn prepared_statements_take_bound_values() {
+    let dir = temp_dir("prepare");
+    let conn = Database::open(&dir).unwrap().connect().unwrap();
+    conn.execute("CREATE TABLE t (id INT NOT NULL, name STRING(20))")
+        .unwrap();
+
+    let mut insert = conn.prepare("INSERT INTO t VALUES (?, ?)").unwrap();
+    assert_eq!(insert.parameter_count(), 2);
+    // Quotes need no escaping when bound
+    for (id, name) in [
+        (1, Some("it's")),
+        (2, None),
+        (3, Some("x'); DROP TABLE t;--")),
+    ] {
+        assert_eq!(
+            insert
+                .bind(1, id)
+                .unwrap()
+                .bind(2, name)
+                .unwrap()
+                .execute()
+                .unwrap(),
+            1
+        );
+    }
+
+    let mut select = conn
+        .prepare("SELECT name FROM t WHERE id >= $1 AND id < $2")
+        .unwrap();
+    let names = |select: &db_project::Statement| -> Vec<Option<String>> {
+        let result = select.query().unwrap();
+        result.rows().map(|row| row.get(0).unwrap()).collect()
+    };
+    select.bind(1, 1).unwrap().bind(2, 3).unwrap();
+    assert_eq!(names(&select), [Some("it's".to_string()), None]);
+    // Values stay bound, so only the changed one needs binding again
+    select.bind(1, 2).unwrap();
+    assert_eq!(names(&select), [None]);
+    select.bind(2, 4).unwrap();
+    assert_eq!(
+        names(&select),
+        [None, Some("x'); DROP TABLE t;--".to_string())]
+    );
+
+    // `?` takes the number after the highest placeholder before it
+    let mut update = conn.prepare("UPDATE t SET name = ? WHERE id = ?").unwrap();
+    assert_eq!(update.parameter_count(), 2);
+    assert_eq!(
+        update
+            .bind(1, "two")
+            .unwrap()
+            .bind(2, 2)
+            .unwrap()
+            .execute()
+            .unwrap(),
+        1
+    );
+    let delete = conn
+        .prepare("DELETE FROM t WHERE id = $2 AND name = ?")
+        .unwrap();
+    assert_eq!(delete.parameter_count(), 3);
+    assert_eq!(
+        conn.query("SELECT * FROM t WHERE name = 'two'")
+            .unwrap()
+            .len(),
+        1
+    );
+    drop(conn);
+}
+
+#[test]
+fn bound_values_are_checked_against_their_columns() {
+    let dir = temp_dir("bind");
+    let conn = Database::open(&dir).unwrap().connect().unwrap();
+    conn.execute("CREATE TABLE t (id INT NOT NULL, name STRING(20))")
+        .unwrap();
+
+    let mut insert = conn.prepare("INSERT INTO t VALUES ($1, $2)").unwrap();
+    assert!(matches!(
+        insert.bind(1, "one"),
+        Err(DatabaseError::ParameterTypeMismatch {
+            number: 1,
+            expected: "INT",
+            found: "STRING",
+            ..
+        })
+    ));
+    assert!(matches!(
+        insert.bind(1, None::<i32>),
+        Err(DatabaseError::ParameterTypeMismatch { found: "NULL", .. })
+    ));
+    assert!(matches!(
+        insert.bind(3, 1),
+        Err(DatabaseError::NoSuchParameter {
+            number: 3,
+            count: 2
+        })
+    ));
+    assert!(matches!(
+        insert.bind(0, 1),
+        Err(DatabaseError::NoSuchParameter { number: 0, .. })
+    ));
+    // Strings must fit the column they are stored in
+    assert!(matches!(
+        insert.bind(2, "a name longer than twenty"),
+        Err(DatabaseError::ParameterTooLong {
+            number: 2,
+            length: 20,
+            ..
+        })
+    ));
+    insert.bind(1, 1).unwrap();
+    assert!(matches!(
+        insert.execute(),
+        Err(DatabaseError::ParameterNotBound(2))
+    ));
+    assert_eq!(insert.bind(2, None::<&str>).unwrap().execute().unwrap(), 1);
+
+    // NULL may still be compared with a NOT NULL column
+    let mut select = conn.prepare("SELECT * FROM t WHERE id = ?").unwrap();
+    assert_eq!(
+        select.bind(1, None::<i32>).unwrap().query().unwrap().len(),
+        0
+    );
+    assert!(matches!(select.execute(), Ok(0)));
+
+    assert!(matches!(
+        conn.prepare("SELECT * FROM t WHERE age = ?"),
+        Err(DatabaseError::ColumnDoesNotExist(_))
+    ));
+    assert!(matches!(
+        conn.prepare("SELECT * FROM missing WHERE id = ?"),
+        Err(DatabaseError::TableDoesNotExist(_))
+    ));
+    // A `$n` past the number of placeholders is a syntax error, not a
+    // request for that many of them
+    assert!(matches!(
+        conn.prepare("SELECT * FROM t WHERE id = $4000000000"),
+        Err(DatabaseError::SyntaxError(_))
+    ));
+    assert!(matches!(
+        conn.prepare("UPDATE t SET name = $3 WHERE id = $1"),
+        Err(DatabaseError::SyntaxError(_))
+    ));
+    // Only stored values must fit, compared ones just match nothing
+    let mut select = conn.prepare("SELECT * FROM t WHERE name = ?").unwrap();
+    assert_eq!(
+        select
+            .bind(1, "a name longer than twenty")
+            .unwrap()
+            .query()
+            .unwrap()
+            .len(),
+        0
+    );
+    // Placeholders only take values through a prepared statement
+    assert!(matches!(
+        conn.execute("DELETE FROM t WHERE id = ?"),
+        Err(DatabaseError::ParameterNotBound(1))
+    ));
+    assert_eq!(conn.query("SELECT * FROM t").unwrap().len(), 1);
+    drop(conn);
+}
+
+#[test]
+fn a_dump_rebuilds_the_same_database() {
+    let dir = temp_dir("dump");
+    let conn = Database::open(&dir).unwrap().connect().unwrap();
+    conn.execute(
+        "CREATE TABLE people (id INT NOT NULL, name STRING(40) NOT NULL, note STRING);
+         CREATE UNIQUE INDEX people_id ON people (id);
+         CREATE INDEX people_name ON people (name, note) USING HASH;
+         CREATE TABLE empty (x INT);",
+    )
+    .unwrap();
+    let awkward = [
+        Some("it's"),
+        Some(""),
+        None,
+        Some("two\nlines; -- not a comment"),
+        Some("''"),
+        Some("caf\u{e9}"),
+    ];
+    let mut insert = conn.prepare("INSERT INTO people VALUES (?, ?, ?)").unwrap();
+    conn.execute("BEGIN").unwrap();
+    for id in -5..245 {
+        let id = if id == -5 { i32::MIN } else { id };
+        let note = awkward[id.rem_euclid(awkward.len() as i32) as usize];
+        insert.bind(1, id).unwrap();
+        insert.bind(2, format!("person {}", id)).unwrap();
+        insert.bind(3, note).unwrap().execute().unwrap();
+    }
+    conn.execute("COMMIT").unwrap();
+
+    let mut dump = Vec::new();
+    conn.dump(&mut dump).unwrap();
+    let dump = String::from_utf8(dump).unwrap();
+    // Rows go in batches
+    assert_eq!(dump.matches("INSERT INTO people").count(), 3);
+    assert!(!dump.contains("INSERT INTO empty"));
+
+    let copy_dir = temp_dir("dump_copy");
+    let copy = Database::open(&copy_dir).unwrap().connect().unwrap();
+    assert_eq!(copy.execute(&dump).unwrap(), 250);
+    let mut again = Vec::new();
+    copy.dump(&mut again).unwrap();
+    assert_eq!(String::from_utf8(again).unwrap(), dump);
+    assert_eq!(copy.tables().unwrap(), conn.tables().unwrap());
+    assert_eq!(
+        copy.query("SELECT * FROM people").unwrap(),
+        conn.query("SELECT * FROM people").unwrap()
+    );
+
+    // A dump taken inside a transaction sees its changes
+    conn.execute("BEGIN; DELETE FROM people WHERE id > 0;")
+        .unwrap();
+    let mut dump = Vec::new();
+    conn.dump(&mut dump).unwrap();
+    assert_eq!(
+        String::from_utf8(dump).unwrap().matches("'person ").count(),
+        6
+    );
+    assert!(conn.in_transaction());
+    drop(conn);
+    drop(copy);
+}